@@ -9,6 +9,35 @@ use std::cell::RefCell;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+// The IDs of the memories in the MemoryManager. They must not change once the
+// canister is deployed; new state takes the next free ID.
+const MEMORY_ID_DOMAIN_SEPARATOR: u8 = 0;
+const MEMORY_ID_ACCESS_CONTROL: u8 = 1;
+const MEMORY_ID_SHARED_KEYS: u8 = 2;
+const MEMORY_ID_ENCRYPTED_MAPS: u8 = 3;
+const MEMORY_ID_LONG_NAMES: u8 = 4;
+const MEMORY_ID_FROZEN_MAPS: u8 = 5;
+const MEMORY_ID_RECOVERY_GUARDIANS: u8 = 6;
+const MEMORY_ID_PENDING_RECOVERIES: u8 = 7;
+const MEMORY_ID_RECOVERY_SUCCESSORS: u8 = 8;
+const MEMORY_ID_RECOVERY_PREDECESSORS: u8 = 9;
+const MEMORY_ID_PENDING_MIGRATIONS: u8 = 10;
+const MEMORY_ID_MIGRATED_PRINCIPALS: u8 = 11;
+const MEMORY_ID_MIGRATION_PREDECESSORS: u8 = 12;
+const MEMORY_ID_PUBLIC_MAPS: u8 = 13;
+const MEMORY_ID_INBOX_MESSAGE_IDS: u8 = 14;
+const MEMORY_ID_INBOX_MESSAGES: u8 = 15;
+const MEMORY_ID_INBOX_SENDERS: u8 = 16;
+const MEMORY_ID_MAP_TOMBSTONES: u8 = 17;
+
+/// The delay after which an owner who froze a map for themselves as well can
+/// unfreeze it.
+const UNFREEZE_DELAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// The delay during which an owner can cancel an approved recovery of their
+/// maps.
+const RECOVERY_DELAY_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
@@ -20,7 +49,33 @@ fn memory(id: u8) -> Memory {
 
 ic_vetkeys::export_encrypted_maps_canister!(
     "encrypted_maps_app",
-    [memory(0), memory(1), memory(2), memory(3)],
+    [
+        memory(MEMORY_ID_DOMAIN_SEPARATOR),
+        memory(MEMORY_ID_ACCESS_CONTROL),
+        memory(MEMORY_ID_SHARED_KEYS),
+        memory(MEMORY_ID_ENCRYPTED_MAPS),
+    ],
+    long_names(memory(MEMORY_ID_LONG_NAMES)),
+    key_freezing(memory(MEMORY_ID_FROZEN_MAPS), UNFREEZE_DELAY_NS),
+    recovery(
+        memory(MEMORY_ID_RECOVERY_GUARDIANS),
+        memory(MEMORY_ID_PENDING_RECOVERIES),
+        memory(MEMORY_ID_RECOVERY_SUCCESSORS),
+        memory(MEMORY_ID_RECOVERY_PREDECESSORS),
+        RECOVERY_DELAY_NS,
+    ),
+    migrations(
+        memory(MEMORY_ID_PENDING_MIGRATIONS),
+        memory(MEMORY_ID_MIGRATED_PRINCIPALS),
+        memory(MEMORY_ID_MIGRATION_PREDECESSORS),
+    ),
+    public_maps(memory(MEMORY_ID_PUBLIC_MAPS)),
+    inbox(
+        memory(MEMORY_ID_INBOX_MESSAGE_IDS),
+        memory(MEMORY_ID_INBOX_MESSAGES),
+        memory(MEMORY_ID_INBOX_SENDERS),
+    ),
+    map_deletion(memory(MEMORY_ID_MAP_TOMBSTONES), false),
 );
 
 ic_cdk::export_candid!();
@@ -17,11 +17,13 @@ use strum::IntoEnumIterator;
 fn should_obtain_verification_key() {
     let rng = &mut reproducible_rng();
     let env = TestEnvironment::new(rng);
-    let verification_key: VetKeyVerificationKey = env.update(
-        env.principal_0,
-        "get_vetkey_verification_key",
-        encode_one(()).unwrap(),
-    );
+    let verification_key = env
+        .update::<Result<VetKeyVerificationKey, String>>(
+            env.principal_0,
+            "get_vetkey_verification_key",
+            encode_one(()).unwrap(),
+        )
+        .unwrap();
     assert_eq!(verification_key.as_ref().len(), 96);
     assert_ne!(verification_key, VetKeyVerificationKey::from(vec![0; 96]));
 }
@@ -51,11 +53,13 @@ fn encrypted_vetkey_should_validate() {
     let rng = &mut reproducible_rng();
     let env = TestEnvironment::new(rng);
 
-    let verification_key_bytes: VetKeyVerificationKey = env.update(
-        env.principal_0,
-        "get_vetkey_verification_key",
-        encode_one(()).unwrap(),
-    );
+    let verification_key_bytes = env
+        .update::<Result<VetKeyVerificationKey, String>>(
+            env.principal_0,
+            "get_vetkey_verification_key",
+            encode_one(()).unwrap(),
+        )
+        .unwrap();
 
     let map_owner = env.principal_0;
     let map_name = random_map_name(rng);
@@ -87,11 +91,13 @@ fn map_sharing_should_work() {
     let rng = &mut reproducible_rng();
     let env = TestEnvironment::new(rng);
 
-    let verification_key_bytes: VetKeyVerificationKey = env.update(
-        env.principal_0,
-        "get_vetkey_verification_key",
-        encode_one(()).unwrap(),
-    );
+    let verification_key_bytes = env
+        .update::<Result<VetKeyVerificationKey, String>>(
+            env.principal_0,
+            "get_vetkey_verification_key",
+            encode_one(()).unwrap(),
+        )
+        .unwrap();
 
     let map_owner = env.principal_0;
     let map_name = random_map_name(rng);
@@ -1094,8 +1100,7 @@ fn load_encrypted_maps_example_canister_wasm() -> Vec<u8> {
         Ok(path) if !path.is_empty() => {
             assert!(
                 Path::new(&path).exists(),
-                "CUSTOM_WASM_PATH is set to '{}' but the file does not exist; run `make compile-wasm` first",
-                path
+                "CUSTOM_WASM_PATH is set to '{path}' but the file does not exist; run `make compile-wasm` first"
             );
             path
         }
@@ -1,21 +1,65 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
 
 use candid::Principal;
 use ic_cdk::{init, post_upgrade, query, update};
 use ic_cdk_management_canister::{VetKDCurve, VetKDKeyId};
+use ic_cdk_timers::TimerId;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::Blob;
 use ic_stable_structures::DefaultMemoryImpl;
-use ic_vetkeys::key_manager::{KeyManager, VetKey, VetKeyVerificationKey};
-use ic_vetkeys::types::{AccessRights, ByteBuf, TransportKey};
+use ic_vetkeys::key_manager::{
+    BackupChunk, BackupCursor, CallPolicy, CertifiedResponse, FreezeConfig, FreezeState,
+    InboxConfig, InboxMessage, InheritanceConfig, InheritancePlan, KeyDeletionConfig, KeyManager,
+    KeyTombstone, PendingRecovery, RecoveryConfig, RecoveryGuardians, RecoveryStatus, VetKey,
+    VetKeyVerificationKey,
+};
+use ic_vetkeys::types::{AccessRights, ByteBuf, Page, TransportKey};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
+type CandidKeyId = (Principal, ByteBuf);
+
+// The IDs of the memories in the MemoryManager. They must not change once the
+// canister is deployed; new state takes the next free ID.
+const MEMORY_ID_CONFIG: u8 = 0;
+const MEMORY_ID_ACCESS_CONTROL: u8 = 1;
+const MEMORY_ID_SHARED_KEYS: u8 = 2;
+const MEMORY_ID_LONG_NAMES: u8 = 3;
+const MEMORY_ID_FROZEN_KEYS: u8 = 4;
+const MEMORY_ID_RECOVERY_GUARDIANS: u8 = 5;
+const MEMORY_ID_PENDING_RECOVERIES: u8 = 6;
+const MEMORY_ID_RECOVERY_SUCCESSORS: u8 = 7;
+const MEMORY_ID_RECOVERY_PREDECESSORS: u8 = 8;
+const MEMORY_ID_INHERITANCE_PLANS: u8 = 9;
+const MEMORY_ID_INHERITANCE_GRANTS: u8 = 10;
+const MEMORY_ID_DUE_INHERITANCE_PLANS: u8 = 11;
+const MEMORY_ID_PENDING_MIGRATIONS: u8 = 12;
+const MEMORY_ID_MIGRATED_PRINCIPALS: u8 = 13;
+const MEMORY_ID_MIGRATION_PREDECESSORS: u8 = 14;
+const MEMORY_ID_PUBLIC_KEYS: u8 = 15;
+const MEMORY_ID_INBOX_MESSAGE_IDS: u8 = 16;
+const MEMORY_ID_INBOX_MESSAGES: u8 = 17;
+const MEMORY_ID_INBOX_SENDERS: u8 = 18;
+const MEMORY_ID_KEY_TOMBSTONES: u8 = 19;
+
+/// The delay after which an owner who froze a vetKey for themselves as well
+/// can unfreeze it.
+const UNFREEZE_DELAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// The delay during which an owner can cancel an approved recovery of their
+/// vetKeys.
+const RECOVERY_DELAY_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+/// The shortest period of inactivity after which beneficiaries inherit access
+/// rights.
+const MIN_INACTIVITY_PERIOD_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
     static KEY_MANAGER: RefCell<Option<KeyManager<AccessRights>>> =
         const { RefCell::new(None) };
+    static INHERITANCE_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
 }
 
 #[init]
@@ -46,15 +90,61 @@ fn setup(key_name: String) {
         curve: VetKDCurve::Bls12_381_G2,
         name: key_name,
     };
-    KEY_MANAGER.with_borrow_mut(|km| {
-        km.replace(KeyManager::init(
-            "key_manager_app",
-            key_id,
-            id_to_memory(0),
-            id_to_memory(1),
-            id_to_memory(2),
-        ))
+    let mut key_manager = KeyManager::init(
+        "key_manager_app",
+        key_id,
+        id_to_memory(MEMORY_ID_CONFIG),
+        id_to_memory(MEMORY_ID_ACCESS_CONTROL),
+        id_to_memory(MEMORY_ID_SHARED_KEYS),
+    );
+    key_manager.enable_long_names(id_to_memory(MEMORY_ID_LONG_NAMES));
+    key_manager.enable_certified_queries();
+    key_manager.enable_key_freezing(
+        FreezeConfig {
+            unfreeze_delay_ns: UNFREEZE_DELAY_NS,
+        },
+        id_to_memory(MEMORY_ID_FROZEN_KEYS),
+    );
+    key_manager.enable_recovery(
+        RecoveryConfig {
+            recovery_delay_ns: RECOVERY_DELAY_NS,
+        },
+        id_to_memory(MEMORY_ID_RECOVERY_GUARDIANS),
+        id_to_memory(MEMORY_ID_PENDING_RECOVERIES),
+        id_to_memory(MEMORY_ID_RECOVERY_SUCCESSORS),
+        id_to_memory(MEMORY_ID_RECOVERY_PREDECESSORS),
+    );
+    key_manager.enable_inheritance(
+        InheritanceConfig {
+            min_inactivity_period_ns: MIN_INACTIVITY_PERIOD_NS,
+        },
+        id_to_memory(MEMORY_ID_INHERITANCE_PLANS),
+        id_to_memory(MEMORY_ID_INHERITANCE_GRANTS),
+        id_to_memory(MEMORY_ID_DUE_INHERITANCE_PLANS),
+    );
+    key_manager.enable_migrations(
+        id_to_memory(MEMORY_ID_PENDING_MIGRATIONS),
+        id_to_memory(MEMORY_ID_MIGRATED_PRINCIPALS),
+        id_to_memory(MEMORY_ID_MIGRATION_PREDECESSORS),
+    );
+    key_manager.enable_public_keys(id_to_memory(MEMORY_ID_PUBLIC_KEYS));
+    key_manager.enable_inbox(
+        InboxConfig::default(),
+        id_to_memory(MEMORY_ID_INBOX_MESSAGE_IDS),
+        id_to_memory(MEMORY_ID_INBOX_MESSAGES),
+        id_to_memory(MEMORY_ID_INBOX_SENDERS),
+    );
+    key_manager.enable_key_deletion(
+        KeyDeletionConfig::default(),
+        id_to_memory(MEMORY_ID_KEY_TOMBSTONES),
+    );
+    key_manager.set_call_policy(CallPolicy {
+        max_attempts: 3,
+        ..Default::default()
     });
+    KEY_MANAGER.with_borrow_mut(|km| km.replace(key_manager));
+    update_certified_data();
+    schedule_inheritance();
 }
 
 #[query]
@@ -64,11 +154,35 @@ fn get_accessible_shared_key_ids() -> Vec<(Principal, ByteBuf)> {
             .unwrap()
             .get_accessible_shared_key_ids(ic_cdk::api::msg_caller())
             .into_iter()
-            .map(|key_id| (key_id.0, ByteBuf::from(key_id.1.as_ref().to_vec())))
+            .map(key_id_to_candid)
             .collect()
     })
 }
 
+#[query]
+fn get_accessible_shared_key_ids_paginated(
+    start_after: Option<CandidKeyId>,
+    limit: u32,
+) -> Result<Page<CandidKeyId, CandidKeyId>, String> {
+    let start_after = match start_after {
+        Some((key_owner, key_name)) => Some((key_owner, bytebuf_to_blob(key_name)?)),
+        None => None,
+    };
+    let page = KEY_MANAGER.with_borrow(|km| {
+        km.as_ref()
+            .unwrap()
+            .get_accessible_shared_key_ids_paginated(
+                ic_cdk::api::msg_caller(),
+                start_after,
+                limit as usize,
+            )
+    });
+    Ok(Page {
+        items: page.items.into_iter().map(key_id_to_candid).collect(),
+        next_cursor: page.next_cursor.map(key_id_to_candid),
+    })
+}
+
 #[query]
 fn get_shared_user_access_for_key(
     key_owner: Principal,
@@ -83,26 +197,135 @@ fn get_shared_user_access_for_key(
     })
 }
 
-#[update]
-async fn get_vetkey_verification_key() -> VetKeyVerificationKey {
+#[query]
+fn get_shared_user_access_for_key_paginated(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    start_after: Option<Principal>,
+    limit: u32,
+) -> Result<Page<(Principal, AccessRights), Principal>, String> {
+    let key_name = bytebuf_to_blob(key_name)?;
+    let key_id = (key_owner, key_name);
+    KEY_MANAGER.with_borrow(|km| {
+        km.as_ref()
+            .unwrap()
+            .get_shared_user_access_for_key_paginated(
+                ic_cdk::api::msg_caller(),
+                key_id,
+                start_after,
+                limit as usize,
+            )
+    })
+}
+
+#[query]
+fn get_owned_shared_key_ids() -> Vec<CandidKeyId> {
     KEY_MANAGER
-        .with_borrow(|km| km.as_ref().unwrap().get_vetkey_verification_key())
-        .await
+        .with_borrow(|km| {
+            km.as_ref()
+                .unwrap()
+                .get_owned_shared_key_ids(ic_cdk::api::msg_caller())
+        })
+        .into_iter()
+        .map(key_id_to_candid)
+        .collect()
 }
 
-#[update]
+#[query]
+fn get_all_grants_by_owner() -> Vec<(CandidKeyId, Principal, AccessRights)> {
+    KEY_MANAGER
+        .with_borrow(|km| {
+            km.as_ref()
+                .unwrap()
+                .get_all_grants_by_owner(ic_cdk::api::msg_caller())
+        })
+        .into_iter()
+        .map(|(key_id, user, access_rights)| (key_id_to_candid(key_id), user, access_rights))
+        .collect()
+}
+
+#[update(guard = "record_activity")]
+async fn get_vetkey_verification_key() -> Result<VetKeyVerificationKey, String> {
+    let (cached_verification_key, fetch_verification_key) = KEY_MANAGER.with_borrow(|km| {
+        let km = km.as_ref().unwrap();
+        (
+            km.config.get().verification_key.clone(),
+            km.fetch_vetkey_verification_key(),
+        )
+    });
+    if let Some(verification_key) = cached_verification_key {
+        return Ok(verification_key);
+    }
+    let verification_key = fetch_verification_key.await.map_err(|e| e.to_string())?;
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .cache_vetkey_verification_key(ic_cdk::api::canister_self(), verification_key.clone())
+    })?;
+    Ok(verification_key)
+}
+
+#[query]
+fn get_cached_vetkey_verification_key() -> Option<VetKeyVerificationKey> {
+    KEY_MANAGER.with_borrow(|km| {
+        km.as_ref()
+            .unwrap()
+            .get_cached_vetkey_verification_key(ic_cdk::api::canister_self())
+    })
+}
+
+#[update(guard = "record_activity")]
 async fn get_encrypted_vetkey(
     key_owner: Principal,
     key_name: ByteBuf,
     transport_key: TransportKey,
 ) -> Result<VetKey, String> {
-    let key_name = bytebuf_to_blob(key_name)?;
-    let key_id = (key_owner, key_name);
+    KEY_MANAGER
+        .with_borrow(|km| {
+            km.as_ref().unwrap().get_encrypted_vetkey_by_name(
+                ic_cdk::api::msg_caller(),
+                key_owner,
+                key_name.as_ref(),
+                transport_key,
+            )
+        })?
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[update(guard = "record_activity")]
+async fn get_encrypted_vetkey_for_purpose(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    purpose: ByteBuf,
+    transport_key: TransportKey,
+) -> Result<VetKey, String> {
+    KEY_MANAGER
+        .with_borrow(|km| {
+            km.as_ref()
+                .unwrap()
+                .get_encrypted_vetkey_by_name_for_purpose(
+                    ic_cdk::api::msg_caller(),
+                    key_owner,
+                    key_name.as_ref(),
+                    purpose.as_ref(),
+                    transport_key,
+                )
+        })?
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[update(guard = "record_activity")]
+async fn get_encrypted_vetkeys(
+    key_ids: Vec<(Principal, ByteBuf)>,
+    transport_key: TransportKey,
+) -> Result<Vec<Result<VetKey, String>>, String> {
     Ok(KEY_MANAGER
         .with_borrow(|km| {
-            km.as_ref().unwrap().get_encrypted_vetkey(
+            km.as_ref().unwrap().get_encrypted_vetkeys_by_name(
                 ic_cdk::api::msg_caller(),
-                key_id,
+                &key_ids,
                 transport_key,
             )
         })?
@@ -124,39 +347,547 @@ fn get_user_rights(
     })
 }
 
-#[update]
+#[query]
+fn get_certified_user_rights(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    user: Principal,
+) -> Result<CertifiedResponse<Option<AccessRights>>, String> {
+    let key_name = bytebuf_to_blob(key_name)?;
+    let key_id = (key_owner, key_name);
+    KEY_MANAGER.with_borrow(|km| {
+        km.as_ref().unwrap().get_certified_user_rights(
+            ic_cdk::api::msg_caller(),
+            key_id,
+            user,
+            data_certificate()?,
+        )
+    })
+}
+
+#[query]
+fn get_certified_shared_user_access_for_key(
+    key_owner: Principal,
+    key_name: ByteBuf,
+) -> Result<CertifiedResponse<Vec<(Principal, AccessRights)>>, String> {
+    let key_name = bytebuf_to_blob(key_name)?;
+    let key_id = (key_owner, key_name);
+    KEY_MANAGER.with_borrow(|km| {
+        km.as_ref()
+            .unwrap()
+            .get_certified_shared_user_access_for_key(
+                ic_cdk::api::msg_caller(),
+                key_id,
+                data_certificate()?,
+            )
+    })
+}
+
+#[query]
+fn get_certified_accessible_shared_key_ids() -> Result<CertifiedResponse<Vec<CandidKeyId>>, String>
+{
+    let response = KEY_MANAGER.with_borrow(|km| {
+        km.as_ref()
+            .unwrap()
+            .get_certified_accessible_shared_key_ids(ic_cdk::api::msg_caller(), data_certificate()?)
+    })?;
+    Ok(CertifiedResponse {
+        value: response.value.into_iter().map(key_id_to_candid).collect(),
+        certificate: response.certificate,
+        witness: response.witness,
+    })
+}
+
+#[update(guard = "record_activity")]
 fn set_user_rights(
     key_owner: Principal,
     key_name: ByteBuf,
     user: Principal,
     access_rights: AccessRights,
+) -> Result<Option<AccessRights>, String> {
+    let key_id = (key_owner, bytebuf_to_blob(key_name.clone())?);
+    let result = KEY_MANAGER.with_borrow_mut(|km| {
+        let km = km.as_mut().unwrap();
+        let result = km.set_user_rights(ic_cdk::api::msg_caller(), key_id, user, access_rights)?;
+        km.register_name(key_name.as_ref())?;
+        Ok(result)
+    });
+    update_certified_data();
+    result
+}
+
+#[update(guard = "record_activity")]
+fn remove_user(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    user: Principal,
 ) -> Result<Option<AccessRights>, String> {
     let key_name = bytebuf_to_blob(key_name)?;
     let key_id = (key_owner, key_name);
+    let result = KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .remove_user(ic_cdk::api::msg_caller(), key_id, user)
+    });
+    update_certified_data();
+    result
+}
+
+#[update(guard = "record_activity")]
+fn revoke_user_everywhere(user: Principal) -> Result<Vec<(CandidKeyId, AccessRights)>, String> {
+    let result = KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .revoke_user_everywhere(ic_cdk::api::msg_caller(), user)
+    });
+    update_certified_data();
+    Ok(result?
+        .into_iter()
+        .map(|(key_id, access_rights)| (key_id_to_candid(key_id), access_rights))
+        .collect())
+}
+
+#[update(guard = "record_activity")]
+fn freeze_key(key_owner: Principal, key_name: ByteBuf, includes_owner: bool) -> Result<(), String> {
+    let key_id = (key_owner, bytebuf_to_blob(key_name)?);
     KEY_MANAGER.with_borrow_mut(|km| {
         km.as_mut()
             .unwrap()
-            .set_user_rights(ic_cdk::api::msg_caller(), key_id, user, access_rights)
+            .freeze_key(ic_cdk::api::msg_caller(), key_id, includes_owner)
     })
 }
 
-#[update]
-fn remove_user(
+#[update(guard = "record_activity")]
+fn unfreeze_key(key_owner: Principal, key_name: ByteBuf) -> Result<Option<u64>, String> {
+    let key_id = (key_owner, bytebuf_to_blob(key_name)?);
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .unfreeze_key(ic_cdk::api::msg_caller(), key_id, ic_cdk::api::time())
+    })
+}
+
+#[query]
+fn get_freeze_state(
     key_owner: Principal,
     key_name: ByteBuf,
-    user: Principal,
+) -> Result<Option<FreezeState>, String> {
+    let key_id = (key_owner, bytebuf_to_blob(key_name)?);
+    KEY_MANAGER.with_borrow(|km| {
+        km.as_ref()
+            .unwrap()
+            .get_freeze_state(ic_cdk::api::msg_caller(), key_id)
+    })
+}
+
+#[update(guard = "record_activity")]
+fn set_recovery_guardians(guardians: Vec<Principal>, threshold: u32) -> Result<(), String> {
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .set_recovery_guardians(ic_cdk::api::msg_caller(), guardians, threshold)
+    })
+}
+
+#[query]
+fn get_recovery_guardians(owner: Principal) -> Option<RecoveryGuardians> {
+    KEY_MANAGER.with_borrow(|km| km.as_ref().unwrap().get_recovery_guardians(owner))
+}
+
+#[update(guard = "record_activity")]
+fn approve_recovery(owner: Principal, new_owner: Principal) -> Result<RecoveryStatus, String> {
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut().unwrap().approve_recovery(
+            ic_cdk::api::msg_caller(),
+            owner,
+            new_owner,
+            ic_cdk::api::time(),
+        )
+    })
+}
+
+#[update(guard = "record_activity")]
+fn cancel_recovery() -> Result<(), String> {
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .cancel_recovery(ic_cdk::api::msg_caller())
+    })
+}
+
+#[update(guard = "record_activity")]
+fn complete_recovery(owner: Principal, new_owner: Principal) -> Result<(), String> {
+    let result = KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut().unwrap().complete_recovery(
+            ic_cdk::api::msg_caller(),
+            owner,
+            new_owner,
+            ic_cdk::api::time(),
+        )
+    });
+    update_certified_data();
+    result
+}
+
+#[query]
+fn get_pending_recoveries(owner: Principal) -> Result<Vec<(Principal, PendingRecovery)>, String> {
+    KEY_MANAGER.with_borrow(|km| {
+        km.as_ref()
+            .unwrap()
+            .get_pending_recoveries(ic_cdk::api::msg_caller(), owner)
+    })
+}
+
+#[query]
+fn get_recovered_principals() -> Vec<Principal> {
+    KEY_MANAGER.with_borrow(|km| {
+        km.as_ref()
+            .unwrap()
+            .get_recovered_principals(ic_cdk::api::msg_caller())
+    })
+}
+
+#[update(guard = "record_activity")]
+fn set_inheritance_plan(inactivity_period_ns: u64) -> Result<(), String> {
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut().unwrap().set_inheritance_plan(
+            ic_cdk::api::msg_caller(),
+            inactivity_period_ns,
+            ic_cdk::api::time(),
+        )
+    })?;
+    schedule_inheritance();
+    Ok(())
+}
+
+#[update(guard = "record_activity")]
+fn cancel_inheritance_plan() -> Result<(), String> {
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .cancel_inheritance_plan(ic_cdk::api::msg_caller())
+    })
+}
+
+#[query]
+fn get_inheritance_plan() -> Option<InheritancePlan> {
+    KEY_MANAGER.with_borrow(|km| {
+        km.as_ref()
+            .unwrap()
+            .get_inheritance_plan(ic_cdk::api::msg_caller())
+    })
+}
+
+#[update(guard = "record_activity")]
+fn add_inheritance_grant(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    beneficiary: Principal,
+    access_rights: AccessRights,
 ) -> Result<Option<AccessRights>, String> {
-    let key_name = bytebuf_to_blob(key_name)?;
-    let key_id = (key_owner, key_name);
+    let key_id = (key_owner, bytebuf_to_blob(key_name)?);
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut().unwrap().add_inheritance_grant(
+            ic_cdk::api::msg_caller(),
+            key_id,
+            beneficiary,
+            access_rights,
+        )
+    })
+}
+
+#[update(guard = "record_activity")]
+fn remove_inheritance_grant(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    beneficiary: Principal,
+) -> Result<Option<AccessRights>, String> {
+    let key_id = (key_owner, bytebuf_to_blob(key_name)?);
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut().unwrap().remove_inheritance_grant(
+            ic_cdk::api::msg_caller(),
+            key_id,
+            beneficiary,
+        )
+    })
+}
+
+#[query]
+fn get_inheritance_grants() -> Vec<(CandidKeyId, Principal, AccessRights)> {
+    KEY_MANAGER
+        .with_borrow(|km| {
+            km.as_ref()
+                .unwrap()
+                .get_inheritance_grants(ic_cdk::api::msg_caller())
+        })
+        .into_iter()
+        .map(|(key_id, beneficiary, access_rights)| {
+            (key_id_to_candid(key_id), beneficiary, access_rights)
+        })
+        .collect()
+}
+
+#[update(guard = "record_activity")]
+fn request_migration(new_principal: Principal) -> Result<(), String> {
     KEY_MANAGER.with_borrow_mut(|km| {
         km.as_mut()
             .unwrap()
-            .remove_user(ic_cdk::api::msg_caller(), key_id, user)
+            .request_migration(ic_cdk::api::msg_caller(), new_principal)
+    })
+}
+
+#[update(guard = "record_activity")]
+fn cancel_migration() -> Result<(), String> {
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .cancel_migration(ic_cdk::api::msg_caller())
+    })
+}
+
+#[query]
+fn get_pending_migration() -> Option<Principal> {
+    KEY_MANAGER.with_borrow(|km| {
+        km.as_ref()
+            .unwrap()
+            .get_pending_migration(ic_cdk::api::msg_caller())
+    })
+}
+
+#[update(guard = "record_activity")]
+fn confirm_migration(old_principal: Principal) -> Result<Vec<CandidKeyId>, String> {
+    let result = KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .confirm_migration(ic_cdk::api::msg_caller(), old_principal)
+    });
+    update_certified_data();
+    Ok(result?.into_iter().map(key_id_to_candid).collect())
+}
+
+#[query]
+fn get_migrated_principal(principal: Principal) -> Option<Principal> {
+    KEY_MANAGER.with_borrow(|km| km.as_ref().unwrap().get_migrated_principal(principal))
+}
+
+#[update(guard = "record_activity")]
+fn make_key_public(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    access_rights: AccessRights,
+) -> Result<Option<AccessRights>, String> {
+    let key_id = (key_owner, bytebuf_to_blob(key_name.clone())?);
+    KEY_MANAGER.with_borrow_mut(|km| {
+        let km = km.as_mut().unwrap();
+        let result = km.make_key_public(ic_cdk::api::msg_caller(), key_id, access_rights)?;
+        km.register_name(key_name.as_ref())?;
+        Ok(result)
+    })
+}
+
+#[update(guard = "record_activity")]
+fn make_key_private(
+    key_owner: Principal,
+    key_name: ByteBuf,
+) -> Result<Option<AccessRights>, String> {
+    let key_id = (key_owner, bytebuf_to_blob(key_name)?);
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .make_key_private(ic_cdk::api::msg_caller(), key_id)
+    })
+}
+
+#[query]
+fn get_public_key_ids_paginated(
+    start_after: Option<CandidKeyId>,
+    limit: u32,
+) -> Result<Page<(CandidKeyId, AccessRights), CandidKeyId>, String> {
+    let start_after = match start_after {
+        Some((key_owner, key_name)) => Some((key_owner, bytebuf_to_blob(key_name)?)),
+        None => None,
+    };
+    let page = KEY_MANAGER.with_borrow(|km| {
+        km.as_ref()
+            .unwrap()
+            .get_public_key_ids_paginated(start_after, limit as usize)
+    });
+    Ok(Page {
+        items: page
+            .items
+            .into_iter()
+            .map(|(key_id, access_rights)| (key_id_to_candid(key_id), access_rights))
+            .collect(),
+        next_cursor: page.next_cursor.map(key_id_to_candid),
+    })
+}
+
+#[update(guard = "record_activity")]
+fn delete_key(
+    key_owner: Principal,
+    key_name: ByteBuf,
+) -> Result<Vec<(Principal, AccessRights)>, String> {
+    let key_id = (key_owner, bytebuf_to_blob(key_name)?);
+    let result = KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .delete_key(ic_cdk::api::msg_caller(), key_id, ic_cdk::api::time())
+    });
+    update_certified_data();
+    result
+}
+
+#[query]
+fn get_key_tombstone(
+    key_owner: Principal,
+    key_name: ByteBuf,
+) -> Result<Option<KeyTombstone>, String> {
+    let key_id = (key_owner, bytebuf_to_blob(key_name)?);
+    Ok(KEY_MANAGER.with_borrow(|km| km.as_ref().unwrap().get_key_tombstone(key_id)))
+}
+
+#[update(guard = "record_activity")]
+fn deposit_inbox_message(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    ciphertext: ByteBuf,
+) -> Result<u64, String> {
+    let key_id = (key_owner, bytebuf_to_blob(key_name)?);
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut().unwrap().deposit_inbox_message(
+            ic_cdk::api::msg_caller(),
+            key_id,
+            ciphertext,
+            ic_cdk::api::time(),
+        )
+    })
+}
+
+#[query]
+fn get_inbox_messages_paginated(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    start_after: Option<u64>,
+    limit: u32,
+) -> Result<Page<(u64, InboxMessage), u64>, String> {
+    let key_id = (key_owner, bytebuf_to_blob(key_name)?);
+    KEY_MANAGER.with_borrow(|km| {
+        km.as_ref().unwrap().get_inbox_messages_paginated(
+            ic_cdk::api::msg_caller(),
+            key_id,
+            start_after,
+            limit as usize,
+        )
     })
 }
 
+#[update(guard = "record_activity")]
+fn remove_inbox_message(
+    key_owner: Principal,
+    key_name: ByteBuf,
+    message_id: u64,
+) -> Result<Option<InboxMessage>, String> {
+    let key_id = (key_owner, bytebuf_to_blob(key_name)?);
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .remove_inbox_message(ic_cdk::api::msg_caller(), key_id, message_id)
+    })
+}
+
+/// Records the activity of the caller of an update, which postpones their
+/// inheritance plan. Runs as a guard, so it applies to every update without
+/// changing its result.
+fn record_activity() -> Result<(), String> {
+    KEY_MANAGER.with_borrow_mut(|km| {
+        km.as_mut()
+            .unwrap()
+            .record_activity(ic_cdk::api::msg_caller(), ic_cdk::api::time())
+    });
+    Ok(())
+}
+
+/// Replaces the inheritance timer with one that fires when the next
+/// inheritance plan is due, if any. Recorded activity only postpones plans, so
+/// a timer that fires early just reschedules itself. Timers do not survive
+/// upgrades, so this also runs in `post_upgrade`.
+fn schedule_inheritance() {
+    if let Some(timer_id) = INHERITANCE_TIMER.take() {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+    let Some(next_due_ns) =
+        KEY_MANAGER.with_borrow(|km| km.as_ref().unwrap().next_inheritance_due_ns())
+    else {
+        return;
+    };
+    let delay = Duration::from_nanos(next_due_ns.saturating_sub(ic_cdk::api::time()));
+    let timer_id = ic_cdk_timers::set_timer(delay, async {
+        INHERITANCE_TIMER.set(None);
+        KEY_MANAGER.with_borrow_mut(|km| {
+            km.as_mut()
+                .unwrap()
+                .execute_inheritance(ic_cdk::api::time())
+        });
+        update_certified_data();
+        schedule_inheritance();
+    });
+    INHERITANCE_TIMER.set(Some(timer_id));
+}
+
+#[query]
+fn export_backup_chunk(
+    cursor: Option<BackupCursor>,
+    max_bytes: u64,
+) -> Result<BackupChunk, String> {
+    ensure_controller()?;
+    KEY_MANAGER.with_borrow(|km| {
+        km.as_ref().unwrap().export_backup_chunk(
+            ic_cdk::api::canister_self(),
+            cursor,
+            max_bytes as usize,
+        )
+    })
+}
+
+#[update]
+fn import_backup_chunk(chunk: BackupChunk) -> Result<(), String> {
+    ensure_controller()?;
+    let result = KEY_MANAGER.with_borrow_mut(|km| km.as_mut().unwrap().import_backup_chunk(chunk));
+    update_certified_data();
+    schedule_inheritance();
+    result
+}
+
+fn ensure_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::api::msg_caller()) {
+        Ok(())
+    } else {
+        Err("only controllers can export or import backups".to_string())
+    }
+}
+
+/// Certifies the current access-control state, see
+/// `KeyManager::enable_certified_queries`. Must be called after every update
+/// that may change access rights.
+fn update_certified_data() {
+    if let Some(certified_data) =
+        KEY_MANAGER.with_borrow(|km| km.as_ref().unwrap().certified_data())
+    {
+        ic_cdk::api::certified_data_set(certified_data);
+    }
+}
+
+fn data_certificate() -> Result<Vec<u8>, String> {
+    ic_cdk::api::data_certificate()
+        .ok_or_else(|| "certificates are only available in query calls".to_string())
+}
+
 fn bytebuf_to_blob(buf: ByteBuf) -> Result<Blob<32>, String> {
-    Blob::try_from(buf.as_ref()).map_err(|_| "too large input".to_string())
+    KEY_MANAGER.with_borrow(|km| km.as_ref().unwrap().name_from_bytes(buf.as_ref()))
+}
+
+fn key_id_to_candid(key_id: (Principal, Blob<32>)) -> CandidKeyId {
+    KEY_MANAGER.with_borrow(|km| (key_id.0, km.as_ref().unwrap().name_to_bytes(&key_id.1)))
 }
 
 fn id_to_memory(id: u8) -> Memory {
@@ -1,26 +1,64 @@
 use assert_matches::assert_matches;
 use candid::{decode_one, encode_args, encode_one, CandidType, Principal};
-use ic_vetkeys::key_manager::{key_id_to_vetkd_input, VetKey, VetKeyVerificationKey};
+use ic_stable_structures::storable::Blob;
+use ic_vetkeys::key_manager::{
+    key_id_to_vetkd_input, CertifiedQueryVerifier, CertifiedResponse, InheritancePlan, LongNames,
+    VetKey, VetKeyVerificationKey,
+};
 use ic_vetkeys::types::{AccessRights, ByteBuf, TransportKey};
 use ic_vetkeys::{DerivedPublicKey, EncryptedVetKey, TransportSecretKey};
 use ic_vetkeys_test_utils::{git_root_dir, random_self_authenticating_principal, reproducible_rng};
 use pocket_ic::{PocketIc, PocketIcBuilder};
 use rand::{CryptoRng, Rng};
 use std::path::Path;
+use std::time::Duration;
 
 #[test]
 fn should_obtain_verification_key() {
     let rng = &mut reproducible_rng();
     let env = TestEnvironment::new(rng);
-    let verification_key: VetKeyVerificationKey = env.update(
-        env.principal_0,
-        "get_vetkey_verification_key",
-        encode_one(()).unwrap(),
-    );
+    let verification_key = env
+        .update::<Result<VetKeyVerificationKey, String>>(
+            env.principal_0,
+            "get_vetkey_verification_key",
+            encode_one(()).unwrap(),
+        )
+        .unwrap();
     assert_eq!(verification_key.as_ref().len(), 96);
     assert_ne!(verification_key, VetKeyVerificationKey::from(vec![0; 96]));
 }
 
+#[test]
+fn should_cache_verification_key() {
+    let rng = &mut reproducible_rng();
+    let env = TestEnvironment::new(rng);
+    // the test key cannot be derived offline
+    assert_eq!(
+        env.query::<Option<VetKeyVerificationKey>>(
+            env.principal_0,
+            "get_cached_vetkey_verification_key",
+            encode_one(()).unwrap(),
+        ),
+        None
+    );
+
+    let verification_key = env
+        .update::<Result<VetKeyVerificationKey, String>>(
+            env.principal_0,
+            "get_vetkey_verification_key",
+            encode_one(()).unwrap(),
+        )
+        .unwrap();
+    assert_eq!(
+        env.query::<Option<VetKeyVerificationKey>>(
+            env.principal_1,
+            "get_cached_vetkey_verification_key",
+            encode_one(()).unwrap(),
+        ),
+        Some(verification_key)
+    );
+}
+
 #[test]
 fn should_obtain_owned_encrypted_vetkey() {
     let rng = &mut reproducible_rng();
@@ -46,11 +84,13 @@ fn encrypted_vetkey_should_validate() {
     let rng = &mut reproducible_rng();
     let env = TestEnvironment::new(rng);
 
-    let verification_key_bytes: VetKeyVerificationKey = env.update(
-        env.principal_0,
-        "get_vetkey_verification_key",
-        encode_one(()).unwrap(),
-    );
+    let verification_key_bytes = env
+        .update::<Result<VetKeyVerificationKey, String>>(
+            env.principal_0,
+            "get_vetkey_verification_key",
+            encode_one(()).unwrap(),
+        )
+        .unwrap();
 
     let key_owner = env.principal_0;
     let key_name = random_key_name(rng);
@@ -83,7 +123,11 @@ fn encrypted_vetkey_should_validate() {
 }
 
 #[test]
-fn key_sharing_should_work() {
+fn long_named_vetkey_should_be_derived_from_canonical_name() {
+    if running_motoko_wasm() {
+        // the Motoko canister does not support long names
+        return;
+    }
     let rng = &mut reproducible_rng();
     let env = TestEnvironment::new(rng);
 
@@ -93,6 +137,51 @@ fn key_sharing_should_work() {
         encode_one(()).unwrap(),
     );
 
+    let key_owner = env.principal_0;
+    let key_name_len = rng.gen_range(33..=LongNames::MAX_NAME_LEN);
+    let key_name: ByteBuf = (0..key_name_len)
+        .map(|_| rng.gen::<u8>())
+        .collect::<Vec<_>>()
+        .into();
+    let canonical_name = LongNames::canonical_name(key_name.as_ref()).unwrap();
+    let transport_key = random_transport_key(rng);
+    let transport_key_bytes = TransportKey::from(transport_key.public_key());
+
+    let encrypted_vetkey = env
+        .update::<Result<VetKey, String>>(
+            key_owner,
+            "get_encrypted_vetkey",
+            encode_args((key_owner, key_name, transport_key_bytes)).unwrap(),
+        )
+        .unwrap();
+
+    // `KeyManager::get_encrypted_vetkey` derives from the canonical key ID,
+    // so the vetKey retrieved by name must verify against the same input
+    let derived_public_key =
+        DerivedPublicKey::deserialize(verification_key_bytes.as_ref()).unwrap();
+    let encrypted_vetkey = EncryptedVetKey::deserialize(encrypted_vetkey.as_ref()).unwrap();
+    encrypted_vetkey
+        .decrypt_and_verify(
+            &transport_key,
+            &derived_public_key,
+            &key_id_to_vetkd_input(key_owner, canonical_name.as_slice()),
+        )
+        .expect("failed to decrypt and verify `vetkey");
+}
+
+#[test]
+fn key_sharing_should_work() {
+    let rng = &mut reproducible_rng();
+    let env = TestEnvironment::new(rng);
+
+    let verification_key_bytes = env
+        .update::<Result<VetKeyVerificationKey, String>>(
+            env.principal_0,
+            "get_vetkey_verification_key",
+            encode_one(()).unwrap(),
+        )
+        .unwrap();
+
     let key_owner = env.principal_0;
     let not_key_owner = env.principal_1;
     let key_name = random_key_name(rng);
@@ -197,7 +286,6 @@ fn should_get_accessible_shared_key_ids() {
                 encode_one(()).unwrap(),
             )
             .into_iter()
-            .map(|(principal, bytebuf)| (principal, bytebuf))
             .collect();
 
         assert_eq!(computed_map_ids, map_ids);
@@ -508,6 +596,134 @@ fn should_fail_add_or_remove_user_by_unauthorized() {
     }
 }
 
+#[test]
+fn should_certify_user_rights() {
+    if running_motoko_wasm() {
+        // the Motoko canister does not expose certified queries
+        return;
+    }
+    let rng = &mut reproducible_rng();
+    let env = TestEnvironment::new(rng);
+    let owner = env.principal_0;
+    let user = env.principal_1;
+    let key_name = random_key_name(rng);
+    let key_id = (owner, Blob::try_from(key_name.as_ref()).unwrap());
+
+    assert_eq!(
+        env.update::<Result<Option<AccessRights>, String>>(
+            owner,
+            "set_user_rights",
+            encode_args((owner, key_name.clone(), user, AccessRights::ReadWrite)).unwrap(),
+        ),
+        Ok(None)
+    );
+    let verifier = CertifiedQueryVerifier {
+        canister_id: env.example_canister_id,
+        root_public_key: env.pic.root_key().unwrap(),
+        now_nanos: env.pic.get_time().as_nanos_since_unix_epoch(),
+        max_certificate_age_nanos: 5 * 60 * 1_000_000_000,
+    };
+
+    let response = env
+        .query::<Result<CertifiedResponse<Option<AccessRights>>, String>>(
+            owner,
+            "get_certified_user_rights",
+            encode_args((owner, key_name.clone(), user)).unwrap(),
+        )
+        .unwrap();
+    assert_eq!(
+        verifier.verify_user_rights(&response, key_id, user),
+        Ok(Some(AccessRights::ReadWrite))
+    );
+    let tampered = CertifiedResponse {
+        value: Some(AccessRights::ReadWriteManage),
+        ..response
+    };
+    assert_eq!(
+        verifier.verify_user_rights(&tampered, key_id, user),
+        Err("response does not match the certified value".to_string())
+    );
+
+    let response = env
+        .query::<Result<CertifiedResponse<Vec<(Principal, AccessRights)>>, String>>(
+            owner,
+            "get_certified_shared_user_access_for_key",
+            encode_args((owner, key_name)).unwrap(),
+        )
+        .unwrap();
+    assert_eq!(
+        verifier.verify_shared_user_access(&response, key_id),
+        Ok(vec![(user, AccessRights::ReadWrite)])
+    );
+}
+
+#[test]
+fn should_execute_inheritance_plan_after_inactivity() {
+    if running_motoko_wasm() {
+        // the Motoko canister does not support inheritance
+        return;
+    }
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+    const INACTIVITY_PERIOD: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+    let rng = &mut reproducible_rng();
+    let env = TestEnvironment::new(rng);
+    let owner = env.principal_0;
+    let beneficiary = env.principal_1;
+    let key_name = random_key_name(rng);
+    let get_beneficiary_rights = || {
+        env.query::<Result<Option<AccessRights>, String>>(
+            owner,
+            "get_user_rights",
+            encode_args((owner, key_name.clone(), beneficiary)).unwrap(),
+        )
+    };
+
+    assert_eq!(
+        env.update::<Result<(), String>>(
+            owner,
+            "set_inheritance_plan",
+            encode_one(INACTIVITY_PERIOD.as_nanos() as u64).unwrap(),
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        env.update::<Result<Option<AccessRights>, String>>(
+            owner,
+            "add_inheritance_grant",
+            encode_args((owner, key_name.clone(), beneficiary, AccessRights::Read)).unwrap(),
+        ),
+        Ok(None)
+    );
+
+    // any update of the owner counts as activity and postpones the plan
+    env.pic.advance_time(INACTIVITY_PERIOD - DAY);
+    fast_forward(&env.pic, 5);
+    assert_eq!(
+        env.update::<Result<Option<AccessRights>, String>>(
+            owner,
+            "remove_inheritance_grant",
+            encode_args((owner, random_key_name(rng), beneficiary)).unwrap(),
+        ),
+        Ok(None)
+    );
+    env.pic.advance_time(2 * DAY);
+    fast_forward(&env.pic, 5);
+    assert_eq!(get_beneficiary_rights(), Ok(None));
+
+    // the timer executes the plan once the owner was inactive long enough
+    env.pic.advance_time(INACTIVITY_PERIOD);
+    fast_forward(&env.pic, 5);
+    assert_eq!(get_beneficiary_rights(), Ok(Some(AccessRights::Read)));
+    assert_eq!(
+        env.query::<Option<InheritancePlan>>(
+            owner,
+            "get_inheritance_plan",
+            encode_args(()).unwrap()
+        ),
+        None
+    );
+}
+
 #[test]
 fn should_preserve_state_across_upgrade() {
     // Runs against both backends via the shared harness: the Rust manager
@@ -557,6 +773,7 @@ struct TestEnvironment {
 impl TestEnvironment {
     fn new<R: Rng + CryptoRng>(rng: &mut R) -> Self {
         let pic = PocketIcBuilder::new()
+            .with_nns_subnet()
             .with_application_subnet()
             .with_ii_subnet()
             .with_fiduciary_subnet()
@@ -621,8 +838,7 @@ fn load_key_manager_example_canister_wasm() -> Vec<u8> {
         Ok(path) if !path.is_empty() => {
             assert!(
                 Path::new(&path).exists(),
-                "CUSTOM_WASM_PATH is set to '{}' but the file does not exist; run `make compile-wasm` first",
-                path
+                "CUSTOM_WASM_PATH is set to '{path}' but the file does not exist; run `make compile-wasm` first"
             );
             path
         }
@@ -117,8 +117,7 @@ fn load_canister_wasm() -> Vec<u8> {
         Ok(path) if !path.is_empty() => {
             assert!(
                 Path::new(&path).exists(),
-                "CUSTOM_WASM_PATH is set to '{}' but the file does not exist; run `make compile-wasm` first",
-                path
+                "CUSTOM_WASM_PATH is set to '{path}' but the file does not exist; run `make compile-wasm` first"
             );
             path
         }
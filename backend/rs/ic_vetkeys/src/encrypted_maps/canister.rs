@@ -111,7 +111,8 @@
 /// * writes: `insert_encrypted_value`, `remove_encrypted_value`,
 ///   `remove_map_values`
 /// * reads: `get_encrypted_value`, `get_encrypted_values_for_map`,
-///   `get_all_accessible_encrypted_values`, `get_all_accessible_encrypted_maps`
+///   `get_all_accessible_encrypted_values`, `get_all_accessible_encrypted_maps`,
+///   and their `*_paginated` variants
 ///
 /// Only the **writes** can break an adopter's invariant (they mutate the value
 /// store, so a raw write could leave your linked side-state out of sync — a
@@ -130,11 +131,250 @@
 /// The generated **control-plane** endpoints are always safe to keep — none of
 /// them read or write map values (`set_user_rights`/`remove_user` touch only the
 /// access-control state, with no value cascade):
-/// `get_accessible_shared_map_names`, `get_shared_user_access_for_map`,
-/// `get_owned_non_empty_map_names`, `get_vetkey_verification_key`,
-/// `get_encrypted_vetkey`, `get_user_rights`, `set_user_rights`, `remove_user`.
+/// `get_accessible_shared_map_names`, `get_shared_user_access_for_map` (and
+/// their `*_paginated` variants), `get_owned_non_empty_map_names`,
+/// `get_owned_shared_map_names`, `get_all_grants_by_owner`, `get_vetkey_verification_key`,
+/// `get_cached_vetkey_verification_key`, `get_encrypted_vetkey`,
+/// `get_encrypted_vetkey_for_purpose`, `get_encrypted_vetkeys`,
+/// `get_user_rights`, `set_user_rights`, `remove_user`,
+/// `revoke_user_everywhere`.
 ///
-/// ## Accessing the EncryptedMaps instance
+/// # Verification key
+///
+/// `get_vetkey_verification_key` caches the key returned by the management
+/// canister in stable memory and returns the cached key afterwards. The
+/// `get_cached_vetkey_verification_key` query returns the cached key or, for
+/// the mainnet vetKD keys, a key derived offline, and `null` otherwise. The
+/// offline key is checked against the management canister's answer on the
+/// first call to `get_vetkey_verification_key`, which returns an error on a
+/// mismatch.
+///
+/// # Failed vetKD calls
+///
+/// The generated canister retries calls to the vetKD endpoints of the
+/// management canister that fail with a transient error up to two times, see
+/// [`CallPolicy`](crate::key_manager::CallPolicy). If the call still fails,
+/// `get_encrypted_vetkey` and `get_vetkey_verification_key` return the error.
+///
+/// # Options
+///
+/// Both forms accept the options below after the memories and
+/// `custom_value_endpoints`, if present, in any order. Each option may be given
+/// at most once.
+///
+/// # Caller-pays derivations (`caller_pays`)
+///
+/// By default, every `get_encrypted_vetkey` call is paid with the canister's
+/// own cycles. Append `caller_pays(memory)` to make callers pay for their
+/// derivations from a cycles balance kept in a
+/// [`CyclesLedger`](crate::key_manager::CyclesLedger) in the given memory:
+///
+/// ```ignore
+/// ic_vetkeys::export_encrypted_maps_canister!(
+///     "my_app_domain_separator",
+///     [memory(0), memory(1), memory(2), memory(3)],
+///     caller_pays(memory(4)),
+/// );
+/// ```
+///
+/// Cycles attached to `get_encrypted_vetkey` or to the additional
+/// `deposit_cycles` endpoint are credited to the caller, and each derivation
+/// debits the cost returned by `get_vetkey_derivation_cost`, and
+/// `get_encrypted_vetkeys` debits the cost of all requested vetKeys at once.
+/// Callers check their balance with `get_cycles_balance`. Deposits are not
+/// refundable, but the cost of a rejected derivation is credited back. A
+/// derivation is charged once even if the vetKD call is retried, see
+/// [`CallPolicy::max_attempts`](crate::key_manager::CallPolicy::max_attempts).
+///
+/// # Names longer than 32 bytes (`long_names`)
+///
+/// By default, map names and map keys are limited to 32 bytes and longer ones
+/// are rejected with "too large input". Append `long_names(memory)` to accept
+/// names of up to [`LongNames::MAX_NAME_LEN`](crate::key_manager::LongNames::MAX_NAME_LEN)
+/// bytes:
+///
+/// ```ignore
+/// ic_vetkeys::export_encrypted_maps_canister!(
+///     "my_app_domain_separator",
+///     [memory(0), memory(1), memory(2), memory(3)],
+///     long_names(memory(5)),
+/// );
+/// ```
+///
+/// The original names are kept in the given memory, see
+/// [`LongNames`](crate::key_manager::LongNames). Names of at most 32 bytes are
+/// stored as before, so `long_names` can be added to an existing canister.
+///
+/// # Freezing maps (`key_freezing`)
+///
+/// Append `key_freezing(memory, unfreeze_delay_ns)` to let owners and
+/// managers freeze a map when a device is compromised. This generates the
+/// endpoints `freeze_map`, `unfreeze_map`, and `get_map_freeze_state`:
+///
+/// ```ignore
+/// ic_vetkeys::export_encrypted_maps_canister!(
+///     "my_app_domain_separator",
+///     [memory(0), memory(1), memory(2), memory(3)],
+///     key_freezing(memory(5), 24 * 60 * 60 * 1_000_000_000),
+/// );
+/// ```
+///
+/// While a map is frozen, `get_encrypted_vetkey` and every value write fail,
+/// except for the owner unless the owner locked themselves out as well, in
+/// which case `unfreeze_map` only takes effect after `unfreeze_delay_ns`, see
+/// [`KeyManager::freeze_key`](crate::key_manager::KeyManager::freeze_key).
+/// The shares of the map are kept. Writes through `with_encrypted_maps_mut`
+/// are checked as well.
+///
+/// # Social recovery (`recovery`)
+///
+/// Append `recovery(memory, memory, memory, memory, recovery_delay_ns)` to let
+/// owners name guardians who can jointly move all of their maps to a new
+/// principal, e.g., after losing their device. This generates the endpoints `set_recovery_guardians`,
+/// `get_recovery_guardians`, `approve_recovery`, `cancel_recovery`,
+/// `complete_recovery`, `get_pending_recoveries`, and
+/// `get_recovered_principals`:
+///
+/// ```ignore
+/// ic_vetkeys::export_encrypted_maps_canister!(
+///     "my_app_domain_separator",
+///     [memory(0), memory(1), memory(2), memory(3)],
+///     recovery(
+///         memory(4),
+///         memory(5),
+///         memory(6),
+///         memory(7),
+///         7 * 24 * 60 * 60 * 1_000_000_000,
+///     ),
+/// );
+/// ```
+///
+/// Once enough guardians approved the same new owner, the recovery can be
+/// completed after `recovery_delay_ns`, during which the original owner can
+/// call `cancel_recovery`, see
+/// [`KeyManager::approve_recovery`](crate::key_manager::KeyManager::approve_recovery).
+/// Recovered maps keep their IDs, so the map owner returned by the endpoints
+/// stays the original principal.
+///
+/// # Migrating principals (`migrations`)
+///
+/// Append `migrations(memory, memory, memory)` to let users move their maps
+/// and the maps shared with them to another principal, e.g., when switching
+/// identity providers. This generates the endpoints `request_migration`,
+/// `cancel_migration`, `get_pending_migration`, `confirm_migration`, and
+/// `get_migrated_principal`:
+///
+/// ```ignore
+/// ic_vetkeys::export_encrypted_maps_canister!(
+///     "my_app_domain_separator",
+///     [memory(0), memory(1), memory(2), memory(3)],
+///     migrations(memory(4), memory(5), memory(6)),
+/// );
+/// ```
+///
+/// The old principal calls `request_migration` and the new principal calls
+/// `confirm_migration`, see
+/// [`KeyManager::confirm_migration`](crate::key_manager::KeyManager::confirm_migration).
+/// Migrated maps keep their IDs, so the map owner returned by the endpoints
+/// stays the old principal, and `get_migrated_principal` tells sharers who
+/// it was migrated to.
+///
+/// # Public maps (`public_maps`)
+///
+/// Append `public_maps(memory)` to let owners make a map readable by every
+/// caller, including the anonymous principal. This generates the endpoints
+/// `make_map_public`, `make_map_private`, and
+/// `get_public_map_names_paginated`:
+///
+/// ```ignore
+/// ic_vetkeys::export_encrypted_maps_canister!(
+///     "my_app_domain_separator",
+///     [memory(0), memory(1), memory(2), memory(3)],
+///     public_maps(memory(4)),
+/// );
+/// ```
+///
+/// Public access rights must be read-only, so writes are still governed by
+/// the rights of individual users, see
+/// [`KeyManager::make_key_public`](crate::key_manager::KeyManager::make_key_public).
+/// Do not share maps with the anonymous principal instead.
+///
+/// # Inboxes (`inbox`)
+///
+/// Append `inbox(memory, memory, memory)` to let any principal deposit IBE
+/// ciphertexts addressed to a map, e.g., encrypted to
+/// [`IbeIdentity::for_key_id`](crate::IbeIdentity::for_key_id) of the map.
+/// This generates the endpoints `deposit_inbox_message`,
+/// `get_inbox_messages_paginated`, and `remove_inbox_message`:
+///
+/// ```ignore
+/// ic_vetkeys::export_encrypted_maps_canister!(
+///     "my_app_domain_separator",
+///     [memory(0), memory(1), memory(2), memory(3)],
+///     inbox(memory(4), memory(5), memory(6)),
+/// );
+/// ```
+///
+/// Users who can read a map can retrieve the messages of its inbox, and users
+/// who can remove values of the map can remove them. Deposits are limited by
+/// the default [`InboxConfig`](crate::key_manager::InboxConfig), see
+/// [`KeyManager::deposit_inbox_message`](crate::key_manager::KeyManager::deposit_inbox_message).
+///
+/// # Deleting maps (`map_deletion`)
+///
+/// Append `map_deletion(memory, managers_can_delete)` to let owners, and
+/// managers if `managers_can_delete` is `true`, delete a map with all its
+/// values and shares. This generates the endpoints `delete_map` and
+/// `get_map_tombstone`:
+///
+/// ```ignore
+/// ic_vetkeys::export_encrypted_maps_canister!(
+///     "my_app_domain_separator",
+///     [memory(0), memory(1), memory(2), memory(3)],
+///     map_deletion(memory(4), false),
+/// );
+/// ```
+///
+/// A deleted map leaves a tombstone that `get_map_tombstone` returns, so that
+/// clients can tell it from a map that never existed, and its name cannot be
+/// used again by the owner, see
+/// [`KeyManager::delete_key`](crate::key_manager::KeyManager::delete_key).
+///
+/// # Event listeners (`event_listener`)
+///
+/// To react to shares, revocations, and value mutations without wrapping the
+/// generated endpoints, append `event_listener(listener)` with an expression
+/// that evaluates to an [`EventListener`](crate::key_manager::EventListener):
+///
+/// ```ignore
+/// ic_vetkeys::export_encrypted_maps_canister!(
+///     "my_app_domain_separator",
+///     [memory(0), memory(1), memory(2), memory(3)],
+///     custom_value_endpoints,
+///     event_listener(MyListener),
+/// );
+/// ```
+///
+/// The listener is called synchronously within the endpoint call that made
+/// the change, so side-state it updates, e.g., in a `thread_local!`, stays
+/// consistent with the maps. The expression is evaluated in `#[init]` and
+/// `#[post_upgrade]`, so any state the listener keeps in the heap is lost on
+/// upgrade.
+///
+/// # Backup and restore
+///
+/// The full form also generates the controller-only endpoints
+/// `export_backup_chunk` and `import_backup_chunk`, which export the stable
+/// state in hashed chunks and import them into an empty canister, e.g., to
+/// migrate to a new canister or to recover from a bad upgrade, see
+/// [`EncryptedMaps::export_backup_chunk`](crate::encrypted_maps::EncryptedMaps::export_backup_chunk).
+/// Each chunk carries the id of the exporting canister and its domain
+/// separator, since the map values must be re-encrypted if either changes.
+/// The `custom_value_endpoints` form omits them because a backup does not
+/// contain your linked side-state; wrap the same methods in your own endpoints
+/// to back up both.
+///
+/// # Accessing the EncryptedMaps instance
 ///
 /// Both forms emit two accessors so your own endpoints can reuse the library's
 /// vetKD/crypto/access-control logic without re-wiring init or memory:
@@ -149,7 +389,7 @@
 /// side-state updated in the *same* endpoint call.
 #[macro_export]
 macro_rules! export_encrypted_maps_canister {
-    // Full canister: control-plane + value endpoints.
+    // Control-plane only: caller provides its own value read/write endpoints.
     (
         $domain_separator:expr,
         [
@@ -157,7 +397,9 @@ macro_rules! export_encrypted_maps_canister {
             $memory_access_control:expr,
             $memory_shared_keys:expr,
             $memory_encrypted_maps:expr $(,)?
-        ] $(,)?
+        ],
+        custom_value_endpoints
+        $(, $option:ident($($option_args:tt)*))* $(,)?
     ) => {
         $crate::__export_encrypted_maps_common!(
             $domain_separator,
@@ -166,13 +408,14 @@ macro_rules! export_encrypted_maps_canister {
                 $memory_access_control,
                 $memory_shared_keys,
                 $memory_encrypted_maps
-            ]
+            ],
+            [$($option($($option_args)*)),*]
         );
+        $($crate::__export_encrypted_maps_option!(@endpoints $option($($option_args)*));)*
         $crate::__export_encrypted_maps_control_plane_endpoints!();
-        $crate::__export_encrypted_maps_value_endpoints!();
     };
 
-    // Control-plane only: caller provides its own value read/write endpoints.
+    // Full canister: control-plane + value endpoints.
     (
         $domain_separator:expr,
         [
@@ -180,8 +423,8 @@ macro_rules! export_encrypted_maps_canister {
             $memory_access_control:expr,
             $memory_shared_keys:expr,
             $memory_encrypted_maps:expr $(,)?
-        ],
-        custom_value_endpoints $(,)?
+        ]
+        $(, $option:ident($($option_args:tt)*))* $(,)?
     ) => {
         $crate::__export_encrypted_maps_common!(
             $domain_separator,
@@ -190,9 +433,13 @@ macro_rules! export_encrypted_maps_canister {
                 $memory_access_control,
                 $memory_shared_keys,
                 $memory_encrypted_maps
-            ]
+            ],
+            [$($option($($option_args)*)),*]
         );
+        $($crate::__export_encrypted_maps_option!(@endpoints $option($($option_args)*));)*
         $crate::__export_encrypted_maps_control_plane_endpoints!();
+        $crate::__export_encrypted_maps_value_endpoints!();
+        $crate::__export_encrypted_maps_backup_endpoints!();
     };
 }
 
@@ -210,7 +457,8 @@ macro_rules! __export_encrypted_maps_common {
             $memory_access_control:expr,
             $memory_shared_keys:expr,
             $memory_encrypted_maps:expr
-        ]
+        ],
+        [$($option:ident($($option_args:tt)*)),*]
     ) => {
         // Import everything under unique aliases so the expansion never binds a
         // common name (`Principal`, `ByteBuf`, …) in the caller's module — that
@@ -220,89 +468,636 @@ macro_rules! __export_encrypted_maps_common {
         use $crate::encrypted_maps::EncryptedMaps as __EmEncryptedMaps;
         use $crate::encrypted_maps::VetKey as __EmVetKey;
         use $crate::encrypted_maps::VetKeyVerificationKey as __EmVetKeyVerificationKey;
+        use $crate::key_manager::CyclesLedger as __EmCyclesLedger;
         use $crate::types::AccessRights as __EmAccessRights;
         use $crate::types::ByteBuf as __EmByteBuf;
         use $crate::types::EncryptedMapValue as __EmEncryptedMapValue;
+        use $crate::types::Page as __EmPage;
         use $crate::types::TransportKey as __EmTransportKey;
 
         ::std::thread_local! {
             static ENCRYPTED_MAPS: ::std::cell::RefCell<Option<__EmEncryptedMaps<__EmAccessRights>>> =
                 const { ::std::cell::RefCell::new(None) };
+            // Only set with the `caller_pays` option; otherwise the canister pays.
+            static CYCLES_LEDGER: ::std::cell::RefCell<Option<__EmCyclesLedger>> =
+                const { ::std::cell::RefCell::new(None) };
         }
 
         fn __encrypted_maps_bytebuf_to_blob(
             buf: __EmByteBuf,
         ) -> Result<::ic_stable_structures::storable::Blob<32>, String> {
-            ::ic_stable_structures::storable::Blob::try_from(buf.as_ref())
-                .map_err(|_| "too large input".to_string())
+            with_encrypted_maps(|encrypted_maps| encrypted_maps.name_from_bytes(buf.as_ref()))
+        }
+
+        fn __encrypted_maps_blob_to_bytebuf(
+            blob: ::ic_stable_structures::storable::Blob<32>,
+        ) -> __EmByteBuf {
+            with_encrypted_maps(|encrypted_maps| encrypted_maps.name_to_bytes(&blob))
+        }
+
+        fn __encrypted_maps_map_id_from_candid(
+            map_id: (__EmPrincipal, __EmByteBuf),
+        ) -> Result<(__EmPrincipal, ::ic_stable_structures::storable::Blob<32>), String> {
+            Ok((map_id.0, __encrypted_maps_bytebuf_to_blob(map_id.1)?))
+        }
+
+        fn __encrypted_maps_map_id_to_candid(
+            map_id: (__EmPrincipal, ::ic_stable_structures::storable::Blob<32>),
+        ) -> (__EmPrincipal, __EmByteBuf) {
+            (map_id.0, __encrypted_maps_blob_to_bytebuf(map_id.1))
+        }
+
+        #[::ic_cdk::init]
+        fn __encrypted_maps_init(key_name: String) {
+            __encrypted_maps_setup(key_name);
+        }
+
+        // After an upgrade `#[init]` does not run, so the thread-local wrapper
+        // would be `None` and every endpoint would trap. Re-attach it to the
+        // (persisted) stable state here. The vetKD key id and domain separator
+        // are loaded from the config `StableCell`, which ignores the passed
+        // defaults when it already holds a value — so the key name given here
+        // is irrelevant after an upgrade.
+        #[::ic_cdk::post_upgrade]
+        fn __encrypted_maps_post_upgrade() {
+            __encrypted_maps_setup(String::new());
+        }
+
+        fn __encrypted_maps_setup(key_name: String) {
+            let key_id = ::ic_cdk_management_canister::VetKDKeyId {
+                curve: ::ic_cdk_management_canister::VetKDCurve::Bls12_381_G2,
+                name: key_name,
+            };
+            let mut instance = __EmEncryptedMaps::init(
+                $domain_separator,
+                key_id,
+                $memory_domain_separator,
+                $memory_access_control,
+                $memory_shared_keys,
+                $memory_encrypted_maps,
+            );
+            instance.set_call_policy($crate::key_manager::CallPolicy {
+                max_attempts: 3,
+                ..Default::default()
+            });
+            $($crate::__export_encrypted_maps_option!(@enable instance, $option($($option_args)*));)*
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| encrypted_maps.replace(instance));
+        }
+
+        /// Credits the cycles attached to the current call to `caller` — even
+        /// if the call fails afterwards — and accepts them, so that a caller
+        /// can attach the cost to each call instead of depositing in advance.
+        fn __encrypted_maps_accept_attached_cycles(caller: __EmPrincipal) -> u128 {
+            let attached = ::ic_cdk::api::msg_cycles_accept(::ic_cdk::api::msg_cycles_available());
+            CYCLES_LEDGER.with_borrow_mut(|cycles_ledger| {
+                cycles_ledger.as_mut().unwrap().deposit(caller, attached)
+            })
+        }
+
+        /// Debits the cost of `count` derivations from `caller`, either for
+        /// all of them or for none, and returns the cost of one derivation.
+        /// Without the `caller_pays` option, the cost is zero.
+        fn __encrypted_maps_charge_vetkey_derivations(
+            caller: __EmPrincipal,
+            count: u128,
+        ) -> Result<u128, String> {
+            if CYCLES_LEDGER.with_borrow(|cycles_ledger| cycles_ledger.is_none()) {
+                return Ok(0);
+            }
+            __encrypted_maps_accept_attached_cycles(caller);
+            let cost =
+                with_encrypted_maps(|encrypted_maps| encrypted_maps.vetkey_derivation_cost())?;
+            CYCLES_LEDGER
+                .with_borrow_mut(|cycles_ledger| {
+                    cycles_ledger
+                        .as_mut()
+                        .unwrap()
+                        .debit(caller, cost.saturating_mul(count))
+                })
+                .map(|_balance| cost)
+        }
+
+        /// Credits a charged derivation back to `caller` if the derivation failed.
+        fn __encrypted_maps_refund_vetkey_derivation(caller: __EmPrincipal, amount: u128) {
+            CYCLES_LEDGER.with_borrow_mut(|cycles_ledger| {
+                if let Some(cycles_ledger) = cycles_ledger.as_mut() {
+                    cycles_ledger.deposit(caller, amount);
+                }
+            });
+        }
+
+        /// Run `f` with a shared reference to the initialized `EncryptedMaps`.
+        ///
+        /// For hand-written endpoints that reuse the library's vetKD / crypto /
+        /// access-control logic. `pub(crate)` so it is reachable from submodules
+        /// of the invoking crate. Traps if called before `#[init]` (it never is —
+        /// the state is set up in `#[init]`/`#[post_upgrade]`).
+        #[allow(dead_code)]
+        pub(crate) fn with_encrypted_maps<__EmR>(
+            f: impl FnOnce(&__EmEncryptedMaps<__EmAccessRights>) -> __EmR,
+        ) -> __EmR {
+            ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                f(encrypted_maps
+                    .as_ref()
+                    .expect("EncryptedMaps is not initialized (called before #[init]/#[post_upgrade])"))
+            })
+        }
+
+        /// Run `f` with a mutable reference to the initialized `EncryptedMaps`.
+        ///
+        /// Gives access to the raw value mutators; when wrapping them, keep any
+        /// linked side-state updated in the same endpoint call. `pub(crate)` so
+        /// it is reachable from submodules of the invoking crate.
+        #[allow(dead_code)]
+        pub(crate) fn with_encrypted_maps_mut<__EmR>(
+            f: impl FnOnce(&mut __EmEncryptedMaps<__EmAccessRights>) -> __EmR,
+        ) -> __EmR {
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                f(encrypted_maps
+                    .as_mut()
+                    .expect("EncryptedMaps is not initialized (called before #[init]/#[post_upgrade])"))
+            })
+        }
+    };
+}
+
+/// The setup (`@enable`) and the endpoints (`@endpoints`) of each option of
+/// [`export_encrypted_maps_canister!`]. Not a public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __export_encrypted_maps_option {
+    (@enable $instance:ident, caller_pays($memory_cycles_ledger:expr)) => {
+        CYCLES_LEDGER.with_borrow_mut(|cycles_ledger| {
+            cycles_ledger.replace(__EmCyclesLedger::init($memory_cycles_ledger))
+        });
+    };
+    (@endpoints caller_pays($memory_cycles_ledger:expr)) => {
+        #[::ic_cdk::update]
+        fn deposit_cycles() -> u128 {
+            __encrypted_maps_accept_attached_cycles(::ic_cdk::api::msg_caller())
+        }
+
+        #[::ic_cdk::query]
+        fn get_cycles_balance() -> u128 {
+            CYCLES_LEDGER.with_borrow(|cycles_ledger| {
+                cycles_ledger
+                    .as_ref()
+                    .unwrap()
+                    .balance(::ic_cdk::api::msg_caller())
+            })
+        }
+
+        #[::ic_cdk::query]
+        fn get_vetkey_derivation_cost() -> Result<u128, String> {
+            with_encrypted_maps(|encrypted_maps| encrypted_maps.vetkey_derivation_cost())
+        }
+    };
+
+    (@enable $instance:ident, long_names($memory_long_names:expr)) => {
+        $instance.enable_long_names($memory_long_names);
+    };
+    (@endpoints long_names($memory_long_names:expr)) => {};
+
+    (@enable $instance:ident, key_freezing($memory_frozen_keys:expr, $unfreeze_delay_ns:expr)) => {
+        $instance.enable_key_freezing(
+            $crate::key_manager::FreezeConfig {
+                unfreeze_delay_ns: $unfreeze_delay_ns,
+            },
+            $memory_frozen_keys,
+        );
+    };
+    (@endpoints key_freezing($memory_frozen_keys:expr, $unfreeze_delay_ns:expr)) => {
+        use $crate::key_manager::FreezeState as __EmFreezeState;
+
+        #[::ic_cdk::update]
+        fn freeze_map(
+            map_owner: __EmPrincipal,
+            map_name: __EmByteBuf,
+            includes_owner: bool,
+        ) -> Result<(), String> {
+            let map_id = (map_owner, __encrypted_maps_bytebuf_to_blob(map_name)?);
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.as_mut().unwrap().freeze_map(
+                    ::ic_cdk::api::msg_caller(),
+                    map_id,
+                    includes_owner,
+                )
+            })
+        }
+
+        #[::ic_cdk::update]
+        fn unfreeze_map(
+            map_owner: __EmPrincipal,
+            map_name: __EmByteBuf,
+        ) -> Result<Option<u64>, String> {
+            let map_id = (map_owner, __encrypted_maps_bytebuf_to_blob(map_name)?);
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.as_mut().unwrap().unfreeze_map(
+                    ::ic_cdk::api::msg_caller(),
+                    map_id,
+                    ::ic_cdk::api::time(),
+                )
+            })
+        }
+
+        #[::ic_cdk::query]
+        fn get_map_freeze_state(
+            map_owner: __EmPrincipal,
+            map_name: __EmByteBuf,
+        ) -> Result<Option<__EmFreezeState>, String> {
+            let map_id = (map_owner, __encrypted_maps_bytebuf_to_blob(map_name)?);
+            ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_map_freeze_state(::ic_cdk::api::msg_caller(), map_id)
+            })
+        }
+    };
+
+    (@enable $instance:ident, recovery(
+        $memory_guardians:expr,
+        $memory_pending_recoveries:expr,
+        $memory_successors:expr,
+        $memory_predecessors:expr,
+        $recovery_delay_ns:expr $(,)?
+    )) => {
+        $instance.enable_recovery(
+            $crate::key_manager::RecoveryConfig {
+                recovery_delay_ns: $recovery_delay_ns,
+            },
+            $memory_guardians,
+            $memory_pending_recoveries,
+            $memory_successors,
+            $memory_predecessors,
+        );
+    };
+    (@endpoints recovery(
+        $memory_guardians:expr,
+        $memory_pending_recoveries:expr,
+        $memory_successors:expr,
+        $memory_predecessors:expr,
+        $recovery_delay_ns:expr $(,)?
+    )) => {
+        use $crate::key_manager::PendingRecovery as __EmPendingRecovery;
+        use $crate::key_manager::RecoveryGuardians as __EmRecoveryGuardians;
+        use $crate::key_manager::RecoveryStatus as __EmRecoveryStatus;
+
+        #[::ic_cdk::update]
+        fn set_recovery_guardians(
+            guardians: Vec<__EmPrincipal>,
+            threshold: u32,
+        ) -> Result<(), String> {
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.as_mut().unwrap().set_recovery_guardians(
+                    ::ic_cdk::api::msg_caller(),
+                    guardians,
+                    threshold,
+                )
+            })
+        }
+
+        #[::ic_cdk::query]
+        fn get_recovery_guardians(owner: __EmPrincipal) -> Option<__EmRecoveryGuardians> {
+            ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_recovery_guardians(owner)
+            })
+        }
+
+        #[::ic_cdk::update]
+        fn approve_recovery(
+            owner: __EmPrincipal,
+            new_owner: __EmPrincipal,
+        ) -> Result<__EmRecoveryStatus, String> {
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.as_mut().unwrap().approve_recovery(
+                    ::ic_cdk::api::msg_caller(),
+                    owner,
+                    new_owner,
+                    ::ic_cdk::api::time(),
+                )
+            })
+        }
+
+        #[::ic_cdk::update]
+        fn cancel_recovery() -> Result<(), String> {
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps
+                    .as_mut()
+                    .unwrap()
+                    .cancel_recovery(::ic_cdk::api::msg_caller())
+            })
+        }
+
+        #[::ic_cdk::update]
+        fn complete_recovery(owner: __EmPrincipal, new_owner: __EmPrincipal) -> Result<(), String> {
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.as_mut().unwrap().complete_recovery(
+                    ::ic_cdk::api::msg_caller(),
+                    owner,
+                    new_owner,
+                    ::ic_cdk::api::time(),
+                )
+            })
+        }
+
+        #[::ic_cdk::query]
+        fn get_pending_recoveries(
+            owner: __EmPrincipal,
+        ) -> Result<Vec<(__EmPrincipal, __EmPendingRecovery)>, String> {
+            ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_pending_recoveries(::ic_cdk::api::msg_caller(), owner)
+            })
+        }
+
+        #[::ic_cdk::query]
+        fn get_recovered_principals() -> Vec<__EmPrincipal> {
+            ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_recovered_principals(::ic_cdk::api::msg_caller())
+            })
+        }
+    };
+
+    (@enable $instance:ident, migrations(
+        $memory_pending_migrations:expr,
+        $memory_migrated_principals:expr,
+        $memory_predecessors:expr $(,)?
+    )) => {
+        $instance.enable_migrations(
+            $memory_pending_migrations,
+            $memory_migrated_principals,
+            $memory_predecessors,
+        );
+    };
+    (@endpoints migrations(
+        $memory_pending_migrations:expr,
+        $memory_migrated_principals:expr,
+        $memory_predecessors:expr $(,)?
+    )) => {
+        #[::ic_cdk::update]
+        fn request_migration(new_principal: __EmPrincipal) -> Result<(), String> {
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps
+                    .as_mut()
+                    .unwrap()
+                    .request_migration(::ic_cdk::api::msg_caller(), new_principal)
+            })
+        }
+
+        #[::ic_cdk::update]
+        fn cancel_migration() -> Result<(), String> {
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps
+                    .as_mut()
+                    .unwrap()
+                    .cancel_migration(::ic_cdk::api::msg_caller())
+            })
+        }
+
+        #[::ic_cdk::query]
+        fn get_pending_migration() -> Option<__EmPrincipal> {
+            ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_pending_migration(::ic_cdk::api::msg_caller())
+            })
+        }
+
+        #[::ic_cdk::update]
+        fn confirm_migration(
+            old_principal: __EmPrincipal,
+        ) -> Result<Vec<(__EmPrincipal, __EmByteBuf)>, String> {
+            let map_ids = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps
+                    .as_mut()
+                    .unwrap()
+                    .confirm_migration(::ic_cdk::api::msg_caller(), old_principal)
+            })?;
+            Ok(map_ids
+                .into_iter()
+                .map(__encrypted_maps_map_id_to_candid)
+                .collect())
+        }
+
+        #[::ic_cdk::query]
+        fn get_migrated_principal(principal: __EmPrincipal) -> Option<__EmPrincipal> {
+            ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_migrated_principal(principal)
+            })
+        }
+    };
+
+    (@enable $instance:ident, public_maps($memory_public_maps:expr)) => {
+        $instance.enable_public_maps($memory_public_maps);
+    };
+    (@endpoints public_maps($memory_public_maps:expr)) => {
+        #[::ic_cdk::update]
+        fn make_map_public(
+            map_owner: __EmPrincipal,
+            map_name: __EmByteBuf,
+            access_rights: __EmAccessRights,
+        ) -> Result<Option<__EmAccessRights>, String> {
+            let map_id = (
+                map_owner,
+                __encrypted_maps_bytebuf_to_blob(map_name.clone())?,
+            );
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                let encrypted_maps = encrypted_maps.as_mut().unwrap();
+                let result = encrypted_maps.make_map_public(
+                    ::ic_cdk::api::msg_caller(),
+                    map_id,
+                    access_rights,
+                )?;
+                encrypted_maps.register_name(map_name.as_ref())?;
+                Ok(result)
+            })
+        }
+
+        #[::ic_cdk::update]
+        fn make_map_private(
+            map_owner: __EmPrincipal,
+            map_name: __EmByteBuf,
+        ) -> Result<Option<__EmAccessRights>, String> {
+            let map_id = (map_owner, __encrypted_maps_bytebuf_to_blob(map_name)?);
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps
+                    .as_mut()
+                    .unwrap()
+                    .make_map_private(::ic_cdk::api::msg_caller(), map_id)
+            })
         }
 
-        #[::ic_cdk::init]
-        fn __encrypted_maps_init(key_name: String) {
-            __encrypted_maps_setup(key_name);
+        #[::ic_cdk::query]
+        fn get_public_map_names_paginated(
+            start_after: Option<(__EmPrincipal, __EmByteBuf)>,
+            limit: u32,
+        ) -> Result<
+            __EmPage<
+                ((__EmPrincipal, __EmByteBuf), __EmAccessRights),
+                (__EmPrincipal, __EmByteBuf),
+            >,
+            String,
+        > {
+            let start_after = start_after
+                .map(__encrypted_maps_map_id_from_candid)
+                .transpose()?;
+            let page = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_public_map_ids_paginated(start_after, limit as usize)
+            });
+            Ok(__EmPage {
+                items: page
+                    .items
+                    .into_iter()
+                    .map(|(map_id, access_rights)| {
+                        (__encrypted_maps_map_id_to_candid(map_id), access_rights)
+                    })
+                    .collect(),
+                next_cursor: page.next_cursor.map(__encrypted_maps_map_id_to_candid),
+            })
         }
+    };
 
-        // After an upgrade `#[init]` does not run, so the thread-local wrapper
-        // would be `None` and every endpoint would trap. Re-attach it to the
-        // (persisted) stable state here. The vetKD key id and domain separator
-        // are loaded from the config `StableCell`, which ignores the passed
-        // defaults when it already holds a value — so the key name given here
-        // is irrelevant after an upgrade.
-        #[::ic_cdk::post_upgrade]
-        fn __encrypted_maps_post_upgrade() {
-            __encrypted_maps_setup(String::new());
-        }
+    (@enable $instance:ident, inbox(
+        $memory_inbox_message_ids:expr,
+        $memory_inbox_messages:expr,
+        $memory_inbox_senders:expr $(,)?
+    )) => {
+        $instance.enable_inbox(
+            $crate::key_manager::InboxConfig::default(),
+            $memory_inbox_message_ids,
+            $memory_inbox_messages,
+            $memory_inbox_senders,
+        );
+    };
+    (@endpoints inbox(
+        $memory_inbox_message_ids:expr,
+        $memory_inbox_messages:expr,
+        $memory_inbox_senders:expr $(,)?
+    )) => {
+        use $crate::key_manager::InboxMessage as __EmInboxMessage;
 
-        fn __encrypted_maps_setup(key_name: String) {
-            let key_id = ::ic_cdk_management_canister::VetKDKeyId {
-                curve: ::ic_cdk_management_canister::VetKDCurve::Bls12_381_G2,
-                name: key_name,
-            };
+        #[::ic_cdk::update]
+        fn deposit_inbox_message(
+            map_owner: __EmPrincipal,
+            map_name: __EmByteBuf,
+            ciphertext: __EmByteBuf,
+        ) -> Result<u64, String> {
+            let map_id = (map_owner, __encrypted_maps_bytebuf_to_blob(map_name)?);
             ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
-                encrypted_maps.replace(__EmEncryptedMaps::init(
-                    $domain_separator,
-                    key_id,
-                    $memory_domain_separator,
-                    $memory_access_control,
-                    $memory_shared_keys,
-                    $memory_encrypted_maps,
-                ))
-            });
+                encrypted_maps.as_mut().unwrap().deposit_inbox_message(
+                    ::ic_cdk::api::msg_caller(),
+                    map_id,
+                    ciphertext,
+                    ::ic_cdk::api::time(),
+                )
+            })
         }
 
-        /// Run `f` with a shared reference to the initialized `EncryptedMaps`.
-        ///
-        /// For hand-written endpoints that reuse the library's vetKD / crypto /
-        /// access-control logic. `pub(crate)` so it is reachable from submodules
-        /// of the invoking crate. Traps if called before `#[init]` (it never is —
-        /// the state is set up in `#[init]`/`#[post_upgrade]`).
-        #[allow(dead_code)]
-        pub(crate) fn with_encrypted_maps<__EmR>(
-            f: impl FnOnce(&__EmEncryptedMaps<__EmAccessRights>) -> __EmR,
-        ) -> __EmR {
+        #[::ic_cdk::query]
+        fn get_inbox_messages_paginated(
+            map_owner: __EmPrincipal,
+            map_name: __EmByteBuf,
+            start_after: Option<u64>,
+            limit: u32,
+        ) -> Result<__EmPage<(u64, __EmInboxMessage), u64>, String> {
+            let map_id = (map_owner, __encrypted_maps_bytebuf_to_blob(map_name)?);
             ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
-                f(encrypted_maps
+                encrypted_maps
                     .as_ref()
-                    .expect("EncryptedMaps is not initialized (called before #[init]/#[post_upgrade])"))
+                    .unwrap()
+                    .get_inbox_messages_paginated(
+                        ::ic_cdk::api::msg_caller(),
+                        map_id,
+                        start_after,
+                        limit as usize,
+                    )
             })
         }
 
-        /// Run `f` with a mutable reference to the initialized `EncryptedMaps`.
-        ///
-        /// Gives access to the raw value mutators; when wrapping them, keep any
-        /// linked side-state updated in the same endpoint call. `pub(crate)` so
-        /// it is reachable from submodules of the invoking crate.
-        #[allow(dead_code)]
-        pub(crate) fn with_encrypted_maps_mut<__EmR>(
-            f: impl FnOnce(&mut __EmEncryptedMaps<__EmAccessRights>) -> __EmR,
-        ) -> __EmR {
+        #[::ic_cdk::update]
+        fn remove_inbox_message(
+            map_owner: __EmPrincipal,
+            map_name: __EmByteBuf,
+            message_id: u64,
+        ) -> Result<Option<__EmInboxMessage>, String> {
+            let map_id = (map_owner, __encrypted_maps_bytebuf_to_blob(map_name)?);
             ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
-                f(encrypted_maps
-                    .as_mut()
-                    .expect("EncryptedMaps is not initialized (called before #[init]/#[post_upgrade])"))
+                encrypted_maps.as_mut().unwrap().remove_inbox_message(
+                    ::ic_cdk::api::msg_caller(),
+                    map_id,
+                    message_id,
+                )
+            })
+        }
+    };
+
+    (@enable $instance:ident, map_deletion($memory_tombstones:expr, $managers_can_delete:expr)) => {
+        $instance.enable_map_deletion(
+            $crate::key_manager::KeyDeletionConfig {
+                managers_can_delete: $managers_can_delete,
+            },
+            $memory_tombstones,
+        );
+    };
+    (@endpoints map_deletion($memory_tombstones:expr, $managers_can_delete:expr)) => {
+        use $crate::key_manager::KeyTombstone as __EmKeyTombstone;
+
+        #[::ic_cdk::update]
+        fn delete_map(
+            map_owner: __EmPrincipal,
+            map_name: __EmByteBuf,
+        ) -> Result<Vec<(__EmPrincipal, __EmAccessRights)>, String> {
+            let map_id = (map_owner, __encrypted_maps_bytebuf_to_blob(map_name)?);
+            ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps.as_mut().unwrap().delete_map(
+                    ::ic_cdk::api::msg_caller(),
+                    map_id,
+                    ::ic_cdk::api::time(),
+                )
             })
         }
+
+        #[::ic_cdk::query]
+        fn get_map_tombstone(
+            map_owner: __EmPrincipal,
+            map_name: __EmByteBuf,
+        ) -> Result<Option<__EmKeyTombstone>, String> {
+            let map_id = (map_owner, __encrypted_maps_bytebuf_to_blob(map_name)?);
+            Ok(ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps.as_ref().unwrap().get_map_tombstone(map_id)
+            }))
+        }
+    };
+
+    (@enable $instance:ident, event_listener($event_listener:expr)) => {
+        $instance.set_event_listener($event_listener);
+    };
+    (@endpoints event_listener($event_listener:expr)) => {};
+
+    (@enable $instance:ident, $option:ident $option_args:tt) => {
+        ::core::compile_error!(::core::concat!(
+            "unknown option `",
+            ::core::stringify!($option),
+            "`"
+        ));
     };
+    (@endpoints $option:ident $option_args:tt) => {};
 }
 
 /// The control-plane endpoints (vetKD keys, access control, map-name
@@ -320,11 +1115,39 @@ macro_rules! __export_encrypted_maps_control_plane_endpoints {
                     .unwrap()
                     .get_accessible_shared_map_names(::ic_cdk::api::msg_caller())
                     .into_iter()
-                    .map(|map_id| (map_id.0, __EmByteBuf::from(map_id.1.as_ref().to_vec())))
+                    .map(|map_id| (map_id.0, __encrypted_maps_blob_to_bytebuf(map_id.1)))
                     .collect()
             })
         }
 
+        #[::ic_cdk::query]
+        fn get_accessible_shared_map_names_paginated(
+            start_after: Option<(__EmPrincipal, __EmByteBuf)>,
+            limit: u32,
+        ) -> Result<__EmPage<(__EmPrincipal, __EmByteBuf), (__EmPrincipal, __EmByteBuf)>, String> {
+            let start_after = start_after
+                .map(__encrypted_maps_map_id_from_candid)
+                .transpose()?;
+            let page = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_accessible_shared_map_names_paginated(
+                        ::ic_cdk::api::msg_caller(),
+                        start_after,
+                        limit as usize,
+                    )
+            });
+            Ok(__EmPage {
+                items: page
+                    .items
+                    .into_iter()
+                    .map(__encrypted_maps_map_id_to_candid)
+                    .collect(),
+                next_cursor: page.next_cursor.map(__encrypted_maps_map_id_to_candid),
+            })
+        }
+
         #[::ic_cdk::query]
         fn get_shared_user_access_for_map(
             key_owner: __EmPrincipal,
@@ -340,6 +1163,28 @@ macro_rules! __export_encrypted_maps_control_plane_endpoints {
             })
         }
 
+        #[::ic_cdk::query]
+        fn get_shared_user_access_for_map_paginated(
+            key_owner: __EmPrincipal,
+            key_name: __EmByteBuf,
+            start_after: Option<__EmPrincipal>,
+            limit: u32,
+        ) -> Result<__EmPage<(__EmPrincipal, __EmAccessRights), __EmPrincipal>, String> {
+            let key_name = __encrypted_maps_bytebuf_to_blob(key_name)?;
+            let key_id = (key_owner, key_name);
+            ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_shared_user_access_for_map_paginated(
+                        ::ic_cdk::api::msg_caller(),
+                        key_id,
+                        start_after,
+                        limit as usize,
+                    )
+            })
+        }
+
         #[::ic_cdk::query]
         fn get_owned_non_empty_map_names() -> Vec<__EmByteBuf> {
             ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
@@ -348,40 +1193,161 @@ macro_rules! __export_encrypted_maps_control_plane_endpoints {
                     .unwrap()
                     .get_owned_non_empty_map_names(::ic_cdk::api::msg_caller())
                     .into_iter()
-                    .map(|map_name| __EmByteBuf::from(map_name.as_slice().to_vec()))
+                    .map(__encrypted_maps_blob_to_bytebuf)
+                    .collect()
+            })
+        }
+
+        #[::ic_cdk::query]
+        fn get_owned_shared_map_names() -> Vec<__EmByteBuf> {
+            ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_owned_shared_map_names(::ic_cdk::api::msg_caller())
+                    .into_iter()
+                    .map(__encrypted_maps_blob_to_bytebuf)
+                    .collect()
+            })
+        }
+
+        #[::ic_cdk::query]
+        fn get_all_grants_by_owner() -> Vec<(__EmByteBuf, __EmPrincipal, __EmAccessRights)> {
+            ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_all_grants_by_owner(::ic_cdk::api::msg_caller())
+                    .into_iter()
+                    .map(|(map_name, user, access_rights)| {
+                        (
+                            __encrypted_maps_blob_to_bytebuf(map_name),
+                            user,
+                            access_rights,
+                        )
+                    })
                     .collect()
             })
         }
 
         #[::ic_cdk::update]
-        async fn get_vetkey_verification_key() -> __EmVetKeyVerificationKey {
-            ENCRYPTED_MAPS
+        async fn get_vetkey_verification_key(
+        ) -> ::core::result::Result<__EmVetKeyVerificationKey, String> {
+            let (cached_verification_key, fetch_verification_key) =
+                ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                    let encrypted_maps = encrypted_maps.as_ref().unwrap();
+                    (
+                        encrypted_maps
+                            .key_manager
+                            .config
+                            .get()
+                            .verification_key
+                            .clone(),
+                        encrypted_maps.fetch_vetkey_verification_key(),
+                    )
+                });
+            if let Some(verification_key) = cached_verification_key {
+                return Ok(verification_key);
+            }
+            let verification_key = fetch_verification_key.await.map_err(|e| e.to_string())?;
+            with_encrypted_maps_mut(|encrypted_maps| {
+                encrypted_maps.cache_vetkey_verification_key(
+                    ::ic_cdk::api::canister_self(),
+                    verification_key.clone(),
+                )
+            })?;
+            Ok(verification_key)
+        }
+
+        #[::ic_cdk::query]
+        fn get_cached_vetkey_verification_key() -> Option<__EmVetKeyVerificationKey> {
+            with_encrypted_maps(|encrypted_maps| {
+                encrypted_maps.get_cached_vetkey_verification_key(::ic_cdk::api::canister_self())
+            })
+        }
+
+        #[::ic_cdk::update]
+        async fn get_encrypted_vetkey(
+            map_owner: __EmPrincipal,
+            map_name: __EmByteBuf,
+            transport_key: __EmTransportKey,
+        ) -> Result<__EmVetKey, String> {
+            let caller = ::ic_cdk::api::msg_caller();
+            // charge before the library call, which consumes rate-limit tokens
+            let cost = __encrypted_maps_charge_vetkey_derivations(caller, 1)?;
+            let encrypted_vetkey = ENCRYPTED_MAPS
                 .with_borrow(|encrypted_maps| {
                     encrypted_maps
                         .as_ref()
                         .unwrap()
-                        .get_vetkey_verification_key()
+                        .get_encrypted_vetkey_by_name(
+                            caller,
+                            map_owner,
+                            map_name.as_ref(),
+                            transport_key,
+                        )
                 })
-                .await
+                .inspect_err(|_| __encrypted_maps_refund_vetkey_derivation(caller, cost))?;
+            encrypted_vetkey.await.map_err(|e| {
+                __encrypted_maps_refund_vetkey_derivation(caller, cost);
+                e.to_string()
+            })
         }
 
         #[::ic_cdk::update]
-        async fn get_encrypted_vetkey(
+        async fn get_encrypted_vetkey_for_purpose(
             map_owner: __EmPrincipal,
             map_name: __EmByteBuf,
+            purpose: __EmByteBuf,
             transport_key: __EmTransportKey,
         ) -> Result<__EmVetKey, String> {
-            let map_name = __encrypted_maps_bytebuf_to_blob(map_name)?;
-            let map_id = (map_owner, map_name);
-            Ok(ENCRYPTED_MAPS
+            let caller = ::ic_cdk::api::msg_caller();
+            // charge before the library call, which consumes rate-limit tokens
+            let cost = __encrypted_maps_charge_vetkey_derivations(caller, 1)?;
+            let encrypted_vetkey = ENCRYPTED_MAPS
                 .with_borrow(|encrypted_maps| {
-                    encrypted_maps.as_ref().unwrap().get_encrypted_vetkey(
-                        ::ic_cdk::api::msg_caller(),
-                        map_id,
-                        transport_key,
-                    )
-                })?
-                .await)
+                    encrypted_maps
+                        .as_ref()
+                        .unwrap()
+                        .get_encrypted_vetkey_by_name_for_purpose(
+                            caller,
+                            map_owner,
+                            map_name.as_ref(),
+                            purpose.as_ref(),
+                            transport_key,
+                        )
+                })
+                .inspect_err(|_| __encrypted_maps_refund_vetkey_derivation(caller, cost))?;
+            encrypted_vetkey.await.map_err(|e| {
+                __encrypted_maps_refund_vetkey_derivation(caller, cost);
+                e.to_string()
+            })
+        }
+
+        #[::ic_cdk::update]
+        async fn get_encrypted_vetkeys(
+            map_ids: Vec<(__EmPrincipal, __EmByteBuf)>,
+            transport_key: __EmTransportKey,
+        ) -> Result<Vec<Result<__EmVetKey, String>>, String> {
+            let caller = ::ic_cdk::api::msg_caller();
+            // charge before the library call, which consumes rate-limit tokens
+            let count = map_ids.len() as u128;
+            let cost = __encrypted_maps_charge_vetkey_derivations(caller, count)?;
+            let encrypted_vetkeys = ENCRYPTED_MAPS
+                .with_borrow(|encrypted_maps| {
+                    encrypted_maps
+                        .as_ref()
+                        .unwrap()
+                        .get_encrypted_vetkeys_by_name(caller, &map_ids, transport_key)
+                })
+                .inspect_err(|_| {
+                    __encrypted_maps_refund_vetkey_derivation(caller, cost.saturating_mul(count))
+                })?;
+            let encrypted_vetkeys = encrypted_vetkeys.await;
+            for _failed in encrypted_vetkeys.iter().filter(|result| result.is_err()) {
+                __encrypted_maps_refund_vetkey_derivation(caller, cost);
+            }
+            Ok(encrypted_vetkeys)
         }
 
         #[::ic_cdk::query]
@@ -408,15 +1374,20 @@ macro_rules! __export_encrypted_maps_control_plane_endpoints {
             user: __EmPrincipal,
             access_rights: __EmAccessRights,
         ) -> Result<Option<__EmAccessRights>, String> {
-            let map_name = __encrypted_maps_bytebuf_to_blob(map_name)?;
-            let map_id = (map_owner, map_name);
+            let map_id = (
+                map_owner,
+                __encrypted_maps_bytebuf_to_blob(map_name.clone())?,
+            );
             ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
-                encrypted_maps.as_mut().unwrap().set_user_rights(
+                let encrypted_maps = encrypted_maps.as_mut().unwrap();
+                let result = encrypted_maps.set_user_rights(
                     ::ic_cdk::api::msg_caller(),
                     map_id,
                     user,
                     access_rights,
-                )
+                )?;
+                encrypted_maps.register_name(map_name.as_ref())?;
+                Ok(result)
             })
         }
 
@@ -436,6 +1407,64 @@ macro_rules! __export_encrypted_maps_control_plane_endpoints {
                 )
             })
         }
+
+        #[::ic_cdk::update]
+        fn revoke_user_everywhere(
+            user: __EmPrincipal,
+        ) -> Result<Vec<(__EmByteBuf, __EmAccessRights)>, String> {
+            let revoked = ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
+                encrypted_maps
+                    .as_mut()
+                    .unwrap()
+                    .revoke_user_everywhere(::ic_cdk::api::msg_caller(), user)
+            })?;
+            Ok(revoked
+                .into_iter()
+                .map(|(map_name, access_rights)| {
+                    (__encrypted_maps_blob_to_bytebuf(map_name), access_rights)
+                })
+                .collect())
+        }
+    };
+}
+
+/// The controller-only backup endpoints. Omitted by the
+/// `custom_value_endpoints` form. Not a public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __export_encrypted_maps_backup_endpoints {
+    () => {
+        use $crate::key_manager::BackupChunk as __EmBackupChunk;
+        use $crate::key_manager::BackupCursor as __EmBackupCursor;
+
+        fn __encrypted_maps_ensure_controller() -> Result<(), String> {
+            if ::ic_cdk::api::is_controller(&::ic_cdk::api::msg_caller()) {
+                Ok(())
+            } else {
+                Err("only controllers can export or import backups".to_string())
+            }
+        }
+
+        #[::ic_cdk::query]
+        fn export_backup_chunk(
+            cursor: Option<__EmBackupCursor>,
+            max_bytes: u64,
+        ) -> Result<__EmBackupChunk, String> {
+            __encrypted_maps_ensure_controller()?;
+            with_encrypted_maps(|encrypted_maps| {
+                encrypted_maps.export_backup_chunk(
+                    ::ic_cdk::api::canister_self(),
+                    cursor,
+                    max_bytes as usize,
+                )
+            })
+        }
+
+        #[::ic_cdk::update]
+        fn import_backup_chunk(chunk: __EmBackupChunk) -> Result<(), String> {
+            __encrypted_maps_ensure_controller()?;
+            with_encrypted_maps_mut(|encrypted_maps| encrypted_maps.import_backup_chunk(chunk))
+        }
     };
 }
 
@@ -445,6 +1474,38 @@ macro_rules! __export_encrypted_maps_control_plane_endpoints {
 #[macro_export]
 macro_rules! __export_encrypted_maps_value_endpoints {
     () => {
+        fn __encrypted_maps_map_position_from_candid(
+            position: ((__EmPrincipal, __EmByteBuf), Option<__EmByteBuf>),
+        ) -> Result<
+            (
+                (__EmPrincipal, ::ic_stable_structures::storable::Blob<32>),
+                Option<::ic_stable_structures::storable::Blob<32>>,
+            ),
+            String,
+        > {
+            Ok((
+                __encrypted_maps_map_id_from_candid(position.0)?,
+                position
+                    .1
+                    .map(__encrypted_maps_bytebuf_to_blob)
+                    .transpose()?,
+            ))
+        }
+
+        fn __encrypted_maps_map_position_to_candid(
+            position: (
+                (__EmPrincipal, ::ic_stable_structures::storable::Blob<32>),
+                Option<::ic_stable_structures::storable::Blob<32>>,
+            ),
+        ) -> ((__EmPrincipal, __EmByteBuf), Option<__EmByteBuf>) {
+            (
+                __encrypted_maps_map_id_to_candid(position.0),
+                position
+                    .1
+                    .map(|key| __EmByteBuf::from(key.as_ref().to_vec())),
+            )
+        }
+
         #[::ic_cdk::query]
         fn get_encrypted_values_for_map(
             map_owner: __EmPrincipal,
@@ -461,11 +1522,44 @@ macro_rules! __export_encrypted_maps_value_endpoints {
             result.map(|map_values| {
                 map_values
                     .into_iter()
-                    .map(|(key, value)| (__EmByteBuf::from(key.as_slice().to_vec()), value))
+                    .map(|(key, value)| (__encrypted_maps_blob_to_bytebuf(key), value))
                     .collect()
             })
         }
 
+        #[::ic_cdk::query]
+        fn get_encrypted_values_for_map_paginated(
+            map_owner: __EmPrincipal,
+            map_name: __EmByteBuf,
+            start_after: Option<__EmByteBuf>,
+            limit: u32,
+        ) -> Result<__EmPage<(__EmByteBuf, __EmEncryptedMapValue), __EmByteBuf>, String> {
+            let map_name = __encrypted_maps_bytebuf_to_blob(map_name)?;
+            let map_id = (map_owner, map_name);
+            let start_after = start_after
+                .map(__encrypted_maps_bytebuf_to_blob)
+                .transpose()?;
+            let page = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_encrypted_values_for_map_paginated(
+                        ::ic_cdk::api::msg_caller(),
+                        map_id,
+                        start_after,
+                        limit as usize,
+                    )
+            })?;
+            Ok(__EmPage {
+                items: page
+                    .items
+                    .into_iter()
+                    .map(|(key, value)| (__encrypted_maps_blob_to_bytebuf(key), value))
+                    .collect(),
+                next_cursor: page.next_cursor.map(__encrypted_maps_blob_to_bytebuf),
+            })
+        }
+
         #[::ic_cdk::query]
         fn get_all_accessible_encrypted_values() -> Vec<(
             (__EmPrincipal, __EmByteBuf),
@@ -481,10 +1575,10 @@ macro_rules! __export_encrypted_maps_value_endpoints {
                 .into_iter()
                 .map(|((owner, map_name), encrypted_values)| {
                     (
-                        (owner, __EmByteBuf::from(map_name.as_ref().to_vec())),
+                        (owner, __encrypted_maps_blob_to_bytebuf(map_name)),
                         encrypted_values
                             .into_iter()
-                            .map(|(key, value)| (__EmByteBuf::from(key.as_ref().to_vec()), value))
+                            .map(|(key, value)| (__encrypted_maps_blob_to_bytebuf(key), value))
                             .collect(),
                     )
                 })
@@ -501,6 +1595,85 @@ macro_rules! __export_encrypted_maps_value_endpoints {
             })
         }
 
+        #[::ic_cdk::query]
+        fn get_all_accessible_encrypted_values_paginated(
+            start_after: Option<((__EmPrincipal, __EmByteBuf), Option<__EmByteBuf>)>,
+            limit: u32,
+        ) -> Result<
+            __EmPage<
+                (
+                    (__EmPrincipal, __EmByteBuf),
+                    Vec<(__EmByteBuf, __EmEncryptedMapValue)>,
+                ),
+                ((__EmPrincipal, __EmByteBuf), Option<__EmByteBuf>),
+            >,
+            String,
+        > {
+            let start_after = start_after
+                .map(__encrypted_maps_map_position_from_candid)
+                .transpose()?;
+            let page = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_all_accessible_encrypted_values_paginated(
+                        ::ic_cdk::api::msg_caller(),
+                        start_after,
+                        limit as usize,
+                    )
+            });
+            Ok(__EmPage {
+                items: page
+                    .items
+                    .into_iter()
+                    .map(|(map_id, encrypted_values)| {
+                        (
+                            __encrypted_maps_map_id_to_candid(map_id),
+                            encrypted_values
+                                .into_iter()
+                                .map(|(key, value)| (__encrypted_maps_blob_to_bytebuf(key), value))
+                                .collect(),
+                        )
+                    })
+                    .collect(),
+                next_cursor: page
+                    .next_cursor
+                    .map(__encrypted_maps_map_position_to_candid),
+            })
+        }
+
+        #[::ic_cdk::query]
+        fn get_all_accessible_encrypted_maps_paginated(
+            start_after: Option<((__EmPrincipal, __EmByteBuf), Option<__EmByteBuf>)>,
+            limit: u32,
+        ) -> Result<
+            __EmPage<
+                __EmEncryptedMapData<__EmAccessRights>,
+                ((__EmPrincipal, __EmByteBuf), Option<__EmByteBuf>),
+            >,
+            String,
+        > {
+            let start_after = start_after
+                .map(__encrypted_maps_map_position_from_candid)
+                .transpose()?;
+            let page = ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
+                encrypted_maps
+                    .as_ref()
+                    .unwrap()
+                    .get_all_accessible_encrypted_maps_paginated(
+                        ::ic_cdk::api::msg_caller(),
+                        start_after,
+                        limit as usize,
+                    )
+            });
+            Ok(__EmPage {
+                items: page.items,
+                next_cursor: page
+                    .next_cursor
+                    .map(__encrypted_maps_map_position_to_candid),
+            })
+        }
+
         #[::ic_cdk::query]
         fn get_encrypted_value(
             map_owner: __EmPrincipal,
@@ -509,11 +1682,12 @@ macro_rules! __export_encrypted_maps_value_endpoints {
         ) -> Result<Option<__EmEncryptedMapValue>, String> {
             let map_name = __encrypted_maps_bytebuf_to_blob(map_name)?;
             let map_id = (map_owner, map_name);
+            let map_key = __encrypted_maps_bytebuf_to_blob(map_key)?;
             ENCRYPTED_MAPS.with_borrow(|encrypted_maps| {
                 encrypted_maps.as_ref().unwrap().get_encrypted_value(
                     ::ic_cdk::api::msg_caller(),
                     map_id,
-                    __encrypted_maps_bytebuf_to_blob(map_key)?,
+                    map_key,
                 )
             })
         }
@@ -534,7 +1708,7 @@ macro_rules! __export_encrypted_maps_value_endpoints {
             result.map(|removed| {
                 removed
                     .into_iter()
-                    .map(|key| __EmByteBuf::from(key.as_ref().to_vec()))
+                    .map(__encrypted_maps_blob_to_bytebuf)
                     .collect()
             })
         }
@@ -546,15 +1720,22 @@ macro_rules! __export_encrypted_maps_value_endpoints {
             map_key: __EmByteBuf,
             value: __EmEncryptedMapValue,
         ) -> Result<Option<__EmEncryptedMapValue>, String> {
-            let map_name = __encrypted_maps_bytebuf_to_blob(map_name)?;
-            let map_id = (map_owner, map_name);
+            let map_id = (
+                map_owner,
+                __encrypted_maps_bytebuf_to_blob(map_name.clone())?,
+            );
+            let map_key_blob = __encrypted_maps_bytebuf_to_blob(map_key.clone())?;
             ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
-                encrypted_maps.as_mut().unwrap().insert_encrypted_value(
+                let encrypted_maps = encrypted_maps.as_mut().unwrap();
+                let result = encrypted_maps.insert_encrypted_value(
                     ::ic_cdk::api::msg_caller(),
                     map_id,
-                    __encrypted_maps_bytebuf_to_blob(map_key)?,
+                    map_key_blob,
                     value,
-                )
+                )?;
+                encrypted_maps.register_name(map_name.as_ref())?;
+                encrypted_maps.register_name(map_key.as_ref())?;
+                Ok(result)
             })
         }
 
@@ -566,11 +1747,12 @@ macro_rules! __export_encrypted_maps_value_endpoints {
         ) -> Result<Option<__EmEncryptedMapValue>, String> {
             let map_name = __encrypted_maps_bytebuf_to_blob(map_name)?;
             let map_id = (map_owner, map_name);
+            let map_key = __encrypted_maps_bytebuf_to_blob(map_key)?;
             ENCRYPTED_MAPS.with_borrow_mut(|encrypted_maps| {
                 encrypted_maps.as_mut().unwrap().remove_encrypted_value(
                     ::ic_cdk::api::msg_caller(),
                     map_id,
-                    __encrypted_maps_bytebuf_to_blob(map_key)?,
+                    map_key,
                 )
             })
         }
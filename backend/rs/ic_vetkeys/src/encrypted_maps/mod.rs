@@ -10,10 +10,20 @@ use ic_stable_structures::storable::Blob;
 use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
 use serde::Deserialize;
 use std::future::Future;
+use std::ops::Bound;
 
-use crate::key_manager::KeyId;
+use crate::key_manager::{migrate_schema, QuotaReference};
+use crate::key_manager::{
+    tenant_key_id, ApprovalStatus, BackupChunk, BackupCursor, BackupSection, CallPolicy,
+    DelegationPolicy, EventListener, FreezeConfig, FreezeState, InboxConfig, InboxMessage,
+    InheritanceConfig, InheritancePlan, InvitationsConfig, KeyDeletionConfig, KeyId, KeyTombstone,
+    OrganizationAction, OrganizationsConfig, PendingRecovery, QuotaConfig, RateLimiter,
+    RecoveryConfig, RecoveryGuardians, RecoveryStatus, StableStateError, TenantId, VetKDCallError,
+    VetKeyResult,
+};
 use crate::types::{
-    AccessControl, ByteBuf, EncryptedMapValue, MapId, MapKey, MapName, TransportKey,
+    AccessControl, ByteBuf, EncryptedMapValue, MapId, MapKey, MapName, MapPosition, Page,
+    SchemaVersions, TransportKey, MAX_PAGE_SIZE,
 };
 use ic_cdk_management_canister::VetKDKeyId;
 
@@ -110,6 +120,10 @@ impl<T: AccessControl> EncryptedMaps<T> {
     ///     MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)))
     /// }
     /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stable state cannot be initialized, see [`EncryptedMaps::try_init`].
     pub fn init(
         domain_separator: &str,
         key_id: VetKDKeyId,
@@ -118,20 +132,703 @@ impl<T: AccessControl> EncryptedMaps<T> {
         memory_shared_keys: Memory,
         memory_encrypted_maps: Memory,
     ) -> Self {
-        let key_manager = crate::key_manager::KeyManager::init(
+        Self::try_init(
             domain_separator,
             key_id,
             memory_domain_separator,
             memory_access_control,
             memory_shared_keys,
-        );
+            memory_encrypted_maps,
+        )
+        .unwrap_or_else(|e| panic!("failed to initialize EncryptedMaps: {e}"))
+    }
+
+    /// Like [`EncryptedMaps::init`], but returns an error instead of panicking if the stable
+    /// state cannot be initialized or migrated to [`EncryptedMaps::SCHEMA_VERSION`].
+    /// See [`crate::key_manager::KeyManager::try_init`] for details.
+    pub fn try_init(
+        domain_separator: &str,
+        key_id: VetKDKeyId,
+        memory_domain_separator: Memory,
+        memory_access_control: Memory,
+        memory_shared_keys: Memory,
+        memory_encrypted_maps: Memory,
+    ) -> Result<Self, StableStateError> {
+        let key_manager = crate::key_manager::KeyManager::try_init(
+            domain_separator,
+            key_id,
+            memory_domain_separator,
+            memory_access_control,
+            memory_shared_keys,
+        )?;
 
         let mapkey_vals = StableBTreeMap::init(memory_encrypted_maps);
 
-        Self {
+        let mut encrypted_maps = Self {
             key_manager,
             mapkey_vals,
+        };
+        encrypted_maps.migrate_schema()?;
+        Ok(encrypted_maps)
+    }
+
+    /// The latest version of the stable-memory layout of the encrypted maps,
+    /// see [`SchemaVersions`].
+    pub const SCHEMA_VERSION: u32 = 1;
+
+    /// Migrates the stable state from the stored schema version to
+    /// [`EncryptedMaps::SCHEMA_VERSION`], one version at a time.
+    fn migrate_schema(&mut self) -> Result<(), StableStateError> {
+        let stored = self.key_manager.config.get().schema_versions.encrypted_maps;
+        migrate_schema(stored, Self::SCHEMA_VERSION, |version| {
+            match version {
+                // the unversioned layout is the one of version 1
+                0 => {}
+                _ => unreachable!("no migration from schema version {version}"),
+            }
+            let schema_versions = self.key_manager.config.get().schema_versions;
+            self.key_manager.set_schema_versions(SchemaVersions {
+                encrypted_maps: version + 1,
+                ..schema_versions
+            });
+            Ok(())
+        })
+    }
+
+    /// Sets the rules for how users with management rights may change the access rights of other users.
+    /// See [`crate::key_manager::KeyManager::set_delegation_policy`] for details.
+    pub fn set_delegation_policy(&mut self, delegation_policy: DelegationPolicy) {
+        self.key_manager.set_delegation_policy(delegation_policy);
+    }
+
+    /// Sets the rate limiter that is consulted before every vetKey derivation.
+    /// See [`crate::key_manager::KeyManager::set_rate_limiter`] for details.
+    pub fn set_rate_limiter(&mut self, rate_limiter: impl RateLimiter + 'static) {
+        self.key_manager.set_rate_limiter(rate_limiter);
+    }
+
+    /// Sets a listener that is notified of changes to the access rights and values of maps.
+    /// See [`crate::key_manager::KeyManager::set_event_listener`] for details.
+    pub fn set_event_listener(&mut self, event_listener: impl EventListener<T> + 'static) {
+        self.key_manager.set_event_listener(event_listener);
+    }
+
+    /// Enables the per-owner quotas that are enforced when sharing maps or inserting values into new maps.
+    /// See [`crate::key_manager::KeyManager::enable_quotas`] for details.
+    pub fn enable_quotas(
+        &mut self,
+        config: QuotaConfig,
+        memory_key_references: Memory,
+        memory_owner_usage: Memory,
+    ) {
+        self.key_manager.enable_quotas_with(
+            config,
+            memory_key_references,
+            memory_owner_usage,
+            self.mapkey_vals.keys().map(|(map_id, _key)| map_id),
+        );
+    }
+
+    /// Sets how the vetKD endpoints of the management canister are called.
+    /// See [`crate::key_manager::KeyManager::set_call_policy`] for details.
+    pub fn set_call_policy(&mut self, call_policy: CallPolicy) {
+        self.key_manager.set_call_policy(call_policy);
+    }
+
+    /// Enables map names and map keys longer than 32 bytes.
+    /// See [`crate::key_manager::KeyManager::enable_long_names`] for details.
+    pub fn enable_long_names(&mut self, memory_long_names: Memory) {
+        self.key_manager.enable_long_names(memory_long_names);
+    }
+
+    /// Converts a map name or map key as provided by a user to its canonical 32-byte form.
+    /// See [`crate::key_manager::KeyManager::name_from_bytes`] for details.
+    pub fn name_from_bytes(&self, name: &[u8]) -> Result<Blob<32>, String> {
+        self.key_manager.name_from_bytes(name)
+    }
+
+    /// Converts a canonical map name or map key back to the one provided by the user.
+    pub fn name_to_bytes(&self, name: &Blob<32>) -> ByteBuf {
+        self.key_manager.name_to_bytes(name)
+    }
+
+    /// Stores a map name or map key that was persisted in its canonical form.
+    /// See [`crate::key_manager::KeyManager::register_name`] for details.
+    pub fn register_name(&mut self, name: &[u8]) -> Result<(), String> {
+        self.key_manager.register_name(name)
+    }
+
+    /// Enables maps owned by organizations instead of a privileged owner.
+    /// See [`crate::key_manager::KeyManager::enable_organizations`] for details.
+    pub fn enable_organizations(
+        &mut self,
+        config: OrganizationsConfig,
+        memory_admins: Memory,
+        memory_quorums: Memory,
+        memory_approvals: Memory,
+    ) {
+        self.key_manager.enable_organizations(
+            config,
+            memory_admins,
+            memory_quorums,
+            memory_approvals,
+        );
+    }
+
+    /// Makes the caller an organization with the given admins and quorum.
+    /// See [`crate::key_manager::KeyManager::create_organization`] for details.
+    pub fn create_organization(
+        &mut self,
+        caller: Principal,
+        admins: Vec<Principal>,
+        quorum: u32,
+    ) -> Result<(), String> {
+        self.key_manager.create_organization(caller, admins, quorum)
+    }
+
+    /// Returns the admins and the quorum of an organization.
+    pub fn get_organization_admins(
+        &self,
+        organization: Principal,
+    ) -> Option<(Vec<Principal>, u32)> {
+        self.key_manager.get_organization_admins(organization)
+    }
+
+    /// Approves an action on the maps of an organization.
+    /// See [`crate::key_manager::KeyManager::approve_organization_action`] for details.
+    pub fn approve_organization_action(
+        &mut self,
+        caller: Principal,
+        organization: Principal,
+        action: OrganizationAction<T>,
+        now_ns: u64,
+    ) -> Result<ApprovalStatus, String> {
+        if let OrganizationAction::SetUserRights { key_name, .. } = &action {
+            self.key_manager
+                .ensure_key_within_quota((organization, *key_name))
+                .map_err(|e| e.to_string())?;
         }
+        self.key_manager
+            .approve_organization_action(caller, organization, action, now_ns)
+    }
+
+    /// Withdraws the caller's approval of an action on the maps of an organization.
+    /// See [`crate::key_manager::KeyManager::withdraw_organization_approval`] for details.
+    pub fn withdraw_organization_approval(
+        &mut self,
+        caller: Principal,
+        organization: Principal,
+        action: &OrganizationAction<T>,
+    ) -> Result<bool, String> {
+        self.key_manager
+            .withdraw_organization_approval(caller, organization, action)
+    }
+
+    /// Enables tenants with their own domain separators.
+    /// See [`crate::key_manager::KeyManager::enable_tenants`] for details.
+    pub fn enable_tenants(
+        &mut self,
+        memory_domain_separators: Memory,
+        memory_admins: Memory,
+        memory_key_tenants: Memory,
+    ) {
+        self.key_manager.enable_tenants(
+            memory_domain_separators,
+            memory_admins,
+            memory_key_tenants,
+        );
+    }
+
+    /// Creates a tenant with the given domain separator and admins.
+    /// See [`crate::key_manager::KeyManager::create_tenant`] for details.
+    pub fn create_tenant(
+        &mut self,
+        tenant: TenantId,
+        domain_separator: String,
+        admins: Vec<Principal>,
+    ) -> Result<(), String> {
+        self.key_manager
+            .create_tenant(tenant, domain_separator, admins)
+    }
+
+    /// Replaces the admins of a tenant.
+    /// See [`crate::key_manager::KeyManager::set_tenant_admins`] for details.
+    pub fn set_tenant_admins(
+        &mut self,
+        tenant: TenantId,
+        admins: Vec<Principal>,
+    ) -> Result<(), String> {
+        self.key_manager.set_tenant_admins(tenant, admins)
+    }
+
+    /// Returns the domain separator and the admins of a tenant.
+    pub fn get_tenant(&self, tenant: TenantId) -> Option<(String, Vec<Principal>)> {
+        self.key_manager.get_tenant(tenant)
+    }
+
+    /// Registers the map `name` of `owner` in `tenant` and returns its id.
+    /// Returns an error if the map is not registered yet but already holds values.
+    /// See [`crate::key_manager::KeyManager::register_tenant_key`] for details.
+    pub fn register_tenant_map(
+        &mut self,
+        tenant: TenantId,
+        owner: Principal,
+        name: &[u8],
+    ) -> Result<MapId, String> {
+        let map_id = tenant_key_id(tenant, owner, name);
+        if self.key_manager.get_tenant(tenant).is_some()
+            && self.key_manager.get_key_tenant(map_id).is_none()
+            && self
+                .mapkey_vals
+                .keys_range((map_id, Blob::default())..)
+                .take_while(|(id, _key)| *id == map_id)
+                .next()
+                .is_some()
+        {
+            return Err("map is already in use".to_string());
+        }
+        self.key_manager.register_tenant_key(tenant, owner, name)
+    }
+
+    /// Retrieves the public verification key of the vetKeys of a tenant.
+    /// See [`crate::key_manager::KeyManager::get_tenant_vetkey_verification_key`] for details.
+    pub fn get_tenant_vetkey_verification_key(
+        &self,
+        tenant: TenantId,
+    ) -> Result<
+        impl Future<Output = Result<VetKeyVerificationKey, VetKDCallError>> + Send + Sync,
+        String,
+    > {
+        self.key_manager.get_tenant_vetkey_verification_key(tenant)
+    }
+
+    /// Enables public maps, which every caller can read.
+    /// See [`crate::key_manager::KeyManager::enable_public_keys`] for details.
+    pub fn enable_public_maps(&mut self, memory_public_maps: Memory) {
+        self.key_manager.enable_public_keys(memory_public_maps);
+    }
+
+    /// Grants read-only `access_rights` to a map to every caller, including
+    /// the anonymous principal.
+    /// See [`crate::key_manager::KeyManager::make_key_public`] for details.
+    pub fn make_map_public(
+        &mut self,
+        caller: Principal,
+        map_id: MapId,
+        access_rights: T,
+    ) -> Result<Option<T>, String> {
+        self.key_manager
+            .make_key_public(caller, map_id, access_rights)
+    }
+
+    /// Revokes the public access rights to a map.
+    /// See [`crate::key_manager::KeyManager::make_key_private`] for details.
+    pub fn make_map_private(
+        &mut self,
+        caller: Principal,
+        map_id: MapId,
+    ) -> Result<Option<T>, String> {
+        self.key_manager.make_key_private(caller, map_id)
+    }
+
+    /// Returns the access rights every caller has to a map, or `None` if it is
+    /// not public.
+    pub fn get_public_map_access_rights(&self, map_id: MapId) -> Option<T> {
+        self.key_manager.get_public_access_rights(map_id)
+    }
+
+    /// Retrieves a page of at most `limit` public maps with their public
+    /// access rights, ordered by map ID, e.g., to discover public maps.
+    /// See [`crate::key_manager::KeyManager::get_public_key_ids_paginated`] for details.
+    pub fn get_public_map_ids_paginated(
+        &self,
+        start_after: Option<MapId>,
+        limit: usize,
+    ) -> Page<(MapId, T), MapId> {
+        self.key_manager
+            .get_public_key_ids_paginated(start_after, limit)
+    }
+
+    /// Enables the deletion of maps, see [`EncryptedMaps::delete_map`].
+    /// See [`crate::key_manager::KeyManager::enable_key_deletion`] for details.
+    pub fn enable_map_deletion(&mut self, config: KeyDeletionConfig, memory_tombstones: Memory) {
+        self.key_manager
+            .enable_key_deletion(config, memory_tombstones);
+    }
+
+    /// Enables inboxes of IBE ciphertexts addressed to maps.
+    /// See [`crate::key_manager::KeyManager::enable_inbox`] for details.
+    pub fn enable_inbox(
+        &mut self,
+        config: InboxConfig,
+        memory_next_message_ids: Memory,
+        memory_messages: Memory,
+        memory_pending_messages_per_sender: Memory,
+    ) {
+        self.key_manager.enable_inbox(
+            config,
+            memory_next_message_ids,
+            memory_messages,
+            memory_pending_messages_per_sender,
+        );
+    }
+
+    /// Deposits an IBE ciphertext into the inbox of a map and returns the ID
+    /// of the message.
+    /// See [`crate::key_manager::KeyManager::deposit_inbox_message`] for details.
+    pub fn deposit_inbox_message(
+        &mut self,
+        caller: Principal,
+        map_id: MapId,
+        ciphertext: ByteBuf,
+        now_ns: u64,
+    ) -> Result<u64, String> {
+        self.key_manager
+            .deposit_inbox_message(caller, map_id, ciphertext, now_ns)
+    }
+
+    /// Retrieves a page of the messages of the inbox of a map.
+    /// See [`crate::key_manager::KeyManager::get_inbox_messages_paginated`] for details.
+    pub fn get_inbox_messages_paginated(
+        &self,
+        caller: Principal,
+        map_id: MapId,
+        start_after: Option<u64>,
+        limit: usize,
+    ) -> Result<Page<(u64, InboxMessage), u64>, String> {
+        self.key_manager
+            .get_inbox_messages_paginated(caller, map_id, start_after, limit)
+    }
+
+    /// Removes a message from the inbox of a map.
+    /// See [`crate::key_manager::KeyManager::remove_inbox_message`] for details.
+    pub fn remove_inbox_message(
+        &mut self,
+        caller: Principal,
+        map_id: MapId,
+        message_id: u64,
+    ) -> Result<Option<InboxMessage>, String> {
+        self.key_manager
+            .remove_inbox_message(caller, map_id, message_id)
+    }
+
+    /// Enables migrations of users to other principals.
+    /// See [`crate::key_manager::KeyManager::enable_migrations`] for details.
+    pub fn enable_migrations(
+        &mut self,
+        memory_pending_migrations: Memory,
+        memory_migrated_principals: Memory,
+        memory_predecessors: Memory,
+    ) {
+        self.key_manager.enable_migrations(
+            memory_pending_migrations,
+            memory_migrated_principals,
+            memory_predecessors,
+        );
+    }
+
+    /// Requests the migration of the caller to `new_principal`.
+    /// See [`crate::key_manager::KeyManager::request_migration`] for details.
+    pub fn request_migration(
+        &mut self,
+        caller: Principal,
+        new_principal: Principal,
+    ) -> Result<(), String> {
+        self.key_manager.request_migration(caller, new_principal)
+    }
+
+    /// Cancels the pending migration of the caller.
+    pub fn cancel_migration(&mut self, caller: Principal) -> Result<(), String> {
+        self.key_manager.cancel_migration(caller)
+    }
+
+    /// Returns the principal the caller requested to migrate to, if any.
+    pub fn get_pending_migration(&self, caller: Principal) -> Option<Principal> {
+        self.key_manager.get_pending_migration(caller)
+    }
+
+    /// Confirms the migration of `old_principal` to the caller and returns
+    /// the IDs of the maps shared with `old_principal` that were moved to the
+    /// caller. The maps of `old_principal` and their values keep their IDs,
+    /// so the values stay decryptable, and are included in the accessible
+    /// maps of the caller.
+    /// See [`crate::key_manager::KeyManager::confirm_migration`] for details.
+    pub fn confirm_migration(
+        &mut self,
+        caller: Principal,
+        old_principal: Principal,
+    ) -> Result<Vec<MapId>, String> {
+        self.key_manager.confirm_migration(caller, old_principal)
+    }
+
+    /// Returns the principal that `principal` was migrated to, if any.
+    pub fn get_migrated_principal(&self, principal: Principal) -> Option<Principal> {
+        self.key_manager.get_migrated_principal(principal)
+    }
+
+    /// Enables inheritance of access rights to maps after a period of
+    /// inactivity of the owner.
+    /// See [`crate::key_manager::KeyManager::enable_inheritance`] for details.
+    pub fn enable_inheritance(
+        &mut self,
+        config: InheritanceConfig,
+        memory_plans: Memory,
+        memory_grants: Memory,
+        memory_due_plans: Memory,
+    ) {
+        self.key_manager
+            .enable_inheritance(config, memory_plans, memory_grants, memory_due_plans);
+    }
+
+    /// Creates or updates the inheritance plan of the caller.
+    /// See [`crate::key_manager::KeyManager::set_inheritance_plan`] for details.
+    pub fn set_inheritance_plan(
+        &mut self,
+        caller: Principal,
+        inactivity_period_ns: u64,
+        now_ns: u64,
+    ) -> Result<(), String> {
+        self.key_manager
+            .set_inheritance_plan(caller, inactivity_period_ns, now_ns)
+    }
+
+    /// Removes the inheritance plan of the caller including all grants.
+    pub fn cancel_inheritance_plan(&mut self, caller: Principal) -> Result<(), String> {
+        self.key_manager.cancel_inheritance_plan(caller)
+    }
+
+    /// Returns the inheritance plan of the caller, if any.
+    pub fn get_inheritance_plan(&self, caller: Principal) -> Option<InheritancePlan> {
+        self.key_manager.get_inheritance_plan(caller)
+    }
+
+    /// Adds or updates a grant of `access_rights` to a map owned by the
+    /// caller, which `beneficiary` receives when the caller's inheritance plan
+    /// is executed.
+    pub fn add_inheritance_grant(
+        &mut self,
+        caller: Principal,
+        map_id: MapId,
+        beneficiary: Principal,
+        access_rights: T,
+    ) -> Result<Option<T>, String> {
+        self.key_manager
+            .add_inheritance_grant(caller, map_id, beneficiary, access_rights)
+    }
+
+    /// Removes a grant of the caller's inheritance plan.
+    pub fn remove_inheritance_grant(
+        &mut self,
+        caller: Principal,
+        map_id: MapId,
+        beneficiary: Principal,
+    ) -> Result<Option<T>, String> {
+        self.key_manager
+            .remove_inheritance_grant(caller, map_id, beneficiary)
+    }
+
+    /// Returns the grants of the caller's inheritance plan as
+    /// `(map_id, beneficiary, access_rights)`.
+    pub fn get_inheritance_grants(&self, caller: Principal) -> Vec<(MapId, Principal, T)> {
+        self.key_manager.get_inheritance_grants(caller)
+    }
+
+    /// Records that the caller was active at `now_ns`.
+    /// See [`crate::key_manager::KeyManager::record_activity`] for details.
+    pub fn record_activity(&mut self, caller: Principal, now_ns: u64) {
+        self.key_manager.record_activity(caller, now_ns);
+    }
+
+    /// Executes the inheritance plans of all inactive owners.
+    /// See [`crate::key_manager::KeyManager::execute_inheritance`] for details.
+    pub fn execute_inheritance(&mut self, now_ns: u64) -> Vec<Principal> {
+        self.key_manager.execute_inheritance(now_ns)
+    }
+
+    /// Enables social recovery of the maps of a principal by guardians.
+    /// See [`crate::key_manager::KeyManager::enable_recovery`] for details.
+    pub fn enable_recovery(
+        &mut self,
+        config: RecoveryConfig,
+        memory_guardians: Memory,
+        memory_pending_recoveries: Memory,
+        memory_successors: Memory,
+        memory_predecessors: Memory,
+    ) {
+        self.key_manager.enable_recovery(
+            config,
+            memory_guardians,
+            memory_pending_recoveries,
+            memory_successors,
+            memory_predecessors,
+        );
+    }
+
+    /// Sets the guardians who can jointly recover the caller's maps.
+    /// See [`crate::key_manager::KeyManager::set_recovery_guardians`] for details.
+    pub fn set_recovery_guardians(
+        &mut self,
+        caller: Principal,
+        guardians: Vec<Principal>,
+        threshold: u32,
+    ) -> Result<(), String> {
+        self.key_manager
+            .set_recovery_guardians(caller, guardians, threshold)
+    }
+
+    /// Returns the guardians of `owner`, or `None` if recovery is not set up.
+    pub fn get_recovery_guardians(&self, owner: Principal) -> Option<RecoveryGuardians> {
+        self.key_manager.get_recovery_guardians(owner)
+    }
+
+    /// Approves the recovery of all maps of `owner` to `new_owner`.
+    /// See [`crate::key_manager::KeyManager::approve_recovery`] for details.
+    pub fn approve_recovery(
+        &mut self,
+        caller: Principal,
+        owner: Principal,
+        new_owner: Principal,
+        now_ns: u64,
+    ) -> Result<RecoveryStatus, String> {
+        self.key_manager
+            .approve_recovery(caller, owner, new_owner, now_ns)
+    }
+
+    /// Cancels all pending recoveries of the caller's maps.
+    pub fn cancel_recovery(&mut self, caller: Principal) -> Result<(), String> {
+        self.key_manager.cancel_recovery(caller)
+    }
+
+    /// Completes the approved recovery of all maps of `owner` to `new_owner`.
+    /// The maps keep their IDs, i.e., `owner` as map owner.
+    /// See [`crate::key_manager::KeyManager::complete_recovery`] for details.
+    pub fn complete_recovery(
+        &mut self,
+        caller: Principal,
+        owner: Principal,
+        new_owner: Principal,
+        now_ns: u64,
+    ) -> Result<(), String> {
+        self.key_manager
+            .complete_recovery(caller, owner, new_owner, now_ns)
+    }
+
+    /// Retrieves the pending recoveries of `owner` by new owner.
+    /// See [`crate::key_manager::KeyManager::get_pending_recoveries`] for details.
+    pub fn get_pending_recoveries(
+        &self,
+        caller: Principal,
+        owner: Principal,
+    ) -> Result<Vec<(Principal, PendingRecovery)>, String> {
+        self.key_manager.get_pending_recoveries(caller, owner)
+    }
+
+    /// Returns the principals whose maps were recovered to the caller. Their
+    /// maps are included in the accessible maps of the caller.
+    pub fn get_recovered_principals(&self, caller: Principal) -> Vec<Principal> {
+        self.key_manager.get_recovered_principals(caller)
+    }
+
+    /// Enables freezing maps in an emergency.
+    /// See [`crate::key_manager::KeyManager::enable_key_freezing`] for details.
+    pub fn enable_key_freezing(&mut self, config: FreezeConfig, memory_frozen_keys: Memory) {
+        self.key_manager
+            .enable_key_freezing(config, memory_frozen_keys);
+    }
+
+    /// Freezes a map: until it is unfrozen, its vetKey cannot be retrieved and
+    /// its values cannot be modified.
+    /// See [`crate::key_manager::KeyManager::freeze_key`] for details.
+    pub fn freeze_map(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        includes_owner: bool,
+    ) -> Result<(), String> {
+        self.key_manager.freeze_key(caller, key_id, includes_owner)
+    }
+
+    /// Lifts or requests to lift the freeze of a map.
+    /// See [`crate::key_manager::KeyManager::unfreeze_key`] for details.
+    pub fn unfreeze_map(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        now_ns: u64,
+    ) -> Result<Option<u64>, String> {
+        self.key_manager.unfreeze_key(caller, key_id, now_ns)
+    }
+
+    /// Retrieves the freeze state of a map, or `None` if it is not frozen.
+    /// The caller must have appropriate permissions to view the access rights of the map.
+    pub fn get_map_freeze_state(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Option<FreezeState>, String> {
+        self.key_manager.get_freeze_state(caller, key_id)
+    }
+
+    /// Enables the invitation flow for sharing maps.
+    /// See [`crate::key_manager::KeyManager::enable_invitations`] for details.
+    pub fn enable_invitations(
+        &mut self,
+        config: InvitationsConfig,
+        memory_pending_invitations: Memory,
+        memory_invited_users: Memory,
+        memory_blocked_principals: Memory,
+    ) {
+        self.key_manager.enable_invitations(
+            config,
+            memory_pending_invitations,
+            memory_invited_users,
+            memory_blocked_principals,
+        );
+    }
+
+    /// Lists the pending invitations of the caller as tuples of the map ID,
+    /// the offered access rights, and the inviting principal.
+    pub fn get_pending_invitations(&self, caller: Principal) -> Vec<(MapId, T, Principal)> {
+        self.key_manager.get_pending_invitations(caller)
+    }
+
+    /// Retrieves the users with a pending invitation to a map, along with the offered access rights.
+    /// The caller must have appropriate permissions to view this information.
+    pub fn get_pending_invitations_for_map(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Vec<(Principal, T)>, String> {
+        self.key_manager
+            .get_pending_invitations_for_key(caller, key_id)
+    }
+
+    /// Accepts a pending invitation to a map, granting the caller the offered access rights.
+    pub fn accept_invitation(&mut self, caller: Principal, key_id: KeyId) -> Result<T, String> {
+        self.key_manager.accept_invitation(caller, key_id)
+    }
+
+    /// Declines a pending invitation to a map.
+    pub fn decline_invitation(&mut self, caller: Principal, key_id: KeyId) -> Result<T, String> {
+        self.key_manager.decline_invitation(caller, key_id)
+    }
+
+    /// Blocks invitations from `principal` to the caller.
+    pub fn block_principal(
+        &mut self,
+        caller: Principal,
+        principal: Principal,
+    ) -> Result<(), String> {
+        self.key_manager.block_principal(caller, principal)
+    }
+
+    /// Unblocks invitations from `principal` to the caller.
+    pub fn unblock_principal(
+        &mut self,
+        caller: Principal,
+        principal: Principal,
+    ) -> Result<bool, String> {
+        self.key_manager.unblock_principal(caller, principal)
     }
 
     /// Lists all map names shared with the caller.
@@ -151,15 +848,46 @@ impl<T: AccessControl> EncryptedMaps<T> {
             .get_shared_user_access_for_key(caller, key_id)
     }
 
+    /// Lists a page of at most `limit` map names shared with the caller.
+    /// The page starts after the map ID `start_after` or at the first map ID if `None`.
+    pub fn get_accessible_shared_map_names_paginated(
+        &self,
+        caller: Principal,
+        start_after: Option<KeyId>,
+        limit: usize,
+    ) -> Page<KeyId, KeyId> {
+        self.key_manager
+            .get_accessible_shared_key_ids_paginated(caller, start_after, limit)
+    }
+
+    /// Retrieves a page of at most `limit` users and their access rights for a specific map.
+    /// The page starts after the user `start_after` or at the first user if `None`.
+    /// The caller must have appropriate permissions to view this information.
+    pub fn get_shared_user_access_for_map_paginated(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        start_after: Option<Principal>,
+        limit: usize,
+    ) -> Result<Page<(Principal, T), Principal>, String> {
+        self.key_manager.get_shared_user_access_for_key_paginated(
+            caller,
+            key_id,
+            start_after,
+            limit,
+        )
+    }
+
     /// Removes all values from a map if the caller has sufficient rights.
     /// Returns the removed keys.
-    /// The caller must have write permissions to perform this operation.
+    /// The caller must have permission to remove values, see [`AccessControl::can_remove`].
     pub fn remove_map_values(
         &mut self,
         caller: Principal,
         key_id: KeyId,
     ) -> Result<Vec<MapKey>, String> {
-        self.key_manager.ensure_user_can_write(caller, key_id)?;
+        self.key_manager.ensure_user_can_remove(caller, key_id)?;
+        self.key_manager.ensure_not_frozen(caller, key_id)?;
 
         let keys: Vec<_> = self
             .mapkey_vals
@@ -170,11 +898,52 @@ impl<T: AccessControl> EncryptedMaps<T> {
 
         for key in keys.iter() {
             self.mapkey_vals.remove(&(key_id, *key));
+            self.key_manager
+                .remove_quota_reference(key_id, QuotaReference::MapValue);
+            self.key_manager
+                .notify(|listener| listener.on_value_removed(caller, key_id, *key));
         }
 
         Ok(keys)
     }
 
+    /// Deletes a map at the current time `now_ns` (nanoseconds since the
+    /// UNIX epoch): removes all its values, shares, and related metadata and
+    /// keeps a tombstone instead. Returns the revoked shares as
+    /// `(user, access_rights)`.
+    /// See [`crate::key_manager::KeyManager::delete_key`] for details.
+    pub fn delete_map(
+        &mut self,
+        caller: Principal,
+        map_id: MapId,
+        now_ns: u64,
+    ) -> Result<Vec<(Principal, T)>, String> {
+        let revoked_shares = self.key_manager.delete_key(caller, map_id, now_ns)?;
+
+        let keys: Vec<_> = self
+            .mapkey_vals
+            .range((map_id, Blob::default())..)
+            .take_while(|entry| entry.key().0 == map_id)
+            .map(|entry| entry.key().1)
+            .collect();
+        for key in keys {
+            self.mapkey_vals.remove(&(map_id, key));
+            self.key_manager
+                .remove_quota_reference(map_id, QuotaReference::MapValue);
+            self.key_manager
+                .notify(|listener| listener.on_value_removed(caller, map_id, key));
+        }
+
+        Ok(revoked_shares)
+    }
+
+    /// Returns the tombstone of a deleted map, or `None` if the map was never
+    /// deleted, so that clients can tell a deleted map from one that never
+    /// existed.
+    pub fn get_map_tombstone(&self, map_id: MapId) -> Option<KeyTombstone> {
+        self.key_manager.get_key_tombstone(map_id)
+    }
+
     /// Retrieves all encrypted key-value pairs from a map.
     /// The caller must have read permissions to access the map values.
     pub fn get_encrypted_values_for_map(
@@ -192,6 +961,31 @@ impl<T: AccessControl> EncryptedMaps<T> {
             .collect())
     }
 
+    /// Retrieves a page of at most `limit` encrypted key-value pairs from a map.
+    /// The page starts after the map key `start_after` or at the first map key if `None`.
+    /// The caller must have read permissions to access the map values.
+    pub fn get_encrypted_values_for_map_paginated(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        start_after: Option<MapKey>,
+        limit: usize,
+    ) -> Result<Page<(MapKey, EncryptedMapValue), MapKey>, String> {
+        self.key_manager.ensure_user_can_read(caller, key_id)?;
+
+        let start = match start_after {
+            Some(key) => Bound::Excluded((key_id, key)),
+            None => Bound::Included((key_id, Blob::default())),
+        };
+        let entries = self
+            .mapkey_vals
+            .range((start, Bound::Unbounded))
+            .take_while(|entry| entry.key().0 == key_id)
+            .map(|entry| (entry.key().1, entry.value()));
+
+        Ok(Page::collect(entries, limit, |(key, _value)| *key))
+    }
+
     /// Retrieves a specific encrypted value from a map.
     /// The caller must have read permissions to access the value.
     pub fn get_encrypted_value(
@@ -205,51 +999,201 @@ impl<T: AccessControl> EncryptedMaps<T> {
     }
 
     /// Retrieves the non-empty map names owned by the caller.
+    /// Maps shared with the caller without read permissions are skipped.
     pub fn get_all_accessible_encrypted_values(
         &self,
         caller: Principal,
     ) -> Vec<(MapId, Vec<(MapKey, EncryptedMapValue)>)> {
         let mut result = Vec::new();
         for map_id in self.get_accessible_map_ids_iter(caller) {
-            let map_values = self.get_encrypted_values_for_map(caller, map_id).unwrap();
-            result.push((map_id, map_values));
+            if let Ok(map_values) = self.get_encrypted_values_for_map(caller, map_id) {
+                result.push((map_id, map_values));
+            }
         }
         result
     }
 
+    /// Retrieves a page of at most `limit` encrypted values from the maps accessible to the caller,
+    /// grouped by map. Each map counts as at least one item, so that maps without values are listed too.
+    /// The page starts after the [`MapPosition`] `start_after` or at the first map if `None`.
+    /// The values of a map may be split across consecutive pages, each containing an item for the
+    /// same map ID.
+    /// Maps shared with the caller without read permissions are skipped.
+    pub fn get_all_accessible_encrypted_values_paginated(
+        &self,
+        caller: Principal,
+        start_after: Option<MapPosition>,
+        limit: usize,
+    ) -> Page<(MapId, Vec<(MapKey, EncryptedMapValue)>), MapPosition> {
+        let mut remaining = limit.clamp(1, MAX_PAGE_SIZE);
+        let mut items = Vec::new();
+        let mut next = start_after;
+        loop {
+            let (map_id, start_key) = match next {
+                Some((map_id, Some(key))) => (map_id, Some(key)),
+                _ => {
+                    let start_after = next.map(|(map_id, _key)| map_id);
+                    match self
+                        .get_accessible_map_ids_page(caller, start_after, 1)
+                        .items
+                        .pop()
+                    {
+                        Some(map_id) => (map_id, None),
+                        None => {
+                            return Page {
+                                items,
+                                next_cursor: None,
+                            }
+                        }
+                    }
+                }
+            };
+            if remaining == 0 {
+                return Page {
+                    items,
+                    next_cursor: next,
+                };
+            }
+            // Skip maps that the caller can no longer read, e.g., because
+            // access was revoked between two pages.
+            let Ok(values) =
+                self.get_encrypted_values_for_map_paginated(caller, map_id, start_key, remaining)
+            else {
+                next = Some((map_id, None));
+                continue;
+            };
+            remaining = remaining.saturating_sub(values.items.len().max(1));
+            next = Some((map_id, values.next_cursor));
+            if start_key.is_none() || !values.items.is_empty() {
+                items.push((map_id, values.items));
+            }
+        }
+    }
+
     /// Retrieves all accessible encrypted maps and their data for the caller.
+    /// Maps shared with the caller without read permissions are skipped.
     pub fn get_all_accessible_encrypted_maps(&self, caller: Principal) -> Vec<EncryptedMapData<T>> {
         let mut result = Vec::new();
         for map_id in self.get_accessible_map_ids_iter(caller) {
-            let keyvals = self
-                .get_encrypted_values_for_map(caller, map_id)
-                .unwrap()
-                .into_iter()
-                .map(|(key, value)| (ByteBuf::from(key.as_ref().to_vec()), value))
-                .collect();
-            let map = EncryptedMapData {
-                map_owner: map_id.0,
-                map_name: ByteBuf::from(map_id.1.as_ref().to_vec()),
-                keyvals,
-                access_control: self
-                    .get_shared_user_access_for_map(caller, map_id)
-                    .unwrap_or_default(),
-            };
-            result.push(map);
+            if let Ok(map_data) = self.get_encrypted_map_data(caller, map_id) {
+                result.push(map_data);
+            }
         }
         result
     }
 
+    /// Retrieves a page of at most `limit` encrypted values from the maps accessible to the caller,
+    /// grouped by map together with the map data.
+    /// Pagination works as in [`Self::get_all_accessible_encrypted_values_paginated`], i.e., the
+    /// values of a map may be split across consecutive pages.
+    pub fn get_all_accessible_encrypted_maps_paginated(
+        &self,
+        caller: Principal,
+        start_after: Option<MapPosition>,
+        limit: usize,
+    ) -> Page<EncryptedMapData<T>, MapPosition> {
+        let page = self.get_all_accessible_encrypted_values_paginated(caller, start_after, limit);
+        Page {
+            items: page
+                .items
+                .into_iter()
+                .map(|(map_id, map_values)| self.to_encrypted_map_data(caller, map_id, map_values))
+                .collect(),
+            next_cursor: page.next_cursor,
+        }
+    }
+
+    fn get_encrypted_map_data(
+        &self,
+        caller: Principal,
+        map_id: MapId,
+    ) -> Result<EncryptedMapData<T>, String> {
+        let map_values = self.get_encrypted_values_for_map(caller, map_id)?;
+        Ok(self.to_encrypted_map_data(caller, map_id, map_values))
+    }
+
+    fn to_encrypted_map_data(
+        &self,
+        caller: Principal,
+        map_id: MapId,
+        map_values: Vec<(MapKey, EncryptedMapValue)>,
+    ) -> EncryptedMapData<T> {
+        let keyvals = map_values
+            .into_iter()
+            .map(|(key, value)| (self.name_to_bytes(&key), value))
+            .collect();
+        EncryptedMapData {
+            map_owner: map_id.0,
+            map_name: self.name_to_bytes(&map_id.1),
+            keyvals,
+            access_control: self
+                .get_shared_user_access_for_map(caller, map_id)
+                .unwrap_or_default(),
+        }
+    }
+
     fn get_accessible_map_ids_iter(
         &self,
         caller: Principal,
     ) -> impl Iterator<Item = (Principal, MapName)> {
         let accessible_map_ids = self.get_accessible_shared_map_names(caller).into_iter();
-        let owned_map_ids =
-            std::iter::repeat(caller).zip(self.get_owned_non_empty_map_names(caller));
+        // includes the maps of principals recovered to the caller
+        let owned_map_ids: Vec<MapId> = self
+            .key_manager
+            .owned_principals(caller)
+            .into_iter()
+            .flat_map(|owner| self.owned_non_empty_map_ids_iter(owner, None))
+            .collect();
         accessible_map_ids.chain(owned_map_ids)
     }
 
+    /// Returns a page of the map IDs accessible to the caller in ascending
+    /// order, i.e., the shared maps merged with the caller's non-empty maps.
+    fn get_accessible_map_ids_page(
+        &self,
+        caller: Principal,
+        start_after: Option<MapId>,
+        limit: usize,
+    ) -> Page<MapId, MapId> {
+        // At most `limit + 1` IDs of each kind can end up in the page, so it
+        // suffices to merge that many of each.
+        let take = limit.max(1).saturating_add(1);
+        let mut map_ids: Vec<MapId> = self
+            .key_manager
+            .get_accessible_shared_key_ids_paginated(caller, start_after, take)
+            .items;
+        for owner in self.key_manager.owned_principals(caller) {
+            map_ids.extend(
+                self.owned_non_empty_map_ids_iter(owner, start_after)
+                    .take(take),
+            );
+        }
+        map_ids.sort();
+        map_ids.dedup();
+        Page::collect(map_ids.into_iter(), limit, |map_id| *map_id)
+    }
+
+    fn owned_non_empty_map_ids_iter(
+        &self,
+        caller: Principal,
+        start_after: Option<MapId>,
+    ) -> impl Iterator<Item = MapId> + '_ {
+        let start = match start_after {
+            // Skip all entries of the map `start_after`, i.e., up to and including its greatest possible key.
+            Some(map_id) if map_id.0 >= caller => {
+                let max_map_key = Blob::try_from([u8::MAX; 32].as_slice()).unwrap();
+                Bound::Excluded((map_id, max_map_key))
+            }
+            _ => Bound::Included(((caller, Blob::default()), Blob::default())),
+        };
+        let mut last_map_id = None;
+        self.mapkey_vals
+            .keys_range((start, Bound::Unbounded))
+            .take_while(move |((principal, _map_name), _key_name)| principal == &caller)
+            .map(|(map_id, _key_name)| map_id)
+            .filter(move |map_id| last_map_id.replace(*map_id) != Some(*map_id))
+    }
+
     /// Retrieves the non-empty map names owned by the caller.
     /// Returns a list of map names that contain at least one key-value pair.
     pub fn get_owned_non_empty_map_names(&self, caller: Principal) -> Vec<MapName> {
@@ -266,7 +1210,9 @@ impl<T: AccessControl> EncryptedMaps<T> {
     }
 
     /// Inserts or updates an encrypted value in a map.
-    /// The caller must have write permissions to modify the map.
+    /// The caller must have permission to insert a new key or to overwrite an existing one,
+    /// see [`AccessControl::can_insert`] and [`AccessControl::can_overwrite`].
+    /// Inserting into a new map fails if it exceeds the map quota of the owner, see [`EncryptedMaps::enable_quotas`].
     pub fn insert_encrypted_value(
         &mut self,
         caller: Principal,
@@ -274,28 +1220,88 @@ impl<T: AccessControl> EncryptedMaps<T> {
         key: MapKey,
         encrypted_value: EncryptedMapValue,
     ) -> Result<Option<EncryptedMapValue>, String> {
-        self.key_manager.ensure_user_can_write(caller, key_id)?;
-        Ok(self.mapkey_vals.insert((key_id, key), encrypted_value))
+        if self.mapkey_vals.contains_key(&(key_id, key)) {
+            self.key_manager.ensure_user_can_overwrite(caller, key_id)?;
+        } else {
+            self.key_manager.ensure_user_can_insert(caller, key_id)?;
+        }
+        self.key_manager.ensure_not_frozen(caller, key_id)?;
+        self.key_manager
+            .ensure_key_within_quota(key_id)
+            .map_err(|e| e.to_string())?;
+        let old_value = self.mapkey_vals.insert((key_id, key), encrypted_value);
+        if old_value.is_none() {
+            self.key_manager
+                .add_quota_reference(key_id, QuotaReference::MapValue);
+        }
+        self.key_manager
+            .notify(|listener| listener.on_value_inserted(caller, key_id, key));
+        Ok(old_value)
     }
 
     /// Removes an encrypted value from a map.
-    /// The caller must have write permissions to modify the map.
+    /// The caller must have permission to remove values, see [`AccessControl::can_remove`].
     pub fn remove_encrypted_value(
         &mut self,
         caller: Principal,
         key_id: KeyId,
         key: MapKey,
     ) -> Result<Option<EncryptedMapValue>, String> {
-        self.key_manager.ensure_user_can_write(caller, key_id)?;
-        Ok(self.mapkey_vals.remove(&(key_id, key)))
+        self.key_manager.ensure_user_can_remove(caller, key_id)?;
+        self.key_manager.ensure_not_frozen(caller, key_id)?;
+        let old_value = self.mapkey_vals.remove(&(key_id, key));
+        if old_value.is_some() {
+            self.key_manager
+                .remove_quota_reference(key_id, QuotaReference::MapValue);
+            self.key_manager
+                .notify(|listener| listener.on_value_removed(caller, key_id, key));
+        }
+        Ok(old_value)
+    }
+
+    /// Returns the number of cycles that deriving a single vetKey costs the canister.
+    /// See [`crate::key_manager::KeyManager::vetkey_derivation_cost`] for details.
+    pub fn vetkey_derivation_cost(&self) -> Result<u128, String> {
+        self.key_manager.vetkey_derivation_cost()
     }
 
     /// Retrieves the public verification key from KeyManager.
     /// This key is used to verify the authenticity of derived keys.
+    /// See [`crate::key_manager::KeyManager::get_vetkey_verification_key`] for details.
     pub fn get_vetkey_verification_key(
         &self,
-    ) -> impl Future<Output = VetKeyVerificationKey> + Send + Sync {
-        self.key_manager.get_vetkey_verification_key()
+        canister_id: Principal,
+    ) -> impl Future<Output = Result<VetKeyVerificationKey, VetKDCallError>> + Send + Sync {
+        self.key_manager.get_vetkey_verification_key(canister_id)
+    }
+
+    /// Retrieves the public verification key from the management canister.
+    /// See [`crate::key_manager::KeyManager::fetch_vetkey_verification_key`] for details.
+    pub fn fetch_vetkey_verification_key(
+        &self,
+    ) -> impl Future<Output = Result<VetKeyVerificationKey, VetKDCallError>> + Send + Sync {
+        self.key_manager.fetch_vetkey_verification_key()
+    }
+
+    /// Returns the vetKD verification key without a call to the management canister, if available.
+    /// See [`crate::key_manager::KeyManager::get_cached_vetkey_verification_key`] for details.
+    pub fn get_cached_vetkey_verification_key(
+        &self,
+        canister_id: Principal,
+    ) -> Option<VetKeyVerificationKey> {
+        self.key_manager
+            .get_cached_vetkey_verification_key(canister_id)
+    }
+
+    /// Caches the vetKD verification key returned by the management canister.
+    /// See [`crate::key_manager::KeyManager::cache_vetkey_verification_key`] for details.
+    pub fn cache_vetkey_verification_key(
+        &mut self,
+        canister_id: Principal,
+        verification_key: VetKeyVerificationKey,
+    ) -> Result<(), String> {
+        self.key_manager
+            .cache_vetkey_verification_key(canister_id, verification_key)
     }
 
     /// Retrieves an encrypted vetkey for caller and key id.
@@ -305,11 +1311,80 @@ impl<T: AccessControl> EncryptedMaps<T> {
         caller: Principal,
         key_id: KeyId,
         transport_key: TransportKey,
-    ) -> Result<impl Future<Output = VetKey> + Send + Sync, String> {
+    ) -> Result<impl Future<Output = Result<VetKey, VetKDCallError>> + Send + Sync, String> {
         self.key_manager
             .get_encrypted_vetkey(caller, key_id, transport_key)
     }
 
+    /// Retrieves an encrypted vetkey for caller and a map name as provided by the user.
+    /// See [`crate::key_manager::KeyManager::get_encrypted_vetkey_by_name`] for details.
+    pub fn get_encrypted_vetkey_by_name(
+        &self,
+        caller: Principal,
+        map_owner: Principal,
+        map_name: &[u8],
+        transport_key: TransportKey,
+    ) -> Result<impl Future<Output = Result<VetKey, VetKDCallError>> + Send + Sync, String> {
+        self.key_manager
+            .get_encrypted_vetkey_by_name(caller, map_owner, map_name, transport_key)
+    }
+
+    /// Retrieves an encrypted vetkey for caller, map id, and purpose.
+    /// See [`crate::key_manager::KeyManager::get_encrypted_vetkey_for_purpose`] for details.
+    pub fn get_encrypted_vetkey_for_purpose(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        purpose: &[u8],
+        transport_key: TransportKey,
+    ) -> Result<impl Future<Output = Result<VetKey, VetKDCallError>> + Send + Sync, String> {
+        self.key_manager
+            .get_encrypted_vetkey_for_purpose(caller, key_id, purpose, transport_key)
+    }
+
+    /// Retrieves an encrypted vetkey for caller, a map name as provided by the user, and purpose.
+    /// See [`crate::key_manager::KeyManager::get_encrypted_vetkey_by_name_for_purpose`] for details.
+    pub fn get_encrypted_vetkey_by_name_for_purpose(
+        &self,
+        caller: Principal,
+        map_owner: Principal,
+        map_name: &[u8],
+        purpose: &[u8],
+        transport_key: TransportKey,
+    ) -> Result<impl Future<Output = Result<VetKey, VetKDCallError>> + Send + Sync, String> {
+        self.key_manager.get_encrypted_vetkey_by_name_for_purpose(
+            caller,
+            map_owner,
+            map_name,
+            purpose,
+            transport_key,
+        )
+    }
+
+    /// Retrieves encrypted vetkeys for caller and each of the given map ids.
+    /// See [`crate::key_manager::KeyManager::get_encrypted_vetkeys`] for details.
+    pub fn get_encrypted_vetkeys(
+        &self,
+        caller: Principal,
+        map_ids: Vec<MapId>,
+        transport_key: TransportKey,
+    ) -> Result<impl Future<Output = Vec<(MapId, VetKeyResult)>> + Send + Sync, String> {
+        self.key_manager
+            .get_encrypted_vetkeys(caller, map_ids, transport_key)
+    }
+
+    /// Retrieves encrypted vetkeys for caller and each of the given map owners and map names as provided by the user.
+    /// See [`crate::key_manager::KeyManager::get_encrypted_vetkeys_by_name`] for details.
+    pub fn get_encrypted_vetkeys_by_name(
+        &self,
+        caller: Principal,
+        maps: &[(Principal, ByteBuf)],
+        transport_key: TransportKey,
+    ) -> Result<impl Future<Output = Vec<VetKeyResult>> + Send + Sync, String> {
+        self.key_manager
+            .get_encrypted_vetkeys_by_name(caller, maps, transport_key)
+    }
+
     /// Retrieves access rights for a user to a map.
     /// The caller must have appropriate permissions to view this information.
     pub fn get_user_rights(
@@ -322,7 +1397,9 @@ impl<T: AccessControl> EncryptedMaps<T> {
     }
 
     /// Sets or updates access rights for a user to a map.
-    /// Only the map owner or a user with management rights can perform this action.
+    /// Only the map owner or a user with management rights can perform this action, see
+    /// [`crate::key_manager::KeyManager::ensure_user_can_share`] for users who can only share certain rights.
+    /// Sharing a new map fails if it exceeds the quotas of the owner, see [`EncryptedMaps::enable_quotas`].
     pub fn set_user_rights(
         &mut self,
         caller: Principal,
@@ -330,6 +1407,11 @@ impl<T: AccessControl> EncryptedMaps<T> {
         user: Principal,
         access_rights: T,
     ) -> Result<Option<T>, String> {
+        self.key_manager
+            .ensure_user_can_share(caller, key_id, user, access_rights)?;
+        self.key_manager
+            .ensure_key_within_quota(key_id)
+            .map_err(|e| e.to_string())?;
         self.key_manager
             .set_user_rights(caller, key_id, user, access_rights)
     }
@@ -344,6 +1426,71 @@ impl<T: AccessControl> EncryptedMaps<T> {
     ) -> Result<Option<T>, String> {
         self.key_manager.remove_user(caller, key_id, user)
     }
+
+    /// Retrieves the names of the maps owned by the caller that are shared
+    /// with at least one user, in ascending order.
+    pub fn get_owned_shared_map_names(&self, caller: Principal) -> Vec<MapName> {
+        self.key_manager
+            .get_owned_shared_key_ids(caller)
+            .into_iter()
+            .map(|(_owner, map_name)| map_name)
+            .collect()
+    }
+
+    /// Retrieves all shares of the maps owned by the caller as
+    /// `(map_name, user, access_rights)`.
+    /// See [`crate::key_manager::KeyManager::get_all_grants_by_owner`] for details.
+    pub fn get_all_grants_by_owner(&self, caller: Principal) -> Vec<(MapName, Principal, T)> {
+        self.key_manager
+            .get_all_grants_by_owner(caller)
+            .into_iter()
+            .map(|((_owner, map_name), user, access_rights)| (map_name, user, access_rights))
+            .collect()
+    }
+
+    /// Revokes a user's access to all maps owned by the caller and returns the
+    /// revoked access rights per map name.
+    /// See [`crate::key_manager::KeyManager::revoke_user_everywhere`] for details.
+    pub fn revoke_user_everywhere(
+        &mut self,
+        caller: Principal,
+        user: Principal,
+    ) -> Result<Vec<(MapName, T)>, String> {
+        Ok(self
+            .key_manager
+            .revoke_user_everywhere(caller, user)?
+            .into_iter()
+            .map(|((_owner, map_name), access_rights)| (map_name, access_rights))
+            .collect())
+    }
+
+    /// Exports a chunk of the stable state, including the encrypted values after the
+    /// state of the key manager.
+    /// See [`crate::key_manager::KeyManager::export_backup_chunk`] for details.
+    pub fn export_backup_chunk(
+        &self,
+        canister_id: Principal,
+        cursor: Option<BackupCursor>,
+        max_bytes: usize,
+    ) -> Result<BackupChunk, String> {
+        self.key_manager.export_backup_chunk_with(
+            canister_id,
+            vec![(BackupSection::MapValues, &self.mapkey_vals)],
+            cursor,
+            max_bytes,
+        )
+    }
+
+    /// Imports a chunk exported with [`EncryptedMaps::export_backup_chunk`] into an empty instance.
+    /// See [`crate::key_manager::KeyManager::import_backup_chunk`] for details.
+    pub fn import_backup_chunk(&mut self, chunk: BackupChunk) -> Result<(), String> {
+        self.key_manager.import_backup_chunk_with(
+            vec![(BackupSection::MapValues, &mut self.mapkey_vals)],
+            &chunk,
+        )?;
+        self.key_manager.certify_backup_chunk(&chunk);
+        Ok(())
+    }
 }
 
 /// Represents the complete data for an encrypted map, including ownership, contents, and access control.
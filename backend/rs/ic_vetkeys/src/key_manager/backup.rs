@@ -0,0 +1,511 @@
+//! Chunked export and import of the stable state, see
+//! [`KeyManager::export_backup_chunk`].
+
+use super::{KeyManager, Memory};
+use crate::types::{AccessControl, ByteBuf};
+use candid::{CandidType, Principal};
+use ic_cdk_management_canister::VetKDKeyId;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::ops::Bound;
+
+/// The version of the backup format produced by [`KeyManager::export_backup_chunk`].
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+/// A stable map contained in a backup. Sections are exported in the order of
+/// their declaration, and the sections of disabled features are omitted.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BackupSection {
+    AccessControl,
+    SharedKeys,
+    LongNames,
+    OrganizationAdmins,
+    OrganizationQuorums,
+    OrganizationApprovals,
+    PendingInvitations,
+    InvitedUsers,
+    BlockedPrincipals,
+    TenantDomainSeparators,
+    TenantAdmins,
+    KeyTenants,
+    FrozenKeys,
+    RecoveryGuardians,
+    PendingRecoveries,
+    RecoverySuccessors,
+    RecoveryPredecessors,
+    InheritancePlans,
+    InheritanceGrants,
+    InheritanceDuePlans,
+    PendingMigrations,
+    MigratedPrincipals,
+    MigrationPredecessors,
+    PublicKeys,
+    InboxMessageIds,
+    InboxMessages,
+    InboxSenders,
+    KeyTombstones,
+    QuotaKeyReferences,
+    QuotaOwnerUsage,
+    /// The encrypted values of [`crate::encrypted_maps::EncryptedMaps`].
+    MapValues,
+}
+
+/// The position in a backup at which a chunk starts.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct BackupCursor {
+    pub section: BackupSection,
+    /// The serialized key of the last entry of `section` exported before, or
+    /// `None` at the start of the section.
+    pub start_after: Option<ByteBuf>,
+    /// The number of entries of all sections exported before.
+    pub entries_before: u64,
+}
+
+/// A chunk of a backup of the stable state.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct BackupChunk {
+    /// The backup format, see [`BACKUP_FORMAT_VERSION`].
+    pub version: u32,
+    /// The canister that exported the chunk. vetKeys are bound to the
+    /// canister id, the domain separator, and the vetKD key, so data encrypted
+    /// with vetKeys of the exporting canister must be re-encrypted if the
+    /// backup is restored into a canister where any of them differs.
+    pub canister_id: Principal,
+    pub domain_separator: String,
+    pub key_id: VetKDKeyId,
+    /// The position at which the chunk starts.
+    pub cursor: BackupCursor,
+    /// The serialized key-value pairs of `cursor.section` in ascending order.
+    pub entries: Vec<(ByteBuf, ByteBuf)>,
+    /// The position at which the next chunk starts, or `None` if this is the
+    /// last chunk.
+    pub next_cursor: Option<BackupCursor>,
+    /// The integrity hash of the chunk, see [`BackupChunk::compute_hash`].
+    pub sha256: ByteBuf,
+}
+
+impl BackupChunk {
+    /// Computes the domain-separated SHA-256 hash of the Candid encoding of
+    /// all fields except `sha256`.
+    pub fn compute_hash(&self) -> ByteBuf {
+        let encoding = candid::encode_args((
+            &self.version,
+            &self.canister_id,
+            &self.domain_separator,
+            &self.key_id,
+            &self.cursor,
+            &self.entries,
+            &self.next_cursor,
+        ))
+        .expect("failed to encode backup chunk");
+        let mut hasher = Sha256::new();
+        hasher.update(b"ic-vetkeys-backup-chunk");
+        hasher.update(encoding);
+        ByteBuf::from(hasher.finalize().to_vec())
+    }
+}
+
+/// A stable map that can be exported to and imported from serialized entries.
+pub(crate) trait BackupTable {
+    fn entry_count(&self) -> u64;
+
+    /// Returns the serialized last key, if any.
+    fn last_key(&self) -> Option<ByteBuf>;
+
+    /// Returns the entries after `start_after` with a total size of at most
+    /// `max_bytes`, but at least one entry, and whether more entries follow.
+    fn export(
+        &self,
+        start_after: Option<&ByteBuf>,
+        max_bytes: usize,
+    ) -> (Vec<(ByteBuf, ByteBuf)>, bool);
+
+    /// Inserts `entries` if all of them are canonically serialized and in
+    /// ascending order after the last key.
+    fn import(&mut self, entries: &[(ByteBuf, ByteBuf)]) -> Result<(), String>;
+}
+
+impl<K: Storable + Ord + Clone, V: Storable> BackupTable for StableBTreeMap<K, V, Memory> {
+    fn entry_count(&self) -> u64 {
+        self.len()
+    }
+
+    fn last_key(&self) -> Option<ByteBuf> {
+        self.keys()
+            .next_back()
+            .map(|key| ByteBuf::from(key.into_bytes()))
+    }
+
+    fn export(
+        &self,
+        start_after: Option<&ByteBuf>,
+        max_bytes: usize,
+    ) -> (Vec<(ByteBuf, ByteBuf)>, bool) {
+        let start = match start_after {
+            Some(key) => Bound::Excluded(K::from_bytes(Cow::Borrowed(key.as_ref()))),
+            None => Bound::Unbounded,
+        };
+        let mut entries = Vec::new();
+        let mut size = 0;
+        for entry in self.range((start, Bound::Unbounded)) {
+            let key = entry.key().to_bytes().into_owned();
+            let value = entry.value().into_bytes();
+            size += key.len() + value.len();
+            if !entries.is_empty() && size > max_bytes {
+                return (entries, true);
+            }
+            entries.push((ByteBuf::from(key), ByteBuf::from(value)));
+        }
+        (entries, false)
+    }
+
+    fn import(&mut self, entries: &[(ByteBuf, ByteBuf)]) -> Result<(), String> {
+        let mut previous_key = self.keys().next_back();
+        let mut decoded = Vec::with_capacity(entries.len());
+        for (key_bytes, value_bytes) in entries {
+            let key = K::from_bytes(Cow::Borrowed(key_bytes.as_ref()));
+            let value = V::from_bytes(Cow::Borrowed(value_bytes.as_ref()));
+            if key.to_bytes().as_ref() != key_bytes.as_ref()
+                || value.to_bytes().as_ref() != value_bytes.as_ref()
+            {
+                return Err("backup entry is not canonically serialized".to_string());
+            }
+            if previous_key
+                .as_ref()
+                .is_some_and(|previous| *previous >= key)
+            {
+                return Err("backup entries are not in ascending order".to_string());
+            }
+            previous_key = Some(key.clone());
+            decoded.push((key, value));
+        }
+        for (key, value) in decoded {
+            self.insert(key, value);
+        }
+        Ok(())
+    }
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Exports a chunk of the stable state for a backup or a migration to
+    /// another canister. Pass `None` as `cursor` for the first chunk and the
+    /// `next_cursor` of the previous chunk afterwards, until it is `None`.
+    /// Each chunk contains the entries of a single [`BackupSection`] with a
+    /// total size of at most `max_bytes`, unless a single entry is larger.
+    ///
+    /// The backup contains the maps of [`KeyManager::access_control`] and
+    /// [`KeyManager::shared_keys`] and of the enabled long names,
+    /// organizations, invitations, tenants, frozen vetKeys, recovery,
+    /// inheritance, migrations, public vetKeys, inboxes, deleted vetKeys,
+    /// and quota usage, but not the configuration set on (re)initialization. This method
+    /// performs no authorization; the canister must restrict it, e.g., to its
+    /// controllers.
+    pub fn export_backup_chunk(
+        &self,
+        canister_id: Principal,
+        cursor: Option<BackupCursor>,
+        max_bytes: usize,
+    ) -> Result<BackupChunk, String> {
+        self.export_backup_chunk_with(canister_id, Vec::new(), cursor, max_bytes)
+    }
+
+    /// Imports a chunk exported with [`KeyManager::export_backup_chunk`],
+    /// possibly by another canister. The chunks must be imported in the order
+    /// of export, starting with an empty instance, i.e., one whose exported
+    /// maps are all empty, and with the same features enabled as the
+    /// exporting instance.
+    ///
+    /// A chunk is rejected if its hash does not match, if it does not continue
+    /// exactly where the previously imported chunk ended, or if any of its
+    /// entries is malformed; a rejected chunk is not imported partially. An
+    /// entry that cannot be deserialized traps. This method performs no
+    /// authorization; the canister must restrict it, e.g., to its controllers.
+    pub fn import_backup_chunk(&mut self, chunk: BackupChunk) -> Result<(), String> {
+        self.import_backup_chunk_with(Vec::new(), &chunk)?;
+        self.certify_backup_chunk(&chunk);
+        Ok(())
+    }
+
+    /// Like [`KeyManager::export_backup_chunk`], with `additional_tables`
+    /// exported after the ones of the key manager.
+    pub(crate) fn export_backup_chunk_with<'a>(
+        &'a self,
+        canister_id: Principal,
+        additional_tables: Vec<(BackupSection, &'a dyn BackupTable)>,
+        cursor: Option<BackupCursor>,
+        max_bytes: usize,
+    ) -> Result<BackupChunk, String> {
+        let mut tables = self.backup_tables();
+        tables.extend(additional_tables);
+        let cursor = cursor.unwrap_or(BackupCursor {
+            section: tables[0].0,
+            start_after: None,
+            entries_before: 0,
+        });
+        let position = position_of(&tables, cursor.section)?;
+
+        let (entries, has_more) = tables[position]
+            .1
+            .export(cursor.start_after.as_ref(), max_bytes);
+        let entries_before = cursor.entries_before + entries.len() as u64;
+        let next_cursor = if has_more {
+            Some(BackupCursor {
+                section: cursor.section,
+                start_after: entries.last().map(|(key, _value)| key.clone()),
+                entries_before,
+            })
+        } else {
+            tables
+                .get(position + 1)
+                .map(|(section, _table)| BackupCursor {
+                    section: *section,
+                    start_after: None,
+                    entries_before,
+                })
+        };
+
+        let config = self.config.get();
+        let mut chunk = BackupChunk {
+            version: BACKUP_FORMAT_VERSION,
+            canister_id,
+            domain_separator: config.domain_separator.clone(),
+            key_id: config.key_id.clone(),
+            cursor,
+            entries,
+            next_cursor,
+            sha256: ByteBuf::new(),
+        };
+        chunk.sha256 = chunk.compute_hash();
+        Ok(chunk)
+    }
+
+    /// Like [`KeyManager::import_backup_chunk`], with `additional_tables`
+    /// imported after the ones of the key manager.
+    pub(crate) fn import_backup_chunk_with<'a>(
+        &'a mut self,
+        additional_tables: Vec<(BackupSection, &'a mut dyn BackupTable)>,
+        chunk: &BackupChunk,
+    ) -> Result<(), String> {
+        if chunk.version != BACKUP_FORMAT_VERSION {
+            return Err(format!(
+                "unsupported backup format version {}",
+                chunk.version
+            ));
+        }
+        if chunk.sha256 != chunk.compute_hash() {
+            return Err("backup chunk hash mismatch".to_string());
+        }
+
+        let mut tables = self.backup_tables_mut();
+        tables.extend(additional_tables);
+        let position = position_of(&tables, chunk.cursor.section)?;
+        let entry_count: u64 = tables.iter().map(|(_, table)| table.entry_count()).sum();
+        if entry_count != chunk.cursor.entries_before
+            || tables[position].1.last_key() != chunk.cursor.start_after
+            || tables[position + 1..]
+                .iter()
+                .any(|(_, table)| table.entry_count() > 0)
+        {
+            return Err("backup chunk does not continue the previously imported chunk".to_string());
+        }
+        tables[position].1.import(&chunk.entries)
+    }
+
+    fn backup_tables(&self) -> Vec<(BackupSection, &dyn BackupTable)> {
+        let mut tables: Vec<(BackupSection, &dyn BackupTable)> = vec![
+            (BackupSection::AccessControl, &self.access_control),
+            (BackupSection::SharedKeys, &self.shared_keys),
+        ];
+        if let Some(long_names) = &self.long_names {
+            tables.push((BackupSection::LongNames, &long_names.names));
+        }
+        if let Some(organizations) = &self.organizations {
+            tables.push((BackupSection::OrganizationAdmins, &organizations.admins));
+            tables.push((BackupSection::OrganizationQuorums, &organizations.quorums));
+            tables.push((
+                BackupSection::OrganizationApprovals,
+                &organizations.approvals,
+            ));
+        }
+        if let Some(invitations) = &self.invitations {
+            tables.push((
+                BackupSection::PendingInvitations,
+                &invitations.pending_invitations,
+            ));
+            tables.push((BackupSection::InvitedUsers, &invitations.invited_users));
+            tables.push((
+                BackupSection::BlockedPrincipals,
+                &invitations.blocked_principals,
+            ));
+        }
+        if let Some(tenants) = &self.tenants {
+            tables.push((
+                BackupSection::TenantDomainSeparators,
+                &tenants.domain_separators,
+            ));
+            tables.push((BackupSection::TenantAdmins, &tenants.admins));
+            tables.push((BackupSection::KeyTenants, &tenants.key_tenants));
+        }
+        if let Some(key_freezing) = &self.key_freezing {
+            tables.push((BackupSection::FrozenKeys, &key_freezing.frozen_keys));
+        }
+        if let Some(recovery) = &self.recovery {
+            tables.push((BackupSection::RecoveryGuardians, &recovery.guardians));
+            tables.push((
+                BackupSection::PendingRecoveries,
+                &recovery.pending_recoveries,
+            ));
+            tables.push((BackupSection::RecoverySuccessors, &recovery.successors));
+            tables.push((BackupSection::RecoveryPredecessors, &recovery.predecessors));
+        }
+        if let Some(inheritance) = &self.inheritance {
+            tables.push((BackupSection::InheritancePlans, &inheritance.plans));
+            tables.push((BackupSection::InheritanceGrants, &inheritance.grants));
+            tables.push((BackupSection::InheritanceDuePlans, &inheritance.due_plans));
+        }
+        if let Some(migrations) = &self.migrations {
+            tables.push((
+                BackupSection::PendingMigrations,
+                &migrations.pending_migrations,
+            ));
+            tables.push((
+                BackupSection::MigratedPrincipals,
+                &migrations.migrated_principals,
+            ));
+            tables.push((
+                BackupSection::MigrationPredecessors,
+                &migrations.predecessors,
+            ));
+        }
+        if let Some(public_keys) = &self.public_keys {
+            tables.push((BackupSection::PublicKeys, &public_keys.public_keys));
+        }
+        if let Some(inbox) = &self.inbox {
+            tables.push((BackupSection::InboxMessageIds, &inbox.next_message_ids));
+            tables.push((BackupSection::InboxMessages, &inbox.messages));
+            tables.push((
+                BackupSection::InboxSenders,
+                &inbox.pending_messages_per_sender,
+            ));
+        }
+        if let Some(key_deletion) = &self.key_deletion {
+            tables.push((BackupSection::KeyTombstones, &key_deletion.tombstones));
+        }
+        if let Some(quotas) = &self.quotas {
+            tables.push((BackupSection::QuotaKeyReferences, &quotas.key_references));
+            tables.push((BackupSection::QuotaOwnerUsage, &quotas.owner_usage));
+        }
+        tables
+    }
+
+    fn backup_tables_mut(&mut self) -> Vec<(BackupSection, &mut dyn BackupTable)> {
+        let mut tables: Vec<(BackupSection, &mut dyn BackupTable)> = vec![
+            (BackupSection::AccessControl, &mut self.access_control),
+            (BackupSection::SharedKeys, &mut self.shared_keys),
+        ];
+        if let Some(long_names) = &mut self.long_names {
+            tables.push((BackupSection::LongNames, &mut long_names.names));
+        }
+        if let Some(organizations) = &mut self.organizations {
+            tables.push((BackupSection::OrganizationAdmins, &mut organizations.admins));
+            tables.push((
+                BackupSection::OrganizationQuorums,
+                &mut organizations.quorums,
+            ));
+            tables.push((
+                BackupSection::OrganizationApprovals,
+                &mut organizations.approvals,
+            ));
+        }
+        if let Some(invitations) = &mut self.invitations {
+            tables.push((
+                BackupSection::PendingInvitations,
+                &mut invitations.pending_invitations,
+            ));
+            tables.push((BackupSection::InvitedUsers, &mut invitations.invited_users));
+            tables.push((
+                BackupSection::BlockedPrincipals,
+                &mut invitations.blocked_principals,
+            ));
+        }
+        if let Some(tenants) = &mut self.tenants {
+            tables.push((
+                BackupSection::TenantDomainSeparators,
+                &mut tenants.domain_separators,
+            ));
+            tables.push((BackupSection::TenantAdmins, &mut tenants.admins));
+            tables.push((BackupSection::KeyTenants, &mut tenants.key_tenants));
+        }
+        if let Some(key_freezing) = &mut self.key_freezing {
+            tables.push((BackupSection::FrozenKeys, &mut key_freezing.frozen_keys));
+        }
+        if let Some(recovery) = &mut self.recovery {
+            tables.push((BackupSection::RecoveryGuardians, &mut recovery.guardians));
+            tables.push((
+                BackupSection::PendingRecoveries,
+                &mut recovery.pending_recoveries,
+            ));
+            tables.push((BackupSection::RecoverySuccessors, &mut recovery.successors));
+            tables.push((
+                BackupSection::RecoveryPredecessors,
+                &mut recovery.predecessors,
+            ));
+        }
+        if let Some(inheritance) = &mut self.inheritance {
+            tables.push((BackupSection::InheritancePlans, &mut inheritance.plans));
+            tables.push((BackupSection::InheritanceGrants, &mut inheritance.grants));
+            tables.push((
+                BackupSection::InheritanceDuePlans,
+                &mut inheritance.due_plans,
+            ));
+        }
+        if let Some(migrations) = &mut self.migrations {
+            tables.push((
+                BackupSection::PendingMigrations,
+                &mut migrations.pending_migrations,
+            ));
+            tables.push((
+                BackupSection::MigratedPrincipals,
+                &mut migrations.migrated_principals,
+            ));
+            tables.push((
+                BackupSection::MigrationPredecessors,
+                &mut migrations.predecessors,
+            ));
+        }
+        if let Some(public_keys) = &mut self.public_keys {
+            tables.push((BackupSection::PublicKeys, &mut public_keys.public_keys));
+        }
+        if let Some(inbox) = &mut self.inbox {
+            tables.push((BackupSection::InboxMessageIds, &mut inbox.next_message_ids));
+            tables.push((BackupSection::InboxMessages, &mut inbox.messages));
+            tables.push((
+                BackupSection::InboxSenders,
+                &mut inbox.pending_messages_per_sender,
+            ));
+        }
+        if let Some(key_deletion) = &mut self.key_deletion {
+            tables.push((BackupSection::KeyTombstones, &mut key_deletion.tombstones));
+        }
+        if let Some(quotas) = &mut self.quotas {
+            tables.push((
+                BackupSection::QuotaKeyReferences,
+                &mut quotas.key_references,
+            ));
+            tables.push((BackupSection::QuotaOwnerUsage, &mut quotas.owner_usage));
+        }
+        tables
+    }
+}
+
+fn position_of<Table>(
+    tables: &[(BackupSection, Table)],
+    section: BackupSection,
+) -> Result<usize, String> {
+    tables
+        .iter()
+        .position(|(table_section, _table)| *table_section == section)
+        .ok_or_else(|| format!("backup section {section:?} is not enabled"))
+}
@@ -0,0 +1,171 @@
+//! Fallible calls to the vetKD endpoints of the management canister with
+//! bounded waits and retries, see [`KeyManager::set_call_policy`].
+
+use super::KeyManager;
+use crate::types::AccessControl;
+use candid::Principal;
+use ic_cdk::call::{Call, CallErrorExt};
+use ic_cdk_management_canister::{
+    VetKDDeriveKeyArgs, VetKDDeriveKeyResult, VetKDPublicKeyArgs, VetKDPublicKeyResult,
+};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// A future that completes after a delay, see [`CallPolicy::sleep`].
+pub type SleepFuture = Pin<Box<dyn Future<Output = ()> + Send + Sync>>;
+
+/// How [`KeyManager`] calls the `vetkd_public_key` and `vetkd_derive_key`
+/// endpoints of the management canister.
+///
+/// The default policy makes a single attempt, with a bounded-wait call to
+/// `vetkd_public_key` and an unbounded-wait call to `vetkd_derive_key`.
+#[derive(Clone, Copy, Debug)]
+pub struct CallPolicy {
+    /// If set, both endpoints are called with bounded wait and this timeout.
+    /// A timed-out call is reported as a `SYS_UNKNOWN` reject and retried
+    /// like a transient error, which is safe because both endpoints are
+    /// idempotent.
+    pub bounded_wait_timeout_seconds: Option<u32>,
+    /// The maximum number of attempts, including the first one. Only errors
+    /// for which [`CallErrorExt::is_immediately_retryable`] holds are retried.
+    pub max_attempts: u32,
+    /// The delay before the first retry, which doubles with every further
+    /// retry up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Waits for the given delay between attempts, e.g., by awaiting a
+    /// one-shot timer set with `ic-cdk-timers`. If `None`, retries are
+    /// immediate and the backoff is ignored.
+    pub sleep: Option<fn(Duration) -> SleepFuture>,
+}
+
+impl Default for CallPolicy {
+    fn default() -> Self {
+        Self {
+            bounded_wait_timeout_seconds: None,
+            max_attempts: 1,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            sleep: None,
+        }
+    }
+}
+
+/// Error returned if a call to a vetKD endpoint of the management canister fails.
+#[derive(Clone, Debug)]
+pub enum VetKDCallError {
+    /// The cycles cost of the call could not be computed, e.g., because the
+    /// vetKD key name is invalid.
+    CostFailed(ic_cdk::api::SignCostError),
+    /// The call failed in its last attempt.
+    CallFailed {
+        method: &'static str,
+        attempts: u32,
+        error: ic_cdk::call::Error,
+    },
+}
+
+impl std::fmt::Display for VetKDCallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VetKDCallError::CostFailed(error) => {
+                write!(f, "failed to compute the cost of vetkd_derive_key: {error}")
+            }
+            VetKDCallError::CallFailed {
+                method,
+                attempts,
+                error,
+            } => write!(
+                f,
+                "call to {method} failed after {attempts} attempt(s): {error}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VetKDCallError {}
+
+impl CallPolicy {
+    /// Returns a call to `method`, which is an unbounded-wait call if no
+    /// timeout is set and `unbounded_by_default`.
+    fn call(&self, method: &'static str, unbounded_by_default: bool) -> Call<'static, 'static> {
+        match self.bounded_wait_timeout_seconds {
+            Some(timeout_seconds) => Call::bounded_wait(Principal::management_canister(), method)
+                .change_timeout(timeout_seconds),
+            None if unbounded_by_default => {
+                Call::unbounded_wait(Principal::management_canister(), method)
+            }
+            None => Call::bounded_wait(Principal::management_canister(), method),
+        }
+    }
+
+    async fn with_retries<R, Fut>(
+        self,
+        method: &'static str,
+        mut call: impl FnMut() -> Fut,
+    ) -> Result<R, VetKDCallError>
+    where
+        Fut: Future<Output = Result<R, ic_cdk::call::Error>>,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut attempts = 1;
+        loop {
+            match call().await {
+                Ok(reply) => return Ok(reply),
+                Err(error) if attempts < self.max_attempts && error.is_immediately_retryable() => {
+                    if let Some(sleep) = self.sleep {
+                        sleep(backoff).await;
+                        backoff = backoff.saturating_mul(2).min(self.max_backoff);
+                    }
+                    attempts += 1;
+                }
+                Err(error) => {
+                    return Err(VetKDCallError::CallFailed {
+                        method,
+                        attempts,
+                        error,
+                    })
+                }
+            }
+        }
+    }
+
+    pub(crate) async fn vetkd_public_key(
+        self,
+        args: VetKDPublicKeyArgs,
+    ) -> Result<VetKDPublicKeyResult, VetKDCallError> {
+        const METHOD: &str = "vetkd_public_key";
+        self.with_retries(METHOD, || async {
+            Ok(self.call(METHOD, false).with_arg(&args).await?.candid()?)
+        })
+        .await
+    }
+
+    pub(crate) async fn vetkd_derive_key(
+        self,
+        args: VetKDDeriveKeyArgs,
+    ) -> Result<VetKDDeriveKeyResult, VetKDCallError> {
+        const METHOD: &str = "vetkd_derive_key";
+        let cycles = crate::management_canister::vetkd_derive_key_cost(&args.key_id)
+            .map_err(VetKDCallError::CostFailed)?;
+        self.with_retries(METHOD, || async {
+            Ok(self
+                .call(METHOD, true)
+                .with_arg(&args)
+                .with_cycles(cycles)
+                .await?
+                .candid()?)
+        })
+        .await
+    }
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Sets how the vetKD endpoints of the management canister are called,
+    /// e.g., with bounded wait and retries, see [`CallPolicy`]. The policy is
+    /// not persisted and must be set on every canister (re)initialization.
+    pub fn set_call_policy(&mut self, call_policy: CallPolicy) {
+        self.call_policy = call_policy;
+    }
+}
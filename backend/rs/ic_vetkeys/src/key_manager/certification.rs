@@ -0,0 +1,465 @@
+//! Certified responses for queries of the access-control state, see
+//! [`KeyManager::enable_certified_queries`].
+
+use super::{BackupChunk, BackupSection, KeyId, KeyManager, LongNames};
+use crate::types::{AccessControl, ByteBuf};
+use crate::utils::verify_ic_bls_signature;
+use candid::{CandidType, Principal};
+use hex_literal::hex;
+use ic_certification::{
+    Certificate, Hash, HashTree, HashTreeNode, LookupResult, NestedTree, SubtreeLookupResult,
+};
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::Storable;
+use serde::Deserialize;
+use std::borrow::Cow;
+
+const ACCESS_CONTROL_LABEL: &[u8] = b"access_control";
+const SHARED_KEYS_LABEL: &[u8] = b"shared_keys";
+
+/// The labels below a path in a witness and the value of the leaf they lead to.
+type CertifiedLeaf = (Vec<Vec<u8>>, Vec<u8>);
+
+/// The DER prefix of a BLS12-381 public key of the Internet Computer.
+const IC_PUBLIC_KEY_DER_PREFIX: [u8; 37] =
+    hex!("308182301d060d2b0601040182dc7c0503010201060c2b0601040182dc7c05030201036100");
+
+/// A query response together with the proof that it matches the certified
+/// access-control state of the canister, see [`CertifiedQueryVerifier`].
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct CertifiedResponse<V> {
+    pub value: V,
+    /// The CBOR-encoded certificate of the certified data of the canister, as
+    /// returned by `ic_cdk::api::data_certificate` in a query call.
+    pub certificate: ByteBuf,
+    /// The CBOR-encoded witness of `value` in the certified access-control
+    /// tree, whose root hash is the certified data of the canister.
+    pub witness: ByteBuf,
+}
+
+/// A Merkle tree over the access-control state, which is kept on the heap and
+/// rebuilt from stable memory by [`KeyManager::enable_certified_queries`].
+///
+/// The tree contains the access rights of each shared user twice: under
+/// `access_control/<user>/<key owner>/<key name>` for the vetKeys accessible
+/// to a user, and under `shared_keys/<key owner>/<key name>/<user>` for the
+/// users of a vetKey.
+#[derive(Default)]
+pub struct CertifiedAccessControl {
+    tree: NestedTree<Vec<u8>, Vec<u8>>,
+}
+
+impl CertifiedAccessControl {
+    fn set(&mut self, key_id: KeyId, user: Principal, access_rights: Option<Vec<u8>>) {
+        let access_control_path = access_control_path(user, Some(key_id));
+        let shared_keys_path = shared_keys_path(key_id, Some(user));
+        match access_rights {
+            Some(access_rights) => {
+                self.tree
+                    .insert(&access_control_path, access_rights.clone());
+                self.tree.insert(&shared_keys_path, access_rights);
+            }
+            None => {
+                self.tree.delete(&access_control_path);
+                self.tree.delete(&shared_keys_path);
+            }
+        }
+    }
+
+    fn witness(&self, path: &[Vec<u8>]) -> ByteBuf {
+        let witness = self.tree.witness(path);
+        ByteBuf::from(serde_cbor::to_vec(&witness).expect("failed to serialize witness"))
+    }
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Enables certified queries of the access-control state by building a
+    /// Merkle tree over [`KeyManager::access_control`], which is then kept up
+    /// to date by every change of access rights.
+    ///
+    /// The tree is kept on the heap and rebuilt by this call. After this call
+    /// and after every update call that changes access rights, the canister
+    /// must pass [`KeyManager::certified_data`] to
+    /// `ic_cdk::api::certified_data_set`.
+    pub fn enable_certified_queries(&mut self) {
+        let mut certified = CertifiedAccessControl::default();
+        for entry in self.access_control.iter() {
+            let (user, key_id) = *entry.key();
+            certified.set(key_id, user, Some(entry.value().into_bytes()));
+        }
+        self.certified_access_control = Some(certified);
+    }
+
+    /// Returns the root hash of the certified access-control tree, or `None`
+    /// if certified queries are not enabled.
+    pub fn certified_data(&self) -> Option<Hash> {
+        self.certified_access_control
+            .as_ref()
+            .map(|certified| ic_certification::AsHashTree::root_hash(&certified.tree))
+    }
+
+    /// Like [`KeyManager::get_user_rights`], but returns the access rights
+    /// shared with `user` together with `certificate`, which the canister
+    /// obtains from `ic_cdk::api::data_certificate`, and a witness. Unlike
+    /// [`KeyManager::get_user_rights`], this returns `None` for the key owner,
+    /// whose rights are implicit and therefore not certified.
+    ///
+    /// Only `access_control` is certified. Rights that do not come from it,
+    /// i.e., those of the owner, of principals the key was recovered or
+    /// migrated to, the management rights of organization and tenant admins,
+    /// and the public access rights set with [`KeyManager::make_key_public`],
+    /// are not, so a certified `None` does not prove that `user` has no access.
+    pub fn get_certified_user_rights(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        user: Principal,
+        certificate: Vec<u8>,
+    ) -> Result<CertifiedResponse<Option<T>>, String> {
+        let certified = self.ensure_certified_queries_enabled()?;
+        self.ensure_user_can_get_user_rights(caller, key_id)?;
+        Ok(CertifiedResponse {
+            value: self.access_control.get(&(user, key_id)),
+            certificate: ByteBuf::from(certificate),
+            witness: certified.witness(&shared_keys_path(key_id, Some(user))),
+        })
+    }
+
+    /// Like [`KeyManager::get_shared_user_access_for_key`], but returns the
+    /// users together with `certificate`, which the canister obtains from
+    /// `ic_cdk::api::data_certificate`, and a witness.
+    pub fn get_certified_shared_user_access_for_key(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        certificate: Vec<u8>,
+    ) -> Result<CertifiedResponse<Vec<(Principal, T)>>, String> {
+        let certified = self.ensure_certified_queries_enabled()?;
+        Ok(CertifiedResponse {
+            value: self.get_shared_user_access_for_key(caller, key_id)?,
+            certificate: ByteBuf::from(certificate),
+            witness: certified.witness(&shared_keys_path(key_id, None)),
+        })
+    }
+
+    /// Like [`KeyManager::get_accessible_shared_key_ids`], but returns the
+    /// vetKey IDs together with `certificate`, which the canister obtains from
+    /// `ic_cdk::api::data_certificate`, and a witness.
+    pub fn get_certified_accessible_shared_key_ids(
+        &self,
+        caller: Principal,
+        certificate: Vec<u8>,
+    ) -> Result<CertifiedResponse<Vec<KeyId>>, String> {
+        let certified = self.ensure_certified_queries_enabled()?;
+        Ok(CertifiedResponse {
+            value: self.get_accessible_shared_key_ids(caller),
+            certificate: ByteBuf::from(certificate),
+            witness: certified.witness(&access_control_path(caller, None)),
+        })
+    }
+
+    /// Updates the certified access rights of `user` to the vetKey, if
+    /// certified queries are enabled.
+    pub(crate) fn certify_user_rights(
+        &mut self,
+        key_id: KeyId,
+        user: Principal,
+        access_rights: Option<T>,
+    ) {
+        if let Some(certified) = self.certified_access_control.as_mut() {
+            certified.set(key_id, user, access_rights.map(Storable::into_bytes));
+        }
+    }
+
+    /// Certifies the access rights contained in an imported backup chunk.
+    pub(crate) fn certify_backup_chunk(&mut self, chunk: &BackupChunk) {
+        if chunk.cursor.section != BackupSection::AccessControl {
+            return;
+        }
+        for (key, value) in &chunk.entries {
+            let (user, key_id) = <(Principal, KeyId)>::from_bytes(Cow::Borrowed(key.as_ref()));
+            let access_rights = T::from_bytes(Cow::Borrowed(value.as_ref()));
+            self.certify_user_rights(key_id, user, Some(access_rights));
+        }
+    }
+
+    fn ensure_certified_queries_enabled(&self) -> Result<&CertifiedAccessControl, String> {
+        self.certified_access_control
+            .as_ref()
+            .ok_or_else(|| "certified queries are not enabled".to_string())
+    }
+}
+
+/// Verifies [`CertifiedResponse`]s of a canister on the client side.
+///
+/// A response is accepted if its certificate is signed by the Internet
+/// Computer, directly or through a subnet delegation, certifies the root hash
+/// of the witness as the certified data of `canister_id`, and is at most
+/// `max_certificate_age_nanos` old, and if the witness proves the value.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CertifiedQueryVerifier {
+    pub canister_id: Principal,
+    /// The DER-encoded root public key of the Internet Computer.
+    pub root_public_key: Vec<u8>,
+    /// The current time in nanoseconds since the UNIX epoch.
+    pub now_nanos: u64,
+    pub max_certificate_age_nanos: u64,
+}
+
+impl CertifiedQueryVerifier {
+    /// Verifies a response of [`KeyManager::get_certified_user_rights`] for
+    /// the given vetKey and user and returns the certified access rights.
+    /// Names longer than 32 bytes are certified by their hash, see
+    /// [`LongNames::canonical_name`].
+    pub fn verify_user_rights<T: AccessControl>(
+        &self,
+        response: &CertifiedResponse<Option<T>>,
+        key_id: KeyId,
+        user: Principal,
+    ) -> Result<Option<T>, String> {
+        let witness = self.verify_witness(&response.certificate, &response.witness)?;
+        let certified = match witness.lookup_path(shared_keys_path(key_id, Some(user))) {
+            LookupResult::Found(access_rights) => Some(T::from_bytes(Cow::Borrowed(access_rights))),
+            LookupResult::Absent => None,
+            LookupResult::Unknown | LookupResult::Error => {
+                return Err("witness does not cover the response".to_string())
+            }
+        };
+        ensure_value_is_certified(response.value == certified, certified)
+    }
+
+    /// Verifies a response of
+    /// [`KeyManager::get_certified_shared_user_access_for_key`] for the given
+    /// vetKey and returns the certified users and their access rights.
+    pub fn verify_shared_user_access<T: AccessControl>(
+        &self,
+        response: &CertifiedResponse<Vec<(Principal, T)>>,
+        key_id: KeyId,
+    ) -> Result<Vec<(Principal, T)>, String> {
+        let witness = self.verify_witness(&response.certificate, &response.witness)?;
+        let mut certified = Vec::new();
+        for (labels, access_rights) in certified_leaves(&witness, &shared_keys_path(key_id, None))?
+        {
+            let [user] = labels.as_slice() else {
+                return Err("unexpected path in witness".to_string());
+            };
+            certified.push((
+                Principal::try_from_slice(user).map_err(|e| e.to_string())?,
+                T::from_bytes(Cow::Owned(access_rights)),
+            ));
+        }
+        let mut value = response.value.clone();
+        value.sort_by(|(a, _), (b, _)| a.as_slice().cmp(b.as_slice()));
+        ensure_value_is_certified(value == certified, certified)
+    }
+
+    /// Verifies a response of the `get_certified_accessible_shared_key_ids`
+    /// endpoint, which returns the original names of the vetKeys, for the
+    /// given user and returns the certified vetKey IDs. Names longer than 32
+    /// bytes are certified by their hash, see [`LongNames::canonical_name`].
+    pub fn verify_accessible_shared_key_ids(
+        &self,
+        response: &CertifiedResponse<Vec<(Principal, ByteBuf)>>,
+        user: Principal,
+    ) -> Result<Vec<KeyId>, String> {
+        let witness = self.verify_witness(&response.certificate, &response.witness)?;
+        let mut certified = Vec::new();
+        for (labels, _access_rights) in
+            certified_leaves(&witness, &access_control_path(user, None))?
+        {
+            let [key_owner, key_name] = labels.as_slice() else {
+                return Err("unexpected path in witness".to_string());
+            };
+            certified.push((
+                Principal::try_from_slice(key_owner).map_err(|e| e.to_string())?,
+                Blob::try_from(key_name.as_slice()).map_err(|_| "invalid key name")?,
+            ));
+        }
+        let mut value = response
+            .value
+            .iter()
+            .map(|(key_owner, key_name)| {
+                LongNames::canonical_name(key_name.as_ref()).map(|name| (*key_owner, name))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        value.sort_by(|(a_owner, a_name), (b_owner, b_name)| {
+            (a_owner.as_slice(), a_name.as_slice()).cmp(&(b_owner.as_slice(), b_name.as_slice()))
+        });
+        ensure_value_is_certified(value == certified, certified)
+    }
+
+    /// Verifies the certificate and returns the witness whose root hash it
+    /// certifies.
+    fn verify_witness(&self, certificate: &ByteBuf, witness: &ByteBuf) -> Result<HashTree, String> {
+        let certificate: Certificate = serde_cbor::from_slice(certificate.as_ref())
+            .map_err(|e| format!("invalid certificate: {e}"))?;
+        let witness: HashTree = serde_cbor::from_slice(witness.as_ref())
+            .map_err(|e| format!("invalid witness: {e}"))?;
+        self.verify_certificate(&certificate)?;
+
+        let certified_data_path: [&[u8]; 3] =
+            [b"canister", self.canister_id.as_slice(), b"certified_data"];
+        match certificate.tree.lookup_path(certified_data_path) {
+            LookupResult::Found(certified_data) if certified_data == witness.digest() => {}
+            LookupResult::Found(_) => {
+                return Err("certified data does not match the witness".to_string())
+            }
+            _ => return Err("certificate does not contain the certified data".to_string()),
+        }
+
+        let time = match certificate.tree.lookup_path([b"time"]) {
+            LookupResult::Found(time) => decode_leb128(time)?,
+            _ => return Err("certificate does not contain the time".to_string()),
+        };
+        if self.now_nanos.saturating_sub(time) > self.max_certificate_age_nanos {
+            return Err("certificate is too old".to_string());
+        }
+        Ok(witness)
+    }
+
+    /// Verifies the signature of the certificate and of its delegation, if any.
+    fn verify_certificate(&self, certificate: &Certificate) -> Result<(), String> {
+        let root_public_key = public_key_from_der(&self.root_public_key)?;
+        let public_key = match &certificate.delegation {
+            None => root_public_key.to_vec(),
+            Some(delegation) => {
+                let subnet_certificate: Certificate =
+                    serde_cbor::from_slice(&delegation.certificate)
+                        .map_err(|e| format!("invalid delegation certificate: {e}"))?;
+                if subnet_certificate.delegation.is_some() {
+                    return Err("nested delegations are not supported".to_string());
+                }
+                verify_certificate_signature(&subnet_certificate, root_public_key)?;
+
+                let subnet_id = delegation.subnet_id.as_slice();
+                let canister_ranges: Vec<(serde_bytes::ByteBuf, serde_bytes::ByteBuf)> =
+                    match subnet_certificate.tree.lookup_path([
+                        b"subnet",
+                        subnet_id,
+                        b"canister_ranges",
+                    ]) {
+                        LookupResult::Found(ranges) => serde_cbor::from_slice(ranges)
+                            .map_err(|e| format!("invalid canister ranges: {e}"))?,
+                        _ => return Err("delegation does not contain canister ranges".to_string()),
+                    };
+                let canister_id = self.canister_id.as_slice();
+                if !canister_ranges.iter().any(|(low, high)| {
+                    low.as_slice() <= canister_id && canister_id <= high.as_slice()
+                }) {
+                    return Err("canister is not in the delegated ranges".to_string());
+                }
+                match subnet_certificate
+                    .tree
+                    .lookup_path([b"subnet", subnet_id, b"public_key"])
+                {
+                    LookupResult::Found(der) => public_key_from_der(der)?.to_vec(),
+                    _ => return Err("delegation does not contain a public key".to_string()),
+                }
+            }
+        };
+        verify_certificate_signature(certificate, &public_key)
+    }
+}
+
+fn access_control_path(user: Principal, key_id: Option<KeyId>) -> Vec<Vec<u8>> {
+    let mut path = vec![ACCESS_CONTROL_LABEL.to_vec(), user.as_slice().to_vec()];
+    if let Some((key_owner, key_name)) = key_id {
+        path.push(key_owner.as_slice().to_vec());
+        path.push(key_name.as_slice().to_vec());
+    }
+    path
+}
+
+fn shared_keys_path(key_id: KeyId, user: Option<Principal>) -> Vec<Vec<u8>> {
+    let (key_owner, key_name) = key_id;
+    let mut path = vec![
+        SHARED_KEYS_LABEL.to_vec(),
+        key_owner.as_slice().to_vec(),
+        key_name.as_slice().to_vec(),
+    ];
+    if let Some(user) = user {
+        path.push(user.as_slice().to_vec());
+    }
+    path
+}
+
+fn ensure_value_is_certified<V>(is_certified: bool, certified: V) -> Result<V, String> {
+    if is_certified {
+        Ok(certified)
+    } else {
+        Err("response does not match the certified value".to_string())
+    }
+}
+
+/// Returns the labels below `path` and the values of all leaves of the
+/// subtree at `path`, which the witness must reveal completely.
+fn certified_leaves(witness: &HashTree, path: &[Vec<u8>]) -> Result<Vec<CertifiedLeaf>, String> {
+    fn collect(
+        node: &HashTreeNode,
+        labels: &mut Vec<Vec<u8>>,
+        leaves: &mut Vec<CertifiedLeaf>,
+    ) -> Result<(), String> {
+        match node {
+            HashTreeNode::Empty() => {}
+            HashTreeNode::Fork(children) => {
+                collect(&children.0, labels, leaves)?;
+                collect(&children.1, labels, leaves)?;
+            }
+            HashTreeNode::Labeled(label, child) => {
+                labels.push(label.as_bytes().to_vec());
+                collect(child, labels, leaves)?;
+                labels.pop();
+            }
+            HashTreeNode::Leaf(value) => leaves.push((labels.clone(), value.clone())),
+            HashTreeNode::Pruned(_) => {
+                return Err("witness does not cover the response".to_string())
+            }
+        }
+        Ok(())
+    }
+
+    match witness.lookup_subtree(path) {
+        SubtreeLookupResult::Found(subtree) => {
+            let mut leaves = Vec::new();
+            collect(subtree.as_ref(), &mut Vec::new(), &mut leaves)?;
+            Ok(leaves)
+        }
+        SubtreeLookupResult::Absent => Ok(Vec::new()),
+        SubtreeLookupResult::Unknown => Err("witness does not cover the response".to_string()),
+    }
+}
+
+fn verify_certificate_signature(
+    certificate: &Certificate,
+    public_key: &[u8],
+) -> Result<(), String> {
+    let mut message = b"\x0dic-state-root".to_vec();
+    message.extend_from_slice(&certificate.tree.digest());
+    if verify_ic_bls_signature(public_key, &message, &certificate.signature) {
+        Ok(())
+    } else {
+        Err("invalid certificate signature".to_string())
+    }
+}
+
+fn public_key_from_der(der: &[u8]) -> Result<&[u8], String> {
+    der.strip_prefix(IC_PUBLIC_KEY_DER_PREFIX.as_slice())
+        .filter(|public_key| public_key.len() == 96)
+        .ok_or_else(|| "invalid DER-encoded public key".to_string())
+}
+
+fn decode_leb128(bytes: &[u8]) -> Result<u64, String> {
+    let mut value: u64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        let shift = 7 * i as u32;
+        let bits = u64::from(byte & 0x7f);
+        if shift >= 64 || (bits << shift) >> shift != bits {
+            break;
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("invalid certificate time".to_string())
+}
@@ -0,0 +1,57 @@
+//! Cycles balances for canisters whose callers pay for vetKey derivations, see
+//! [`CyclesLedger`].
+
+use super::Memory;
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+
+/// Cycles balances of callers that pre-deposit or attach cycles to pay for
+/// their vetKey derivations, see [`super::KeyManager::vetkey_derivation_cost`].
+///
+/// The ledger only does the bookkeeping: the canister accepts the cycles
+/// attached to a call and credits them with [`CyclesLedger::deposit`], and
+/// debits the cost of a derivation with [`CyclesLedger::debit`] before
+/// deriving the vetKey. Deposited cycles are not refundable.
+pub struct CyclesLedger {
+    pub balances: StableBTreeMap<Principal, u128, Memory>,
+}
+
+impl CyclesLedger {
+    /// Initializes the ledger with stable storage.
+    pub fn init(memory_balances: Memory) -> Self {
+        Self {
+            balances: StableBTreeMap::init(memory_balances),
+        }
+    }
+
+    /// Returns the cycles balance of `principal`.
+    pub fn balance(&self, principal: Principal) -> u128 {
+        self.balances.get(&principal).unwrap_or_default()
+    }
+
+    /// Credits `amount` cycles to `principal` and returns the new balance.
+    pub fn deposit(&mut self, principal: Principal, amount: u128) -> u128 {
+        let balance = self.balance(principal).saturating_add(amount);
+        if balance > 0 {
+            self.balances.insert(principal, balance);
+        }
+        balance
+    }
+
+    /// Debits `amount` cycles from `principal` and returns the new balance.
+    /// Returns an error and leaves the balance unchanged if it is insufficient.
+    pub fn debit(&mut self, principal: Principal, amount: u128) -> Result<u128, String> {
+        let balance = self.balance(principal);
+        let Some(new_balance) = balance.checked_sub(amount) else {
+            return Err(format!(
+                "insufficient cycles balance: {balance} available, {amount} required"
+            ));
+        };
+        if new_balance == 0 {
+            self.balances.remove(&principal);
+        } else {
+            self.balances.insert(principal, new_balance);
+        }
+        Ok(new_balance)
+    }
+}
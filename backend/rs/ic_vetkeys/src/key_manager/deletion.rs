@@ -0,0 +1,178 @@
+//! Deletion of vetKeys with all their shares and metadata, see
+//! [`KeyManager::enable_key_deletion`].
+
+use super::{KeyId, KeyManager, Memory};
+use crate::types::AccessControl;
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Configuration of the deletion of vetKeys, see
+/// [`KeyManager::enable_key_deletion`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct KeyDeletionConfig {
+    /// Users with [`AccessControl::can_set_user_rights`] can delete a vetKey,
+    /// and not only its owner.
+    pub managers_can_delete: bool,
+}
+
+/// The record of a deleted vetKey, see [`KeyManager::delete_key`].
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyTombstone {
+    pub deleted_by: Principal,
+    /// The time of the deletion in nanoseconds since the UNIX epoch.
+    pub deleted_at_ns: u64,
+}
+
+impl KeyTombstone {
+    /// Decodes a stored tombstone, returning an error instead of panicking if
+    /// the bytes are corrupted.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        serde_cbor::from_slice(bytes).map_err(|e| format!("invalid KeyTombstone: {e}"))
+    }
+}
+
+impl Storable for KeyTombstone {
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self::try_from_bytes(bytes.as_ref()).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Stable state of deleted vetKeys.
+pub struct KeyDeletion {
+    pub config: KeyDeletionConfig,
+    /// Maps the deleted vetKeys to their tombstones.
+    pub tombstones: StableBTreeMap<KeyId, KeyTombstone, Memory>,
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Enables the deletion of vetKeys, see [`KeyManager::delete_key`].
+    pub fn enable_key_deletion(&mut self, config: KeyDeletionConfig, memory_tombstones: Memory) {
+        self.key_deletion = Some(KeyDeletion {
+            config,
+            tombstones: StableBTreeMap::init(memory_tombstones),
+        });
+    }
+
+    /// Deletes a vetKey at the current time `now_ns` (nanoseconds since the
+    /// UNIX epoch) and returns the revoked shares as `(user, access_rights)`.
+    ///
+    /// Revokes all shares and pending invitations and removes the freeze
+    /// state, the public access rights, the tenant registration, the
+    /// inheritance grants, and the inbox of the vetKey. A tombstone is kept
+    /// instead, see [`KeyManager::get_key_tombstone`], and every later access
+    /// to the vetKey fails with "vetKey was deleted": the vetKey of a key id
+    /// never changes, so reusing the key id would let former users decrypt
+    /// new data.
+    ///
+    /// Only users with owner authority, or with
+    /// [`AccessControl::can_set_user_rights`] if
+    /// [`KeyDeletionConfig::managers_can_delete`] is set, can delete a vetKey,
+    /// and frozen vetKeys cannot be deleted.
+    pub fn delete_key(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        now_ns: u64,
+    ) -> Result<Vec<(Principal, T)>, String> {
+        let config = self.ensure_key_deletion_enabled_mut()?.config;
+        self.ensure_not_deleted(key_id)?;
+        let is_authorized = self.has_owner_authority(caller, key_id)
+            || (config.managers_can_delete
+                && self
+                    .access_control
+                    .get(&(caller, key_id))
+                    .is_some_and(|access_rights| access_rights.can_set_user_rights()));
+        if !is_authorized {
+            return Err("unauthorized".to_string());
+        }
+        self.ensure_not_frozen(caller, key_id)?;
+
+        let users: Vec<Principal> = self
+            .shared_keys
+            .keys_range((key_id, Principal::management_canister())..)
+            .take_while(|(shared_key_id, _user)| *shared_key_id == key_id)
+            .map(|(_key_id, user)| user)
+            .collect();
+        let revoked_shares = users
+            .into_iter()
+            .filter_map(|user| {
+                self.revoke_user(caller, key_id, user)
+                    .map(|access_rights| (user, access_rights))
+            })
+            .collect();
+        if let Some(invitations) = &self.invitations {
+            let invited_users: Vec<Principal> = invitations
+                .invited_users
+                .keys_range((key_id, Principal::management_canister())..)
+                .take_while(|(invited_key_id, _user)| *invited_key_id == key_id)
+                .map(|(_key_id, user)| user)
+                .collect();
+            for user in invited_users {
+                self.cancel_invitation(key_id, user);
+            }
+        }
+        if let Some(key_freezing) = self.key_freezing.as_mut() {
+            key_freezing.frozen_keys.remove(&key_id);
+        }
+        if let Some(public_keys) = self.public_keys.as_mut() {
+            public_keys.public_keys.remove(&key_id);
+        }
+        if let Some(tenants) = self.tenants.as_mut() {
+            tenants.key_tenants.remove(&key_id);
+        }
+        let owner = self.resolve_owner(key_id.0);
+        if let Some(inheritance) = self.inheritance.as_mut() {
+            inheritance.remove_grants_for_key(owner, key_id);
+        }
+        if let Some(inbox) = self.inbox.as_mut() {
+            inbox.remove_inbox(key_id);
+        }
+
+        self.ensure_key_deletion_enabled_mut()?.tombstones.insert(
+            key_id,
+            KeyTombstone {
+                deleted_by: caller,
+                deleted_at_ns: now_ns,
+            },
+        );
+        self.notify(|listener| listener.on_vetkey_deleted(caller, key_id));
+        Ok(revoked_shares)
+    }
+
+    /// Returns the tombstone of a deleted vetKey, or `None` if the vetKey was
+    /// never deleted.
+    pub fn get_key_tombstone(&self, key_id: KeyId) -> Option<KeyTombstone> {
+        self.key_deletion
+            .as_ref()
+            .and_then(|key_deletion| key_deletion.tombstones.get(&key_id))
+    }
+
+    /// Ensures that a vetKey was not deleted with [`KeyManager::delete_key`].
+    pub fn ensure_not_deleted(&self, key_id: KeyId) -> Result<(), String> {
+        match &self.key_deletion {
+            Some(key_deletion) if key_deletion.tombstones.contains_key(&key_id) => {
+                Err("vetKey was deleted".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn ensure_key_deletion_enabled_mut(&mut self) -> Result<&mut KeyDeletion, String> {
+        self.key_deletion
+            .as_mut()
+            .ok_or_else(|| "key deletion is not enabled".to_string())
+    }
+}
@@ -0,0 +1,77 @@
+//! Listeners for mutations of the access rights and of encrypted values, see
+//! [`KeyManager::set_event_listener`].
+
+use super::{KeyId, KeyManager};
+use crate::types::{AccessControl, MapKey};
+use candid::Principal;
+use std::cell::RefCell;
+
+/// Reacts to mutations of a [`KeyManager`] or
+/// [`EncryptedMaps`](crate::encrypted_maps::EncryptedMaps), e.g., to send
+/// notifications or maintain counters.
+///
+/// The methods are called synchronously after a mutation succeeded, within
+/// the same message, so state updated by the listener stays consistent with
+/// the mutation. All methods do nothing by default. Restoring a backup does
+/// not trigger any events.
+pub trait EventListener<T: AccessControl> {
+    /// Called after `caller` granted or changed the access rights of `user`
+    /// to a vetKey. If invitations are enabled, this is called when `user`
+    /// accepts the invitation, with the inviter as `caller`.
+    fn on_share(
+        &mut self,
+        _caller: Principal,
+        _key_id: KeyId,
+        _user: Principal,
+        _access_rights: T,
+    ) {
+    }
+
+    /// Called after `caller` revoked the access rights of `user` to a vetKey.
+    /// Cancelling a pending invitation does not trigger this event.
+    fn on_revoke(
+        &mut self,
+        _caller: Principal,
+        _key_id: KeyId,
+        _user: Principal,
+        _access_rights: T,
+    ) {
+    }
+
+    /// Called after `caller` inserted or overwrote the value of `map_key` in
+    /// the map `map_id`.
+    fn on_value_inserted(&mut self, _caller: Principal, _map_id: KeyId, _map_key: MapKey) {}
+
+    /// Called after `caller` removed the value of `map_key` from the map
+    /// `map_id`.
+    fn on_value_removed(&mut self, _caller: Principal, _map_id: KeyId, _map_key: MapKey) {}
+
+    /// Called when `caller` requests the derivation of a vetKey, i.e., after
+    /// access was checked but before the vetKD call, which may still fail.
+    fn on_vetkey_derived(&mut self, _caller: Principal, _key_id: KeyId) {}
+
+    /// Called after `old_principal` was migrated to `new_principal`. The
+    /// moved shares do not trigger [`EventListener::on_share`] or
+    /// [`EventListener::on_revoke`].
+    fn on_migrate(&mut self, _old_principal: Principal, _new_principal: Principal) {}
+
+    /// Called after `caller` deleted a vetKey. The revoked shares trigger
+    /// [`EventListener::on_revoke`] before.
+    fn on_vetkey_deleted(&mut self, _caller: Principal, _key_id: KeyId) {}
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Sets a listener that is notified of mutations, see [`EventListener`].
+    /// The listener is not persisted and must be set on every canister
+    /// (re)initialization.
+    pub fn set_event_listener(&mut self, event_listener: impl EventListener<T> + 'static) {
+        self.event_listener = Some(RefCell::new(Box::new(event_listener)));
+    }
+
+    /// Notifies the event listener, if any.
+    pub(crate) fn notify(&self, event: impl FnOnce(&mut dyn EventListener<T>)) {
+        if let Some(event_listener) = &self.event_listener {
+            event(event_listener.borrow_mut().as_mut());
+        }
+    }
+}
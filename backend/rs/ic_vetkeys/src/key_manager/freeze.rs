@@ -0,0 +1,191 @@
+//! Emergency freezing of vetKeys, see [`KeyManager::freeze_key`].
+
+use super::{KeyId, KeyManager, Memory};
+use crate::types::AccessControl;
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Configuration of key freezing, see [`KeyManager::enable_key_freezing`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FreezeConfig {
+    /// The delay after which a freeze that includes the owner can be lifted,
+    /// see [`KeyManager::unfreeze_key`].
+    pub unfreeze_delay_ns: u64,
+}
+
+/// The state of a frozen vetKey, see [`KeyManager::freeze_key`].
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FreezeState {
+    /// The principal who froze the vetKey last.
+    pub frozen_by: Principal,
+    /// Whether the owner is locked out as well.
+    pub includes_owner: bool,
+    /// The time after which the owner can lift the freeze, if the owner
+    /// requested to unfreeze the vetKey.
+    pub unfreeze_after_ns: Option<u64>,
+}
+
+impl FreezeState {
+    /// Decodes a stored freeze state, returning an error instead of panicking
+    /// if the bytes are corrupted.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        serde_cbor::from_slice(bytes).map_err(|e| format!("invalid FreezeState: {e}"))
+    }
+}
+
+impl Storable for FreezeState {
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self::try_from_bytes(bytes.as_ref()).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Stable state of key freezing.
+pub struct KeyFreezing {
+    pub config: FreezeConfig,
+    /// Maps the frozen vetKeys to their freeze state.
+    pub frozen_keys: StableBTreeMap<KeyId, FreezeState, Memory>,
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Enables freezing vetKeys, e.g., when a device with access is
+    /// compromised, see [`KeyManager::freeze_key`].
+    pub fn enable_key_freezing(&mut self, config: FreezeConfig, memory_frozen_keys: Memory) {
+        self.key_freezing = Some(KeyFreezing {
+            config,
+            frozen_keys: StableBTreeMap::init(memory_frozen_keys),
+        });
+    }
+
+    /// Freezes a vetKey: until it is unfrozen, no user can retrieve it and
+    /// the values of the encrypted map with the same ID cannot be modified,
+    /// but its shares are kept. The owner is exempt unless `includes_owner`
+    /// is set, in which case the freeze can only be lifted by the owner after
+    /// a delay, see [`KeyManager::unfreeze_key`].
+    ///
+    /// Only the vetKey owner or a user with management rights can freeze a
+    /// vetKey, and only the owner can include themselves. Freezing a frozen
+    /// vetKey again cancels a requested unfreeze and never lifts the lockout
+    /// of the owner.
+    pub fn freeze_key(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        includes_owner: bool,
+    ) -> Result<(), String> {
+        self.ensure_user_can_set_user_rights(caller, key_id)?;
+        if includes_owner && !self.is_owner(caller, key_id) {
+            return Err("only the key owner can lock out the key owner".to_string());
+        }
+        let key_freezing = self.ensure_key_freezing_enabled_mut()?;
+        let already_includes_owner = key_freezing
+            .frozen_keys
+            .get(&key_id)
+            .is_some_and(|state| state.includes_owner);
+        key_freezing.frozen_keys.insert(
+            key_id,
+            FreezeState {
+                frozen_by: caller,
+                includes_owner: includes_owner || already_includes_owner,
+                unfreeze_after_ns: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Lifts the freeze of a vetKey at the current time `now_ns`
+    /// (nanoseconds since the UNIX epoch). Returns `Ok(None)` if the vetKey
+    /// is unfrozen.
+    ///
+    /// If the freeze includes the owner, only the owner can lift it, and the
+    /// first call only requests the unfreeze and returns
+    /// `Ok(Some(unfreeze_after_ns))`: the vetKey stays frozen until a call
+    /// after [`FreezeConfig::unfreeze_delay_ns`] has passed, which gives the
+    /// owner time to notice and cancel an unfreeze requested from a
+    /// compromised device by freezing again. Otherwise, the owner or a user
+    /// with management rights can lift it immediately.
+    pub fn unfreeze_key(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        now_ns: u64,
+    ) -> Result<Option<u64>, String> {
+        self.ensure_user_can_set_user_rights(caller, key_id)?;
+        let is_owner = self.is_owner(caller, key_id);
+        let key_freezing = self.ensure_key_freezing_enabled_mut()?;
+        let state = key_freezing
+            .frozen_keys
+            .get(&key_id)
+            .ok_or_else(|| "vetKey is not frozen".to_string())?;
+        if state.includes_owner {
+            if !is_owner {
+                return Err("only the key owner can unfreeze the key".to_string());
+            }
+            match state.unfreeze_after_ns {
+                Some(unfreeze_after_ns) if now_ns >= unfreeze_after_ns => {}
+                Some(unfreeze_after_ns) => return Ok(Some(unfreeze_after_ns)),
+                None => {
+                    let unfreeze_after_ns =
+                        now_ns.saturating_add(key_freezing.config.unfreeze_delay_ns);
+                    key_freezing.frozen_keys.insert(
+                        key_id,
+                        FreezeState {
+                            unfreeze_after_ns: Some(unfreeze_after_ns),
+                            ..state
+                        },
+                    );
+                    return Ok(Some(unfreeze_after_ns));
+                }
+            }
+        }
+        key_freezing.frozen_keys.remove(&key_id);
+        Ok(None)
+    }
+
+    /// Retrieves the freeze state of a vetKey, or `None` if it is not frozen.
+    /// The caller must have appropriate permissions to view the access rights
+    /// of the vetKey.
+    pub fn get_freeze_state(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Option<FreezeState>, String> {
+        self.ensure_user_can_get_user_rights(caller, key_id)?;
+        Ok(self.freeze_state(key_id))
+    }
+
+    /// Ensures that a vetKey is not frozen for a user before proceeding, see
+    /// [`KeyManager::freeze_key`]. Returns an error if it is.
+    pub fn ensure_not_frozen(&self, user: Principal, key_id: KeyId) -> Result<(), String> {
+        match self.freeze_state(key_id) {
+            Some(state) if state.includes_owner || !self.is_owner(user, key_id) => {
+                Err("vetKey is frozen".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn freeze_state(&self, key_id: KeyId) -> Option<FreezeState> {
+        self.key_freezing
+            .as_ref()
+            .and_then(|key_freezing| key_freezing.frozen_keys.get(&key_id))
+    }
+
+    fn ensure_key_freezing_enabled_mut(&mut self) -> Result<&mut KeyFreezing, String> {
+        self.key_freezing
+            .as_mut()
+            .ok_or_else(|| "key freezing is not enabled".to_string())
+    }
+}
@@ -0,0 +1,259 @@
+//! Inboxes of IBE ciphertexts addressed to vetKeys, see
+//! [`KeyManager::enable_inbox`].
+
+use super::{KeyId, KeyManager, Memory};
+use crate::types::{AccessControl, ByteBuf, Page};
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::Bound as StorableBound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::ops::Bound;
+
+/// Configuration of inboxes, see [`KeyManager::enable_inbox`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InboxConfig {
+    /// The maximum size of a deposited ciphertext in bytes.
+    pub max_message_bytes: u64,
+    /// The maximum number of messages in the inbox of a single vetKey.
+    /// Further deposits fail until some messages are removed.
+    pub max_messages_per_inbox: u64,
+    /// The maximum number of messages of a single sender that have not been
+    /// removed yet, across all inboxes.
+    pub max_pending_messages_per_sender: u64,
+}
+
+impl Default for InboxConfig {
+    fn default() -> Self {
+        Self {
+            max_message_bytes: 4 * 1024,
+            max_messages_per_inbox: 1_000,
+            max_pending_messages_per_sender: 100,
+        }
+    }
+}
+
+/// A message deposited into the inbox of a vetKey, see
+/// [`KeyManager::deposit_inbox_message`].
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct InboxMessage {
+    pub sender: Principal,
+    /// The time of the deposit in nanoseconds since the UNIX epoch.
+    pub deposited_at_ns: u64,
+    /// The IBE ciphertext, e.g., encrypted to
+    /// [`IbeIdentity::for_key_id`](crate::IbeIdentity::for_key_id).
+    pub ciphertext: ByteBuf,
+}
+
+impl InboxMessage {
+    /// Decodes a stored message, returning an error instead of panicking if
+    /// the bytes are corrupted.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        serde_cbor::from_slice(bytes).map_err(|e| format!("invalid InboxMessage: {e}"))
+    }
+}
+
+impl Storable for InboxMessage {
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self::try_from_bytes(bytes.as_ref()).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    const BOUND: StorableBound = StorableBound::Unbounded;
+}
+
+/// Stable state of inboxes.
+pub struct Inbox {
+    pub config: InboxConfig,
+    /// Maps vetKeys to the ID of the next message deposited into their inbox,
+    /// so that message IDs are never reused.
+    pub next_message_ids: StableBTreeMap<KeyId, u64, Memory>,
+    /// Maps `(key_id, message_id)` to the deposited messages.
+    pub messages: StableBTreeMap<(KeyId, u64), InboxMessage, Memory>,
+    /// Maps senders to the number of their messages that have not been
+    /// removed yet.
+    pub pending_messages_per_sender: StableBTreeMap<Principal, u64, Memory>,
+}
+
+impl Inbox {
+    fn message_count(&self, key_id: KeyId) -> usize {
+        self.messages
+            .range((key_id, 0)..)
+            .take_while(|entry| entry.key().0 == key_id)
+            .count()
+    }
+
+    fn remove_message(&mut self, key_id: KeyId, message_id: u64) -> Option<InboxMessage> {
+        let message = self.messages.remove(&(key_id, message_id))?;
+        match self.pending_messages_per_sender.get(&message.sender) {
+            Some(count) if count > 1 => {
+                self.pending_messages_per_sender
+                    .insert(message.sender, count - 1);
+            }
+            _ => {
+                self.pending_messages_per_sender.remove(&message.sender);
+            }
+        }
+        Some(message)
+    }
+
+    pub(super) fn remove_inbox(&mut self, key_id: KeyId) {
+        let message_ids: Vec<u64> = self
+            .messages
+            .keys_range((key_id, 0)..)
+            .take_while(|(message_key_id, _message_id)| *message_key_id == key_id)
+            .map(|(_key_id, message_id)| message_id)
+            .collect();
+        for message_id in message_ids {
+            self.remove_message(key_id, message_id);
+        }
+        self.next_message_ids.remove(&key_id);
+    }
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Enables inboxes: any principal can deposit IBE ciphertexts addressed
+    /// to a vetKey, and the users who can read the vetKey can retrieve them,
+    /// see [`KeyManager::deposit_inbox_message`].
+    pub fn enable_inbox(
+        &mut self,
+        config: InboxConfig,
+        memory_next_message_ids: Memory,
+        memory_messages: Memory,
+        memory_pending_messages_per_sender: Memory,
+    ) {
+        self.inbox = Some(Inbox {
+            config,
+            next_message_ids: StableBTreeMap::init(memory_next_message_ids),
+            messages: StableBTreeMap::init(memory_messages),
+            pending_messages_per_sender: StableBTreeMap::init(memory_pending_messages_per_sender),
+        });
+    }
+
+    /// Deposits `ciphertext` into the inbox of a vetKey at the current time
+    /// `now_ns` (nanoseconds since the UNIX epoch) and returns the ID of the
+    /// message.
+    ///
+    /// The ciphertext is meant to be encrypted to
+    /// [`IbeIdentity::for_key_id`](crate::IbeIdentity::for_key_id) of the
+    /// vetKey with the derived public key of the canister's domain separator,
+    /// but the canister cannot check this. To limit spam, deposits fail for
+    /// the anonymous principal, for ciphertexts or inboxes exceeding the
+    /// limits of the [`InboxConfig`], for senders with too many pending
+    /// messages, and, if invitations are enabled, for senders blocked by the
+    /// vetKey owner, see [`KeyManager::block_principal`].
+    pub fn deposit_inbox_message(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        ciphertext: ByteBuf,
+        now_ns: u64,
+    ) -> Result<u64, String> {
+        if caller == Principal::anonymous() {
+            return Err("anonymous principal cannot deposit messages".to_string());
+        }
+        self.ensure_not_deleted(key_id)?;
+        let owner = self.resolve_owner(key_id.0);
+        if self
+            .invitations
+            .as_ref()
+            .is_some_and(|invitations| invitations.is_blocked(owner, caller))
+        {
+            return Err("blocked by recipient".to_string());
+        }
+        let inbox = self.ensure_inbox_enabled_mut()?;
+        if ciphertext.as_ref().is_empty()
+            || ciphertext.as_ref().len() as u64 > inbox.config.max_message_bytes
+        {
+            return Err(format!(
+                "message must be between 1 and {} bytes",
+                inbox.config.max_message_bytes
+            ));
+        }
+        if inbox.message_count(key_id) as u64 >= inbox.config.max_messages_per_inbox {
+            return Err("inbox is full".to_string());
+        }
+        let pending_messages = inbox
+            .pending_messages_per_sender
+            .get(&caller)
+            .unwrap_or_default();
+        if pending_messages >= inbox.config.max_pending_messages_per_sender {
+            return Err("too many pending messages".to_string());
+        }
+
+        let message_id = inbox.next_message_ids.get(&key_id).unwrap_or_default();
+        inbox.next_message_ids.insert(key_id, message_id + 1);
+        inbox
+            .pending_messages_per_sender
+            .insert(caller, pending_messages + 1);
+        inbox.messages.insert(
+            (key_id, message_id),
+            InboxMessage {
+                sender: caller,
+                deposited_at_ns: now_ns,
+                ciphertext,
+            },
+        );
+        Ok(message_id)
+    }
+
+    /// Retrieves a page of at most `limit` messages of the inbox of a vetKey,
+    /// ordered by message ID. The page starts after the message `start_after`
+    /// or at the first message if `None`. Only users who can read the vetKey,
+    /// see [`KeyManager::ensure_user_can_read`], can retrieve its messages.
+    pub fn get_inbox_messages_paginated(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        start_after: Option<u64>,
+        limit: usize,
+    ) -> Result<Page<(u64, InboxMessage), u64>, String> {
+        self.ensure_user_can_read(caller, key_id)?;
+        let inbox = self
+            .inbox
+            .as_ref()
+            .ok_or_else(|| "inbox is not enabled".to_string())?;
+        let start = match start_after {
+            Some(message_id) => Bound::Excluded((key_id, message_id)),
+            None => Bound::Included((key_id, 0)),
+        };
+        Ok(Page::collect(
+            inbox
+                .messages
+                .range((start, Bound::Unbounded))
+                .take_while(|entry| entry.key().0 == key_id)
+                .map(|entry| (entry.key().1, entry.value())),
+            limit,
+            |(message_id, _message)| *message_id,
+        ))
+    }
+
+    /// Removes a message from the inbox of a vetKey, e.g., once it has been
+    /// processed, and returns it, if any. Only users who can remove entries
+    /// of the vetKey, see [`KeyManager::ensure_user_can_remove`], can remove
+    /// its messages.
+    pub fn remove_inbox_message(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        message_id: u64,
+    ) -> Result<Option<InboxMessage>, String> {
+        self.ensure_user_can_remove(caller, key_id)?;
+        Ok(self
+            .ensure_inbox_enabled_mut()?
+            .remove_message(key_id, message_id))
+    }
+
+    fn ensure_inbox_enabled_mut(&mut self) -> Result<&mut Inbox, String> {
+        self.inbox
+            .as_mut()
+            .ok_or_else(|| "inbox is not enabled".to_string())
+    }
+}
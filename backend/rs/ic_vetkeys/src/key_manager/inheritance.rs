@@ -0,0 +1,312 @@
+//! Inheritance of access rights after a period of inactivity of the owner,
+//! see [`KeyManager::enable_inheritance`].
+
+use super::{KeyId, KeyManager, Memory};
+use crate::types::AccessControl;
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::{Blob, Bound};
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Configuration of inheritance, see [`KeyManager::enable_inheritance`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InheritanceConfig {
+    /// The shortest inactivity period an owner can choose, which protects
+    /// owners from losing exclusive access by a typo.
+    pub min_inactivity_period_ns: u64,
+}
+
+/// The inheritance plan of an owner, see [`KeyManager::set_inheritance_plan`].
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InheritancePlan {
+    /// The period without activity of the owner after which the beneficiaries
+    /// receive their access rights.
+    pub inactivity_period_ns: u64,
+    /// The time of the last recorded activity of the owner, see
+    /// [`KeyManager::record_activity`].
+    pub last_activity_ns: u64,
+}
+
+impl InheritancePlan {
+    /// Decodes a stored plan, returning an error instead of panicking if the
+    /// bytes are corrupted.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        serde_cbor::from_slice(bytes).map_err(|e| format!("invalid InheritancePlan: {e}"))
+    }
+
+    /// Returns the time after which the plan is executed unless the owner is
+    /// active again.
+    pub fn due_ns(&self) -> u64 {
+        self.last_activity_ns
+            .saturating_add(self.inactivity_period_ns)
+    }
+}
+
+impl Storable for InheritancePlan {
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self::try_from_bytes(bytes.as_ref()).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Stable state of inheritance.
+pub struct Inheritance<T: AccessControl> {
+    pub config: InheritanceConfig,
+    /// Maps owners to their inheritance plans.
+    pub plans: StableBTreeMap<Principal, InheritancePlan, Memory>,
+    /// Maps `(owner, (key_id, beneficiary))` to the access rights the
+    /// beneficiary inherits.
+    pub grants: StableBTreeMap<(Principal, (KeyId, Principal)), T, Memory>,
+    /// Contains `(due_ns, owner)` pairs for all plans, ordered by the time at
+    /// which they are due, so that due plans are found without a scan.
+    pub due_plans: StableBTreeMap<(u64, Principal), (), Memory>,
+}
+
+impl<T: AccessControl> Inheritance<T> {
+    fn grants_iter(&self, owner: Principal) -> impl Iterator<Item = (KeyId, Principal, T)> + '_ {
+        let min_key_id = (Principal::management_canister(), Blob::default());
+        self.grants
+            .range((owner, (min_key_id, Principal::management_canister()))..)
+            .take_while(move |entry| entry.key().0 == owner)
+            .map(|entry| {
+                let (key_id, beneficiary) = entry.key().1;
+                (key_id, beneficiary, entry.value())
+            })
+    }
+
+    fn insert_plan(&mut self, owner: Principal, plan: InheritancePlan) {
+        if let Some(old_plan) = self.plans.insert(owner, plan) {
+            self.due_plans.remove(&(old_plan.due_ns(), owner));
+        }
+        self.due_plans.insert((plan.due_ns(), owner), ());
+    }
+
+    pub(super) fn remove_plan(&mut self, owner: Principal) -> Vec<(KeyId, Principal, T)> {
+        if let Some(plan) = self.plans.remove(&owner) {
+            self.due_plans.remove(&(plan.due_ns(), owner));
+        }
+        let grants: Vec<_> = self.grants_iter(owner).collect();
+        for (key_id, beneficiary, _access_rights) in grants.iter() {
+            self.grants.remove(&(owner, (*key_id, *beneficiary)));
+        }
+        grants
+    }
+
+    pub(super) fn remove_grants_for_key(&mut self, owner: Principal, key_id: KeyId) {
+        let beneficiaries: Vec<Principal> = self
+            .grants_iter(owner)
+            .filter(|(grant_key_id, _beneficiary, _access_rights)| *grant_key_id == key_id)
+            .map(|(_key_id, beneficiary, _access_rights)| beneficiary)
+            .collect();
+        for beneficiary in beneficiaries {
+            self.grants.remove(&(owner, (key_id, beneficiary)));
+        }
+    }
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Enables inheritance: owners can name beneficiaries who receive access
+    /// rights to chosen vetKeys if the owner is inactive for a chosen period,
+    /// see [`KeyManager::set_inheritance_plan`].
+    ///
+    /// The canister must call [`KeyManager::record_activity`] in its update
+    /// endpoints and [`KeyManager::execute_inheritance`] periodically, e.g.,
+    /// from a timer.
+    pub fn enable_inheritance(
+        &mut self,
+        config: InheritanceConfig,
+        memory_plans: Memory,
+        memory_grants: Memory,
+        memory_due_plans: Memory,
+    ) {
+        let mut inheritance = Inheritance {
+            config,
+            plans: StableBTreeMap::init(memory_plans),
+            grants: StableBTreeMap::init(memory_grants),
+            due_plans: StableBTreeMap::init(memory_due_plans),
+        };
+        if inheritance.due_plans.is_empty() {
+            for entry in inheritance.plans.iter() {
+                inheritance
+                    .due_plans
+                    .insert((entry.value().due_ns(), *entry.key()), ());
+            }
+        }
+        self.inheritance = Some(inheritance);
+    }
+
+    /// Creates or updates the inheritance plan of the caller at the current
+    /// time `now_ns` (nanoseconds since the UNIX epoch), which also counts as
+    /// activity. If the caller is inactive for `inactivity_period_ns`, the
+    /// beneficiaries added with [`KeyManager::add_inheritance_grant`] receive
+    /// their access rights.
+    pub fn set_inheritance_plan(
+        &mut self,
+        caller: Principal,
+        inactivity_period_ns: u64,
+        now_ns: u64,
+    ) -> Result<(), String> {
+        let inheritance = self.ensure_inheritance_enabled_mut()?;
+        if inactivity_period_ns < inheritance.config.min_inactivity_period_ns {
+            return Err(format!(
+                "inactivity period must be at least {} ns",
+                inheritance.config.min_inactivity_period_ns
+            ));
+        }
+        inheritance.insert_plan(
+            caller,
+            InheritancePlan {
+                inactivity_period_ns,
+                last_activity_ns: now_ns,
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes the inheritance plan of the caller including all grants.
+    pub fn cancel_inheritance_plan(&mut self, caller: Principal) -> Result<(), String> {
+        let inheritance = self.ensure_inheritance_enabled_mut()?;
+        if !inheritance.plans.contains_key(&caller) {
+            return Err("no inheritance plan".to_string());
+        }
+        inheritance.remove_plan(caller);
+        Ok(())
+    }
+
+    /// Returns the inheritance plan of the caller, if any.
+    pub fn get_inheritance_plan(&self, caller: Principal) -> Option<InheritancePlan> {
+        self.inheritance
+            .as_ref()
+            .and_then(|inheritance| inheritance.plans.get(&caller))
+    }
+
+    /// Adds or updates a grant of `access_rights` to a vetKey owned by the
+    /// caller, which `beneficiary` receives when the inheritance plan of the
+    /// caller is executed. Returns the previously planned access rights, if
+    /// any.
+    pub fn add_inheritance_grant(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        beneficiary: Principal,
+        access_rights: T,
+    ) -> Result<Option<T>, String> {
+        if !self.is_owner(caller, key_id) {
+            return Err("unauthorized".to_string());
+        }
+        self.ensure_not_deleted(key_id)?;
+        if beneficiary == caller {
+            return Err("cannot inherit from oneself".to_string());
+        }
+        let inheritance = self.ensure_inheritance_enabled_mut()?;
+        if !inheritance.plans.contains_key(&caller) {
+            return Err("no inheritance plan".to_string());
+        }
+        Ok(inheritance
+            .grants
+            .insert((caller, (key_id, beneficiary)), access_rights))
+    }
+
+    /// Removes a grant added with [`KeyManager::add_inheritance_grant`].
+    /// Returns the removed access rights, if any.
+    pub fn remove_inheritance_grant(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        beneficiary: Principal,
+    ) -> Result<Option<T>, String> {
+        let inheritance = self.ensure_inheritance_enabled_mut()?;
+        Ok(inheritance.grants.remove(&(caller, (key_id, beneficiary))))
+    }
+
+    /// Returns the grants of the caller's inheritance plan as
+    /// `(key_id, beneficiary, access_rights)`.
+    pub fn get_inheritance_grants(&self, caller: Principal) -> Vec<(KeyId, Principal, T)> {
+        self.inheritance
+            .as_ref()
+            .map(|inheritance| inheritance.grants_iter(caller).collect())
+            .unwrap_or_default()
+    }
+
+    /// Records that the caller was active at `now_ns`, which postpones the
+    /// execution of their inheritance plan, if any. Does nothing if
+    /// inheritance is not enabled.
+    pub fn record_activity(&mut self, caller: Principal, now_ns: u64) {
+        let Some(inheritance) = &mut self.inheritance else {
+            return;
+        };
+        if let Some(plan) = inheritance.plans.get(&caller) {
+            if plan.last_activity_ns < now_ns {
+                inheritance.insert_plan(
+                    caller,
+                    InheritancePlan {
+                        last_activity_ns: now_ns,
+                        ..plan
+                    },
+                );
+            }
+        }
+    }
+
+    /// Executes the inheritance plans of all owners who were inactive for
+    /// their inactivity period at `now_ns`, and returns these owners.
+    ///
+    /// The beneficiaries receive their access rights directly, bypassing
+    /// quotas and invitations, and grants for vetKeys the owner no longer
+    /// owns are skipped. An executed plan is removed, so the owner must set
+    /// up a new one if they return.
+    pub fn execute_inheritance(&mut self, now_ns: u64) -> Vec<Principal> {
+        let Some(inheritance) = &mut self.inheritance else {
+            return vec![];
+        };
+        let due_owners: Vec<Principal> = inheritance
+            .due_plans
+            .iter()
+            .take_while(|entry| entry.key().0 <= now_ns)
+            .map(|entry| entry.key().1)
+            .collect();
+        let grants: Vec<_> = due_owners
+            .iter()
+            .flat_map(|owner| {
+                inheritance
+                    .remove_plan(*owner)
+                    .into_iter()
+                    .map(move |grant| (*owner, grant))
+            })
+            .collect();
+        for (owner, (key_id, beneficiary, access_rights)) in grants {
+            if self.is_owner(owner, key_id)
+                && !self.is_owner(beneficiary, key_id)
+                && self.ensure_not_deleted(key_id).is_ok()
+            {
+                self.insert_user_rights(owner, key_id, beneficiary, access_rights);
+            }
+        }
+        due_owners
+    }
+
+    /// Returns the earliest time at which an inheritance plan is due, e.g.,
+    /// to schedule the next call of [`KeyManager::execute_inheritance`].
+    pub fn next_inheritance_due_ns(&self) -> Option<u64> {
+        self.inheritance
+            .as_ref()
+            .and_then(|inheritance| inheritance.due_plans.first_key_value())
+            .map(|((due_ns, _owner), ())| due_ns)
+    }
+
+    fn ensure_inheritance_enabled_mut(&mut self) -> Result<&mut Inheritance<T>, String> {
+        self.inheritance
+            .as_mut()
+            .ok_or_else(|| "inheritance is not enabled".to_string())
+    }
+}
@@ -0,0 +1,344 @@
+//! Optional invitation/acceptance flow for sharing vetKeys, see
+//! [`KeyManager::enable_invitations`].
+
+use super::{KeyId, KeyManager, Memory, QuotaReference};
+use crate::types::AccessControl;
+use candid::Principal;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::StableBTreeMap;
+
+/// Configuration of the invitation flow, see [`KeyManager::enable_invitations`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InvitationsConfig {
+    /// The maximum number of pending invitations a single recipient can have.
+    /// Further invitations to the recipient fail until some are accepted or declined.
+    pub max_pending_invitations_per_recipient: u64,
+}
+
+/// Stable state of the invitation flow.
+///
+/// Pending invitations are indexed both by recipient (`pending_invitations`)
+/// and by vetKey (`invited_users`), analogously to
+/// [`KeyManager::access_control`] and [`KeyManager::shared_keys`].
+pub struct Invitations<T: AccessControl> {
+    pub config: InvitationsConfig,
+    /// Maps `(recipient, KeyId)` to the offered access rights and the inviting principal.
+    pub pending_invitations: StableBTreeMap<(Principal, KeyId), (T, Principal), Memory>,
+    pub invited_users: StableBTreeMap<(KeyId, Principal), (), Memory>,
+    /// Contains `(recipient, blocked principal)` pairs.
+    pub blocked_principals: StableBTreeMap<(Principal, Principal), (), Memory>,
+}
+
+impl<T: AccessControl> Invitations<T> {
+    fn pending_invitations_iter(
+        &self,
+        recipient: Principal,
+    ) -> impl Iterator<Item = (KeyId, T, Principal)> + '_ {
+        self.pending_invitations
+            .range(
+                (
+                    recipient,
+                    (Principal::management_canister(), Blob::default()),
+                )..,
+            )
+            .take_while(move |entry| entry.key().0 == recipient)
+            .map(|entry| {
+                let (access_rights, inviter) = entry.value();
+                (entry.key().1, access_rights, inviter)
+            })
+    }
+
+    pub(super) fn is_blocked(&self, recipient: Principal, principal: Principal) -> bool {
+        self.blocked_principals
+            .contains_key(&(recipient, principal))
+    }
+
+    fn remove_invitation(&mut self, recipient: Principal, key_id: KeyId) -> Option<(T, Principal)> {
+        self.invited_users.remove(&(key_id, recipient));
+        self.pending_invitations.remove(&(recipient, key_id))
+    }
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Enables the invitation flow for sharing vetKeys.
+    ///
+    /// Once enabled, [`KeyManager::set_user_rights`] no longer grants access to
+    /// a user who does not have access yet. Instead, it creates a pending
+    /// invitation that shows up in [`KeyManager::get_pending_invitations`] of
+    /// the recipient, and the access rights are only granted once the recipient
+    /// calls [`KeyManager::accept_invitation`]. Changing the access rights of a
+    /// user who already has access takes effect immediately.
+    ///
+    /// Recipients can block principals with [`KeyManager::block_principal`]:
+    /// invitations to a vetKey fail if the recipient blocked either the inviting
+    /// principal or the owner of the vetKey.
+    pub fn enable_invitations(
+        &mut self,
+        config: InvitationsConfig,
+        memory_pending_invitations: Memory,
+        memory_invited_users: Memory,
+        memory_blocked_principals: Memory,
+    ) {
+        self.invitations = Some(Invitations {
+            config,
+            pending_invitations: StableBTreeMap::init(memory_pending_invitations),
+            invited_users: StableBTreeMap::init(memory_invited_users),
+            blocked_principals: StableBTreeMap::init(memory_blocked_principals),
+        });
+    }
+
+    /// Retrieves the pending invitations of the caller as tuples of the
+    /// vetKey ID, the offered access rights, and the inviting principal.
+    pub fn get_pending_invitations(&self, caller: Principal) -> Vec<(KeyId, T, Principal)> {
+        self.invitations
+            .as_ref()
+            .map(|invitations| invitations.pending_invitations_iter(caller).collect())
+            .unwrap_or_default()
+    }
+
+    /// Retrieves the users with a pending invitation to a given vetKey, along with the offered access rights.
+    /// The caller must have appropriate permissions to view this information.
+    pub fn get_pending_invitations_for_key(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Vec<(Principal, T)>, String> {
+        self.ensure_user_can_get_user_rights(caller, key_id)?;
+        let invitations = self.ensure_invitations_enabled()?;
+
+        Ok(invitations
+            .invited_users
+            .range((key_id, Principal::management_canister())..)
+            .take_while(|entry| entry.key().0 == key_id)
+            .map(|entry| {
+                let user = entry.key().1;
+                let (access_rights, _inviter) = invitations
+                    .pending_invitations
+                    .get(&(user, key_id))
+                    .expect("always a pending invitation");
+                (user, access_rights)
+            })
+            .collect())
+    }
+
+    /// Accepts a pending invitation to a vetKey, granting the caller the offered access rights.
+    /// Returns the granted access rights.
+    ///
+    /// The inviting principal must still be allowed to grant the offered
+    /// access rights, see [`KeyManager::ensure_user_can_share`] and
+    /// [`DelegationPolicy`](super::DelegationPolicy), e.g., invitations sent
+    /// by a manager whose management rights were revoked in the meantime
+    /// cannot be accepted anymore. Such invitations are removed.
+    pub fn accept_invitation(&mut self, caller: Principal, key_id: KeyId) -> Result<T, String> {
+        self.ensure_invitations_enabled()?;
+        let (access_rights, inviter) = self
+            .remove_pending_invitation(caller, key_id)
+            .ok_or_else(|| "no pending invitation".to_string())?;
+
+        // the invitation was removed above, so the caller is checked as a new user
+        self.ensure_user_can_share(inviter, key_id, caller, access_rights)
+            .and_then(|inviter_rights| {
+                self.ensure_delegation_allowed(
+                    inviter,
+                    inviter_rights,
+                    key_id,
+                    caller,
+                    Some(access_rights),
+                )
+            })
+            .map_err(|e| format!("invitation is no longer valid: {e}"))?;
+
+        if self.shared_keys.insert((key_id, caller), ()).is_none() {
+            self.add_quota_reference(key_id, QuotaReference::Share);
+        }
+        self.access_control.insert((caller, key_id), access_rights);
+        self.certify_user_rights(key_id, caller, Some(access_rights));
+        self.notify(|listener| listener.on_share(inviter, key_id, caller, access_rights));
+        Ok(access_rights)
+    }
+
+    /// Declines a pending invitation to a vetKey.
+    /// Returns the access rights that were offered.
+    pub fn decline_invitation(&mut self, caller: Principal, key_id: KeyId) -> Result<T, String> {
+        self.ensure_invitations_enabled()?;
+        self.remove_pending_invitation(caller, key_id)
+            .map(|(access_rights, _inviter)| access_rights)
+            .ok_or_else(|| "no pending invitation".to_string())
+    }
+
+    /// Blocks invitations from `principal` to the caller.
+    /// Pending invitations of the caller that were sent by or are for vetKeys owned by `principal` are declined.
+    pub fn block_principal(
+        &mut self,
+        caller: Principal,
+        principal: Principal,
+    ) -> Result<(), String> {
+        let declined: Vec<KeyId> = self
+            .ensure_invitations_enabled()?
+            .pending_invitations_iter(caller)
+            .filter(|(key_id, _access_rights, inviter)| {
+                *inviter == principal || key_id.0 == principal
+            })
+            .map(|(key_id, _access_rights, _inviter)| key_id)
+            .collect();
+        for key_id in declined {
+            self.remove_pending_invitation(caller, key_id);
+        }
+
+        self.ensure_invitations_enabled_mut()?
+            .blocked_principals
+            .insert((caller, principal), ());
+        Ok(())
+    }
+
+    /// Unblocks invitations from `principal` to the caller.
+    /// Returns whether `principal` was blocked.
+    pub fn unblock_principal(
+        &mut self,
+        caller: Principal,
+        principal: Principal,
+    ) -> Result<bool, String> {
+        Ok(self
+            .ensure_invitations_enabled_mut()?
+            .blocked_principals
+            .remove(&(caller, principal))
+            .is_some())
+    }
+
+    /// Retrieves the principals blocked by the caller.
+    pub fn get_blocked_principals(&self, caller: Principal) -> Vec<Principal> {
+        self.invitations
+            .as_ref()
+            .map(|invitations| {
+                invitations
+                    .blocked_principals
+                    .range((caller, Principal::management_canister())..)
+                    .take_while(|entry| entry.key().0 == caller)
+                    .map(|entry| entry.key().1)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Creates or updates a pending invitation if the invitation flow is
+    /// enabled and `user` has no access to the vetKey yet. Returns `None` if
+    /// the access rights should be granted directly instead.
+    pub(crate) fn invite_user(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        user: Principal,
+        access_rights: T,
+    ) -> Option<Result<(), String>> {
+        if user == key_id.0 || self.access_control.contains_key(&(user, key_id)) {
+            return None;
+        }
+        let invitations = self.invitations.as_mut()?;
+
+        if invitations.is_blocked(user, caller) || invitations.is_blocked(user, key_id.0) {
+            return Some(Err("blocked by recipient".to_string()));
+        }
+
+        let is_new_invitation = !invitations
+            .pending_invitations
+            .contains_key(&(user, key_id));
+        if is_new_invitation
+            && invitations.pending_invitations_iter(user).count() as u64
+                >= invitations.config.max_pending_invitations_per_recipient
+        {
+            return Some(Err("too many pending invitations".to_string()));
+        }
+
+        invitations.invited_users.insert((key_id, user), ());
+        invitations
+            .pending_invitations
+            .insert((user, key_id), (access_rights, caller));
+        if is_new_invitation {
+            self.add_quota_reference(key_id, QuotaReference::Share);
+        }
+        Some(Ok(()))
+    }
+
+    /// Removes a pending invitation of `user` to the vetKey, if any.
+    pub(crate) fn cancel_invitation(&mut self, key_id: KeyId, user: Principal) {
+        self.remove_pending_invitation(user, key_id);
+    }
+
+    /// Removes a pending invitation of `recipient` to the vetKey, if any, and
+    /// returns the offered access rights and the inviting principal.
+    fn remove_pending_invitation(
+        &mut self,
+        recipient: Principal,
+        key_id: KeyId,
+    ) -> Option<(T, Principal)> {
+        let invitation = self
+            .invitations
+            .as_mut()?
+            .remove_invitation(recipient, key_id)?;
+        self.remove_quota_reference(key_id, QuotaReference::Share);
+        Some(invitation)
+    }
+
+    /// Cancels the pending invitations of `user` to vetKeys owned by `owner`.
+    pub(crate) fn cancel_invitations_from_owner(&mut self, owner: Principal, user: Principal) {
+        let key_ids: Vec<KeyId> = self
+            .invitations
+            .iter()
+            .flat_map(|invitations| invitations.pending_invitations_iter(user))
+            .map(|(key_id, _access_rights, _inviter)| key_id)
+            .filter(|key_id| key_id.0 == owner)
+            .collect();
+        for key_id in key_ids {
+            self.remove_pending_invitation(user, key_id);
+        }
+    }
+
+    /// Moves the pending invitations of `old_recipient` to `new_recipient`.
+    /// Invitations that `new_recipient` would not get, e.g., because it has
+    /// access already or blocked the inviter, are removed instead.
+    pub(crate) fn move_pending_invitations(
+        &mut self,
+        old_recipient: Principal,
+        new_recipient: Principal,
+    ) {
+        let pending: Vec<(KeyId, T, Principal)> = self
+            .invitations
+            .iter()
+            .flat_map(|invitations| invitations.pending_invitations_iter(old_recipient))
+            .collect();
+        for (key_id, access_rights, inviter) in pending {
+            let is_obsolete = self.is_owner(new_recipient, key_id)
+                || self.access_control.contains_key(&(new_recipient, key_id))
+                || self.invitations.as_ref().is_some_and(|invitations| {
+                    invitations
+                        .pending_invitations
+                        .contains_key(&(new_recipient, key_id))
+                        || invitations.is_blocked(new_recipient, inviter)
+                        || invitations.is_blocked(new_recipient, key_id.0)
+                });
+            if is_obsolete {
+                self.remove_pending_invitation(old_recipient, key_id);
+            } else if let Some(invitations) = self.invitations.as_mut() {
+                invitations.remove_invitation(old_recipient, key_id);
+                invitations
+                    .invited_users
+                    .insert((key_id, new_recipient), ());
+                invitations
+                    .pending_invitations
+                    .insert((new_recipient, key_id), (access_rights, inviter));
+            }
+        }
+    }
+
+    fn ensure_invitations_enabled(&self) -> Result<&Invitations<T>, String> {
+        self.invitations
+            .as_ref()
+            .ok_or_else(|| "invitations are not enabled".to_string())
+    }
+
+    fn ensure_invitations_enabled_mut(&mut self) -> Result<&mut Invitations<T>, String> {
+        self.invitations
+            .as_mut()
+            .ok_or_else(|| "invitations are not enabled".to_string())
+    }
+}
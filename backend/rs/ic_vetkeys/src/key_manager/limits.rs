@@ -0,0 +1,382 @@
+//! Rate limiting of vetKey derivations and per-owner quotas, see
+//! [`KeyManager::set_rate_limiter`] and [`KeyManager::enable_quotas`].
+
+use super::{KeyId, KeyManager, Memory};
+use crate::types::AccessControl;
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableBTreeMap;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// Error returned if a rate limit or a quota is exceeded.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LimitError {
+    /// The caller exceeded its own rate limit.
+    CallerRateLimited { retry_after_ns: u64 },
+    /// The rate limit shared by all callers is exceeded.
+    GlobalRateLimited { retry_after_ns: u64 },
+    /// The owner already shares the maximum number of vetKeys.
+    KeyQuotaExceeded { max_keys_per_owner: u64 },
+    /// The owner already has the maximum number of shares across their vetKeys.
+    ShareQuotaExceeded { max_shares_per_owner: u64 },
+}
+
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitError::CallerRateLimited { retry_after_ns } => {
+                write!(
+                    f,
+                    "caller rate limit exceeded, retry after {retry_after_ns} ns"
+                )
+            }
+            LimitError::GlobalRateLimited { retry_after_ns } => {
+                write!(
+                    f,
+                    "global rate limit exceeded, retry after {retry_after_ns} ns"
+                )
+            }
+            LimitError::KeyQuotaExceeded { max_keys_per_owner } => {
+                write!(f, "key quota of {max_keys_per_owner} per owner exceeded")
+            }
+            LimitError::ShareQuotaExceeded {
+                max_shares_per_owner,
+            } => {
+                write!(
+                    f,
+                    "share quota of {max_shares_per_owner} per owner exceeded"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+/// Decides whether a vetKey derivation may proceed, see [`KeyManager::set_rate_limiter`].
+pub trait RateLimiter {
+    /// Consumes the permission for one vetKey derivation by `caller` at time
+    /// `now_ns` (nanoseconds since the UNIX epoch), or returns an error if the
+    /// derivation must be rejected.
+    fn try_acquire(&mut self, caller: Principal, now_ns: u64) -> Result<(), LimitError>;
+}
+
+/// Configuration of a token bucket that holds at most `capacity` tokens and
+/// regains one token every `refill_interval_ns` nanoseconds.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TokenBucketConfig {
+    pub capacity: u64,
+    pub refill_interval_ns: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    tokens: u64,
+    last_refill_ns: u64,
+}
+
+impl TokenBucket {
+    fn full(config: &TokenBucketConfig, now_ns: u64) -> Self {
+        Self {
+            tokens: config.capacity,
+            last_refill_ns: now_ns,
+        }
+    }
+
+    fn refill(&mut self, config: &TokenBucketConfig, now_ns: u64) {
+        let interval = config.refill_interval_ns.max(1);
+        let new_tokens = now_ns.saturating_sub(self.last_refill_ns) / interval;
+        self.tokens = self.tokens.saturating_add(new_tokens);
+        if self.tokens >= config.capacity {
+            self.tokens = config.capacity;
+            self.last_refill_ns = now_ns;
+        } else {
+            // keep the time elapsed since the last whole token
+            self.last_refill_ns += new_tokens * interval;
+        }
+    }
+
+    fn is_full(&self, config: &TokenBucketConfig, now_ns: u64) -> bool {
+        let mut bucket = *self;
+        bucket.refill(config, now_ns);
+        bucket.tokens == config.capacity
+    }
+
+    fn retry_after_ns(&self, config: &TokenBucketConfig, now_ns: u64) -> u64 {
+        (self.last_refill_ns + config.refill_interval_ns.max(1)).saturating_sub(now_ns)
+    }
+}
+
+/// A [`RateLimiter`] with a token bucket per caller and an optional global
+/// token bucket shared by all callers. Each derivation consumes one token from
+/// both buckets and is rejected if either of them is empty.
+///
+/// The buckets are kept on the heap, i.e., they are reset to full on canister
+/// upgrades. This only allows a burst of at most the bucket capacities per
+/// upgrade, which is under the control of the canister controllers.
+pub struct TokenBucketRateLimiter {
+    per_caller: Option<TokenBucketConfig>,
+    global: Option<TokenBucketConfig>,
+    global_bucket: Option<TokenBucket>,
+    caller_buckets: BTreeMap<Principal, TokenBucket>,
+    prune_threshold: usize,
+}
+
+impl TokenBucketRateLimiter {
+    const MIN_PRUNE_THRESHOLD: usize = 1024;
+
+    pub fn new(per_caller: Option<TokenBucketConfig>, global: Option<TokenBucketConfig>) -> Self {
+        Self {
+            per_caller,
+            global,
+            global_bucket: None,
+            caller_buckets: BTreeMap::new(),
+            prune_threshold: Self::MIN_PRUNE_THRESHOLD,
+        }
+    }
+
+    /// Forgets the buckets of callers that are full again, which is equivalent
+    /// to keeping them. Runs in amortized constant time per call.
+    fn prune(&mut self, config: &TokenBucketConfig, now_ns: u64) {
+        if self.caller_buckets.len() < self.prune_threshold {
+            return;
+        }
+        self.caller_buckets
+            .retain(|_, bucket| !bucket.is_full(config, now_ns));
+        self.prune_threshold = (2 * self.caller_buckets.len()).max(Self::MIN_PRUNE_THRESHOLD);
+    }
+}
+
+impl RateLimiter for TokenBucketRateLimiter {
+    fn try_acquire(&mut self, caller: Principal, now_ns: u64) -> Result<(), LimitError> {
+        let mut global_bucket = None;
+        if let Some(config) = self.global {
+            let mut bucket = self
+                .global_bucket
+                .unwrap_or_else(|| TokenBucket::full(&config, now_ns));
+            bucket.refill(&config, now_ns);
+            if bucket.tokens == 0 {
+                return Err(LimitError::GlobalRateLimited {
+                    retry_after_ns: bucket.retry_after_ns(&config, now_ns),
+                });
+            }
+            global_bucket = Some(bucket);
+        }
+
+        if let Some(config) = self.per_caller {
+            self.prune(&config, now_ns);
+            let bucket = self
+                .caller_buckets
+                .entry(caller)
+                .or_insert_with(|| TokenBucket::full(&config, now_ns));
+            bucket.refill(&config, now_ns);
+            if bucket.tokens == 0 {
+                return Err(LimitError::CallerRateLimited {
+                    retry_after_ns: bucket.retry_after_ns(&config, now_ns),
+                });
+            }
+            bucket.tokens -= 1;
+        }
+
+        if let Some(mut bucket) = global_bucket {
+            bucket.tokens -= 1;
+            self.global_bucket = Some(bucket);
+        }
+        Ok(())
+    }
+}
+
+/// Per-owner quotas, see [`KeyManager::enable_quotas`]. `None` means unlimited.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct QuotaConfig {
+    /// The maximum number of vetKeys an owner can share, or, in
+    /// [`crate::encrypted_maps::EncryptedMaps`], the maximum number of maps an
+    /// owner can have that are shared or hold values.
+    pub max_keys_per_owner: Option<u64>,
+    /// The maximum number of shares, including pending invitations, across all vetKeys of an owner.
+    pub max_shares_per_owner: Option<u64>,
+}
+
+/// Stable state of per-owner quotas.
+///
+/// The usage of each owner is counted whenever a vetKey is shared or
+/// revoked, an invitation is created or removed, or a map value is inserted
+/// or removed, so that the quotas are checked in constant time.
+pub struct Quotas {
+    pub config: QuotaConfig,
+    /// Maps vetKeys to the number of their shares and pending invitations
+    /// and, in [`crate::encrypted_maps::EncryptedMaps`], the number of values
+    /// of the map with the same ID. vetKeys without any are omitted.
+    pub key_references: StableBTreeMap<KeyId, u64, Memory>,
+    /// Maps owners to the number of shares and pending invitations across
+    /// their vetKeys and the number of their vetKeys in `key_references`.
+    pub owner_usage: StableBTreeMap<Principal, (u64, u64), Memory>,
+}
+
+/// A reference to a vetKey that counts towards the quotas of its owner.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum QuotaReference {
+    /// A share or a pending invitation.
+    Share,
+    /// A value of the map with the same ID.
+    MapValue,
+}
+
+impl Quotas {
+    fn add_reference(&mut self, key_id: KeyId, reference: QuotaReference) {
+        let references = self.key_references.get(&key_id).unwrap_or_default();
+        self.key_references.insert(key_id, references + 1);
+        let (shares, keys) = self.owner_usage.get(&key_id.0).unwrap_or_default();
+        self.owner_usage.insert(
+            key_id.0,
+            (
+                shares + u64::from(reference == QuotaReference::Share),
+                keys + u64::from(references == 0),
+            ),
+        );
+    }
+
+    fn remove_reference(&mut self, key_id: KeyId, reference: QuotaReference) {
+        let references = self.key_references.get(&key_id).unwrap_or_default();
+        if references > 1 {
+            self.key_references.insert(key_id, references - 1);
+        } else {
+            self.key_references.remove(&key_id);
+        }
+        let (shares, keys) = self.owner_usage.get(&key_id.0).unwrap_or_default();
+        let usage = (
+            shares.saturating_sub(u64::from(reference == QuotaReference::Share)),
+            keys.saturating_sub(u64::from(references == 1)),
+        );
+        if usage == (0, 0) {
+            self.owner_usage.remove(&key_id.0);
+        } else {
+            self.owner_usage.insert(key_id.0, usage);
+        }
+    }
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Sets the rate limiter that is consulted before every vetKey derivation
+    /// in [`KeyManager::get_encrypted_vetkey`], e.g., a [`TokenBucketRateLimiter`].
+    /// The rate limiter is not persisted and must be set on every canister
+    /// (re)initialization.
+    pub fn set_rate_limiter(&mut self, rate_limiter: impl RateLimiter + 'static) {
+        self.rate_limiter = Some(RefCell::new(Box::new(rate_limiter)));
+    }
+
+    /// Enables the per-owner quotas that are enforced when sharing vetKeys.
+    /// Lowering a quota does not revoke existing shares.
+    ///
+    /// The usage of the owners is kept in stable memory and only counted
+    /// while quotas are enabled, so, once enabled, they must stay enabled.
+    /// When they are enabled for the first time, the existing shares and
+    /// pending invitations are counted once, so this must be called after
+    /// [`KeyManager::enable_invitations`].
+    pub fn enable_quotas(
+        &mut self,
+        config: QuotaConfig,
+        memory_key_references: Memory,
+        memory_owner_usage: Memory,
+    ) {
+        self.enable_quotas_with(
+            config,
+            memory_key_references,
+            memory_owner_usage,
+            std::iter::empty(),
+        );
+    }
+
+    /// Like [`KeyManager::enable_quotas`], with `map_values`, the map IDs of
+    /// the existing map values, counted as well when quotas are enabled for
+    /// the first time.
+    pub(crate) fn enable_quotas_with(
+        &mut self,
+        config: QuotaConfig,
+        memory_key_references: Memory,
+        memory_owner_usage: Memory,
+        map_values: impl Iterator<Item = KeyId>,
+    ) {
+        let mut quotas = Quotas {
+            config,
+            key_references: StableBTreeMap::init(memory_key_references),
+            owner_usage: StableBTreeMap::init(memory_owner_usage),
+        };
+        if quotas.owner_usage.is_empty() {
+            let shares = self.shared_keys.keys().map(|(key_id, _user)| key_id);
+            let invitations = self.invitations.iter().flat_map(|invitations| {
+                invitations
+                    .invited_users
+                    .keys()
+                    .map(|(key_id, _user)| key_id)
+            });
+            for key_id in shares.chain(invitations) {
+                quotas.add_reference(key_id, QuotaReference::Share);
+            }
+            for map_id in map_values {
+                quotas.add_reference(map_id, QuotaReference::MapValue);
+            }
+        }
+        self.quotas = Some(quotas);
+    }
+
+    /// Consumes the permission for one vetKey derivation by `caller` from the
+    /// rate limiter, if any.
+    pub fn try_acquire_vetkey_derivation(
+        &self,
+        caller: Principal,
+        now_ns: u64,
+    ) -> Result<(), LimitError> {
+        match &self.rate_limiter {
+            Some(rate_limiter) => rate_limiter.borrow_mut().try_acquire(caller, now_ns),
+            None => Ok(()),
+        }
+    }
+
+    /// Ensures that the owner of `key_id` can add one more share to it without exceeding the quotas.
+    pub fn ensure_share_within_quotas(&self, key_id: KeyId) -> Result<(), LimitError> {
+        let Some(quotas) = &self.quotas else {
+            return Ok(());
+        };
+        if let Some(max_shares_per_owner) = quotas.config.max_shares_per_owner {
+            let (shares, _keys) = quotas.owner_usage.get(&key_id.0).unwrap_or_default();
+            if shares >= max_shares_per_owner {
+                return Err(LimitError::ShareQuotaExceeded {
+                    max_shares_per_owner,
+                });
+            }
+        }
+        self.ensure_key_within_quota(key_id)
+    }
+
+    /// Ensures that `key_id` already counts towards the vetKeys of its owner,
+    /// or that it can be added without exceeding
+    /// [`QuotaConfig::max_keys_per_owner`].
+    pub fn ensure_key_within_quota(&self, key_id: KeyId) -> Result<(), LimitError> {
+        let Some(quotas) = &self.quotas else {
+            return Ok(());
+        };
+        if let Some(max_keys_per_owner) = quotas.config.max_keys_per_owner {
+            let (_shares, keys) = quotas.owner_usage.get(&key_id.0).unwrap_or_default();
+            if !quotas.key_references.contains_key(&key_id) && keys >= max_keys_per_owner {
+                return Err(LimitError::KeyQuotaExceeded { max_keys_per_owner });
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a reference to `key_id` towards the quotas of its owner, if enabled.
+    pub(crate) fn add_quota_reference(&mut self, key_id: KeyId, reference: QuotaReference) {
+        if let Some(quotas) = self.quotas.as_mut() {
+            quotas.add_reference(key_id, reference);
+        }
+    }
+
+    /// Stops counting a reference to `key_id` towards the quotas of its owner, if enabled.
+    pub(crate) fn remove_quota_reference(&mut self, key_id: KeyId, reference: QuotaReference) {
+        if let Some(quotas) = self.quotas.as_mut() {
+            quotas.remove_reference(key_id, reference);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Support for key names, map names, and map keys longer than 32 bytes, see
+//! [`KeyManager::enable_long_names`].
+
+use super::{KeyManager, Memory, VetKDCallError, VetKey, VetKeyResult};
+use crate::types::{AccessControl, ByteBuf, TransportKey};
+use candid::Principal;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+
+/// Side table of the names longer than 32 bytes, indexed by their canonical
+/// 32-byte form.
+///
+/// A name of at most 32 bytes is its own canonical form, so existing stable
+/// data remains valid. A longer name is canonicalized to a domain-separated
+/// SHA-256 hash of the name, see [`LongNames::canonical_name`], and the
+/// original name is stored here so that listings can return it. The table is
+/// content-addressed and entries are never removed.
+///
+/// Since a 32-byte name and the canonical form of a longer name share the
+/// same space, a 32-byte name equal to the canonical form of a registered
+/// longer name is rejected, see [`KeyManager::name_from_bytes`].
+pub struct LongNames {
+    pub names: StableBTreeMap<Blob<32>, Vec<u8>, Memory>,
+}
+
+impl LongNames {
+    /// The maximum length of a name in bytes.
+    pub const MAX_NAME_LEN: usize = 1024;
+
+    const DOMAIN_SEPARATOR: &'static [u8] = b"ic-vetkeys-long-name";
+
+    /// Returns the canonical 32-byte form of `name`.
+    /// Returns an error if `name` is longer than [`LongNames::MAX_NAME_LEN`].
+    pub fn canonical_name(name: &[u8]) -> Result<Blob<32>, String> {
+        if name.len() > Self::MAX_NAME_LEN {
+            return Err("too large input".to_string());
+        }
+        if let Ok(blob) = Blob::try_from(name) {
+            return Ok(blob);
+        }
+        let mut hasher = Sha256::new();
+        hasher.update([Self::DOMAIN_SEPARATOR.len() as u8]);
+        hasher.update(Self::DOMAIN_SEPARATOR);
+        hasher.update(name);
+        Ok(Blob::try_from(hasher.finalize().as_slice()).expect("SHA-256 hashes are 32 bytes"))
+    }
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Enables names longer than 32 bytes, see [`LongNames`].
+    ///
+    /// Once enabled, [`KeyManager::name_from_bytes`] accepts names of up to
+    /// [`LongNames::MAX_NAME_LEN`] bytes, and names registered with
+    /// [`KeyManager::register_name`] are returned in full by
+    /// [`KeyManager::name_to_bytes`].
+    pub fn enable_long_names(&mut self, memory_long_names: Memory) {
+        self.long_names = Some(LongNames {
+            names: StableBTreeMap::init(memory_long_names),
+        });
+    }
+
+    /// Converts a name as provided by a user to its canonical 32-byte form.
+    /// Returns an error if the name is too long, i.e., longer than 32 bytes if
+    /// long names are not enabled, or if it is a 32-byte name equal to the
+    /// canonical form of a longer name registered with
+    /// [`KeyManager::register_name`], which would otherwise refer to the same
+    /// vetKey, access rights, and map values as the longer name.
+    pub fn name_from_bytes(&self, name: &[u8]) -> Result<Blob<32>, String> {
+        let Some(long_names) = &self.long_names else {
+            return Blob::try_from(name).map_err(|_| "too large input".to_string());
+        };
+        let canonical_name = LongNames::canonical_name(name)?;
+        if name.len() == canonical_name.as_slice().len()
+            && long_names.names.contains_key(&canonical_name)
+        {
+            return Err("name is reserved for a longer name".to_string());
+        }
+        Ok(canonical_name)
+    }
+
+    /// Converts a canonical name back to the name as provided by the user.
+    pub fn name_to_bytes(&self, name: &Blob<32>) -> ByteBuf {
+        self.long_names
+            .as_ref()
+            .and_then(|long_names| long_names.names.get(name))
+            .unwrap_or_else(|| name.as_slice().to_vec())
+            .into()
+    }
+
+    /// Stores a name that was persisted in its canonical form, so that
+    /// [`KeyManager::name_to_bytes`] can return it in full. Does nothing for
+    /// names of at most 32 bytes or if long names are not enabled.
+    pub fn register_name(&mut self, name: &[u8]) -> Result<(), String> {
+        let Some(long_names) = self.long_names.as_mut() else {
+            return Ok(());
+        };
+        if Blob::<32>::try_from(name).is_err() {
+            let canonical_name = LongNames::canonical_name(name)?;
+            long_names.names.insert(canonical_name, name.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Retrieves an encrypted vetKey for caller and a key name as provided by
+    /// the user, see [`KeyManager::get_encrypted_vetkey`].
+    ///
+    /// Both access and the vetKey are determined by the canonical form of the
+    /// name, so the vetKey is the same as the one retrieved with
+    /// [`KeyManager::get_encrypted_vetkey`] for the canonical key ID.
+    pub fn get_encrypted_vetkey_by_name(
+        &self,
+        caller: Principal,
+        key_owner: Principal,
+        key_name: &[u8],
+        transport_key: TransportKey,
+    ) -> Result<impl Future<Output = Result<VetKey, VetKDCallError>> + Send + Sync, String> {
+        let key_id = (key_owner, self.name_from_bytes(key_name)?);
+        self.get_encrypted_vetkey(caller, key_id, transport_key)
+    }
+
+    /// Retrieves an encrypted vetKey for caller, a key name as provided by
+    /// the user, and `purpose`, see [`KeyManager::get_encrypted_vetkey_by_name`]
+    /// and [`KeyManager::get_encrypted_vetkey_for_purpose`].
+    pub fn get_encrypted_vetkey_by_name_for_purpose(
+        &self,
+        caller: Principal,
+        key_owner: Principal,
+        key_name: &[u8],
+        purpose: &[u8],
+        transport_key: TransportKey,
+    ) -> Result<impl Future<Output = Result<VetKey, VetKDCallError>> + Send + Sync, String> {
+        let key_id = (key_owner, self.name_from_bytes(key_name)?);
+        self.get_encrypted_vetkey_for_purpose(caller, key_id, purpose, transport_key)
+    }
+
+    /// Retrieves encrypted vetKeys for caller and each of the given key
+    /// owners and key names as provided by the user, see
+    /// [`KeyManager::get_encrypted_vetkeys`] and
+    /// [`KeyManager::get_encrypted_vetkey_by_name`]. The returned future
+    /// resolves to a result per vetKey, in the order of `keys`.
+    pub fn get_encrypted_vetkeys_by_name(
+        &self,
+        caller: Principal,
+        keys: &[(Principal, ByteBuf)],
+        transport_key: TransportKey,
+    ) -> Result<impl Future<Output = Vec<VetKeyResult>> + Send + Sync, String> {
+        let requests = keys
+            .iter()
+            .map(|(key_owner, key_name)| Ok((*key_owner, self.name_from_bytes(key_name.as_ref())?)))
+            .collect();
+        self.derive_encrypted_vetkeys(caller, requests, transport_key)
+    }
+}
@@ -0,0 +1,209 @@
+//! Migration of a principal's vetKeys and shares to another principal, see
+//! [`KeyManager::enable_migrations`].
+
+use super::{KeyId, KeyManager, Memory, QuotaReference};
+use crate::types::AccessControl;
+use candid::Principal;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::StableBTreeMap;
+
+/// Stable state of principal migrations.
+///
+/// Like recovered vetKeys, migrated vetKeys keep their IDs, and thus their
+/// vetKD inputs, so that data encrypted with them remains decryptable.
+/// Instead, `migrated_principals` records who owns them now, see
+/// [`KeyManager::resolve_owner`].
+pub struct Migrations {
+    /// Maps a principal to the principal it requested to migrate to.
+    pub pending_migrations: StableBTreeMap<Principal, Principal, Memory>,
+    /// Maps a migrated principal to the principal it was migrated to.
+    pub migrated_principals: StableBTreeMap<Principal, Principal, Memory>,
+    /// Contains `(new principal, migrated principal)` pairs, i.e., the
+    /// reverse of `migrated_principals`, so that the principals migrated to a
+    /// principal are found without a scan.
+    pub predecessors: StableBTreeMap<(Principal, Principal), (), Memory>,
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Enables migrations: users can move their vetKeys and the vetKeys
+    /// shared with them to another principal, e.g., when switching identity
+    /// providers, see [`KeyManager::request_migration`].
+    pub fn enable_migrations(
+        &mut self,
+        memory_pending_migrations: Memory,
+        memory_migrated_principals: Memory,
+        memory_predecessors: Memory,
+    ) {
+        let mut migrations = Migrations {
+            pending_migrations: StableBTreeMap::init(memory_pending_migrations),
+            migrated_principals: StableBTreeMap::init(memory_migrated_principals),
+            predecessors: StableBTreeMap::init(memory_predecessors),
+        };
+        if migrations.predecessors.is_empty() {
+            for entry in migrations.migrated_principals.iter() {
+                migrations
+                    .predecessors
+                    .insert((entry.value(), *entry.key()), ());
+            }
+        }
+        self.migrations = Some(migrations);
+    }
+
+    /// Requests the migration of the caller to `new_principal`, which takes
+    /// effect once `new_principal` confirms it with
+    /// [`KeyManager::confirm_migration`]. A later request replaces an earlier
+    /// one.
+    pub fn request_migration(
+        &mut self,
+        caller: Principal,
+        new_principal: Principal,
+    ) -> Result<(), String> {
+        self.ensure_valid_migration(caller, new_principal)?;
+        self.ensure_migrations_enabled_mut()?
+            .pending_migrations
+            .insert(caller, new_principal);
+        Ok(())
+    }
+
+    /// Cancels the pending migration of the caller.
+    pub fn cancel_migration(&mut self, caller: Principal) -> Result<(), String> {
+        self.ensure_migrations_enabled_mut()?
+            .pending_migrations
+            .remove(&caller)
+            .map(|_new_principal| ())
+            .ok_or_else(|| "no pending migration".to_string())
+    }
+
+    /// Returns the principal the caller requested to migrate to, if any.
+    pub fn get_pending_migration(&self, caller: Principal) -> Option<Principal> {
+        self.migrations
+            .as_ref()
+            .and_then(|migrations| migrations.pending_migrations.get(&caller))
+    }
+
+    /// Confirms the migration of `old_principal` to the caller, which
+    /// `old_principal` requested with [`KeyManager::request_migration`].
+    /// Returns the IDs of the vetKeys shared with `old_principal` that were
+    /// moved to the caller.
+    ///
+    /// Afterwards, the caller owns the vetKeys of `old_principal`, see
+    /// [`KeyManager::resolve_owner`], and holds its access rights to vetKeys
+    /// of others. If both had access to a vetKey, the caller keeps the higher
+    /// access rights. The vetKey IDs do not change, so the vetKeys and the
+    /// data encrypted with them stay the same, and the migration is recorded
+    /// for sharers to see, see [`KeyManager::get_migrated_principal`].
+    /// Pending invitations of `old_principal` are moved to the caller, and
+    /// its guardians and inheritance plan are removed.
+    pub fn confirm_migration(
+        &mut self,
+        caller: Principal,
+        old_principal: Principal,
+    ) -> Result<Vec<KeyId>, String> {
+        let migrations = self.ensure_migrations_enabled_mut()?;
+        if migrations.pending_migrations.get(&old_principal) != Some(caller) {
+            return Err("no pending migration".to_string());
+        }
+        self.ensure_valid_migration(old_principal, caller)?;
+
+        let shares: Vec<(KeyId, T)> = self
+            .access_control
+            .range(
+                (
+                    old_principal,
+                    (Principal::management_canister(), Blob::default()),
+                )..,
+            )
+            .take_while(|entry| entry.key().0 == old_principal)
+            .map(|entry| (entry.key().1, entry.value()))
+            .collect();
+        let mut moved_key_ids = Vec::new();
+        for (key_id, access_rights) in shares {
+            if self.shared_keys.remove(&(key_id, old_principal)).is_some() {
+                self.remove_quota_reference(key_id, QuotaReference::Share);
+            }
+            self.certify_user_rights(key_id, old_principal, None);
+            self.access_control.remove(&(old_principal, key_id));
+            if self.is_owner(caller, key_id)
+                || self
+                    .access_control
+                    .get(&(caller, key_id))
+                    .is_some_and(|existing| existing >= access_rights)
+            {
+                continue;
+            }
+            if self.shared_keys.insert((key_id, caller), ()).is_none() {
+                self.add_quota_reference(key_id, QuotaReference::Share);
+            }
+            self.certify_user_rights(key_id, caller, Some(access_rights));
+            self.access_control.insert((caller, key_id), access_rights);
+            moved_key_ids.push(key_id);
+        }
+        self.move_pending_invitations(old_principal, caller);
+
+        let migrations = self.ensure_migrations_enabled_mut()?;
+        migrations.pending_migrations.remove(&old_principal);
+        migrations.migrated_principals.insert(old_principal, caller);
+        migrations.predecessors.insert((caller, old_principal), ());
+        self.remove_recovery_setup(old_principal);
+        if let Some(inheritance) = self.inheritance.as_mut() {
+            inheritance.remove_plan(old_principal);
+        }
+        self.notify(|listener| listener.on_migrate(old_principal, caller));
+        Ok(moved_key_ids)
+    }
+
+    /// Returns the principal that `principal` was migrated to, if any, so
+    /// that sharers can follow the users they shared vetKeys with.
+    pub fn get_migrated_principal(&self, principal: Principal) -> Option<Principal> {
+        self.migrations
+            .as_ref()
+            .and_then(|migrations| migrations.migrated_principals.get(&principal))
+    }
+
+    /// Returns the principals that were migrated to the caller.
+    pub fn get_migrated_principals(&self, caller: Principal) -> Vec<Principal> {
+        let Some(migrations) = &self.migrations else {
+            return vec![];
+        };
+        self.predecessors(caller)
+            .into_iter()
+            .filter(|principal| migrations.migrated_principals.contains_key(principal))
+            .collect()
+    }
+
+    /// Returns the principals that were migrated directly to `principal`.
+    pub(crate) fn migrated_predecessors(&self, principal: Principal) -> Vec<Principal> {
+        let Some(migrations) = &self.migrations else {
+            return vec![];
+        };
+        migrations
+            .predecessors
+            .keys_range((principal, Principal::management_canister())..)
+            .take_while(|(new_principal, _old_principal)| *new_principal == principal)
+            .map(|(_new_principal, old_principal)| old_principal)
+            .collect()
+    }
+
+    fn ensure_valid_migration(
+        &self,
+        old_principal: Principal,
+        new_principal: Principal,
+    ) -> Result<(), String> {
+        if old_principal == new_principal {
+            return Err("cannot migrate to oneself".to_string());
+        }
+        if self.resolve_owner(old_principal) != old_principal {
+            return Err("caller has been recovered or migrated".to_string());
+        }
+        if self.resolve_owner(new_principal) != new_principal {
+            return Err("invalid new principal".to_string());
+        }
+        Ok(())
+    }
+
+    fn ensure_migrations_enabled_mut(&mut self) -> Result<&mut Migrations, String> {
+        self.migrations
+            .as_mut()
+            .ok_or_else(|| "migrations are not enabled".to_string())
+    }
+}
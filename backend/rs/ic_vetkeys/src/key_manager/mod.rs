@@ -1,16 +1,65 @@
 //! See [`KeyManager`] for the main documentation.
 
-use crate::types::{AccessControl, ByteBuf, KeyManagerConfig, KeyName, TransportKey};
+mod backup;
+mod calls;
+mod certification;
+mod cycles;
+mod deletion;
+mod events;
+mod freeze;
+mod inbox;
+mod inheritance;
+mod invitations;
+mod limits;
+mod long_names;
+mod migration;
+mod organizations;
+mod public;
+mod recovery;
+mod schema;
+mod tenants;
+mod verification_key;
+
+use crate::types::{
+    AccessControl, ByteBuf, KeyManagerConfig, KeyName, Page, SchemaVersions, TransportKey,
+};
 use candid::Principal;
 use ic_stable_structures::memory_manager::VirtualMemory;
 use ic_stable_structures::storable::Blob;
 use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use std::cell::RefCell;
 use std::future::Future;
+use std::ops::Bound;
 
 use ic_cdk_management_canister::{VetKDDeriveKeyArgs, VetKDKeyId, VetKDPublicKeyArgs};
 
+pub use backup::{BackupChunk, BackupCursor, BackupSection, BACKUP_FORMAT_VERSION};
+pub use calls::{CallPolicy, SleepFuture, VetKDCallError};
+pub use certification::{CertifiedAccessControl, CertifiedQueryVerifier, CertifiedResponse};
+pub use cycles::CyclesLedger;
+pub use deletion::{KeyDeletion, KeyDeletionConfig, KeyTombstone};
+pub use events::EventListener;
+pub use freeze::{FreezeConfig, FreezeState, KeyFreezing};
+pub use inbox::{Inbox, InboxConfig, InboxMessage};
+pub use inheritance::{Inheritance, InheritanceConfig, InheritancePlan};
+pub use invitations::{Invitations, InvitationsConfig};
+pub(crate) use limits::QuotaReference;
+pub use limits::{
+    LimitError, QuotaConfig, Quotas, RateLimiter, TokenBucketConfig, TokenBucketRateLimiter,
+};
+pub use long_names::LongNames;
+pub use migration::Migrations;
+pub use organizations::{ApprovalStatus, OrganizationAction, Organizations, OrganizationsConfig};
+pub use public::PublicKeys;
+pub use recovery::{PendingRecovery, Recovery, RecoveryConfig, RecoveryGuardians, RecoveryStatus};
+pub(crate) use schema::migrate_schema;
+pub use schema::StableStateError;
+pub use tenants::{tenant_key_id, TenantId, Tenants};
+
 pub type VetKeyVerificationKey = ByteBuf;
 pub type VetKey = ByteBuf;
+/// The result of retrieving one of several encrypted vetKeys, see [`KeyManager::get_encrypted_vetkeys`].
+pub type VetKeyResult = Result<VetKey, String>;
 pub type Owner = Principal;
 pub type Caller = Principal;
 pub type KeyId = (Owner, KeyName);
@@ -38,6 +87,32 @@ type Memory = VirtualMemory<DefaultMemoryImpl>;
 ///
 /// 1. **Access Control Map** (`access_control`): Maps `(Caller, KeyId)` to `T`, defining permissions for each user.
 /// 2. **Shared Keys Map** (`shared_keys`): Tracks which users have access to shared vetKeys.
+/// 3. **Invitations** (`invitations`, optional): Tracks pending invitations and blocked principals if shares must be accepted by the recipient, see [`KeyManager::enable_invitations`].
+///
+/// How users with management rights may delegate is governed by the
+/// [`DelegationPolicy`] in `delegation_policy`, see [`KeyManager::set_delegation_policy`].
+/// vetKey derivations can be rate limited and shares can be subject to per-owner
+/// quotas, see [`KeyManager::set_rate_limiter`] and [`KeyManager::enable_quotas`].
+/// vetKeys can be owned by organizations that are governed by a set of admins
+/// instead of a privileged owner, see [`KeyManager::enable_organizations`].
+/// Tenants can derive vetKeys with their own domain separator and manage their
+/// access rights through tenant admins, see [`KeyManager::enable_tenants`].
+/// Queries of the access rights can be certified, see
+/// [`KeyManager::enable_certified_queries`], and mutations can be observed by
+/// an [`EventListener`], see [`KeyManager::set_event_listener`]. vetKeys can be
+/// frozen in an emergency, see [`KeyManager::enable_key_freezing`], and
+/// recovered by guardians if the owner loses access, see
+/// [`KeyManager::enable_recovery`]. Owners can name beneficiaries who inherit
+/// access rights if the owner is inactive, see
+/// [`KeyManager::enable_inheritance`], and users can migrate to another
+/// principal, see [`KeyManager::enable_migrations`]. vetKeys can be made
+/// readable by every caller, see [`KeyManager::enable_public_keys`], and can
+/// receive IBE ciphertexts from anyone, see [`KeyManager::enable_inbox`].
+/// vetKeys can be deleted with all their shares, see
+/// [`KeyManager::enable_key_deletion`].
+///
+/// Optional components are enabled with the `enable_*` methods after [`KeyManager::init`].
+/// Like [`KeyManager::init`], these must be called on every canister (re)initialization, e.g., in both `#[init]` and `#[post_upgrade]`.
 ///
 /// ## Example Use Case
 ///
@@ -59,9 +134,71 @@ pub struct KeyManager<T: AccessControl> {
     pub config: StableCell<KeyManagerConfig, Memory>,
     pub access_control: StableBTreeMap<(Principal, KeyId), T, Memory>,
     pub shared_keys: StableBTreeMap<(KeyId, Principal), (), Memory>,
+    /// Pending invitations, if enabled with [`KeyManager::enable_invitations`].
+    pub invitations: Option<Invitations<T>>,
+    pub delegation_policy: DelegationPolicy,
+    /// Consulted before every vetKey derivation, if set with [`KeyManager::set_rate_limiter`].
+    pub rate_limiter: Option<RefCell<Box<dyn RateLimiter>>>,
+    /// Per-owner quotas and usage, if enabled with [`KeyManager::enable_quotas`].
+    pub quotas: Option<Quotas>,
+    /// Original names longer than 32 bytes, if enabled with [`KeyManager::enable_long_names`].
+    pub long_names: Option<LongNames>,
+    /// Organizations owning vetKeys, if enabled with [`KeyManager::enable_organizations`].
+    pub organizations: Option<Organizations>,
+    /// Tenants with their own domain separators, if enabled with [`KeyManager::enable_tenants`].
+    pub tenants: Option<Tenants>,
+    /// Merkle tree over `access_control`, if enabled with [`KeyManager::enable_certified_queries`].
+    pub certified_access_control: Option<CertifiedAccessControl>,
+    /// Guardians and recovered owners, if enabled with [`KeyManager::enable_recovery`].
+    pub recovery: Option<Recovery>,
+    /// Frozen vetKeys, if enabled with [`KeyManager::enable_key_freezing`].
+    pub key_freezing: Option<KeyFreezing>,
+    /// Inheritance plans of owners, if enabled with [`KeyManager::enable_inheritance`].
+    pub inheritance: Option<Inheritance<T>>,
+    /// Migrated principals, if enabled with [`KeyManager::enable_migrations`].
+    pub migrations: Option<Migrations>,
+    /// vetKeys readable by every caller, if enabled with [`KeyManager::enable_public_keys`].
+    pub public_keys: Option<PublicKeys<T>>,
+    /// Messages deposited for vetKeys, if enabled with [`KeyManager::enable_inbox`].
+    pub inbox: Option<Inbox>,
+    /// Tombstones of deleted vetKeys, if enabled with [`KeyManager::enable_key_deletion`].
+    pub key_deletion: Option<KeyDeletion>,
+    /// Notified of mutations, if set with [`KeyManager::set_event_listener`].
+    pub event_listener: Option<RefCell<Box<dyn EventListener<T>>>>,
+    /// How the vetKD endpoints are called, see [`KeyManager::set_call_policy`].
+    pub call_policy: CallPolicy,
+}
+
+/// Rules restricting how users with management rights, who are not the owner
+/// of a vetKey, may change the access rights of other users.
+///
+/// The default policy imposes no restrictions beyond
+/// [`AccessControl::can_set_user_rights`]. The rules are expressed through
+/// [`AccessControl::can_grant`] and [`AccessControl::can_manage`], so custom
+/// access rights types can define what "at or below their own rights" means.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct DelegationPolicy {
+    /// Managers can only grant access rights allowed by [`AccessControl::can_grant`].
+    pub restrict_grants_to_own_rights: bool,
+    /// Managers can only modify or remove users whose current access rights
+    /// are allowed by [`AccessControl::can_manage`], e.g., not demote or
+    /// remove other managers with equal rights. Managers can always remove
+    /// themselves.
+    pub protect_peers: bool,
+    /// Only the owner can grant, modify, or revoke access rights that include
+    /// [`AccessControl::can_set_user_rights`].
+    pub owner_only_manager_management: bool,
 }
 
 impl<T: AccessControl> KeyManager<T> {
+    /// The maximum number of vetKeys that can be requested with
+    /// [`KeyManager::get_encrypted_vetkeys`] at once.
+    pub const MAX_VETKEYS_PER_BATCH: usize = 32;
+
+    /// The maximum length in bytes of a purpose passed to
+    /// [`KeyManager::get_encrypted_vetkey_for_purpose`].
+    pub const MAX_PURPOSE_LEN: usize = MAX_PURPOSE_LEN;
+
     /// Initializes the KeyManager with stable storage.
     ///
     /// # Example
@@ -106,6 +243,10 @@ impl<T: AccessControl> KeyManager<T> {
     ///     MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)))
     /// }
     /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the stable state cannot be initialized, see [`KeyManager::try_init`].
     pub fn init(
         domain_separator: &str,
         key_id: VetKDKeyId,
@@ -113,28 +254,95 @@ impl<T: AccessControl> KeyManager<T> {
         memory_access_control: Memory,
         memory_shared_keys: Memory,
     ) -> Self {
-        let config = StableCell::init(
+        Self::try_init(
+            domain_separator,
+            key_id,
             memory_key_manager_config,
-            KeyManagerConfig {
-                domain_separator: domain_separator.to_string(),
-                key_id: key_id.clone(),
+            memory_access_control,
+            memory_shared_keys,
+        )
+        .unwrap_or_else(|e| panic!("failed to initialize KeyManager: {e}"))
+    }
+
+    /// Like [`KeyManager::init`], but returns an error instead of panicking
+    /// if the stored configuration is corrupted or the stable state cannot be
+    /// migrated to [`KeyManager::SCHEMA_VERSION`].
+    ///
+    /// The versions of the stable-memory layout are stored in the
+    /// configuration, see [`SchemaVersions`]. State written by an older
+    /// version of the library is migrated one version at a time, and state
+    /// written by a newer version is rejected.
+    pub fn try_init(
+        domain_separator: &str,
+        key_id: VetKDKeyId,
+        memory_key_manager_config: Memory,
+        memory_access_control: Memory,
+        memory_shared_keys: Memory,
+    ) -> Result<Self, StableStateError> {
+        let default_config = KeyManagerConfig {
+            domain_separator: domain_separator.to_string(),
+            key_id,
+            verification_key: None,
+            schema_versions: SchemaVersions {
+                key_manager: Self::SCHEMA_VERSION,
+                encrypted_maps: 0,
             },
-        );
-        KeyManager {
+        };
+        schema::check_stored_config(&memory_key_manager_config, &default_config)?;
+        let config = StableCell::init(memory_key_manager_config, default_config);
+        let mut key_manager = KeyManager {
             config,
             access_control: StableBTreeMap::init(memory_access_control),
             shared_keys: StableBTreeMap::init(memory_shared_keys),
-        }
+            invitations: None,
+            delegation_policy: DelegationPolicy::default(),
+            rate_limiter: None,
+            quotas: None,
+            long_names: None,
+            organizations: None,
+            tenants: None,
+            certified_access_control: None,
+            recovery: None,
+            key_freezing: None,
+            inheritance: None,
+            migrations: None,
+            public_keys: None,
+            inbox: None,
+            key_deletion: None,
+            event_listener: None,
+            call_policy: CallPolicy::default(),
+        };
+        key_manager.migrate_schema()?;
+        Ok(key_manager)
+    }
+
+    /// Sets the rules for how users with management rights may change the
+    /// access rights of other users, see [`DelegationPolicy`]. The policy is
+    /// not persisted and must be set on every canister (re)initialization.
+    pub fn set_delegation_policy(&mut self, delegation_policy: DelegationPolicy) {
+        self.delegation_policy = delegation_policy;
     }
 
     /// Retrieves all vetKey IDs shared with the given caller.
     /// This method returns a list of all vetKeys that the caller has access to.
     pub fn get_accessible_shared_key_ids(&self, caller: Principal) -> Vec<KeyId> {
-        self.access_control
-            .range((caller, (Principal::management_canister(), Blob::default()))..)
-            .take_while(|entry| entry.key().0 == caller)
-            .map(|entry| entry.key().1)
-            .collect()
+        self.accessible_shared_key_ids_iter(caller, None).collect()
+    }
+
+    /// Retrieves a page of at most `limit` vetKey IDs shared with the given caller.
+    /// The page starts after the key ID `start_after` or at the first key ID if `None`.
+    /// See [`Page`] for how to retrieve the next page.
+    pub fn get_accessible_shared_key_ids_paginated(
+        &self,
+        caller: Principal,
+        start_after: Option<KeyId>,
+        limit: usize,
+    ) -> Page<KeyId, KeyId> {
+        Page::collect(
+            self.accessible_shared_key_ids_iter(caller, start_after),
+            limit,
+            |key_id| *key_id,
+        )
     }
 
     /// Retrieves a list of users with whom a given vetKey has been shared, along with their access rights.
@@ -146,80 +354,311 @@ impl<T: AccessControl> KeyManager<T> {
     ) -> Result<Vec<(Principal, T)>, String> {
         self.ensure_user_can_get_user_rights(caller, key_id)?;
 
-        let users: Vec<_> = self
-            .shared_keys
-            .range((key_id, Principal::management_canister())..)
-            .take_while(|entry| entry.key().0 == key_id)
-            .map(|entry| entry.key().1)
-            .collect();
+        let users: Vec<_> = self.shared_users_iter(key_id, None).collect();
 
         users
             .into_iter()
-            .map(|user| {
-                self.get_user_rights(caller, key_id, user)
-                    .map(|opt_user_rights| {
-                        (user, opt_user_rights.expect("always some access rights"))
-                    })
-            })
+            .map(|user| self.get_shared_user_access(caller, key_id, user))
             .collect::<Result<Vec<_>, _>>()
     }
 
-    /// Retrieves the vetKD verification key for this canister.
+    /// Retrieves a page of at most `limit` users with whom a given vetKey has been shared, along with their access rights.
+    /// The page starts after the user `start_after` or at the first user if `None`.
+    /// The caller must have appropriate permissions to view this information.
+    pub fn get_shared_user_access_for_key_paginated(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        start_after: Option<Principal>,
+        limit: usize,
+    ) -> Result<Page<(Principal, T), Principal>, String> {
+        self.ensure_user_can_get_user_rights(caller, key_id)?;
+
+        let page = Page::collect(self.shared_users_iter(key_id, start_after), limit, |user| {
+            *user
+        });
+
+        Ok(Page {
+            items: page
+                .items
+                .into_iter()
+                .map(|user| self.get_shared_user_access(caller, key_id, user))
+                .collect::<Result<Vec<_>, _>>()?,
+            next_cursor: page.next_cursor,
+        })
+    }
+
+    /// Retrieves the IDs of the vetKeys owned by the caller that are shared
+    /// with at least one user, in ascending order.
+    pub fn get_owned_shared_key_ids(&self, caller: Principal) -> Vec<KeyId> {
+        let mut key_ids: Vec<_> = self
+            .owned_principals(caller)
+            .into_iter()
+            .flat_map(|owner| self.owned_shares_iter(owner))
+            .map(|(key_id, _user)| key_id)
+            .collect();
+        key_ids.dedup();
+        key_ids
+    }
+
+    /// Retrieves all shares of the vetKeys owned by the caller as
+    /// `(key_id, user, access_rights)`, ordered by vetKey and user.
+    /// Pending invitations are not included.
+    pub fn get_all_grants_by_owner(&self, caller: Principal) -> Vec<(KeyId, Principal, T)> {
+        self.owned_principals(caller)
+            .into_iter()
+            .flat_map(|owner| self.owned_shares_iter(owner))
+            .map(|(key_id, user)| {
+                let access_rights = self
+                    .access_control
+                    .get(&(user, key_id))
+                    .expect("shared vetKeys always have access rights");
+                (key_id, user, access_rights)
+            })
+            .collect()
+    }
+
+    /// Revokes a user's access to all vetKeys owned by the caller, including
+    /// pending invitations, e.g., when a collaborator leaves.
+    /// Returns the revoked access rights per vetKey.
+    pub fn revoke_user_everywhere(
+        &mut self,
+        caller: Principal,
+        user: Principal,
+    ) -> Result<Vec<(KeyId, T)>, String> {
+        if caller == user {
+            return Err("cannot remove key owner".to_string());
+        }
+        let mut key_ids: Vec<KeyId> = Vec::new();
+        for owner in self.owned_principals(caller) {
+            key_ids.extend(
+                self.access_control
+                    .keys_range((user, (owner, Blob::default()))..)
+                    .take_while(|(u, key_id)| *u == user && key_id.0 == owner)
+                    .map(|(_user, key_id)| key_id),
+            );
+            self.cancel_invitations_from_owner(owner, user);
+        }
+        Ok(key_ids
+            .into_iter()
+            .filter_map(|key_id| {
+                self.revoke_user(caller, key_id, user)
+                    .map(|access_rights| (key_id, access_rights))
+            })
+            .collect())
+    }
+
+    fn get_shared_user_access(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        user: Principal,
+    ) -> Result<(Principal, T), String> {
+        self.get_user_rights(caller, key_id, user)
+            .map(|opt_user_rights| (user, opt_user_rights.expect("always some access rights")))
+    }
+
+    fn accessible_shared_key_ids_iter(
+        &self,
+        caller: Principal,
+        start_after: Option<KeyId>,
+    ) -> impl Iterator<Item = KeyId> + '_ {
+        let start = match start_after {
+            Some(key_id) => Bound::Excluded((caller, key_id)),
+            None => Bound::Included((caller, (Principal::management_canister(), Blob::default()))),
+        };
+        self.access_control
+            .range((start, Bound::Unbounded))
+            .take_while(move |entry| entry.key().0 == caller)
+            .map(|entry| entry.key().1)
+    }
+
+    /// Iterates over the `(key_id, user)` shares of the vetKeys of `owner`.
+    /// `shared_keys` is ordered by vetKey ID and thus by owner first, so this
+    /// reads only the entries of `owner`.
+    fn owned_shares_iter(&self, owner: Principal) -> impl Iterator<Item = (KeyId, Principal)> + '_ {
+        self.shared_keys
+            .keys_range(((owner, Blob::default()), Principal::management_canister())..)
+            .take_while(move |(key_id, _user)| key_id.0 == owner)
+    }
+
+    fn shared_users_iter(
+        &self,
+        key_id: KeyId,
+        start_after: Option<Principal>,
+    ) -> impl Iterator<Item = Principal> + '_ {
+        let start = match start_after {
+            Some(user) => Bound::Excluded((key_id, user)),
+            None => Bound::Included((key_id, Principal::management_canister())),
+        };
+        self.shared_keys
+            .range((start, Bound::Unbounded))
+            .take_while(move |entry| entry.key().0 == key_id)
+            .map(|entry| entry.key().1)
+    }
+
+    /// Retrieves the vetKD verification key of the canister `canister_id`,
+    /// i.e., of this canister.
     /// This key is used to verify the authenticity of derived vetKeys.
+    /// Returns the key without a call to the management canister if it is
+    /// cached or can be derived offline, see
+    /// [`KeyManager::get_cached_vetkey_verification_key`].
+    /// The offline key is not compared against the management canister's,
+    /// which is a separate step with
+    /// [`KeyManager::fetch_vetkey_verification_key`] and
+    /// [`KeyManager::cache_vetkey_verification_key`].
+    /// The returned future fails if the call to the management canister fails,
+    /// see [`KeyManager::set_call_policy`].
     pub fn get_vetkey_verification_key(
         &self,
-    ) -> impl Future<Output = VetKeyVerificationKey> + Send + Sync {
-        use futures::future::FutureExt;
+        canister_id: Principal,
+    ) -> impl Future<Output = Result<VetKeyVerificationKey, VetKDCallError>> + Send + Sync {
+        let verification_key = self.get_cached_vetkey_verification_key(canister_id);
+        let fetch_verification_key = self.fetch_vetkey_verification_key();
+
+        async move {
+            match verification_key {
+                Some(verification_key) => Ok(verification_key),
+                None => fetch_verification_key.await,
+            }
+        }
+    }
 
+    /// Retrieves the vetKD verification key for this canister from the
+    /// management canister, ignoring the cached and the offline key.
+    /// The returned future fails if the call to the management canister fails,
+    /// see [`KeyManager::set_call_policy`].
+    pub fn fetch_vetkey_verification_key(
+        &self,
+    ) -> impl Future<Output = Result<VetKeyVerificationKey, VetKDCallError>> + Send + Sync {
         let domain_separator = self.config.get().domain_separator.clone();
         let key_id = self.config.get().key_id.clone();
+        let call_policy = self.call_policy;
 
-        let future = async move {
+        async move {
             let request = VetKDPublicKeyArgs {
                 canister_id: None,
                 context: domain_separator.to_bytes().to_vec(),
                 key_id,
             };
 
-            ic_cdk_management_canister::vetkd_public_key(&request).await
-        };
+            let reply = call_policy.vetkd_public_key(request).await?;
+            Ok(VetKeyVerificationKey::from(reply.public_key))
+        }
+    }
 
-        future.map(|call_result| {
-            let reply = call_result.expect("call to vetkd_public_key failed");
-            VetKeyVerificationKey::from(reply.public_key)
-        })
+    /// Returns the number of cycles that deriving a single vetKey with
+    /// [`KeyManager::get_encrypted_vetkey`] costs the canister, as computed by
+    /// `ic0_cost_vetkd_derive_key` for the configured vetKD key.
+    ///
+    /// Canisters that let callers pay for their derivations can debit this
+    /// amount from a [`CyclesLedger`].
+    pub fn vetkey_derivation_cost(&self) -> Result<u128, String> {
+        crate::management_canister::vetkd_derive_key_cost(&self.config.get().key_id)
+            .map_err(|e| e.to_string())
     }
 
     /// Retrieves an encrypted vetKey for caller and key id.
     /// The vetKey is secured using the provided transport key and can only be accessed by authorized users.
-    /// Returns an error if the caller is not authorized to access the vetKey.
+    /// Returns an error if the caller is not authorized to access the vetKey or
+    /// if the derivation is rejected by the rate limiter, see [`KeyManager::set_rate_limiter`].
+    /// The returned future fails if the call to the management canister fails,
+    /// see [`KeyManager::set_call_policy`].
     pub fn get_encrypted_vetkey(
         &self,
         caller: Principal,
         subkey_key_id: KeyId,
         transport_key: TransportKey,
-    ) -> Result<impl Future<Output = VetKey> + Send + Sync, String> {
-        use futures::future::FutureExt;
+    ) -> Result<impl Future<Output = Result<VetKey, VetKDCallError>> + Send + Sync, String> {
+        self.get_encrypted_vetkey_for_purpose(caller, subkey_key_id, &[], transport_key)
+    }
 
+    /// Retrieves an encrypted vetKey for caller, key id, and `purpose`, see
+    /// [`KeyManager::get_encrypted_vetkey`].
+    ///
+    /// Each purpose, e.g., `b"encryption"` or `b"signing"`, yields an
+    /// independent vetKey, whose vetKD input is computed with
+    /// [`key_id_to_vetkd_input_for_purpose`], while access is governed by
+    /// the access rights to the key id. The empty purpose yields the vetKey
+    /// returned by [`KeyManager::get_encrypted_vetkey`].
+    /// Returns an error if `purpose` is longer than
+    /// [`KeyManager::MAX_PURPOSE_LEN`] bytes.
+    pub fn get_encrypted_vetkey_for_purpose(
+        &self,
+        caller: Principal,
+        subkey_key_id: KeyId,
+        purpose: &[u8],
+        transport_key: TransportKey,
+    ) -> Result<impl Future<Output = Result<VetKey, VetKDCallError>> + Send + Sync, String> {
+        let input =
+            key_id_to_vetkd_input_for_purpose(subkey_key_id.0, subkey_key_id.1.as_ref(), purpose)?;
         self.ensure_user_can_read(caller, subkey_key_id)?;
+        self.ensure_not_frozen(caller, subkey_key_id)?;
+        if self.rate_limiter.is_some() {
+            self.try_acquire_vetkey_derivation(caller, ic_cdk::api::time())
+                .map_err(|e| e.to_string())?;
+        }
+        self.notify(|listener| listener.on_vetkey_derived(caller, subkey_key_id));
 
-        let domain_separator = self.config.get().domain_separator.clone();
+        let context = self.vetkd_context(subkey_key_id);
         let vetkd_key_id = self.config.get().key_id.clone();
-        let future = async move {
+        let call_policy = self.call_policy;
+        Ok(async move {
             let request = VetKDDeriveKeyArgs {
-                input: key_id_to_vetkd_input(subkey_key_id.0, subkey_key_id.1.as_ref()),
-                context: domain_separator.to_bytes().to_vec(),
+                input,
+                context,
                 key_id: vetkd_key_id,
                 transport_public_key: transport_key.into(),
             };
 
-            ic_cdk_management_canister::vetkd_derive_key(&request).await
-        };
+            let reply = call_policy.vetkd_derive_key(request).await?;
+            Ok(VetKey::from(reply.encrypted_key))
+        })
+    }
 
-        Ok(future.map(|call_result| {
-            let reply = call_result.expect("call to vetkd_derive_key failed");
-            VetKey::from(reply.encrypted_key)
-        }))
+    /// Retrieves encrypted vetKeys for caller and each of the given key ids,
+    /// secured using the same transport key. Access is checked, and the
+    /// derivation is rate limited, for each vetKey as in
+    /// [`KeyManager::get_encrypted_vetkey`], and the vetKeys are derived
+    /// concurrently. The returned future resolves to a result per key id, in
+    /// the order of `key_ids`.
+    /// Returns an error if more than [`KeyManager::MAX_VETKEYS_PER_BATCH`] vetKeys are requested.
+    pub fn get_encrypted_vetkeys(
+        &self,
+        caller: Principal,
+        key_ids: Vec<KeyId>,
+        transport_key: TransportKey,
+    ) -> Result<impl Future<Output = Vec<(KeyId, VetKeyResult)>> + Send + Sync, String> {
+        let requests = key_ids.iter().copied().map(Ok).collect();
+        let derivations = self.derive_encrypted_vetkeys(caller, requests, transport_key)?;
+        Ok(async move { key_ids.into_iter().zip(derivations.await).collect() })
+    }
+
+    /// Derives the encrypted vetKeys for the given key ids, or fails for the
+    /// requests that are errors already, see [`KeyManager::get_encrypted_vetkeys`].
+    fn derive_encrypted_vetkeys(
+        &self,
+        caller: Principal,
+        requests: Vec<Result<KeyId, String>>,
+        transport_key: TransportKey,
+    ) -> Result<impl Future<Output = Vec<VetKeyResult>> + Send + Sync, String> {
+        if requests.len() > Self::MAX_VETKEYS_PER_BATCH {
+            return Err(format!(
+                "too many vetKeys requested: at most {} per batch",
+                Self::MAX_VETKEYS_PER_BATCH
+            ));
+        }
+        let derivations: Vec<_> = requests
+            .into_iter()
+            .map(|request| {
+                request.and_then(|key_id| {
+                    self.get_encrypted_vetkey(caller, key_id, transport_key.clone())
+                })
+            })
+            .collect();
+        Ok(futures::future::join_all(derivations.into_iter().map(
+            |derivation| async move { derivation?.await.map_err(|e| e.to_string()) },
+        )))
     }
 
     /// Retrieves the access rights a given user has to a specific vetKey.
@@ -235,8 +674,12 @@ impl<T: AccessControl> KeyManager<T> {
     }
 
     /// Grants or modifies access rights for a user to a given vetKey.
-    /// Only the vetKey owner or a user with management rights can perform this action.
+    /// Only the vetKey owner or a user with management rights can perform this action, see
+    /// [`KeyManager::ensure_user_can_share`] for users who can only share certain rights.
     /// The vetKey owner cannot change their own rights.
+    /// If invitations are enabled (see [`KeyManager::enable_invitations`]), a user without access
+    /// is invited instead and `Ok(None)` is returned.
+    /// Adding a user fails if it exceeds the quotas of the owner, see [`KeyManager::enable_quotas`].
     pub fn set_user_rights(
         &mut self,
         caller: Principal,
@@ -244,16 +687,54 @@ impl<T: AccessControl> KeyManager<T> {
         user: Principal,
         access_rights: T,
     ) -> Result<Option<T>, String> {
-        self.ensure_user_can_set_user_rights(caller, key_id)?;
+        let caller_rights = self.ensure_user_can_share(caller, key_id, user, access_rights)?;
 
-        if caller == key_id.0 && caller == user {
+        if self.is_owner(caller, key_id) && caller == user {
             return Err("cannot change key owner's user rights".to_string());
         }
-        self.shared_keys.insert((key_id, user), ());
-        Ok(self.access_control.insert((user, key_id), access_rights))
+        self.ensure_delegation_allowed(caller, caller_rights, key_id, user, Some(access_rights))?;
+        self.grant_user_rights(caller, key_id, user, access_rights)
     }
 
-    /// Revokes a user's access to a shared vetKey.
+    /// Grants or modifies access rights for a user to a given vetKey on behalf
+    /// of the authorized `caller`, subject to the quotas and the invitation flow.
+    fn grant_user_rights(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        user: Principal,
+        access_rights: T,
+    ) -> Result<Option<T>, String> {
+        self.ensure_not_deleted(key_id)?;
+        if !self.is_shared_with_or_invited(key_id, user) {
+            self.ensure_share_within_quotas(key_id)
+                .map_err(|e| e.to_string())?;
+        }
+        if let Some(result) = self.invite_user(caller, key_id, user, access_rights) {
+            return result.map(|()| None);
+        }
+        Ok(self.insert_user_rights(caller, key_id, user, access_rights))
+    }
+
+    /// Stores the access rights of a user to a vetKey granted by `caller`
+    /// without any checks.
+    fn insert_user_rights(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        user: Principal,
+        access_rights: T,
+    ) -> Option<T> {
+        if self.shared_keys.insert((key_id, user), ()).is_none() {
+            self.add_quota_reference(key_id, QuotaReference::Share);
+        }
+        self.certify_user_rights(key_id, user, Some(access_rights));
+        let old_rights = self.access_control.insert((user, key_id), access_rights);
+        self.notify(|listener| listener.on_share(caller, key_id, user, access_rights));
+        old_rights
+    }
+
+    /// Revokes a user's access to a shared vetKey, including a pending invitation, if any.
     /// The vetKey owner cannot remove their own access.
     /// Only the vetKey owner or a user with management rights can perform this action.
     pub fn remove_user(
@@ -262,43 +743,81 @@ impl<T: AccessControl> KeyManager<T> {
         key_id: KeyId,
         user: Principal,
     ) -> Result<Option<T>, String> {
-        self.ensure_user_can_set_user_rights(caller, key_id)?;
+        let caller_rights = self.ensure_user_can_set_user_rights(caller, key_id)?;
 
-        if caller == user && caller == key_id.0 {
+        if caller == user && self.is_owner(caller, key_id) {
             return Err("cannot remove key owner".to_string());
         }
+        self.ensure_delegation_allowed(caller, caller_rights, key_id, user, None)?;
+        Ok(self.revoke_user(caller, key_id, user))
+    }
 
-        self.shared_keys.remove(&(key_id, user));
-        Ok(self.access_control.remove(&(user, key_id)))
+    /// Revokes a user's access to a vetKey on behalf of the authorized
+    /// `caller`, including a pending invitation, if any.
+    fn revoke_user(&mut self, caller: Principal, key_id: KeyId, user: Principal) -> Option<T> {
+        self.cancel_invitation(key_id, user);
+        if self.shared_keys.remove(&(key_id, user)).is_some() {
+            self.remove_quota_reference(key_id, QuotaReference::Share);
+        }
+        self.certify_user_rights(key_id, user, None);
+        let old_rights = self.access_control.remove(&(user, key_id));
+        if let Some(access_rights) = old_rights {
+            self.notify(|listener| listener.on_revoke(caller, key_id, user, access_rights));
+        }
+        old_rights
     }
 
     /// Ensures that a user has read access to a vetKey before proceeding.
     /// Returns an error if the user is not authorized.
     pub fn ensure_user_can_read(&self, user: Principal, key_id: KeyId) -> Result<T, String> {
-        let is_owner = user == key_id.0;
-        if is_owner {
-            return Ok(T::owner_rights());
-        }
-
-        let has_shared_access = self.access_control.get(&(user, key_id));
-        match has_shared_access {
-            Some(access_rights) if access_rights.can_read() => Ok(access_rights),
-            _ => Err("unauthorized".to_string()),
-        }
+        self.ensure_owner_or_shared_access(user, key_id, T::can_read)
     }
 
     /// Ensures that a user has write access to a vetKey before proceeding.
     /// Returns an error if the user is not authorized.
     pub fn ensure_user_can_write(&self, user: Principal, key_id: KeyId) -> Result<T, String> {
-        let is_owner = user == key_id.0;
-        if is_owner {
+        self.ensure_owner_or_shared_access(user, key_id, T::can_write)
+    }
+
+    /// Ensures that a user can insert values under new keys, see [`AccessControl::can_insert`].
+    /// Returns an error if the user is not authorized.
+    pub fn ensure_user_can_insert(&self, user: Principal, key_id: KeyId) -> Result<T, String> {
+        self.ensure_owner_or_shared_access(user, key_id, T::can_insert)
+    }
+
+    /// Ensures that a user can overwrite existing values, see [`AccessControl::can_overwrite`].
+    /// Returns an error if the user is not authorized.
+    pub fn ensure_user_can_overwrite(&self, user: Principal, key_id: KeyId) -> Result<T, String> {
+        self.ensure_owner_or_shared_access(user, key_id, T::can_overwrite)
+    }
+
+    /// Ensures that a user can remove values, see [`AccessControl::can_remove`].
+    /// Returns an error if the user is not authorized.
+    pub fn ensure_user_can_remove(&self, user: Principal, key_id: KeyId) -> Result<T, String> {
+        self.ensure_owner_or_shared_access(user, key_id, T::can_remove)
+    }
+
+    /// Returns the owner rights if `user` owns the vetKey, and otherwise the
+    /// shared access rights of `user` if they satisfy `check`.
+    fn ensure_owner_or_shared_access(
+        &self,
+        user: Principal,
+        key_id: KeyId,
+        check: impl Fn(&T) -> bool,
+    ) -> Result<T, String> {
+        self.ensure_not_deleted(key_id)?;
+        if self.is_owner(user, key_id) {
             return Ok(T::owner_rights());
         }
 
         let has_shared_access = self.access_control.get(&(user, key_id));
         match has_shared_access {
-            Some(access_rights) if access_rights.can_write() => Ok(access_rights),
-            _ => Err("unauthorized".to_string()),
+            Some(access_rights) if check(&access_rights) => Ok(access_rights),
+            // public access rights apply to every user, see `make_key_public`
+            _ => match self.get_public_access_rights(key_id) {
+                Some(access_rights) if check(&access_rights) => Ok(access_rights),
+                _ => Err("unauthorized".to_string()),
+            },
         }
     }
 
@@ -309,8 +828,11 @@ impl<T: AccessControl> KeyManager<T> {
         user: Principal,
         key_id: KeyId,
     ) -> Result<T, String> {
-        let is_owner = user == key_id.0;
-        if is_owner {
+        self.ensure_not_deleted(key_id)?;
+        if self.is_owner(user, key_id)
+            || self.is_organization_admin(user, key_id)
+            || self.is_tenant_admin(user, key_id)
+        {
             return Ok(T::owner_rights());
         }
 
@@ -328,8 +850,8 @@ impl<T: AccessControl> KeyManager<T> {
         user: Principal,
         key_id: KeyId,
     ) -> Result<T, String> {
-        let is_owner = user == key_id.0;
-        if is_owner {
+        self.ensure_not_deleted(key_id)?;
+        if self.has_owner_authority(user, key_id) {
             return Ok(T::owner_rights());
         }
 
@@ -339,6 +861,87 @@ impl<T: AccessControl> KeyManager<T> {
             _ => Err("unauthorized".to_string()),
         }
     }
+
+    /// Ensures that `caller` can set the access rights of `user` to
+    /// `access_rights`: sharing with a user who neither has access nor is
+    /// invited requires [`AccessControl::can_share`], and modifying existing
+    /// access rights requires [`AccessControl::can_set_user_rights`].
+    /// Returns an error if the caller is not authorized.
+    pub fn ensure_user_can_share(
+        &self,
+        caller: Principal,
+        key_id: KeyId,
+        user: Principal,
+        access_rights: T,
+    ) -> Result<T, String> {
+        self.ensure_not_deleted(key_id)?;
+        if self.has_owner_authority(caller, key_id) {
+            return Ok(T::owner_rights());
+        }
+
+        let is_new_user = !self.is_shared_with_or_invited(key_id, user);
+        let has_shared_access = self.access_control.get(&(caller, key_id));
+        match has_shared_access {
+            Some(caller_rights) if is_new_user && caller_rights.can_share(&access_rights) => {
+                Ok(caller_rights)
+            }
+            Some(caller_rights) if !is_new_user && caller_rights.can_set_user_rights() => {
+                Ok(caller_rights)
+            }
+            _ => Err("unauthorized".to_string()),
+        }
+    }
+
+    /// Returns if `user` is the owner of, has access to, or is invited to the vetKey.
+    fn is_shared_with_or_invited(&self, key_id: KeyId, user: Principal) -> bool {
+        user == key_id.0
+            || self.access_control.contains_key(&(user, key_id))
+            || self
+                .invitations
+                .as_ref()
+                .is_some_and(|invitations| invitations.invited_users.contains_key(&(key_id, user)))
+    }
+
+    /// Ensures that the configured [`DelegationPolicy`] allows `caller` with
+    /// `caller_rights` to set the access rights of `user` to `new_rights`, or
+    /// to remove `user` if `new_rights` is `None`. The owner and admins of an
+    /// owning organization with a quorum of one are not restricted.
+    fn ensure_delegation_allowed(
+        &self,
+        caller: Principal,
+        caller_rights: T,
+        key_id: KeyId,
+        user: Principal,
+        new_rights: Option<T>,
+    ) -> Result<(), String> {
+        if self.has_owner_authority(caller, key_id) {
+            return Ok(());
+        }
+        let policy = self.delegation_policy;
+        let current_rights = self.access_control.get(&(user, key_id));
+
+        if policy.owner_only_manager_management
+            && current_rights
+                .iter()
+                .chain(new_rights.iter())
+                .any(|rights| rights.can_set_user_rights())
+        {
+            return Err("only the owner can manage managers".to_string());
+        }
+        if policy.restrict_grants_to_own_rights
+            && new_rights.is_some_and(|rights| !caller_rights.can_grant(&rights))
+        {
+            return Err("cannot grant rights exceeding own rights".to_string());
+        }
+        let is_self_removal = caller == user && new_rights.is_none();
+        if policy.protect_peers
+            && !is_self_removal
+            && current_rights.is_some_and(|rights| !caller_rights.can_manage(&rights))
+        {
+            return Err("cannot manage user with equal or higher rights".to_string());
+        }
+        Ok(())
+    }
 }
 
 pub fn key_id_to_vetkd_input(principal: Principal, key_name: &[u8]) -> Vec<u8> {
@@ -348,3 +951,42 @@ pub fn key_id_to_vetkd_input(principal: Principal, key_name: &[u8]) -> Vec<u8> {
     vetkd_input.extend(key_name);
     vetkd_input
 }
+
+/// See [`KeyManager::MAX_PURPOSE_LEN`].
+const MAX_PURPOSE_LEN: usize = 32;
+
+/// The first byte of a purpose-bound vetKD input, which is never the first
+/// byte of an input computed with [`key_id_to_vetkd_input`] because
+/// principals are at most 29 bytes long.
+const PURPOSE_INPUT_TAG: u8 = 0xff;
+
+/// Computes the vetKD input of the vetKey for `purpose` of a key id, see
+/// [`KeyManager::get_encrypted_vetkey_for_purpose`].
+///
+/// For the empty purpose, the input is the one computed with
+/// [`key_id_to_vetkd_input`]. Otherwise, it consists of a tag byte, the
+/// length-prefixed principal, the length-prefixed purpose, and the key name,
+/// so that no two pairs of key id and purpose share an input.
+/// Returns an error if `purpose` is longer than
+/// [`KeyManager::MAX_PURPOSE_LEN`] bytes.
+pub fn key_id_to_vetkd_input_for_purpose(
+    principal: Principal,
+    key_name: &[u8],
+    purpose: &[u8],
+) -> Result<Vec<u8>, String> {
+    if purpose.is_empty() {
+        return Ok(key_id_to_vetkd_input(principal, key_name));
+    }
+    if purpose.len() > MAX_PURPOSE_LEN {
+        return Err(format!("purpose must be at most {MAX_PURPOSE_LEN} bytes"));
+    }
+    let mut vetkd_input =
+        Vec::with_capacity(3 + principal.as_slice().len() + purpose.len() + key_name.len());
+    vetkd_input.push(PURPOSE_INPUT_TAG);
+    vetkd_input.push(principal.as_slice().len() as u8);
+    vetkd_input.extend(principal.as_slice());
+    vetkd_input.push(purpose.len() as u8);
+    vetkd_input.extend(purpose);
+    vetkd_input.extend(key_name);
+    Ok(vetkd_input)
+}
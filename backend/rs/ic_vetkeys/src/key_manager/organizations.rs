@@ -0,0 +1,385 @@
+//! Organizational vetKeys without a privileged owner, see
+//! [`KeyManager::enable_organizations`].
+
+use super::{KeyId, KeyManager, Memory};
+use crate::types::{AccessControl, KeyName};
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::StableBTreeMap;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// Configuration of organizations, see [`KeyManager::enable_organizations`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OrganizationsConfig {
+    /// The time in nanoseconds after which an approval of an
+    /// [`OrganizationAction`] expires and no longer counts towards the quorum.
+    pub approval_expiry_ns: u64,
+}
+
+impl Default for OrganizationsConfig {
+    fn default() -> Self {
+        Self {
+            approval_expiry_ns: 7 * 24 * 60 * 60 * 1_000_000_000,
+        }
+    }
+}
+
+/// Stable state of the organizations.
+///
+/// An organization is a principal, e.g., the canister itself, that owns
+/// vetKeys but never acts as a caller. Unlike for other owners, the owner of
+/// an organizational vetKey has no implicit rights: all access rights come
+/// from the entries in [`KeyManager::access_control`], and the access rights
+/// are managed by the admins of the organization.
+pub struct Organizations {
+    pub config: OrganizationsConfig,
+    /// Contains `(organization, admin)` pairs.
+    pub admins: StableBTreeMap<(Principal, Principal), (), Memory>,
+    /// Maps an organization to the number of admins required to approve an
+    /// [`OrganizationAction`].
+    pub quorums: StableBTreeMap<Principal, u32, Memory>,
+    /// Maps `(organization, action hash, admin)` triples of pending approvals
+    /// to the time of the approval in nanoseconds since the UNIX epoch.
+    pub approvals: StableBTreeMap<(Principal, Blob<32>, Principal), u64, Memory>,
+}
+
+/// An action on behalf of an organization that takes effect once approved by
+/// a quorum of its admins, see [`KeyManager::approve_organization_action`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum OrganizationAction<T> {
+    /// Grants or modifies the access rights of `user` to the organizational vetKey `key_name`.
+    SetUserRights {
+        key_name: KeyName,
+        user: Principal,
+        access_rights: T,
+    },
+    /// Revokes the access of `user` to the organizational vetKey `key_name`.
+    RemoveUser { key_name: KeyName, user: Principal },
+    /// Replaces the admins and the quorum of the organization. The order of
+    /// the admins and duplicates are irrelevant.
+    SetAdmins { admins: Vec<Principal>, quorum: u32 },
+}
+
+/// The result of approving an [`OrganizationAction`].
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ApprovalStatus {
+    /// The action awaits approval by further admins.
+    Pending { approvals: u32, quorum: u32 },
+    /// The action was approved by a quorum of admins and took effect.
+    Executed,
+}
+
+impl Organizations {
+    fn is_admin(&self, organization: Principal, user: Principal) -> bool {
+        self.admins.contains_key(&(organization, user))
+    }
+
+    /// Returns the admins who approved the action, along with the time of
+    /// their approval.
+    fn approvals_iter(
+        &self,
+        organization: Principal,
+        action_hash: Blob<32>,
+    ) -> impl Iterator<Item = (Principal, u64)> + '_ {
+        self.approvals
+            .range((organization, action_hash, Principal::management_canister())..)
+            .take_while(move |entry| entry.key().0 == organization && entry.key().1 == action_hash)
+            .map(|entry| (entry.key().2, entry.value()))
+    }
+
+    fn set_admins(
+        &mut self,
+        organization: Principal,
+        admins: &[Principal],
+        quorum: u32,
+    ) -> Result<(), String> {
+        let admins: std::collections::BTreeSet<Principal> = admins.iter().copied().collect();
+        if quorum == 0 || quorum as usize > admins.len() {
+            return Err("quorum must be between 1 and the number of admins".to_string());
+        }
+
+        let old_admins: Vec<_> = self
+            .admins
+            .keys_range((organization, Principal::management_canister())..)
+            .take_while(|(org, _admin)| *org == organization)
+            .collect();
+        for key in old_admins {
+            self.admins.remove(&key);
+        }
+        // approvals given under the previous admin set are void
+        let old_approvals: Vec<_> = self
+            .approvals
+            .keys_range(
+                (
+                    organization,
+                    Blob::default(),
+                    Principal::management_canister(),
+                )..,
+            )
+            .take_while(|(org, _hash, _admin)| *org == organization)
+            .collect();
+        for key in old_approvals {
+            self.approvals.remove(&key);
+        }
+
+        for admin in admins {
+            self.admins.insert((organization, admin), ());
+        }
+        self.quorums.insert(organization, quorum);
+        Ok(())
+    }
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Enables organizational vetKeys, i.e., vetKeys owned by an organization
+    /// created with [`KeyManager::create_organization`].
+    ///
+    /// The owner short-circuit in the `ensure_user_can_*` methods does not
+    /// apply to organizational vetKeys. Instead, reading and writing requires
+    /// access rights in [`KeyManager::access_control`], and the admins of the
+    /// organization can view the access rights. If the quorum of the
+    /// organization is one, each admin can also manage the access rights with
+    /// [`KeyManager::set_user_rights`] and [`KeyManager::remove_user`] like an
+    /// owner. Otherwise, changes require the approval of a quorum of admins,
+    /// see [`KeyManager::approve_organization_action`].
+    pub fn enable_organizations(
+        &mut self,
+        config: OrganizationsConfig,
+        memory_admins: Memory,
+        memory_quorums: Memory,
+        memory_approvals: Memory,
+    ) {
+        self.organizations = Some(Organizations {
+            config,
+            admins: StableBTreeMap::init(memory_admins),
+            quorums: StableBTreeMap::init(memory_quorums),
+            approvals: StableBTreeMap::init(memory_approvals),
+        });
+    }
+
+    /// Makes the caller an organization with the given admins, of which
+    /// `quorum` must approve each [`OrganizationAction`].
+    ///
+    /// Only a principal itself can become an organization, so no caller can
+    /// turn the vetKeys of another principal into organizational vetKeys, and
+    /// each principal creates at most one organization. Typically, a canister
+    /// creates an organization for its own principal, so that the vetKeys
+    /// owned by it have no privileged human owner. Once an organization, the
+    /// caller has no implicit rights to the vetKeys it owns.
+    pub fn create_organization(
+        &mut self,
+        caller: Principal,
+        admins: Vec<Principal>,
+        quorum: u32,
+    ) -> Result<(), String> {
+        let organizations = self
+            .organizations
+            .as_mut()
+            .ok_or_else(|| "organizations are not enabled".to_string())?;
+        if organizations.quorums.contains_key(&caller) {
+            return Err("organization already exists".to_string());
+        }
+        organizations.set_admins(caller, &admins, quorum)
+    }
+
+    /// Returns whether `principal` is an organization.
+    pub fn is_organization(&self, principal: Principal) -> bool {
+        self.organizations
+            .as_ref()
+            .is_some_and(|organizations| organizations.quorums.contains_key(&principal))
+    }
+
+    /// Returns the admins and the quorum of an organization, or `None` if
+    /// `organization` is not an organization.
+    pub fn get_organization_admins(
+        &self,
+        organization: Principal,
+    ) -> Option<(Vec<Principal>, u32)> {
+        let organizations = self.organizations.as_ref()?;
+        let quorum = organizations.quorums.get(&organization)?;
+        let admins = organizations
+            .admins
+            .keys_range((organization, Principal::management_canister())..)
+            .take_while(|(org, _admin)| *org == organization)
+            .map(|(_org, admin)| admin)
+            .collect();
+        Some((admins, quorum))
+    }
+
+    /// Approves `action` on behalf of `organization` at the current time
+    /// `now_ns` (nanoseconds since the UNIX epoch). The caller must be an
+    /// admin of the organization.
+    ///
+    /// The action takes effect once the same action was approved by a quorum
+    /// of distinct admins within [`OrganizationsConfig::approval_expiry_ns`],
+    /// and all approvals of it are cleared. Expired approvals are removed, and
+    /// approving again renews an approval. If the action fails, e.g., because
+    /// of the quotas, the error is returned and the approval of the caller is
+    /// not recorded, while the other approvals are kept until they expire or
+    /// are withdrawn, see [`KeyManager::withdraw_organization_approval`].
+    /// Approvals are voided if the admins of the organization change. Access rights set
+    /// this way are subject to the quotas and the invitation flow, but not to
+    /// the [`super::DelegationPolicy`].
+    pub fn approve_organization_action(
+        &mut self,
+        caller: Principal,
+        organization: Principal,
+        action: OrganizationAction<T>,
+        now_ns: u64,
+    ) -> Result<ApprovalStatus, String> {
+        let organizations = self
+            .organizations
+            .as_mut()
+            .ok_or_else(|| "organizations are not enabled".to_string())?;
+        let quorum = organizations
+            .quorums
+            .get(&organization)
+            .ok_or_else(|| "organization does not exist".to_string())?;
+        if !organizations.is_admin(organization, caller) {
+            return Err("unauthorized".to_string());
+        }
+
+        let action_hash = action_hash(organization, &action);
+        let approval_expiry_ns = organizations.config.approval_expiry_ns;
+        let (other_approvers, expired): (Vec<_>, Vec<_>) = organizations
+            .approvals_iter(organization, action_hash)
+            .filter(|(admin, _approved_at_ns)| *admin != caller)
+            .partition(|(_admin, approved_at_ns)| {
+                now_ns.saturating_sub(*approved_at_ns) < approval_expiry_ns
+            });
+        for (admin, _approved_at_ns) in expired {
+            organizations
+                .approvals
+                .remove(&(organization, action_hash, admin));
+        }
+        let approvals = other_approvers.len() as u64 + 1;
+        if approvals < quorum as u64 {
+            organizations
+                .approvals
+                .insert((organization, action_hash, caller), now_ns);
+            return Ok(ApprovalStatus::Pending {
+                approvals: approvals as u32,
+                quorum,
+            });
+        }
+
+        match action {
+            OrganizationAction::SetUserRights {
+                key_name,
+                user,
+                access_rights,
+            } => {
+                self.grant_user_rights(caller, (organization, key_name), user, access_rights)?;
+            }
+            OrganizationAction::RemoveUser { key_name, user } => {
+                self.revoke_user(caller, (organization, key_name), user);
+            }
+            OrganizationAction::SetAdmins { admins, quorum } => self
+                .organizations
+                .as_mut()
+                .expect("organizations are enabled")
+                .set_admins(organization, &admins, quorum)?,
+        }
+        if let Some(organizations) = self.organizations.as_mut() {
+            for (admin, _approved_at_ns) in other_approvers {
+                organizations
+                    .approvals
+                    .remove(&(organization, action_hash, admin));
+            }
+            organizations
+                .approvals
+                .remove(&(organization, action_hash, caller));
+        }
+        Ok(ApprovalStatus::Executed)
+    }
+
+    /// Withdraws the approval of `action` on behalf of `organization` by the
+    /// caller, see [`KeyManager::approve_organization_action`]. Returns
+    /// whether the caller had approved the action.
+    pub fn withdraw_organization_approval(
+        &mut self,
+        caller: Principal,
+        organization: Principal,
+        action: &OrganizationAction<T>,
+    ) -> Result<bool, String> {
+        let organizations = self
+            .organizations
+            .as_mut()
+            .ok_or_else(|| "organizations are not enabled".to_string())?;
+        Ok(organizations
+            .approvals
+            .remove(&(organization, action_hash(organization, action), caller))
+            .is_some())
+    }
+
+    /// Returns whether `user` owns the vetKey and therefore has implicit owner
+    /// rights, which is never the case for organizational vetKeys. The owner
+    /// is the principal in the vetKey ID, unless its vetKeys were recovered,
+    /// see [`KeyManager::resolve_owner`].
+    pub(crate) fn is_owner(&self, user: Principal, key_id: KeyId) -> bool {
+        user == self.resolve_owner(key_id.0) && !self.is_organization(key_id.0)
+    }
+
+    /// Returns whether `user` is an admin of the organization owning the vetKey.
+    pub(crate) fn is_organization_admin(&self, user: Principal, key_id: KeyId) -> bool {
+        self.organizations
+            .as_ref()
+            .is_some_and(|organizations| organizations.is_admin(key_id.0, user))
+    }
+
+    /// Returns whether `user` can manage the vetKey like an owner, i.e., is
+    /// its owner, an admin of its tenant, or an admin of the owning
+    /// organization whose quorum is one.
+    pub(crate) fn has_owner_authority(&self, user: Principal, key_id: KeyId) -> bool {
+        self.is_owner(user, key_id)
+            || self.is_tenant_admin(user, key_id)
+            || (self.is_organization_admin(user, key_id)
+                && self
+                    .organizations
+                    .as_ref()
+                    .and_then(|organizations| organizations.quorums.get(&key_id.0))
+                    == Some(1))
+    }
+}
+
+fn action_hash<T: AccessControl>(
+    organization: Principal,
+    action: &OrganizationAction<T>,
+) -> Blob<32> {
+    fn update_principal(hasher: &mut Sha256, principal: &Principal) {
+        hasher.update([principal.as_slice().len() as u8]);
+        hasher.update(principal.as_slice());
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(b"ic-vetkeys-organization-action");
+    update_principal(&mut hasher, &organization);
+    match action {
+        OrganizationAction::SetUserRights {
+            key_name,
+            user,
+            access_rights,
+        } => {
+            hasher.update([0]);
+            hasher.update(key_name.as_slice());
+            update_principal(&mut hasher, user);
+            hasher.update(access_rights.to_bytes());
+        }
+        OrganizationAction::RemoveUser { key_name, user } => {
+            hasher.update([1]);
+            hasher.update(key_name.as_slice());
+            update_principal(&mut hasher, user);
+        }
+        OrganizationAction::SetAdmins { admins, quorum } => {
+            let admins: std::collections::BTreeSet<&Principal> = admins.iter().collect();
+            hasher.update([2]);
+            hasher.update(quorum.to_be_bytes());
+            hasher.update((admins.len() as u64).to_be_bytes());
+            for admin in admins {
+                update_principal(&mut hasher, admin);
+            }
+        }
+    }
+    Blob::try_from(hasher.finalize().as_slice()).expect("SHA-256 hashes are 32 bytes")
+}
@@ -0,0 +1,113 @@
+//! Public read-only access to vetKeys, see [`KeyManager::enable_public_keys`].
+
+use super::{KeyId, KeyManager, Memory};
+use crate::types::{AccessControl, Page};
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+use std::ops::Bound;
+
+/// Stable state of public vetKeys.
+pub struct PublicKeys<T: AccessControl> {
+    /// Maps the public vetKeys to the access rights every caller has.
+    pub public_keys: StableBTreeMap<KeyId, T, Memory>,
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Enables public vetKeys, which every caller, including the anonymous
+    /// principal, can read, see [`KeyManager::make_key_public`].
+    pub fn enable_public_keys(&mut self, memory_public_keys: Memory) {
+        self.public_keys = Some(PublicKeys {
+            public_keys: StableBTreeMap::init(memory_public_keys),
+        });
+    }
+
+    /// Grants `access_rights` to a vetKey to every caller, including the
+    /// anonymous principal, so that they can retrieve the vetKey and read the
+    /// encrypted map with the same ID. Returns the previous public access
+    /// rights, if any.
+    ///
+    /// The access rights must be read-only, so writes remain governed by the
+    /// rights granted to individual users. Users with shared access rights
+    /// keep them. Only the vetKey owner can make a vetKey public.
+    pub fn make_key_public(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        access_rights: T,
+    ) -> Result<Option<T>, String> {
+        if !self.has_owner_authority(caller, key_id) {
+            return Err("unauthorized".to_string());
+        }
+        self.ensure_not_deleted(key_id)?;
+        if !access_rights.can_read()
+            || access_rights.can_write()
+            || access_rights.can_insert()
+            || access_rights.can_overwrite()
+            || access_rights.can_remove()
+            || access_rights.can_get_user_rights()
+            || access_rights.can_set_user_rights()
+        {
+            return Err("public access rights must be read-only".to_string());
+        }
+        Ok(self
+            .ensure_public_keys_enabled_mut()?
+            .public_keys
+            .insert(key_id, access_rights))
+    }
+
+    /// Revokes the public access rights to a vetKey granted with
+    /// [`KeyManager::make_key_public`] and returns them, if any. Only the
+    /// vetKey owner can make a vetKey private.
+    pub fn make_key_private(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+    ) -> Result<Option<T>, String> {
+        if !self.has_owner_authority(caller, key_id) {
+            return Err("unauthorized".to_string());
+        }
+        Ok(self
+            .ensure_public_keys_enabled_mut()?
+            .public_keys
+            .remove(&key_id))
+    }
+
+    /// Returns the access rights every caller has to a vetKey, or `None` if
+    /// it is not public.
+    pub fn get_public_access_rights(&self, key_id: KeyId) -> Option<T> {
+        self.public_keys
+            .as_ref()
+            .and_then(|public_keys| public_keys.public_keys.get(&key_id))
+    }
+
+    /// Retrieves a page of at most `limit` public vetKeys with their public
+    /// access rights, ordered by vetKey ID. The page starts after the vetKey
+    /// `start_after` or at the first public vetKey if `None`.
+    pub fn get_public_key_ids_paginated(
+        &self,
+        start_after: Option<KeyId>,
+        limit: usize,
+    ) -> Page<(KeyId, T), KeyId> {
+        let Some(public_keys) = &self.public_keys else {
+            return Page::collect(std::iter::empty(), limit, |(key_id, _)| *key_id);
+        };
+        let start = match start_after {
+            Some(key_id) => Bound::Excluded(key_id),
+            None => Bound::Unbounded,
+        };
+        Page::collect(
+            public_keys
+                .public_keys
+                .range((start, Bound::Unbounded))
+                .map(|entry| (*entry.key(), entry.value())),
+            limit,
+            |(key_id, _access_rights)| *key_id,
+        )
+    }
+
+    fn ensure_public_keys_enabled_mut(&mut self) -> Result<&mut PublicKeys<T>, String> {
+        self.public_keys
+            .as_mut()
+            .ok_or_else(|| "public keys are not enabled".to_string())
+    }
+}
@@ -0,0 +1,471 @@
+//! Social recovery of the vetKeys of a principal by guardians, see
+//! [`KeyManager::enable_recovery`].
+
+use super::{KeyManager, Memory};
+use crate::types::AccessControl;
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+/// Configuration of social recovery, see [`KeyManager::enable_recovery`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RecoveryConfig {
+    /// The delay between the approval of a recovery by the guardians and its
+    /// completion, during which the owner can cancel it.
+    pub recovery_delay_ns: u64,
+}
+
+/// The guardians of an owner, of which `threshold` must approve a recovery,
+/// see [`KeyManager::set_recovery_guardians`].
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct RecoveryGuardians {
+    pub guardians: Vec<Principal>,
+    pub threshold: u32,
+}
+
+/// A recovery of the vetKeys of an owner to a new owner that awaits approval
+/// or completion, see [`KeyManager::approve_recovery`].
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct PendingRecovery {
+    /// The guardians who approved the recovery.
+    pub approvals: Vec<Principal>,
+    /// The time after which the recovery can be completed, once approved by
+    /// the threshold of guardians.
+    pub executable_after_ns: Option<u64>,
+}
+
+/// The result of approving a recovery.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RecoveryStatus {
+    /// The recovery awaits approval by further guardians.
+    Pending { approvals: u32, threshold: u32 },
+    /// The recovery was approved by the threshold of guardians and can be
+    /// completed after `executable_after_ns` unless the owner cancels it.
+    Scheduled { executable_after_ns: u64 },
+}
+
+impl RecoveryGuardians {
+    /// Decodes a stored value, returning an error instead of panicking if the
+    /// bytes are corrupted.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        serde_cbor::from_slice(bytes).map_err(|e| format!("invalid RecoveryGuardians: {e}"))
+    }
+}
+
+impl Storable for RecoveryGuardians {
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self::try_from_bytes(bytes.as_ref()).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl PendingRecovery {
+    /// Decodes a stored value, returning an error instead of panicking if the
+    /// bytes are corrupted.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        serde_cbor::from_slice(bytes).map_err(|e| format!("invalid PendingRecovery: {e}"))
+    }
+}
+
+impl Storable for PendingRecovery {
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self::try_from_bytes(bytes.as_ref()).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Stable state of social recovery.
+///
+/// The vetKeys of a recovered principal keep their IDs, and thus their vetKD
+/// inputs, so that data encrypted with them remains decryptable. Instead,
+/// `successors` records who owns them now, see [`KeyManager::resolve_owner`].
+pub struct Recovery {
+    pub config: RecoveryConfig,
+    /// Maps an owner to their guardians.
+    pub guardians: StableBTreeMap<Principal, RecoveryGuardians, Memory>,
+    /// Maps `(owner, new owner)` pairs to pending recoveries.
+    pub pending_recoveries: StableBTreeMap<(Principal, Principal), PendingRecovery, Memory>,
+    /// Maps a recovered principal to the principal its vetKeys were recovered to.
+    pub successors: StableBTreeMap<Principal, Principal, Memory>,
+    /// Contains `(new owner, recovered principal)` pairs, i.e., the reverse
+    /// of `successors`, so that the principals recovered to an owner are
+    /// found without a scan.
+    pub predecessors: StableBTreeMap<(Principal, Principal), (), Memory>,
+}
+
+impl Recovery {
+    fn pending_recoveries_iter(
+        &self,
+        owner: Principal,
+    ) -> impl Iterator<Item = (Principal, PendingRecovery)> + '_ {
+        self.pending_recoveries
+            .range((owner, Principal::management_canister())..)
+            .take_while(move |entry| entry.key().0 == owner)
+            .map(|entry| (entry.key().1, entry.value()))
+    }
+
+    fn predecessors_iter(&self, new_owner: Principal) -> impl Iterator<Item = Principal> + '_ {
+        self.predecessors
+            .keys_range((new_owner, Principal::management_canister())..)
+            .take_while(move |(owner, _principal)| *owner == new_owner)
+            .map(|(_owner, principal)| principal)
+    }
+
+    fn clear_pending_recoveries(&mut self, owner: Principal) -> usize {
+        let new_owners: Vec<Principal> = self
+            .pending_recoveries_iter(owner)
+            .map(|(new_owner, _recovery)| new_owner)
+            .collect();
+        for new_owner in new_owners.iter() {
+            self.pending_recoveries.remove(&(owner, *new_owner));
+        }
+        new_owners.len()
+    }
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Enables social recovery: owners can name guardians, who can jointly
+    /// re-assign all vetKeys of the owner to a new principal, e.g., if the
+    /// owner lost access to their identity, see
+    /// [`KeyManager::set_recovery_guardians`].
+    pub fn enable_recovery(
+        &mut self,
+        config: RecoveryConfig,
+        memory_guardians: Memory,
+        memory_pending_recoveries: Memory,
+        memory_successors: Memory,
+        memory_predecessors: Memory,
+    ) {
+        let mut recovery = Recovery {
+            config,
+            guardians: StableBTreeMap::init(memory_guardians),
+            pending_recoveries: StableBTreeMap::init(memory_pending_recoveries),
+            successors: StableBTreeMap::init(memory_successors),
+            predecessors: StableBTreeMap::init(memory_predecessors),
+        };
+        if recovery.predecessors.is_empty() {
+            for entry in recovery.successors.iter() {
+                recovery
+                    .predecessors
+                    .insert((entry.value(), *entry.key()), ());
+            }
+        }
+        self.recovery = Some(recovery);
+    }
+
+    /// Sets the guardians of the caller, of which `threshold` must approve a
+    /// recovery of the caller's vetKeys. The order of the guardians and
+    /// duplicates are irrelevant, and an empty list disables recovery.
+    /// Pending recoveries of the caller are cancelled.
+    pub fn set_recovery_guardians(
+        &mut self,
+        caller: Principal,
+        guardians: Vec<Principal>,
+        threshold: u32,
+    ) -> Result<(), String> {
+        if self.resolve_owner(caller) != caller {
+            return Err("caller has been recovered or migrated".to_string());
+        }
+        let recovery = self.ensure_recovery_enabled_mut()?;
+        let guardians: BTreeSet<Principal> = guardians.into_iter().collect();
+        if guardians.contains(&caller) {
+            return Err("cannot be one's own guardian".to_string());
+        }
+        if !guardians.is_empty() && (threshold == 0 || threshold as usize > guardians.len()) {
+            return Err("threshold must be between 1 and the number of guardians".to_string());
+        }
+        recovery.clear_pending_recoveries(caller);
+        if guardians.is_empty() {
+            recovery.guardians.remove(&caller);
+            return Ok(());
+        }
+        recovery.guardians.insert(
+            caller,
+            RecoveryGuardians {
+                guardians: guardians.into_iter().collect(),
+                threshold,
+            },
+        );
+        Ok(())
+    }
+
+    /// Returns the guardians of `owner`, or `None` if recovery is not set up.
+    pub fn get_recovery_guardians(&self, owner: Principal) -> Option<RecoveryGuardians> {
+        self.recovery
+            .as_ref()
+            .and_then(|recovery| recovery.guardians.get(&owner))
+    }
+
+    /// Approves the recovery of all vetKeys of `owner` to `new_owner` at the
+    /// current time `now_ns` (nanoseconds since the UNIX epoch). The caller
+    /// must be a guardian of the owner and can approve only one new owner at
+    /// a time, so approving another one withdraws the earlier approval.
+    ///
+    /// Once the threshold of guardians approved the same new owner, the
+    /// recovery can be completed with [`KeyManager::complete_recovery`] after
+    /// [`RecoveryConfig::recovery_delay_ns`], during which the owner can
+    /// cancel it with [`KeyManager::cancel_recovery`].
+    pub fn approve_recovery(
+        &mut self,
+        caller: Principal,
+        owner: Principal,
+        new_owner: Principal,
+        now_ns: u64,
+    ) -> Result<RecoveryStatus, String> {
+        let is_valid_new_owner = new_owner != owner && self.resolve_owner(new_owner) == new_owner;
+        let recovery = self.ensure_recovery_enabled_mut()?;
+        let guardians = recovery
+            .guardians
+            .get(&owner)
+            .ok_or_else(|| "recovery is not set up".to_string())?;
+        if !guardians.guardians.contains(&caller) {
+            return Err("unauthorized".to_string());
+        }
+        if !is_valid_new_owner {
+            return Err("invalid new owner".to_string());
+        }
+
+        for (other_new_owner, mut other) in
+            recovery.pending_recoveries_iter(owner).collect::<Vec<_>>()
+        {
+            if other_new_owner != new_owner && other.approvals.contains(&caller) {
+                other.approvals.retain(|guardian| *guardian != caller);
+                if other.approvals.is_empty() {
+                    recovery
+                        .pending_recoveries
+                        .remove(&(owner, other_new_owner));
+                } else {
+                    recovery
+                        .pending_recoveries
+                        .insert((owner, other_new_owner), other);
+                }
+            }
+        }
+        let mut pending = recovery
+            .pending_recoveries
+            .get(&(owner, new_owner))
+            .unwrap_or(PendingRecovery {
+                approvals: vec![],
+                executable_after_ns: None,
+            });
+        if !pending.approvals.contains(&caller) {
+            pending.approvals.push(caller);
+        }
+        let approvals = pending.approvals.len() as u32;
+        if approvals >= guardians.threshold && pending.executable_after_ns.is_none() {
+            pending.executable_after_ns =
+                Some(now_ns.saturating_add(recovery.config.recovery_delay_ns));
+        }
+        let status = match pending.executable_after_ns {
+            Some(executable_after_ns) => RecoveryStatus::Scheduled {
+                executable_after_ns,
+            },
+            None => RecoveryStatus::Pending {
+                approvals,
+                threshold: guardians.threshold,
+            },
+        };
+        recovery
+            .pending_recoveries
+            .insert((owner, new_owner), pending);
+        Ok(status)
+    }
+
+    /// Cancels all pending recoveries of the caller's vetKeys.
+    pub fn cancel_recovery(&mut self, caller: Principal) -> Result<(), String> {
+        let recovery = self.ensure_recovery_enabled_mut()?;
+        if recovery.clear_pending_recoveries(caller) == 0 {
+            return Err("no pending recovery".to_string());
+        }
+        Ok(())
+    }
+
+    /// Completes the approved recovery of all vetKeys of `owner` to
+    /// `new_owner` at the current time `now_ns`. The caller must be the new
+    /// owner or a guardian of the owner.
+    ///
+    /// Afterwards, `new_owner` owns the vetKeys of `owner` and `owner` loses
+    /// all owner rights, see [`KeyManager::resolve_owner`]. The vetKey IDs do
+    /// not change, so the vetKeys and the data encrypted with them stay the
+    /// same. The guardians and other pending recoveries of `owner` are
+    /// removed.
+    pub fn complete_recovery(
+        &mut self,
+        caller: Principal,
+        owner: Principal,
+        new_owner: Principal,
+        now_ns: u64,
+    ) -> Result<(), String> {
+        let is_valid_new_owner = self.resolve_owner(new_owner) == new_owner;
+        let recovery = self.ensure_recovery_enabled_mut()?;
+        let guardians = recovery
+            .guardians
+            .get(&owner)
+            .ok_or_else(|| "recovery is not set up".to_string())?;
+        if caller != new_owner && !guardians.guardians.contains(&caller) {
+            return Err("unauthorized".to_string());
+        }
+        let executable_after_ns = recovery
+            .pending_recoveries
+            .get(&(owner, new_owner))
+            .and_then(|pending| pending.executable_after_ns)
+            .ok_or_else(|| "recovery is not approved".to_string())?;
+        if now_ns < executable_after_ns {
+            return Err(format!(
+                "recovery can be completed after {executable_after_ns}"
+            ));
+        }
+        if !is_valid_new_owner {
+            return Err("invalid new owner".to_string());
+        }
+
+        recovery.clear_pending_recoveries(owner);
+        recovery.guardians.remove(&owner);
+        recovery.successors.insert(owner, new_owner);
+        recovery.predecessors.insert((new_owner, owner), ());
+        Ok(())
+    }
+
+    /// Retrieves the pending recoveries of `owner` by new owner. The caller
+    /// must be the owner or one of their guardians.
+    pub fn get_pending_recoveries(
+        &self,
+        caller: Principal,
+        owner: Principal,
+    ) -> Result<Vec<(Principal, PendingRecovery)>, String> {
+        let recovery = self.ensure_recovery_enabled()?;
+        let is_guardian = recovery
+            .guardians
+            .get(&owner)
+            .is_some_and(|guardians| guardians.guardians.contains(&caller));
+        if caller != owner && !is_guardian {
+            return Err("unauthorized".to_string());
+        }
+        Ok(recovery.pending_recoveries_iter(owner).collect())
+    }
+
+    /// Returns the principal that currently owns the vetKeys whose ID names
+    /// `principal` as owner, which differs from `principal` if its vetKeys
+    /// were recovered or migrated, see [`KeyManager::complete_recovery`] and
+    /// [`KeyManager::confirm_migration`].
+    ///
+    /// Recoveries and migrations never rewrite vetKey IDs: the vetKD input of
+    /// a vetKey is derived from its ID, so a new ID would be a new vetKey and
+    /// the data encrypted so far would become undecryptable. Instead, each
+    /// recovery or migration records a successor of the old principal, and
+    /// ownership checks follow the chain of successors to its end. The chain
+    /// cannot cycle, since a recovered or migrated principal can neither be
+    /// the new owner of a recovery nor the target of a migration.
+    pub fn resolve_owner(&self, principal: Principal) -> Principal {
+        let mut owner = principal;
+        while let Some(successor) = self.successor(owner) {
+            owner = successor;
+        }
+        owner
+    }
+
+    fn successor(&self, principal: Principal) -> Option<Principal> {
+        self.recovery
+            .as_ref()
+            .and_then(|recovery| recovery.successors.get(&principal))
+            .or_else(|| {
+                self.migrations
+                    .as_ref()
+                    .and_then(|migrations| migrations.migrated_principals.get(&principal))
+            })
+    }
+
+    /// Returns the principals whose vetKeys were recovered to the caller,
+    /// i.e., whose vetKeys the caller owns in addition to their own.
+    pub fn get_recovered_principals(&self, caller: Principal) -> Vec<Principal> {
+        let Some(recovery) = &self.recovery else {
+            return vec![];
+        };
+        self.predecessors(caller)
+            .into_iter()
+            .filter(|principal| recovery.successors.contains_key(principal))
+            .collect()
+    }
+
+    /// Returns the principals in the owner position of the vetKey IDs that
+    /// `caller` owns, i.e., the caller, unless recovered or migrated, and the
+    /// principals recovered or migrated to the caller.
+    pub(crate) fn owned_principals(&self, caller: Principal) -> Vec<Principal> {
+        let mut principals = self.predecessors(caller);
+        if self.resolve_owner(caller) == caller {
+            principals.push(caller);
+        }
+        principals.sort();
+        principals
+    }
+
+    /// Returns the principals that resolve to `owner`, i.e., whose vetKeys
+    /// were recovered or migrated to `owner` directly or through other
+    /// principals, see [`KeyManager::resolve_owner`].
+    pub(crate) fn predecessors(&self, owner: Principal) -> Vec<Principal> {
+        let mut predecessors = Vec::new();
+        if self.successor(owner).is_some() {
+            return predecessors;
+        }
+        let mut pending = vec![owner];
+        while let Some(principal) = pending.pop() {
+            let recovered = self
+                .recovery
+                .as_ref()
+                .into_iter()
+                .flat_map(|recovery| recovery.predecessors_iter(principal));
+            let migrated = self.migrated_predecessors(principal);
+            for predecessor in recovered.chain(migrated) {
+                // follow only the edges that `resolve_owner` follows
+                if self.successor(predecessor) == Some(principal)
+                    && !predecessors.contains(&predecessor)
+                {
+                    predecessors.push(predecessor);
+                    pending.push(predecessor);
+                }
+            }
+        }
+        predecessors
+    }
+
+    /// Removes the guardians and pending recoveries of `owner`, if any.
+    pub(crate) fn remove_recovery_setup(&mut self, owner: Principal) {
+        if let Some(recovery) = self.recovery.as_mut() {
+            recovery.clear_pending_recoveries(owner);
+            recovery.guardians.remove(&owner);
+        }
+    }
+
+    fn ensure_recovery_enabled(&self) -> Result<&Recovery, String> {
+        self.recovery
+            .as_ref()
+            .ok_or_else(|| "recovery is not enabled".to_string())
+    }
+
+    fn ensure_recovery_enabled_mut(&mut self) -> Result<&mut Recovery, String> {
+        self.recovery
+            .as_mut()
+            .ok_or_else(|| "recovery is not enabled".to_string())
+    }
+}
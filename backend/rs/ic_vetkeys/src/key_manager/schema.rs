@@ -0,0 +1,127 @@
+//! Versioning and migration of the stable-memory layout, see
+//! [`KeyManager::try_init`].
+
+use super::{KeyManager, Memory};
+use crate::types::{AccessControl, KeyManagerConfig, SchemaVersions};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableCell, Storable};
+use std::borrow::Cow;
+
+/// Error returned if the stable state cannot be initialized, see
+/// [`KeyManager::try_init`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum StableStateError {
+    /// A stored value cannot be decoded.
+    Corrupted(String),
+    /// The stored schema version is newer than the latest version supported
+    /// by this version of the library, e.g., after a downgrade.
+    UnsupportedSchemaVersion { stored: u32, latest: u32 },
+    /// The migration from schema version `from` to `from + 1` failed.
+    MigrationFailed { from: u32, error: String },
+}
+
+impl std::fmt::Display for StableStateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StableStateError::Corrupted(error) => write!(f, "corrupted stable state: {error}"),
+            StableStateError::UnsupportedSchemaVersion { stored, latest } => write!(
+                f,
+                "stored schema version {stored} is newer than the latest supported version {latest}"
+            ),
+            StableStateError::MigrationFailed { from, error } => {
+                write!(f, "migration from schema version {from} failed: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StableStateError {}
+
+/// The stored configuration as raw bytes, which allows checking that it can
+/// be decoded before initializing the typed [`StableCell`].
+struct RawBytes(Vec<u8>);
+
+impl Storable for RawBytes {
+    fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Returns an error if `memory` contains a configuration that cannot be
+/// decoded. Like the typed cell, this writes `default_config` to `memory` if
+/// it contains no configuration.
+pub(crate) fn check_stored_config(
+    memory: &Memory,
+    default_config: &KeyManagerConfig,
+) -> Result<(), StableStateError> {
+    let cell = StableCell::init(
+        memory.clone(),
+        RawBytes(default_config.to_bytes().into_owned()),
+    );
+    KeyManagerConfig::try_from_bytes(&cell.get().0)
+        .map(|_config| ())
+        .map_err(StableStateError::Corrupted)
+}
+
+/// Calls `migrate_from(n)` for each schema version `n` from `stored` up to,
+/// but excluding, `latest`. `migrate_from(n)` migrates the state from version
+/// `n` to `n + 1` and stores the new version.
+pub(crate) fn migrate_schema(
+    stored: u32,
+    latest: u32,
+    mut migrate_from: impl FnMut(u32) -> Result<(), String>,
+) -> Result<(), StableStateError> {
+    if stored > latest {
+        return Err(StableStateError::UnsupportedSchemaVersion { stored, latest });
+    }
+    for version in stored..latest {
+        migrate_from(version).map_err(|error| StableStateError::MigrationFailed {
+            from: version,
+            error,
+        })?;
+    }
+    Ok(())
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// The latest version of the stable-memory layout of the key manager,
+    /// see [`SchemaVersions`].
+    pub const SCHEMA_VERSION: u32 = 1;
+
+    /// Migrates the stable state from the stored schema version to
+    /// [`KeyManager::SCHEMA_VERSION`], one version at a time.
+    pub(crate) fn migrate_schema(&mut self) -> Result<(), StableStateError> {
+        let stored = self.config.get().schema_versions.key_manager;
+        migrate_schema(stored, Self::SCHEMA_VERSION, |version| {
+            match version {
+                // the unversioned layout is the one of version 1
+                0 => {}
+                _ => unreachable!("no migration from schema version {version}"),
+            }
+            self.set_schema_versions(SchemaVersions {
+                key_manager: version + 1,
+                ..self.config.get().schema_versions
+            });
+            Ok(())
+        })
+    }
+
+    /// Stores the versions of the stable-memory layout.
+    pub(crate) fn set_schema_versions(&mut self, schema_versions: SchemaVersions) {
+        let config = KeyManagerConfig {
+            schema_versions,
+            ..self.config.get().clone()
+        };
+        self.config.set(config);
+    }
+}
@@ -0,0 +1,240 @@
+//! Tenants with their own vetKD contexts in a single canister, see
+//! [`KeyManager::enable_tenants`].
+
+use super::{KeyId, KeyManager, Memory, VetKeyVerificationKey};
+use crate::key_manager::VetKDCallError;
+use crate::types::{AccessControl, KeyName};
+use candid::Principal;
+use ic_cdk_management_canister::VetKDPublicKeyArgs;
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+
+/// The identifier of a tenant, see [`KeyManager::create_tenant`].
+pub type TenantId = Blob<32>;
+
+/// Stable state of the tenant registry.
+///
+/// Each tenant has its own domain separator, which is used as the vetKD
+/// context of its vetKeys instead of the one in [`KeyManager::config`], and
+/// admins who manage the access rights to its vetKeys.
+pub struct Tenants {
+    /// Maps a tenant to its domain separator.
+    pub domain_separators: StableBTreeMap<TenantId, String, Memory>,
+    /// Contains `(tenant, admin)` pairs.
+    pub admins: StableBTreeMap<(TenantId, Principal), (), Memory>,
+    /// Maps the registered vetKeys of the tenants to their tenant, see
+    /// [`KeyManager::register_tenant_key`].
+    pub key_tenants: StableBTreeMap<KeyId, TenantId, Memory>,
+}
+
+impl Tenants {
+    const KEY_NAME_DOMAIN_SEPARATOR: &'static [u8] = b"ic-vetkeys-tenant-key";
+
+    fn set_admins(&mut self, tenant: TenantId, admins: &[Principal]) {
+        let old_admins: Vec<_> = self
+            .admins
+            .keys_range((tenant, Principal::management_canister())..)
+            .take_while(|(t, _admin)| *t == tenant)
+            .collect();
+        for key in old_admins {
+            self.admins.remove(&key);
+        }
+        for admin in admins {
+            self.admins.insert((tenant, *admin), ());
+        }
+    }
+}
+
+/// Returns the id of the vetKey `name` of `owner` in `tenant`, whose name
+/// is the domain-separated SHA-256 hash of the tenant and `name`, so that
+/// the vetKey ids of different tenants never collide.
+pub fn tenant_key_id(tenant: TenantId, owner: Principal, name: &[u8]) -> KeyId {
+    let mut hasher = Sha256::new();
+    hasher.update(Tenants::KEY_NAME_DOMAIN_SEPARATOR);
+    hasher.update(tenant.as_slice());
+    hasher.update(name);
+    let key_name =
+        KeyName::try_from(hasher.finalize().as_slice()).expect("SHA-256 hashes are 32 bytes");
+    (owner, key_name)
+}
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Enables tenants, i.e., groups of vetKeys that are derived with their
+    /// own domain separator and whose access rights are managed by the admins
+    /// of the tenant, see [`KeyManager::create_tenant`].
+    pub fn enable_tenants(
+        &mut self,
+        memory_domain_separators: Memory,
+        memory_admins: Memory,
+        memory_key_tenants: Memory,
+    ) {
+        self.tenants = Some(Tenants {
+            domain_separators: StableBTreeMap::init(memory_domain_separators),
+            admins: StableBTreeMap::init(memory_admins),
+            key_tenants: StableBTreeMap::init(memory_key_tenants),
+        });
+    }
+
+    /// Creates a tenant whose vetKeys are derived with `domain_separator` as
+    /// vetKD context. The domain separator must differ from the one of the
+    /// key manager and of all other tenants, so that the vetKeys of different
+    /// tenants are independent.
+    ///
+    /// This method performs no authorization; the canister must restrict who
+    /// can create tenants, e.g., to its controllers.
+    pub fn create_tenant(
+        &mut self,
+        tenant: TenantId,
+        domain_separator: String,
+        admins: Vec<Principal>,
+    ) -> Result<(), String> {
+        let default_domain_separator = self.config.get().domain_separator.clone();
+        let tenants = self
+            .tenants
+            .as_mut()
+            .ok_or_else(|| "tenants are not enabled".to_string())?;
+        if tenants.domain_separators.contains_key(&tenant) {
+            return Err("tenant already exists".to_string());
+        }
+        if domain_separator == default_domain_separator
+            || tenants
+                .domain_separators
+                .values()
+                .any(|existing| existing == domain_separator)
+        {
+            return Err("domain separator is already in use".to_string());
+        }
+        tenants.domain_separators.insert(tenant, domain_separator);
+        tenants.set_admins(tenant, &admins);
+        Ok(())
+    }
+
+    /// Replaces the admins of a tenant. This method performs no
+    /// authorization; the canister must restrict it, e.g., to its controllers.
+    pub fn set_tenant_admins(
+        &mut self,
+        tenant: TenantId,
+        admins: Vec<Principal>,
+    ) -> Result<(), String> {
+        let tenants = self
+            .tenants
+            .as_mut()
+            .ok_or_else(|| "tenants are not enabled".to_string())?;
+        if !tenants.domain_separators.contains_key(&tenant) {
+            return Err("tenant does not exist".to_string());
+        }
+        tenants.set_admins(tenant, &admins);
+        Ok(())
+    }
+
+    /// Returns the domain separator and the admins of a tenant, or `None` if
+    /// `tenant` does not exist.
+    pub fn get_tenant(&self, tenant: TenantId) -> Option<(String, Vec<Principal>)> {
+        let tenants = self.tenants.as_ref()?;
+        let domain_separator = tenants.domain_separators.get(&tenant)?;
+        let admins = tenants
+            .admins
+            .keys_range((tenant, Principal::management_canister())..)
+            .take_while(|(t, _admin)| *t == tenant)
+            .map(|(_tenant, admin)| admin)
+            .collect();
+        Some((domain_separator, admins))
+    }
+
+    /// Registers the vetKey `name` of `owner` in `tenant` and returns its id,
+    /// see [`tenant_key_id`]. Registration is idempotent and must
+    /// happen before the vetKey is used: afterwards, the vetKey is derived
+    /// with the domain separator of the tenant, and the admins of the tenant
+    /// can manage its access rights like the owner, but cannot read it unless
+    /// they grant themselves access. Returns an error if the vetKey is not
+    /// registered yet but already shared with users.
+    ///
+    /// This method performs no authorization; the canister must restrict who
+    /// can register vetKeys in a tenant, e.g., to `owner` or the admins of
+    /// the tenant.
+    pub fn register_tenant_key(
+        &mut self,
+        tenant: TenantId,
+        owner: Principal,
+        name: &[u8],
+    ) -> Result<KeyId, String> {
+        let tenants = self
+            .tenants
+            .as_ref()
+            .ok_or_else(|| "tenants are not enabled".to_string())?;
+        if !tenants.domain_separators.contains_key(&tenant) {
+            return Err("tenant does not exist".to_string());
+        }
+        let key_id = tenant_key_id(tenant, owner, name);
+        if tenants.key_tenants.contains_key(&key_id) {
+            return Ok(key_id);
+        }
+        if self.shared_users_iter(key_id, None).next().is_some() {
+            return Err("vetKey is already in use".to_string());
+        }
+        if let Some(tenants) = self.tenants.as_mut() {
+            tenants.key_tenants.insert(key_id, tenant);
+        }
+        Ok(key_id)
+    }
+
+    /// Returns the tenant of a registered vetKey, or `None` if the vetKey does
+    /// not belong to a tenant.
+    pub fn get_key_tenant(&self, key_id: KeyId) -> Option<TenantId> {
+        self.tenants
+            .as_ref()
+            .and_then(|tenants| tenants.key_tenants.get(&key_id))
+    }
+
+    /// Retrieves the public verification key of the vetKeys of `tenant`, see
+    /// [`KeyManager::get_vetkey_verification_key`]. Returns an error if the
+    /// tenant does not exist.
+    pub fn get_tenant_vetkey_verification_key(
+        &self,
+        tenant: TenantId,
+    ) -> Result<
+        impl Future<Output = Result<VetKeyVerificationKey, VetKDCallError>> + Send + Sync,
+        String,
+    > {
+        let (domain_separator, _admins) = self
+            .get_tenant(tenant)
+            .ok_or_else(|| "tenant does not exist".to_string())?;
+        let key_id = self.config.get().key_id.clone();
+        let call_policy = self.call_policy;
+
+        Ok(async move {
+            let request = VetKDPublicKeyArgs {
+                canister_id: None,
+                context: domain_separator.into_bytes(),
+                key_id,
+            };
+            let reply = call_policy.vetkd_public_key(request).await?;
+            Ok(VetKeyVerificationKey::from(reply.public_key))
+        })
+    }
+
+    /// Returns the domain separator used as vetKD context for the vetKey,
+    /// which is the one of its tenant, if any.
+    pub(crate) fn vetkd_context(&self, key_id: KeyId) -> Vec<u8> {
+        self.tenants
+            .as_ref()
+            .and_then(|tenants| {
+                let tenant = tenants.key_tenants.get(&key_id)?;
+                tenants.domain_separators.get(&tenant)
+            })
+            .unwrap_or_else(|| self.config.get().domain_separator.clone())
+            .into_bytes()
+    }
+
+    /// Returns whether `user` is an admin of the tenant of the vetKey.
+    pub(crate) fn is_tenant_admin(&self, user: Principal, key_id: KeyId) -> bool {
+        self.tenants.as_ref().is_some_and(|tenants| {
+            tenants
+                .key_tenants
+                .get(&key_id)
+                .is_some_and(|tenant| tenants.admins.contains_key(&(tenant, user)))
+        })
+    }
+}
@@ -0,0 +1,80 @@
+//! Offline derivation and caching of the vetKD verification key, see
+//! [`KeyManager::get_cached_vetkey_verification_key`].
+
+use super::{KeyManager, VetKeyVerificationKey};
+use crate::types::{AccessControl, KeyManagerConfig};
+use crate::MasterPublicKey;
+use candid::Principal;
+use ic_stable_structures::Storable;
+
+impl<T: AccessControl> KeyManager<T> {
+    /// Derives the vetKD verification key of the canister `canister_id`
+    /// locally, without a call to the management canister. Returns `None` if
+    /// the master public key of the configured vetKD key is not known, see
+    /// [`MasterPublicKey::for_mainnet_key`].
+    pub fn derive_vetkey_verification_key_offline(
+        &self,
+        canister_id: Principal,
+    ) -> Option<VetKeyVerificationKey> {
+        let config = self.config.get();
+        let master_public_key = MasterPublicKey::for_mainnet_key(&config.key_id)?;
+        let verification_key = master_public_key
+            .derive_canister_key(canister_id.as_slice())
+            .derive_sub_key(&config.domain_separator.to_bytes());
+        Some(VetKeyVerificationKey::from(verification_key.serialize()))
+    }
+
+    /// Returns the vetKD verification key of the canister `canister_id`
+    /// without a call to the management canister, which allows serving it
+    /// from a query. This is the cached key if available, and otherwise the
+    /// key derived by [`KeyManager::derive_vetkey_verification_key_offline`].
+    /// Returns `None` if neither is available, in which case the key must be
+    /// retrieved with [`KeyManager::get_vetkey_verification_key`].
+    pub fn get_cached_vetkey_verification_key(
+        &self,
+        canister_id: Principal,
+    ) -> Option<VetKeyVerificationKey> {
+        self.config
+            .get()
+            .verification_key
+            .clone()
+            .or_else(|| self.derive_vetkey_verification_key_offline(canister_id))
+    }
+
+    /// Caches the vetKD verification key returned by the management canister,
+    /// e.g., with [`KeyManager::fetch_vetkey_verification_key`], in stable
+    /// memory, so that further calls to
+    /// [`KeyManager::get_vetkey_verification_key`] return it without a call.
+    ///
+    /// If the key can also be derived offline, this checks once that the
+    /// offline key matches the management canister's answer and returns an
+    /// error without caching if it does not, as the offline key is served by
+    /// [`KeyManager::get_cached_vetkey_verification_key`].
+    pub fn cache_vetkey_verification_key(
+        &mut self,
+        canister_id: Principal,
+        verification_key: VetKeyVerificationKey,
+    ) -> Result<(), String> {
+        let config = self.config.get();
+        if config.verification_key.as_ref() == Some(&verification_key) {
+            return Ok(());
+        }
+        if let Some(offline_verification_key) =
+            self.derive_vetkey_verification_key_offline(canister_id)
+        {
+            if offline_verification_key != verification_key {
+                return Err(
+                    "offline vetKD verification key does not match the management canister's"
+                        .to_string(),
+                );
+            }
+        }
+
+        let config = KeyManagerConfig {
+            verification_key: Some(verification_key),
+            ..config.clone()
+        };
+        self.config.set(config);
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use std::{borrow::Cow, convert::TryInto};
+use std::borrow::Cow;
 
 use candid::{CandidType, Decode, Encode};
 use ic_stable_structures::{
@@ -16,11 +16,29 @@ pub type KeyId = (candid::Principal, KeyName);
 pub type MapKey = Blob<32>;
 pub type TransportKey = ByteBuf;
 pub type EncryptedMapValue = ByteBuf;
+/// A position in the listing of encrypted map values: `(map_id, Some(key))` is
+/// the map key `key` within the map `map_id` and `(map_id, None)` is the end
+/// of the map `map_id`.
+pub type MapPosition = (MapId, Option<MapKey>);
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct KeyManagerConfig {
     pub domain_separator: String,
     pub key_id: ic_cdk_management_canister::VetKDKeyId,
+    /// The vetKD verification key as returned by the management canister, once retrieved.
+    #[serde(default)]
+    pub verification_key: Option<ByteBuf>,
+    /// The versions of the stable-memory layout, see [`SchemaVersions`].
+    #[serde(default)]
+    pub schema_versions: SchemaVersions,
+}
+
+impl KeyManagerConfig {
+    /// Decodes a stored configuration, returning an error instead of
+    /// panicking if the bytes are corrupted.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        serde_cbor::from_slice(bytes).map_err(|e| format!("invalid KeyManagerConfig: {e}"))
+    }
 }
 
 impl Storable for KeyManagerConfig {
@@ -33,12 +51,25 @@ impl Storable for KeyManagerConfig {
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+        Self::try_from_bytes(bytes.as_ref()).unwrap_or_else(|e| panic!("{e}"))
     }
 
     const BOUND: Bound = Bound::Unbounded;
 }
 
+/// The versions of the stable-memory layouts of
+/// [`crate::key_manager::KeyManager`] and
+/// [`crate::encrypted_maps::EncryptedMaps`], which are stored in the
+/// [`KeyManagerConfig`] and migrated on initialization.
+///
+/// Version 0 denotes state written before the layouts were versioned, whose
+/// layout is the one of version 1.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct SchemaVersions {
+    pub key_manager: u32,
+    pub encrypted_maps: u32,
+}
+
 /// Access rights of a user to a vetKey in [`crate::key_manager::KeyManager`] and/or an encrypted map in [`crate::encrypted_maps::EncryptedMaps`].
 #[repr(u8)]
 #[derive(
@@ -74,8 +105,7 @@ impl Storable for AccessRights {
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        let v = <u8>::from_be_bytes(bytes.as_ref().try_into().unwrap());
-        Self::from_repr(v).unwrap()
+        Self::try_from_bytes(bytes.as_ref()).unwrap_or_else(|e| panic!("{e}"))
     }
 
     const BOUND: Bound = Bound::Bounded {
@@ -84,6 +114,16 @@ impl Storable for AccessRights {
     };
 }
 
+impl AccessRights {
+    /// Decodes stored access rights, returning an error instead of panicking
+    /// if the bytes are corrupted.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let v = <[u8; 1]>::try_from(bytes)
+            .map_err(|_| format!("invalid AccessRights: expected 1 byte, got {}", bytes.len()))?;
+        Self::from_repr(v[0]).ok_or_else(|| format!("invalid AccessRights: unknown value {}", v[0]))
+    }
+}
+
 impl AccessControl for AccessRights {
     fn can_read(&self) -> bool {
         matches!(
@@ -130,11 +170,90 @@ pub trait AccessControl:
     /// Returns if the user can write to the vetKey or encrypted map.
     fn can_write(&self) -> bool;
     /// Returns if the user can view the access rights to the vetKey or encrypted map.
+    /// Access rights for which this holds but [`AccessControl::can_set_user_rights`]
+    /// does not allow viewing the members without managing them.
     fn can_get_user_rights(&self) -> bool;
     /// Returns if the user can modify the access rights to the vetKey or encrypted map.
     fn can_set_user_rights(&self) -> bool;
     /// Returns the access rights of the owner of the vetKey or encrypted map.
     fn owner_rights() -> Self;
+    /// Returns if the user can insert a value under a new key into the
+    /// encrypted map. Together with [`AccessControl::can_overwrite`] and
+    /// [`AccessControl::can_remove`], this allows, e.g., append-only or
+    /// delete-only access rights. By default, this is [`AccessControl::can_write`].
+    fn can_insert(&self) -> bool {
+        self.can_write()
+    }
+    /// Returns if the user can overwrite an existing value in the encrypted
+    /// map. By default, this is [`AccessControl::can_write`].
+    fn can_overwrite(&self) -> bool {
+        self.can_write()
+    }
+    /// Returns if the user can remove values from the encrypted map. By
+    /// default, this is [`AccessControl::can_write`].
+    fn can_remove(&self) -> bool {
+        self.can_write()
+    }
+    /// Returns if the user can share the vetKey or encrypted map with a user
+    /// without access by granting them `access_rights`, e.g., to allow sharing
+    /// read access only. Modifying or revoking existing access rights always
+    /// requires [`AccessControl::can_set_user_rights`]. By default, this is
+    /// [`AccessControl::can_set_user_rights`] for any `access_rights`.
+    fn can_share(&self, access_rights: &Self) -> bool {
+        let _ = access_rights;
+        self.can_set_user_rights()
+    }
+    /// Returns if a user with these access rights can grant `access_rights` to
+    /// another user if [`crate::key_manager::DelegationPolicy::restrict_grants_to_own_rights`]
+    /// is set. By default, a user can grant rights at or below their own.
+    fn can_grant(&self, access_rights: &Self) -> bool {
+        access_rights <= self
+    }
+    /// Returns if a user with these access rights can modify or remove the
+    /// access rights `other` of another user if
+    /// [`crate::key_manager::DelegationPolicy::protect_peers`] is set. By
+    /// default, a user can only manage users with lower rights than their own.
+    fn can_manage(&self, other: &Self) -> bool {
+        other < self
+    }
+}
+
+/// The maximum number of items returned in a [`Page`]. Greater limits passed
+/// to the paginated listing methods are clamped to this value so that a single
+/// response stays within the message size limits.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// A page of results returned by the paginated listing methods, e.g.,
+/// [`crate::key_manager::KeyManager::get_accessible_shared_key_ids_paginated`].
+///
+/// To retrieve the next page, pass `next_cursor` as `start_after` to the same
+/// method. A `next_cursor` of `None` means that there are no more results.
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Page<T, C> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<C>,
+}
+
+impl<T, C> Page<T, C> {
+    /// Collects at most `limit` items from `items`, where `cursor` computes the
+    /// cursor of the last returned item if more items are available. The
+    /// `limit` is clamped to `1..=MAX_PAGE_SIZE`, i.e., a `limit` of zero is
+    /// treated as one so that paging always makes progress.
+    pub(crate) fn collect(
+        items: impl Iterator<Item = T>,
+        limit: usize,
+        cursor: impl FnOnce(&T) -> C,
+    ) -> Self {
+        let limit = limit.clamp(1, MAX_PAGE_SIZE);
+        let mut items: Vec<T> = items.take(limit.saturating_add(1)).collect();
+        let next_cursor = if items.len() > limit {
+            items.truncate(limit);
+            items.last().map(cursor)
+        } else {
+            None
+        };
+        Self { items, next_cursor }
+    }
 }
 
 /// Efficiently serializable and deserializable byte vector that is `Storable` with `ic_stable_structures`.
@@ -150,6 +269,12 @@ impl ByteBuf {
     pub fn new() -> Self {
         Self { inner: Vec::new() }
     }
+
+    /// Decodes a stored `ByteBuf`, returning an error instead of panicking if
+    /// the bytes are corrupted.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        Decode!(bytes, Self).map_err(|e| format!("invalid ByteBuf: {e}"))
+    }
 }
 
 impl From<Vec<u8>> for ByteBuf {
@@ -186,7 +311,7 @@ impl Storable for ByteBuf {
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        Self::try_from_bytes(bytes.as_ref()).unwrap_or_else(|e| panic!("{e}"))
     }
     const BOUND: Bound = Bound::Unbounded;
 }
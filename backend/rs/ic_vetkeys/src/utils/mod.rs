@@ -903,6 +903,19 @@ impl IbeIdentity {
         Self::from_bytes(principal.as_slice())
     }
 
+    /// Create the identity of the vetKey that a
+    /// [`KeyManager`](crate::key_manager::KeyManager) derives for a key id
+    ///
+    /// The identity matches [`crate::key_manager::key_id_to_vetkd_input`], so a
+    /// ciphertext encrypted to it with the canister's derived public key, e.g.,
+    /// as returned by `get_vetkey_verification_key`, can be decrypted by every
+    /// user who can retrieve the vetKey.
+    pub fn for_key_id(owner: &candid::Principal, key_name: &[u8]) -> Self {
+        Self {
+            val: crate::key_manager::key_id_to_vetkd_input(*owner, key_name),
+        }
+    }
+
     /// Return the bytestring of this identity
     pub fn value(&self) -> &[u8] {
         &self.val
@@ -1392,6 +1405,40 @@ fn verify_bls_signature_pt(dpk: &DerivedPublicKey, input: &[u8], signature: &G1A
     bool::from(is_valid)
 }
 
+/// Verify a (non-augmented) BLS signature as used by the certificates of the
+/// Internet Computer, with the public key in compressed G2 form.
+///
+/// Returns true if and only if the provided signature is valid with respect to
+/// the provided public key and message
+pub(crate) fn verify_ic_bls_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let public_key = match deserialize_g2(public_key) {
+        Ok(pt) if !bool::from(pt.is_identity()) => pt,
+        _ => return false,
+    };
+    let signature: G1Affine = match <[u8; G1AFFINE_BYTES]>::try_from(signature) {
+        Ok(bytes) => match G1Affine::from_compressed(&bytes).into_option() {
+            Some(pt) => pt,
+            None => return false,
+        },
+        Err(_) => return false,
+    };
+
+    let domain_sep = b"BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_NUL_";
+    let msg = G1Affine::from(
+        <G1Projective as HashToCurve<ExpandMsgXmd<sha2::Sha256>>>::hash_to_curve(
+            message, domain_sep,
+        ),
+    );
+
+    use pairing::group::Group;
+    let is_valid = gt_multipairing(&[
+        (&signature, &G2PREPARED_NEG_G),
+        (&msg, &G2Prepared::from(public_key)),
+    ])
+    .is_identity();
+    bool::from(is_valid)
+}
+
 fn augmented_hash_to_g1(pk: &G2Affine, data: &[u8]) -> G1Affine {
     let domain_sep = b"BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_AUG_";
 
@@ -1599,4 +1646,18 @@ pub mod management_canister {
 
         VrfOutput::create(vetkey, input, dpk).map_err(|_| VetKDDeriveKeyCallError::InvalidReply)
     }
+
+    /// Returns the number of cycles attached to a `vetkd_derive_key` call for the given `key_id`,
+    /// as computed by `ic0_cost_vetkd_derive_key`.
+    ///
+    /// This is the cost of a single [`sign_with_bls`] or [`compute_vrf`] call,
+    /// and of each vetKey derived by [`crate::key_manager::KeyManager::get_encrypted_vetkey`].
+    /// The cost depends on the subnet the canister is deployed to.
+    ///
+    /// # Returns
+    /// * `Ok(u128)` - The cost in cycles
+    /// * `Err(SignCostError)` - If the curve or the key name is invalid
+    pub fn vetkd_derive_key_cost(key_id: &VetKDKeyId) -> Result<u128, ic_cdk::api::SignCostError> {
+        ic_cdk::api::cost_vetkd_derive_key(&key_id.name, key_id.curve.into())
+    }
 }
@@ -1,12 +1,12 @@
-use std::{collections::BTreeMap, iter::FromIterator};
+use std::{cell::RefCell, collections::BTreeMap, iter::FromIterator, rc::Rc};
 
 use assert_matches::assert_matches;
 use candid::Principal;
 use ic_cdk_management_canister::{VetKDCurve, VetKDKeyId};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager},
-    storable::Blob,
-    DefaultMemoryImpl,
+    storable::{Blob, Bound},
+    DefaultMemoryImpl, Storable,
 };
 use ic_vetkeys_test_utils::{
     random_access_rights, random_bytebuf, random_key, random_name,
@@ -17,7 +17,13 @@ use rand::{CryptoRng, Rng};
 use strum::IntoEnumIterator;
 
 use ic_vetkeys::encrypted_maps::EncryptedMaps;
-use ic_vetkeys::types::{AccessControl, AccessRights};
+use ic_vetkeys::key_manager::{
+    tenant_key_id, EventListener, FreezeConfig, InvitationsConfig, KeyDeletionConfig, KeyId,
+    KeyManager, KeyTombstone, LimitError, LongNames, QuotaConfig, RecoveryConfig,
+};
+use ic_vetkeys::types::{
+    AccessControl, AccessRights, ByteBuf, MapKey, SchemaVersions, MAX_PAGE_SIZE,
+};
 
 #[test]
 fn can_init_memory() {
@@ -25,6 +31,18 @@ fn can_init_memory() {
     std::hint::black_box(random_encrypted_maps(&mut reproducible_rng()));
 }
 
+#[test]
+fn schema_versions_are_stored_on_init() {
+    let encrypted_maps = random_encrypted_maps(&mut reproducible_rng());
+    assert_eq!(
+        encrypted_maps.key_manager.config.get().schema_versions,
+        SchemaVersions {
+            key_manager: KeyManager::<AccessRights>::SCHEMA_VERSION,
+            encrypted_maps: EncryptedMaps::<AccessRights>::SCHEMA_VERSION,
+        }
+    );
+}
+
 #[test]
 fn can_remove_map_values() {
     let rng = &mut reproducible_rng();
@@ -395,6 +413,168 @@ fn can_access_map_values() {
     }
 }
 
+#[test]
+fn get_encrypted_values_for_map_paginated_works_correctly() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let map_id = (caller, random_name(rng));
+    let mut encrypted_maps = random_encrypted_maps(rng);
+
+    for _ in 0..10 {
+        encrypted_maps
+            .insert_encrypted_value(caller, map_id, random_key(rng), random_bytebuf(rng, 0..100))
+            .unwrap();
+    }
+
+    let limit = rng.gen_range(1..4);
+    let mut paginated_values = vec![];
+    let mut start_after = None;
+    loop {
+        let page = encrypted_maps
+            .get_encrypted_values_for_map_paginated(caller, map_id, start_after, limit)
+            .unwrap();
+        assert!(page.items.len() <= limit);
+        paginated_values.extend(page.items);
+        start_after = page.next_cursor;
+        if start_after.is_none() {
+            break;
+        }
+    }
+
+    assert_eq!(
+        Ok(paginated_values),
+        encrypted_maps.get_encrypted_values_for_map(caller, map_id)
+    );
+
+    let unauthorized = random_self_authenticating_principal(rng);
+    assert_eq!(
+        encrypted_maps.get_encrypted_values_for_map_paginated(unauthorized, map_id, None, limit),
+        Err("unauthorized".to_string())
+    );
+}
+
+#[test]
+fn get_all_accessible_encrypted_maps_paginated_works_correctly() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let mut encrypted_maps = random_encrypted_maps(rng);
+
+    for _ in 0..5 {
+        let owned_map_id = (caller, random_name(rng));
+        for _ in 0..rng.gen_range(1..4) {
+            encrypted_maps
+                .insert_encrypted_value(
+                    caller,
+                    owned_map_id,
+                    random_key(rng),
+                    random_bytebuf(rng, 0..100),
+                )
+                .unwrap();
+        }
+
+        let owner = random_self_authenticating_principal(rng);
+        let shared_map_id = (owner, random_name(rng));
+        for _ in 0..rng.gen_range(0..4) {
+            encrypted_maps
+                .insert_encrypted_value(
+                    owner,
+                    shared_map_id,
+                    random_key(rng),
+                    random_bytebuf(rng, 0..100),
+                )
+                .unwrap();
+        }
+        encrypted_maps
+            .set_user_rights(owner, shared_map_id, caller, random_access_rights(rng))
+            .unwrap();
+    }
+
+    let limit = rng.gen_range(1..4);
+    let mut paginated_values = BTreeMap::<_, Vec<_>>::new();
+    let mut paginated_maps = BTreeMap::new();
+    let mut start_after = None;
+    loop {
+        let values_page = encrypted_maps.get_all_accessible_encrypted_values_paginated(
+            caller,
+            start_after,
+            limit,
+        );
+        let maps_page =
+            encrypted_maps.get_all_accessible_encrypted_maps_paginated(caller, start_after, limit);
+        // Each map counts as at least one item, even if it has no values.
+        let page_size: usize = values_page
+            .items
+            .iter()
+            .map(|(_map_id, map_values)| map_values.len().max(1))
+            .sum();
+        assert!(page_size <= limit);
+        assert_eq!(values_page.next_cursor, maps_page.next_cursor);
+        assert_eq!(values_page.items.len(), maps_page.items.len());
+        for (map_id, map_values) in values_page.items {
+            paginated_values
+                .entry(map_id)
+                .or_default()
+                .extend(map_values);
+        }
+        for map_data in maps_page.items {
+            let (access_control, keyvals) = paginated_maps
+                .entry((map_data.map_owner, map_data.map_name))
+                .or_insert_with(|| (map_data.access_control.clone(), vec![]));
+            assert_eq!(*access_control, map_data.access_control);
+            keyvals.extend(map_data.keyvals);
+        }
+        start_after = values_page.next_cursor;
+        if start_after.is_none() {
+            break;
+        }
+    }
+
+    assert_eq!(paginated_values.len(), 10);
+    assert_eq!(
+        paginated_values,
+        BTreeMap::from_iter(encrypted_maps.get_all_accessible_encrypted_values(caller))
+    );
+
+    let all_maps = encrypted_maps.get_all_accessible_encrypted_maps(caller);
+    assert_eq!(paginated_maps.len(), all_maps.len());
+    for expected in all_maps {
+        assert_eq!(
+            paginated_maps[&(expected.map_owner, expected.map_name)],
+            (expected.access_control, expected.keyvals)
+        );
+    }
+}
+
+#[test]
+fn get_all_accessible_encrypted_values_paginated_bounds_values_per_page() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let map_id = (caller, random_name(rng));
+    let mut encrypted_maps = random_encrypted_maps(rng);
+
+    for _ in 0..=MAX_PAGE_SIZE {
+        encrypted_maps
+            .insert_encrypted_value(caller, map_id, random_key(rng), random_bytebuf(rng, 0..100))
+            .unwrap();
+    }
+
+    let page = encrypted_maps.get_all_accessible_encrypted_values_paginated(caller, None, 1);
+    assert_eq!(page.items.len(), 1);
+    assert_eq!(page.items[0].1.len(), 1);
+    let last_key = page.items[0].1[0].0;
+    assert_eq!(page.next_cursor, Some((map_id, Some(last_key))));
+
+    let page = encrypted_maps.get_all_accessible_encrypted_values_paginated(
+        caller,
+        page.next_cursor,
+        usize::MAX,
+    );
+    assert_eq!(page.items.len(), 1);
+    assert_eq!(page.items[0].0, map_id);
+    assert_eq!(page.items[0].1.len(), MAX_PAGE_SIZE);
+    assert_eq!(page.next_cursor, None);
+}
+
 #[test]
 fn can_modify_a_key_value_in_map() {
     let rng = &mut reproducible_rng();
@@ -499,7 +679,991 @@ fn can_get_owned_map_names() {
     }
 }
 
+#[test]
+fn map_quota_counts_shared_and_non_empty_maps() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let map_ids: Vec<_> = (0..3).map(|_| (owner, random_name(rng))).collect();
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let mut encrypted_maps = EncryptedMaps::<AccessRights>::init(
+        "quotas",
+        bls12_381_dfx_test_key(),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        memory_manager.get(MemoryId::new(3)),
+    );
+    encrypted_maps.enable_quotas(
+        QuotaConfig {
+            max_keys_per_owner: Some(2),
+            max_shares_per_owner: None,
+        },
+        memory_manager.get(MemoryId::new(4)),
+        memory_manager.get(MemoryId::new(5)),
+    );
+    let quota_exceeded = LimitError::KeyQuotaExceeded {
+        max_keys_per_owner: 2,
+    }
+    .to_string();
+
+    encrypted_maps
+        .insert_encrypted_value(
+            owner,
+            map_ids[0],
+            random_key(rng),
+            random_bytebuf(rng, 0..100),
+        )
+        .unwrap();
+    encrypted_maps
+        .set_user_rights(owner, map_ids[1], user, AccessRights::ReadWrite)
+        .unwrap();
+
+    assert_eq!(
+        encrypted_maps.insert_encrypted_value(
+            owner,
+            map_ids[2],
+            random_key(rng),
+            random_bytebuf(rng, 0..100)
+        ),
+        Err(quota_exceeded.clone())
+    );
+    assert_eq!(
+        encrypted_maps.set_user_rights(owner, map_ids[2], user, AccessRights::Read),
+        Err(quota_exceeded.clone())
+    );
+
+    // existing maps can still be used
+    assert_matches!(
+        encrypted_maps.insert_encrypted_value(
+            user,
+            map_ids[1],
+            random_key(rng),
+            random_bytebuf(rng, 0..100)
+        ),
+        Ok(None)
+    );
+    assert_eq!(
+        encrypted_maps.set_user_rights(owner, map_ids[0], user, AccessRights::Read),
+        Ok(None)
+    );
+
+    // maps stop counting once they are neither shared nor hold values
+    encrypted_maps.remove_user(owner, map_ids[1], user).unwrap();
+    assert_eq!(
+        encrypted_maps.insert_encrypted_value(
+            owner,
+            map_ids[2],
+            random_key(rng),
+            random_bytebuf(rng, 0..100)
+        ),
+        Err(quota_exceeded.clone())
+    );
+    encrypted_maps.remove_map_values(owner, map_ids[1]).unwrap();
+    assert_matches!(
+        encrypted_maps.insert_encrypted_value(
+            owner,
+            map_ids[2],
+            random_key(rng),
+            random_bytebuf(rng, 0..100)
+        ),
+        Ok(None)
+    );
+}
+
+#[test]
+fn maps_with_values_cannot_be_registered_in_tenants() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let tenant = random_name(rng);
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let mut encrypted_maps = EncryptedMaps::<AccessRights>::init(
+        "tenants",
+        bls12_381_dfx_test_key(),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        memory_manager.get(MemoryId::new(3)),
+    );
+    encrypted_maps.enable_tenants(
+        memory_manager.get(MemoryId::new(4)),
+        memory_manager.get(MemoryId::new(5)),
+        memory_manager.get(MemoryId::new(6)),
+    );
+    encrypted_maps
+        .create_tenant(tenant, "tenant".to_string(), vec![])
+        .unwrap();
+    let map_id = tenant_key_id(tenant, owner, b"map");
+    let map_key = random_key(rng);
+    encrypted_maps
+        .insert_encrypted_value(owner, map_id, map_key, random_bytebuf(rng, 0..100))
+        .unwrap();
+
+    assert_eq!(
+        encrypted_maps.register_tenant_map(tenant, owner, b"map"),
+        Err("map is already in use".to_string())
+    );
+    encrypted_maps
+        .remove_encrypted_value(owner, map_id, map_key)
+        .unwrap();
+    assert_eq!(
+        encrypted_maps.register_tenant_map(tenant, owner, b"map"),
+        Ok(map_id)
+    );
+}
+
+#[test]
+fn long_map_names_and_keys_are_returned_in_full() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let mut encrypted_maps = EncryptedMaps::<AccessRights>::init(
+        "long_names",
+        bls12_381_dfx_test_key(),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        memory_manager.get(MemoryId::new(3)),
+    );
+    let long_map_name = random_bytebuf(rng, 33..LongNames::MAX_NAME_LEN + 1);
+    let long_map_key = random_bytebuf(rng, 33..LongNames::MAX_NAME_LEN + 1);
+    assert_eq!(
+        encrypted_maps.name_from_bytes(long_map_name.as_ref()),
+        Err("too large input".to_string())
+    );
+
+    encrypted_maps.enable_long_names(memory_manager.get(MemoryId::new(4)));
+    let map_id = (
+        owner,
+        encrypted_maps
+            .name_from_bytes(long_map_name.as_ref())
+            .unwrap(),
+    );
+    let map_key = encrypted_maps
+        .name_from_bytes(long_map_key.as_ref())
+        .unwrap();
+    let short_map_key = random_key(rng);
+    assert_eq!(
+        encrypted_maps.name_from_bytes(short_map_key.as_slice()),
+        Ok(short_map_key)
+    );
+    let value = random_bytebuf(rng, 0..100);
+
+    for key in [map_key, short_map_key] {
+        encrypted_maps
+            .insert_encrypted_value(owner, map_id, key, value.clone())
+            .unwrap();
+    }
+    encrypted_maps
+        .set_user_rights(owner, map_id, user, AccessRights::Read)
+        .unwrap();
+    for name in [&long_map_name, &long_map_key] {
+        encrypted_maps.register_name(name.as_ref()).unwrap();
+    }
+
+    let maps = encrypted_maps.get_all_accessible_encrypted_maps(user);
+    assert_eq!(maps.len(), 1);
+    assert_eq!(maps[0].map_name, long_map_name);
+    assert_eq!(
+        maps[0].keyvals.iter().cloned().collect::<BTreeMap<_, _>>(),
+        BTreeMap::from([
+            (long_map_key, value.clone()),
+            (ByteBuf::from(short_map_key.as_slice().to_vec()), value),
+        ])
+    );
+
+    let too_long_name = vec![0; LongNames::MAX_NAME_LEN + 1];
+    assert_eq!(
+        encrypted_maps.name_from_bytes(&too_long_name),
+        Err("too large input".to_string())
+    );
+}
+
+#[test]
+fn fine_grained_capabilities_are_enforced() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let map_id = (owner, random_name(rng));
+    let mut encrypted_maps: EncryptedMaps<Capability> =
+        random_encrypted_maps_with_access_rights(rng);
+
+    let existing_key = random_key(rng);
+    let value = random_bytebuf(rng, 0..100);
+    encrypted_maps
+        .insert_encrypted_value(owner, map_id, existing_key, value.clone())
+        .unwrap();
+
+    let mut share = |capability| {
+        let user = random_self_authenticating_principal(rng);
+        encrypted_maps
+            .set_user_rights(owner, map_id, user, capability)
+            .unwrap();
+        user
+    };
+    let appender = share(Capability::AppendOnly);
+    let deleter = share(Capability::DeleteOnly);
+    let sharer = share(Capability::ShareRead);
+    let viewer = share(Capability::ViewMembers);
+
+    // append-only: insert new keys, but neither overwrite nor remove
+    let new_key = random_key(rng);
+    assert_eq!(
+        encrypted_maps.insert_encrypted_value(appender, map_id, new_key, value.clone()),
+        Ok(None)
+    );
+    assert_eq!(
+        encrypted_maps.insert_encrypted_value(appender, map_id, existing_key, value.clone()),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.remove_encrypted_value(appender, map_id, new_key),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.remove_map_values(appender, map_id),
+        Err("unauthorized".to_string())
+    );
+
+    // delete-only: remove, but not insert
+    assert_eq!(
+        encrypted_maps.insert_encrypted_value(deleter, map_id, random_key(rng), value.clone()),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.remove_encrypted_value(deleter, map_id, new_key),
+        Ok(Some(value.clone()))
+    );
+    assert_eq!(
+        encrypted_maps.remove_map_values(deleter, map_id),
+        Ok(vec![existing_key])
+    );
+
+    // share-read-only: grant read access to new users, but nothing else
+    let reader = random_self_authenticating_principal(rng);
+    assert_eq!(
+        encrypted_maps.set_user_rights(sharer, map_id, reader, Capability::Read),
+        Ok(None)
+    );
+    assert_eq!(
+        encrypted_maps.set_user_rights(
+            sharer,
+            map_id,
+            random_self_authenticating_principal(rng),
+            Capability::Full
+        ),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.set_user_rights(sharer, map_id, appender, Capability::Read),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.remove_user(sharer, map_id, reader),
+        Err("unauthorized".to_string())
+    );
+
+    // view-members-without-managing
+    assert_eq!(
+        encrypted_maps.get_user_rights(viewer, map_id, reader),
+        Ok(Some(Capability::Read))
+    );
+    assert_eq!(
+        encrypted_maps.set_user_rights(viewer, map_id, reader, Capability::Read),
+        Err("unauthorized".to_string())
+    );
+}
+
+#[test]
+fn maps_without_read_access_are_skipped_when_listing() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let deleter = random_self_authenticating_principal(rng);
+    let mut encrypted_maps: EncryptedMaps<Capability> =
+        random_encrypted_maps_with_access_rights(rng);
+
+    let readable_map_id = (owner, random_name(rng));
+    let unreadable_map_id = (owner, random_name(rng));
+    for (map_id, capability) in [
+        (readable_map_id, Capability::ViewMembers),
+        (unreadable_map_id, Capability::DeleteOnly),
+    ] {
+        encrypted_maps
+            .insert_encrypted_value(owner, map_id, random_key(rng), random_bytebuf(rng, 0..100))
+            .unwrap();
+        encrypted_maps
+            .set_user_rights(owner, map_id, deleter, capability)
+            .unwrap();
+    }
+    assert_eq!(
+        encrypted_maps.get_encrypted_values_for_map(deleter, unreadable_map_id),
+        Err("unauthorized".to_string())
+    );
+
+    let map_ids = |values: Vec<(_, Vec<_>)>| -> Vec<_> {
+        values.into_iter().map(|(map_id, _)| map_id).collect()
+    };
+    assert_eq!(
+        map_ids(encrypted_maps.get_all_accessible_encrypted_values(deleter)),
+        vec![readable_map_id]
+    );
+    assert_eq!(
+        map_ids(
+            encrypted_maps
+                .get_all_accessible_encrypted_values_paginated(deleter, None, 10)
+                .items
+        ),
+        vec![readable_map_id]
+    );
+    let map_names: Vec<_> = encrypted_maps
+        .get_all_accessible_encrypted_maps(deleter)
+        .into_iter()
+        .map(|map| map.map_name)
+        .collect();
+    assert_eq!(
+        map_names,
+        vec![ByteBuf::from(readable_map_id.1.as_ref().to_vec())]
+    );
+    assert_eq!(
+        encrypted_maps
+            .get_all_accessible_encrypted_maps_paginated(deleter, None, 10)
+            .items
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn backup_can_be_restored_into_empty_instance() {
+    let rng = &mut reproducible_rng();
+    let mut source = random_encrypted_maps(rng);
+    for _ in 0..3 {
+        let owner = random_self_authenticating_principal(rng);
+        let map_id = (owner, random_name(rng));
+        for _ in 0..5 {
+            let value = random_bytebuf(rng, 0..100);
+            source
+                .insert_encrypted_value(owner, map_id, random_key(rng), value)
+                .unwrap();
+        }
+        let user = random_self_authenticating_principal(rng);
+        source
+            .set_user_rights(owner, map_id, user, random_access_rights(rng))
+            .unwrap();
+    }
+
+    let canister_id = random_self_authenticating_principal(rng);
+    let mut chunks = vec![];
+    let mut cursor = None;
+    loop {
+        let chunk = source
+            .export_backup_chunk(canister_id, cursor, 300)
+            .unwrap();
+        assert_eq!(chunk.canister_id, canister_id);
+        cursor = chunk.next_cursor.clone();
+        chunks.push(chunk);
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert!(chunks.len() > 3);
+
+    let mut target = random_encrypted_maps(rng);
+    let discontinued =
+        Err("backup chunk does not continue the previously imported chunk".to_string());
+    assert_eq!(target.import_backup_chunk(chunks[1].clone()), discontinued);
+    let mut tampered = chunks[0].clone();
+    tampered.entries.pop();
+    assert_eq!(
+        target.import_backup_chunk(tampered),
+        Err("backup chunk hash mismatch".to_string())
+    );
+
+    for chunk in &chunks {
+        assert_eq!(target.import_backup_chunk(chunk.clone()), Ok(()));
+    }
+    assert_eq!(target.import_backup_chunk(chunks[0].clone()), discontinued);
+
+    let values = |encrypted_maps: &EncryptedMaps<AccessRights>| {
+        encrypted_maps
+            .mapkey_vals
+            .iter()
+            .map(|entry| (*entry.key(), entry.value()))
+            .collect::<Vec<_>>()
+    };
+    let access_control = |encrypted_maps: &EncryptedMaps<AccessRights>| {
+        encrypted_maps
+            .key_manager
+            .access_control
+            .iter()
+            .map(|entry| (*entry.key(), entry.value()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(values(&target), values(&source));
+    assert_eq!(access_control(&target), access_control(&source));
+    assert_eq!(
+        target.key_manager.shared_keys.len(),
+        source.key_manager.shared_keys.len()
+    );
+}
+
+/// Access rights with fine-grained capabilities that are not a linear ladder.
+#[derive(
+    candid::CandidType,
+    serde::Serialize,
+    serde::Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+    strum_macros::FromRepr,
+    strum_macros::EnumIter,
+)]
+#[repr(u8)]
+enum Capability {
+    Read = 0,
+    AppendOnly = 1,
+    DeleteOnly = 2,
+    ShareRead = 3,
+    ViewMembers = 4,
+    Full = 5,
+}
+
+impl Storable for Capability {
+    fn into_bytes(self) -> Vec<u8> {
+        vec![self as u8]
+    }
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(vec![*self as u8])
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self::from_repr(bytes[0]).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1,
+        is_fixed_size: true,
+    };
+}
+
+impl AccessControl for Capability {
+    fn can_read(&self) -> bool {
+        !matches!(self, Capability::DeleteOnly)
+    }
+
+    fn can_write(&self) -> bool {
+        matches!(self, Capability::Full)
+    }
+
+    fn can_get_user_rights(&self) -> bool {
+        matches!(self, Capability::ViewMembers | Capability::Full)
+    }
+
+    fn can_set_user_rights(&self) -> bool {
+        matches!(self, Capability::Full)
+    }
+
+    fn owner_rights() -> Self {
+        Capability::Full
+    }
+
+    fn can_insert(&self) -> bool {
+        matches!(self, Capability::AppendOnly | Capability::Full)
+    }
+
+    fn can_remove(&self) -> bool {
+        matches!(self, Capability::DeleteOnly | Capability::Full)
+    }
+
+    fn can_share(&self, access_rights: &Self) -> bool {
+        match self {
+            Capability::ShareRead => *access_rights == Capability::Read,
+            _ => self.can_set_user_rights(),
+        }
+    }
+}
+
+#[test]
+fn recovered_maps_are_accessible_to_new_owner() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let new_owner = random_self_authenticating_principal(rng);
+    let guardian = random_self_authenticating_principal(rng);
+    let map_id = (owner, random_name(rng));
+    let key = random_key(rng);
+    let value = random_bytebuf(rng, 0..100);
+    let mut encrypted_maps = random_encrypted_maps(rng);
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    encrypted_maps.enable_recovery(
+        RecoveryConfig {
+            recovery_delay_ns: 0,
+        },
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+        memory_manager.get(MemoryId::new(3)),
+    );
+    encrypted_maps
+        .insert_encrypted_value(owner, map_id, key, value.clone())
+        .unwrap();
+    encrypted_maps
+        .set_recovery_guardians(owner, vec![guardian], 1)
+        .unwrap();
+    encrypted_maps
+        .approve_recovery(guardian, owner, new_owner, 0)
+        .unwrap();
+    encrypted_maps
+        .complete_recovery(guardian, owner, new_owner, 0)
+        .unwrap();
+
+    assert_eq!(
+        encrypted_maps.get_all_accessible_encrypted_values(new_owner),
+        vec![(map_id, vec![(key, value.clone())])]
+    );
+    assert_eq!(
+        encrypted_maps.get_all_accessible_encrypted_values(owner),
+        vec![]
+    );
+    assert_eq!(
+        encrypted_maps.get_encrypted_value(new_owner, map_id, key),
+        Ok(Some(value))
+    );
+    assert_eq!(
+        encrypted_maps.get_encrypted_value(owner, map_id, key),
+        Err("unauthorized".to_string())
+    );
+}
+
+#[test]
+fn migrated_maps_are_accessible_to_new_principal() {
+    let rng = &mut reproducible_rng();
+    let old_principal = random_self_authenticating_principal(rng);
+    let new_principal = random_self_authenticating_principal(rng);
+    let map_id = (old_principal, random_name(rng));
+    let key = random_key(rng);
+    let value = random_bytebuf(rng, 0..100);
+    let mut encrypted_maps = random_encrypted_maps(rng);
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    encrypted_maps.enable_migrations(
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+    );
+    encrypted_maps
+        .insert_encrypted_value(old_principal, map_id, key, value.clone())
+        .unwrap();
+    encrypted_maps
+        .request_migration(old_principal, new_principal)
+        .unwrap();
+    assert_eq!(
+        encrypted_maps.confirm_migration(new_principal, old_principal),
+        Ok(vec![])
+    );
+
+    // the values stay under the original map ID
+    assert_eq!(
+        encrypted_maps.get_all_accessible_encrypted_values(new_principal),
+        vec![(map_id, vec![(key, value)])]
+    );
+    assert_eq!(
+        encrypted_maps.get_all_accessible_encrypted_values(old_principal),
+        vec![]
+    );
+    assert_eq!(
+        encrypted_maps
+            .insert_encrypted_value(old_principal, map_id, key, random_bytebuf(rng, 0..100))
+            .map(|_| ()),
+        Err("unauthorized".to_string())
+    );
+}
+
+#[test]
+fn public_maps_can_be_read_but_not_written_by_anyone() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let map_id = (owner, random_name(rng));
+    let key = random_key(rng);
+    let value = random_bytebuf(rng, 0..100);
+    let mut encrypted_maps = random_encrypted_maps(rng);
+    encrypted_maps.enable_public_maps(
+        MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0)),
+    );
+    encrypted_maps
+        .insert_encrypted_value(owner, map_id, key, value.clone())
+        .unwrap();
+    encrypted_maps
+        .make_map_public(owner, map_id, AccessRights::Read)
+        .unwrap();
+
+    let anonymous = Principal::anonymous();
+    assert_eq!(
+        encrypted_maps.get_encrypted_value(anonymous, map_id, key),
+        Ok(Some(value.clone()))
+    );
+    assert_eq!(
+        encrypted_maps.get_encrypted_values_for_map(anonymous, map_id),
+        Ok(vec![(key, value)])
+    );
+    assert_eq!(
+        encrypted_maps
+            .insert_encrypted_value(anonymous, map_id, key, random_bytebuf(rng, 0..100))
+            .map(|_| ()),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        encrypted_maps
+            .remove_encrypted_value(anonymous, map_id, key)
+            .map(|_| ()),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.get_public_map_ids_paginated(None, 10).items,
+        vec![(map_id, AccessRights::Read)]
+    );
+}
+
+#[test]
+fn deleted_maps_are_removed_for_all_users_and_leave_a_tombstone() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let manager = random_self_authenticating_principal(rng);
+    let reader = random_self_authenticating_principal(rng);
+    let map_id = (owner, random_name(rng));
+    let other_map_id = (owner, random_name(rng));
+    let key = random_key(rng);
+    let value = random_bytebuf(rng, 0..100);
+    let mut encrypted_maps = random_encrypted_maps(rng);
+
+    assert_eq!(
+        encrypted_maps.delete_map(owner, map_id, 1),
+        Err("key deletion is not enabled".to_string())
+    );
+    encrypted_maps.enable_map_deletion(
+        KeyDeletionConfig::default(),
+        MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0)),
+    );
+    for id in [map_id, other_map_id] {
+        encrypted_maps
+            .insert_encrypted_value(owner, id, key, value.clone())
+            .unwrap();
+    }
+    for (user, access_rights) in [
+        (manager, AccessRights::ReadWriteManage),
+        (reader, AccessRights::Read),
+    ] {
+        encrypted_maps
+            .set_user_rights(owner, map_id, user, access_rights)
+            .unwrap();
+    }
+
+    assert_eq!(
+        encrypted_maps.delete_map(manager, map_id, 1),
+        Err("unauthorized".to_string())
+    );
+    let mut revoked_shares = encrypted_maps.delete_map(owner, map_id, 1).unwrap();
+    revoked_shares.sort();
+    let mut expected_shares = vec![
+        (manager, AccessRights::ReadWriteManage),
+        (reader, AccessRights::Read),
+    ];
+    expected_shares.sort();
+    assert_eq!(revoked_shares, expected_shares);
+
+    assert_eq!(
+        encrypted_maps.get_map_tombstone(map_id),
+        Some(KeyTombstone {
+            deleted_by: owner,
+            deleted_at_ns: 1,
+        })
+    );
+    assert_eq!(encrypted_maps.get_map_tombstone(other_map_id), None);
+    for user in [manager, reader] {
+        assert_eq!(encrypted_maps.get_accessible_shared_map_names(user), vec![]);
+    }
+    assert_eq!(
+        encrypted_maps.get_owned_non_empty_map_names(owner),
+        vec![other_map_id.1]
+    );
+    assert_eq!(
+        encrypted_maps.get_encrypted_values_for_map(owner, map_id),
+        Err("vetKey was deleted".to_string())
+    );
+    assert_eq!(
+        encrypted_maps
+            .insert_encrypted_value(owner, map_id, key, value)
+            .map(|_| ()),
+        Err("vetKey was deleted".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.set_user_rights(owner, map_id, reader, AccessRights::Read),
+        Err("vetKey was deleted".to_string())
+    );
+    assert_eq!(
+        encrypted_maps.delete_map(owner, map_id, 2),
+        Err("vetKey was deleted".to_string())
+    );
+}
+
+#[test]
+fn managers_can_delete_maps_if_configured() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let manager = random_self_authenticating_principal(rng);
+    let writer = random_self_authenticating_principal(rng);
+    let map_id = (owner, random_name(rng));
+    let mut encrypted_maps = random_encrypted_maps(rng);
+    encrypted_maps.enable_map_deletion(
+        KeyDeletionConfig {
+            managers_can_delete: true,
+        },
+        MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0)),
+    );
+    for (user, access_rights) in [
+        (manager, AccessRights::ReadWriteManage),
+        (writer, AccessRights::ReadWrite),
+    ] {
+        encrypted_maps
+            .set_user_rights(owner, map_id, user, access_rights)
+            .unwrap();
+    }
+
+    assert_eq!(
+        encrypted_maps.delete_map(writer, map_id, 1),
+        Err("unauthorized".to_string())
+    );
+    assert!(encrypted_maps.delete_map(manager, map_id, 1).is_ok());
+    assert_eq!(
+        encrypted_maps
+            .get_map_tombstone(map_id)
+            .map(|tombstone| tombstone.deleted_by),
+        Some(manager)
+    );
+}
+
+#[test]
+fn frozen_maps_cannot_be_modified() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let writer = random_self_authenticating_principal(rng);
+    let map_id = (owner, random_name(rng));
+    let key = random_key(rng);
+    let mut encrypted_maps = random_encrypted_maps(rng);
+    encrypted_maps.enable_key_freezing(
+        FreezeConfig {
+            unfreeze_delay_ns: 0,
+        },
+        MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0)),
+    );
+    encrypted_maps
+        .set_user_rights(owner, map_id, writer, AccessRights::ReadWrite)
+        .unwrap();
+    encrypted_maps
+        .insert_encrypted_value(writer, map_id, key, random_bytebuf(rng, 0..100))
+        .unwrap();
+
+    assert_eq!(encrypted_maps.freeze_map(owner, map_id, false), Ok(()));
+    let frozen = Err("vetKey is frozen".to_string());
+    assert_eq!(
+        encrypted_maps
+            .insert_encrypted_value(writer, map_id, key, random_bytebuf(rng, 0..100))
+            .map(|_| ()),
+        frozen
+    );
+    assert_eq!(
+        encrypted_maps
+            .remove_encrypted_value(writer, map_id, key)
+            .map(|_| ()),
+        frozen
+    );
+    assert_eq!(
+        encrypted_maps.remove_map_values(writer, map_id).map(|_| ()),
+        frozen
+    );
+    // values can still be read, but not decrypted with a new vetKey
+    assert!(encrypted_maps
+        .get_encrypted_value(writer, map_id, key)
+        .unwrap()
+        .is_some());
+    // the owner is not locked out
+    assert!(encrypted_maps
+        .insert_encrypted_value(owner, map_id, key, random_bytebuf(rng, 0..100))
+        .is_ok());
+
+    assert_eq!(encrypted_maps.freeze_map(owner, map_id, true), Ok(()));
+    assert_eq!(
+        encrypted_maps
+            .remove_encrypted_value(owner, map_id, key)
+            .map(|_| ()),
+        frozen
+    );
+    assert_eq!(encrypted_maps.unfreeze_map(owner, map_id, 0), Ok(Some(0)));
+    assert_eq!(encrypted_maps.unfreeze_map(owner, map_id, 0), Ok(None));
+    assert!(encrypted_maps
+        .remove_encrypted_value(writer, map_id, key)
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+fn event_listener_is_notified_of_mutations() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let map_id = (caller, random_name(rng));
+    let key = random_key(rng);
+    let other_key = random_key(rng);
+    let mut encrypted_maps = random_encrypted_maps(rng);
+    let events = Rc::new(RefCell::new(Vec::new()));
+    encrypted_maps.set_event_listener(RecordingListener(events.clone()));
+
+    encrypted_maps
+        .set_user_rights(caller, map_id, user, AccessRights::ReadWrite)
+        .unwrap();
+    encrypted_maps
+        .insert_encrypted_value(user, map_id, key, random_bytebuf(rng, 0..100))
+        .unwrap();
+    encrypted_maps
+        .insert_encrypted_value(caller, map_id, other_key, random_bytebuf(rng, 0..100))
+        .unwrap();
+    encrypted_maps
+        .remove_encrypted_value(user, map_id, key)
+        .unwrap();
+    // removing a missing value is not an event
+    encrypted_maps
+        .remove_encrypted_value(user, map_id, key)
+        .unwrap();
+    encrypted_maps.remove_map_values(caller, map_id).unwrap();
+    // the future is dropped, so no vetKD call is made
+    drop(
+        encrypted_maps
+            .get_encrypted_vetkey(user, map_id, random_bytebuf(rng, 48..49))
+            .unwrap(),
+    );
+    encrypted_maps.remove_user(caller, map_id, user).unwrap();
+    // revoking access of a user without access is not an event
+    encrypted_maps.remove_user(caller, map_id, user).unwrap();
+    // unauthorized mutations are not events
+    assert!(encrypted_maps
+        .insert_encrypted_value(user, map_id, key, random_bytebuf(rng, 0..100))
+        .is_err());
+
+    assert_eq!(
+        *events.borrow(),
+        vec![
+            Event::Share(caller, map_id, user, AccessRights::ReadWrite),
+            Event::ValueInserted(user, map_id, key),
+            Event::ValueInserted(caller, map_id, other_key),
+            Event::ValueRemoved(user, map_id, key),
+            Event::ValueRemoved(caller, map_id, other_key),
+            Event::VetKeyDerived(user, map_id),
+            Event::Revoke(caller, map_id, user, AccessRights::ReadWrite),
+        ]
+    );
+}
+
+#[test]
+fn event_listener_is_notified_of_accepted_invitations_with_the_inviter() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let manager = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let map_id = (owner, random_name(rng));
+    let mut encrypted_maps = random_encrypted_maps(rng);
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    encrypted_maps.enable_invitations(
+        InvitationsConfig {
+            max_pending_invitations_per_recipient: 10,
+        },
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+    );
+    encrypted_maps
+        .set_user_rights(owner, map_id, manager, AccessRights::ReadWriteManage)
+        .unwrap();
+    encrypted_maps.accept_invitation(manager, map_id).unwrap();
+    let events = Rc::new(RefCell::new(Vec::new()));
+    encrypted_maps.set_event_listener(RecordingListener(events.clone()));
+
+    encrypted_maps
+        .set_user_rights(manager, map_id, user, AccessRights::Read)
+        .unwrap();
+    assert!(events.borrow().is_empty());
+    encrypted_maps.accept_invitation(user, map_id).unwrap();
+
+    assert_eq!(
+        *events.borrow(),
+        vec![Event::Share(manager, map_id, user, AccessRights::Read)]
+    );
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum Event {
+    Share(Principal, KeyId, Principal, AccessRights),
+    Revoke(Principal, KeyId, Principal, AccessRights),
+    ValueInserted(Principal, KeyId, MapKey),
+    ValueRemoved(Principal, KeyId, MapKey),
+    VetKeyDerived(Principal, KeyId),
+}
+
+struct RecordingListener(Rc<RefCell<Vec<Event>>>);
+
+impl EventListener<AccessRights> for RecordingListener {
+    fn on_share(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        user: Principal,
+        rights: AccessRights,
+    ) {
+        self.0
+            .borrow_mut()
+            .push(Event::Share(caller, key_id, user, rights));
+    }
+
+    fn on_revoke(
+        &mut self,
+        caller: Principal,
+        key_id: KeyId,
+        user: Principal,
+        rights: AccessRights,
+    ) {
+        self.0
+            .borrow_mut()
+            .push(Event::Revoke(caller, key_id, user, rights));
+    }
+
+    fn on_value_inserted(&mut self, caller: Principal, map_id: KeyId, map_key: MapKey) {
+        self.0
+            .borrow_mut()
+            .push(Event::ValueInserted(caller, map_id, map_key));
+    }
+
+    fn on_value_removed(&mut self, caller: Principal, map_id: KeyId, map_key: MapKey) {
+        self.0
+            .borrow_mut()
+            .push(Event::ValueRemoved(caller, map_id, map_key));
+    }
+
+    fn on_vetkey_derived(&mut self, caller: Principal, key_id: KeyId) {
+        self.0
+            .borrow_mut()
+            .push(Event::VetKeyDerived(caller, key_id));
+    }
+}
+
 fn random_encrypted_maps<R: Rng + CryptoRng>(rng: &mut R) -> EncryptedMaps<AccessRights> {
+    random_encrypted_maps_with_access_rights(rng)
+}
+
+fn random_encrypted_maps_with_access_rights<T: AccessControl, R: Rng + CryptoRng>(
+    rng: &mut R,
+) -> EncryptedMaps<T> {
     let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
     let (memory_id_encrypted_maps, memory_ids_key_manager) = random_unique_memory_ids(rng);
     let domain_separator_len = rng.gen_range(0..32);
@@ -1,19 +1,35 @@
 use std::collections::BTreeSet;
 
 use assert_matches::assert_matches;
+use candid::Principal;
+use ic_bls12_381::hash_to_curve::{ExpandMsgXmd, HashToCurve};
+use ic_bls12_381::{G1Affine, G1Projective, G2Affine, Scalar};
+use ic_cdk::call::CallRejected;
 use ic_cdk_management_canister::{VetKDCurve, VetKDKeyId};
+use ic_certification::{fork, labeled, leaf, Certificate, Delegation, HashTree};
 use ic_stable_structures::{
-    memory_manager::{MemoryId, MemoryManager},
-    DefaultMemoryImpl,
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    DefaultMemoryImpl, StableCell,
 };
-use ic_vetkeys::key_manager::KeyManager;
-use ic_vetkeys::types::AccessRights;
+use ic_vetkeys::key_manager::{
+    key_id_to_vetkd_input, key_id_to_vetkd_input_for_purpose, tenant_key_id, ApprovalStatus,
+    BackupSection, CallPolicy, CertifiedQueryVerifier, CertifiedResponse, CyclesLedger,
+    DelegationPolicy, FreezeConfig, FreezeState, InboxConfig, InboxMessage, InheritanceConfig,
+    InheritancePlan, InvitationsConfig, KeyDeletionConfig, KeyManager, KeyTombstone, LimitError,
+    LongNames, OrganizationAction, OrganizationsConfig, PendingRecovery, QuotaConfig, RateLimiter,
+    RecoveryConfig, RecoveryGuardians, RecoveryStatus, StableStateError, TokenBucketConfig,
+    TokenBucketRateLimiter, VetKDCallError,
+};
+use ic_vetkeys::types::{AccessRights, ByteBuf, KeyManagerConfig, SchemaVersions, MAX_PAGE_SIZE};
+use ic_vetkeys::MasterPublicKey;
 use ic_vetkeys_test_utils::{
-    random_access_rights, random_name, random_self_authenticating_principal,
+    random_access_rights, random_bytebuf, random_name, random_self_authenticating_principal,
     random_unique_memory_ids, random_utf8_string, reproducible_rng,
 };
 use rand::{CryptoRng, Rng};
 
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
 #[test]
 fn can_init_memory() {
     // prevent the compiler from optimizing away the function call
@@ -48,6 +64,48 @@ fn get_accessible_shared_key_ids_works_correctly() {
     }
 }
 
+#[test]
+fn get_accessible_shared_key_ids_paginated_works_correctly() {
+    let rng = &mut reproducible_rng();
+    let user_to_be_added = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager(rng);
+
+    for _ in 0..10 {
+        let caller = random_self_authenticating_principal(rng);
+        let name = random_name(rng);
+        key_manager
+            .set_user_rights(
+                caller,
+                (caller, name),
+                user_to_be_added,
+                random_access_rights(rng),
+            )
+            .unwrap();
+    }
+
+    let limit = rng.gen_range(1..4);
+    let mut paginated_key_ids = vec![];
+    let mut start_after = None;
+    loop {
+        let page = key_manager.get_accessible_shared_key_ids_paginated(
+            user_to_be_added,
+            start_after,
+            limit,
+        );
+        assert!(page.items.len() <= limit);
+        paginated_key_ids.extend(page.items);
+        start_after = page.next_cursor;
+        if start_after.is_none() {
+            break;
+        }
+    }
+
+    assert_eq!(
+        paginated_key_ids,
+        key_manager.get_accessible_shared_key_ids(user_to_be_added)
+    );
+}
+
 #[test]
 fn can_get_shared_user_access_for_key() {
     let rng = &mut reproducible_rng();
@@ -78,6 +136,74 @@ fn can_get_shared_user_access_for_key() {
     }
 }
 
+#[test]
+fn get_shared_user_access_for_key_paginated_works_correctly() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let key_id = (caller, random_name(rng));
+    let mut key_manager = random_key_manager(rng);
+
+    for _ in 0..10 {
+        let user_to_be_added = random_self_authenticating_principal(rng);
+        key_manager
+            .set_user_rights(caller, key_id, user_to_be_added, random_access_rights(rng))
+            .unwrap();
+    }
+
+    let limit = rng.gen_range(1..4);
+    let mut paginated_shared_access = vec![];
+    let mut start_after = None;
+    loop {
+        let page = key_manager
+            .get_shared_user_access_for_key_paginated(caller, key_id, start_after, limit)
+            .unwrap();
+        assert!(page.items.len() <= limit);
+        paginated_shared_access.extend(page.items);
+        start_after = page.next_cursor;
+        if start_after.is_none() {
+            break;
+        }
+    }
+
+    assert_eq!(
+        Ok(paginated_shared_access),
+        key_manager.get_shared_user_access_for_key(caller, key_id)
+    );
+
+    let unauthorized = random_self_authenticating_principal(rng);
+    assert_eq!(
+        key_manager.get_shared_user_access_for_key_paginated(unauthorized, key_id, None, limit),
+        Err("unauthorized".to_string())
+    );
+}
+
+#[test]
+fn paginated_listing_clamps_limit_to_max_page_size() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let key_id = (caller, random_name(rng));
+    let mut key_manager = random_key_manager(rng);
+
+    for _ in 0..=MAX_PAGE_SIZE {
+        let user_to_be_added = random_self_authenticating_principal(rng);
+        key_manager
+            .set_user_rights(caller, key_id, user_to_be_added, random_access_rights(rng))
+            .unwrap();
+    }
+
+    let page = key_manager
+        .get_shared_user_access_for_key_paginated(caller, key_id, None, usize::MAX)
+        .unwrap();
+    assert_eq!(page.items.len(), MAX_PAGE_SIZE);
+    assert!(page.next_cursor.is_some());
+
+    let page = key_manager
+        .get_shared_user_access_for_key_paginated(caller, key_id, page.next_cursor, usize::MAX)
+        .unwrap();
+    assert_eq!(page.items.len(), 1);
+    assert_eq!(page.next_cursor, None);
+}
+
 #[test]
 fn get_shared_user_access_for_key_fails_for_unauthorized() {
     let rng = &mut reproducible_rng();
@@ -179,99 +305,3113 @@ fn other_user_can_manage_key() {
 }
 
 #[test]
-fn can_remove_user_from_key() {
+fn delegation_policy_restricts_managers() {
     let rng = &mut reproducible_rng();
-    let caller = random_self_authenticating_principal(rng);
-    let name = random_name(rng);
+    let owner = random_self_authenticating_principal(rng);
+    let manager1 = random_self_authenticating_principal(rng);
+    let manager2 = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
     let mut key_manager = random_key_manager(rng);
+    key_manager.set_delegation_policy(DelegationPolicy {
+        restrict_grants_to_own_rights: true,
+        protect_peers: true,
+        owner_only_manager_management: false,
+    });
 
-    let user_to_be_added = random_self_authenticating_principal(rng);
-    let access_rights = random_access_rights(rng);
+    for manager in [manager1, manager2] {
+        key_manager
+            .set_user_rights(owner, key_id, manager, AccessRights::ReadWriteManage)
+            .unwrap();
+    }
+
+    // managers can manage users with lower rights
     assert_eq!(
-        key_manager.set_user_rights(caller, (caller, name), user_to_be_added, access_rights,),
+        key_manager.set_user_rights(manager1, key_id, user, AccessRights::ReadWrite),
         Ok(None)
     );
     assert_eq!(
-        key_manager.remove_user(caller, (caller, name), user_to_be_added,),
-        Ok(Some(access_rights))
+        key_manager.set_user_rights(manager1, key_id, user, AccessRights::Read),
+        Ok(Some(AccessRights::ReadWrite))
     );
+
+    // but not peers with equal rights
     assert_eq!(
-        key_manager.get_user_rights(caller, (caller, name), user_to_be_added),
-        Ok(None)
+        key_manager.set_user_rights(manager1, key_id, manager2, AccessRights::Read),
+        Err("cannot manage user with equal or higher rights".to_string())
+    );
+    assert_eq!(
+        key_manager.remove_user(manager1, key_id, manager2),
+        Err("cannot manage user with equal or higher rights".to_string())
+    );
+
+    // the owner is not restricted and managers can remove themselves
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id, manager2, AccessRights::ReadWrite),
+        Ok(Some(AccessRights::ReadWriteManage))
+    );
+    assert_eq!(
+        key_manager.remove_user(manager1, key_id, manager1),
+        Ok(Some(AccessRights::ReadWriteManage))
     );
 }
 
 #[test]
-fn add_or_remove_user_by_unauthorized_fails() {
+fn owner_only_manager_management_is_enforced() {
     let rng = &mut reproducible_rng();
-    let caller = random_self_authenticating_principal(rng);
-    let name = random_name(rng);
+    let owner = random_self_authenticating_principal(rng);
+    let manager1 = random_self_authenticating_principal(rng);
+    let manager2 = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
     let mut key_manager = random_key_manager(rng);
+    key_manager.set_delegation_policy(DelegationPolicy {
+        owner_only_manager_management: true,
+        ..Default::default()
+    });
 
-    let mut unauthorized_callers = vec![random_self_authenticating_principal(rng)];
+    key_manager
+        .set_user_rights(owner, key_id, manager1, AccessRights::ReadWriteManage)
+        .unwrap();
 
-    for access_rights in [AccessRights::Read, AccessRights::ReadWrite] {
-        let user_to_be_added = random_self_authenticating_principal(rng);
+    assert_eq!(
+        key_manager.set_user_rights(manager1, key_id, user, AccessRights::ReadWriteManage),
+        Err("only the owner can manage managers".to_string())
+    );
+    assert_eq!(
+        key_manager.set_user_rights(manager1, key_id, user, AccessRights::ReadWrite),
+        Ok(None)
+    );
+    assert_eq!(
+        key_manager.set_user_rights(manager1, key_id, user, AccessRights::Read),
+        Ok(Some(AccessRights::ReadWrite))
+    );
 
-        assert_matches!(
-            key_manager.set_user_rights(caller, (caller, name), user_to_be_added, access_rights,),
-            Ok(_)
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id, manager2, AccessRights::ReadWriteManage),
+        Ok(None)
+    );
+    assert_eq!(
+        key_manager.remove_user(manager1, key_id, manager2),
+        Err("only the owner can manage managers".to_string())
+    );
+    assert_eq!(
+        key_manager.remove_user(owner, key_id, manager2),
+        Ok(Some(AccessRights::ReadWriteManage))
+    );
+}
+
+#[test]
+fn token_bucket_rate_limiter_limits_callers() {
+    let rng = &mut reproducible_rng();
+    let caller1 = random_self_authenticating_principal(rng);
+    let caller2 = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager(rng);
+    key_manager.set_rate_limiter(TokenBucketRateLimiter::new(
+        Some(TokenBucketConfig {
+            capacity: 2,
+            refill_interval_ns: 100,
+        }),
+        None,
+    ));
+
+    let now = rng.gen_range(0..1_000_000_000);
+    for _ in 0..2 {
+        assert_eq!(
+            key_manager.try_acquire_vetkey_derivation(caller1, now),
+            Ok(())
         );
+    }
+    assert_eq!(
+        key_manager.try_acquire_vetkey_derivation(caller1, now + 30),
+        Err(LimitError::CallerRateLimited { retry_after_ns: 70 })
+    );
+    assert_eq!(
+        key_manager.try_acquire_vetkey_derivation(caller2, now),
+        Ok(())
+    );
 
-        unauthorized_callers.push(user_to_be_added);
+    assert_eq!(
+        key_manager.try_acquire_vetkey_derivation(caller1, now + 100),
+        Ok(())
+    );
+    assert_eq!(
+        key_manager.try_acquire_vetkey_derivation(caller1, now + 150),
+        Err(LimitError::CallerRateLimited { retry_after_ns: 50 })
+    );
+}
+
+#[test]
+fn token_bucket_rate_limiter_limits_globally() {
+    let rng = &mut reproducible_rng();
+    let mut rate_limiter = TokenBucketRateLimiter::new(
+        Some(TokenBucketConfig {
+            capacity: 1,
+            refill_interval_ns: 1_000,
+        }),
+        Some(TokenBucketConfig {
+            capacity: 3,
+            refill_interval_ns: 10,
+        }),
+    );
+
+    for _ in 0..3 {
+        let caller = random_self_authenticating_principal(rng);
+        assert_eq!(rate_limiter.try_acquire(caller, 0), Ok(()));
+    }
+    let caller = random_self_authenticating_principal(rng);
+    assert_eq!(
+        rate_limiter.try_acquire(caller, 5),
+        Err(LimitError::GlobalRateLimited { retry_after_ns: 5 })
+    );
+
+    // a caller that is rate limited does not consume global tokens
+    assert_eq!(rate_limiter.try_acquire(caller, 10), Ok(()));
+    assert_eq!(
+        rate_limiter.try_acquire(caller, 20),
+        Err(LimitError::CallerRateLimited {
+            retry_after_ns: 990
+        })
+    );
+    let other_caller = random_self_authenticating_principal(rng);
+    assert_eq!(rate_limiter.try_acquire(other_caller, 20), Ok(()));
+}
+
+#[test]
+fn share_quotas_are_enforced() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let key_id_1 = (owner, random_name(rng));
+    let key_id_2 = (owner, random_name(rng));
+    let key_id_3 = (owner, random_name(rng));
+    let users: Vec<_> = (0..3)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_quotas(
+            QuotaConfig {
+                max_keys_per_owner: Some(2),
+                max_shares_per_owner: Some(3),
+            },
+            memory(),
+            memory(),
+        )
+    });
+
+    for key_id in [key_id_1, key_id_2] {
+        key_manager
+            .set_user_rights(owner, key_id, users[0], AccessRights::Read)
+            .unwrap();
     }
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id_3, users[0], AccessRights::Read),
+        Err(LimitError::KeyQuotaExceeded {
+            max_keys_per_owner: 2
+        }
+        .to_string())
+    );
 
-    for unauthorized_caller in unauthorized_callers {
-        for target in [random_self_authenticating_principal(rng), caller] {
-            assert_eq!(
-                key_manager.remove_user(unauthorized_caller, (caller, name), target),
-                Err("unauthorized".to_string())
-            );
-            assert_eq!(
-                key_manager.set_user_rights(
-                    unauthorized_caller,
-                    (caller, name),
-                    target,
-                    AccessRights::Read,
-                ),
-                Err("unauthorized".to_string())
+    key_manager
+        .set_user_rights(owner, key_id_1, users[1], AccessRights::Read)
+        .unwrap();
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id_1, users[2], AccessRights::Read),
+        Err(LimitError::ShareQuotaExceeded {
+            max_shares_per_owner: 3
+        }
+        .to_string())
+    );
+
+    // modifying existing shares is not restricted
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id_1, users[1], AccessRights::ReadWrite),
+        Ok(Some(AccessRights::Read))
+    );
+
+    key_manager.remove_user(owner, key_id_2, users[0]).unwrap();
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id_3, users[0], AccessRights::Read),
+        Ok(None)
+    );
+}
+
+#[test]
+fn existing_shares_are_counted_when_quotas_are_enabled() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let key_id_1 = (owner, random_name(rng));
+    let key_id_2 = (owner, random_name(rng));
+    let users: Vec<_> = (0..3)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let init = |quotas: Option<QuotaConfig>| {
+        let mut key_manager = KeyManager::<AccessRights>::init(
+            "quotas",
+            bls12_381_dfx_test_key(),
+            memory_manager.get(MemoryId::new(0)),
+            memory_manager.get(MemoryId::new(1)),
+            memory_manager.get(MemoryId::new(2)),
+        );
+        key_manager.enable_invitations(
+            InvitationsConfig {
+                max_pending_invitations_per_recipient: 10,
+            },
+            memory_manager.get(MemoryId::new(3)),
+            memory_manager.get(MemoryId::new(4)),
+            memory_manager.get(MemoryId::new(5)),
+        );
+        if let Some(quotas) = quotas {
+            key_manager.enable_quotas(
+                quotas,
+                memory_manager.get(MemoryId::new(6)),
+                memory_manager.get(MemoryId::new(7)),
             );
         }
-    }
+        key_manager
+    };
+
+    let mut key_manager = init(None);
+    key_manager
+        .set_user_rights(owner, key_id_1, users[0], AccessRights::Read)
+        .unwrap();
+    key_manager.accept_invitation(users[0], key_id_1).unwrap();
+    key_manager
+        .set_user_rights(owner, key_id_1, users[1], AccessRights::Read)
+        .unwrap();
+
+    // the share and the pending invitation count after an upgrade
+    let mut key_manager = init(Some(QuotaConfig {
+        max_keys_per_owner: Some(1),
+        max_shares_per_owner: Some(2),
+    }));
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id_1, users[2], AccessRights::Read),
+        Err(LimitError::ShareQuotaExceeded {
+            max_shares_per_owner: 2
+        }
+        .to_string())
+    );
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id_2, users[2], AccessRights::Read),
+        Err(LimitError::ShareQuotaExceeded {
+            max_shares_per_owner: 2
+        }
+        .to_string())
+    );
+
+    // declining an invitation frees its share, but not the vetKey
+    key_manager.decline_invitation(users[1], key_id_1).unwrap();
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id_2, users[2], AccessRights::Read),
+        Err(LimitError::KeyQuotaExceeded {
+            max_keys_per_owner: 1
+        }
+        .to_string())
+    );
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id_1, users[2], AccessRights::Read),
+        Ok(None)
+    );
+
+    // the usage is kept across upgrades and freed once all shares are removed
+    let mut key_manager = init(Some(QuotaConfig {
+        max_keys_per_owner: Some(1),
+        max_shares_per_owner: Some(2),
+    }));
+    key_manager.remove_user(owner, key_id_1, users[0]).unwrap();
+    key_manager.remove_user(owner, key_id_1, users[2]).unwrap();
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id_2, users[2], AccessRights::Read),
+        Ok(None)
+    );
 }
 
 #[test]
-fn can_instantiate_two_key_managers() {
+fn cycles_ledger_tracks_balances() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let other = random_self_authenticating_principal(rng);
     let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
-    let key_manager_1 = KeyManager::<AccessRights>::init(
-        "key_manager_1",
-        bls12_381_dfx_test_key(),
-        memory_manager.get(MemoryId::new(0)),
-        memory_manager.get(MemoryId::new(1)),
-        memory_manager.get(MemoryId::new(2)),
+    let mut ledger = CyclesLedger::init(memory_manager.get(MemoryId::new(0)));
+
+    assert_eq!(ledger.balance(caller), 0);
+    assert_eq!(ledger.deposit(caller, 100), 100);
+    assert_eq!(ledger.deposit(caller, 50), 150);
+    assert_eq!(ledger.balance(other), 0);
+
+    assert_eq!(ledger.debit(caller, 120), Ok(30));
+    assert_eq!(
+        ledger.debit(caller, 31),
+        Err("insufficient cycles balance: 30 available, 31 required".to_string())
     );
-    let key_manager_2 = KeyManager::<AccessRights>::init(
-        "key_manager_2",
-        bls12_381_dfx_test_key(),
-        memory_manager.get(MemoryId::new(3)),
-        memory_manager.get(MemoryId::new(4)),
-        memory_manager.get(MemoryId::new(5)),
+    assert_eq!(ledger.balance(caller), 30);
+    assert_eq!(ledger.debit(caller, 30), Ok(0));
+    assert!(ledger.balances.is_empty());
+    assert_eq!(ledger.deposit(caller, u128::MAX), u128::MAX);
+    assert_eq!(ledger.deposit(caller, 1), u128::MAX);
+}
+
+#[test]
+fn long_names_are_canonicalized_to_distinct_hashes() {
+    let rng = &mut reproducible_rng();
+    let short_name = random_bytebuf(rng, 0..33);
+    assert_eq!(
+        LongNames::canonical_name(short_name.as_ref())
+            .unwrap()
+            .as_slice(),
+        short_name.as_ref()
+    );
+
+    let long_name = random_bytebuf(rng, 33..LongNames::MAX_NAME_LEN + 1);
+    let canonical_name = LongNames::canonical_name(long_name.as_ref()).unwrap();
+    assert_eq!(canonical_name.as_slice().len(), 32);
+    assert_eq!(
+        LongNames::canonical_name(long_name.as_ref()),
+        Ok(canonical_name)
+    );
+    let mut other_long_name = long_name.as_ref().to_vec();
+    other_long_name.push(0);
+    assert_ne!(
+        LongNames::canonical_name(&other_long_name).unwrap(),
+        canonical_name
+    );
+    assert_eq!(
+        LongNames::canonical_name(&vec![0; LongNames::MAX_NAME_LEN + 1]),
+        Err("too large input".to_string())
     );
-    // prevent the compiler from optimizing away the function call
-    std::hint::black_box((key_manager_1, key_manager_2));
 }
 
-fn random_key_manager<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager<AccessRights> {
+#[test]
+fn registered_long_names_are_resolved() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager(rng);
+    let long_name = random_bytebuf(rng, 33..LongNames::MAX_NAME_LEN + 1);
+
+    // registering is a no-op if long names are not enabled
+    key_manager.register_name(long_name.as_ref()).unwrap();
+    assert!(key_manager.name_from_bytes(long_name.as_ref()).is_err());
+
     let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
-    let (_memory_id_encrypted_maps, memory_ids_key_manager) = random_unique_memory_ids(rng);
-    let domain_separator_len = rng.gen_range(0..32);
-    KeyManager::<AccessRights>::init(
-        &random_utf8_string(rng, domain_separator_len),
-        bls12_381_dfx_test_key(),
-        memory_manager.get(MemoryId::new(memory_ids_key_manager[0])),
-        memory_manager.get(MemoryId::new(memory_ids_key_manager[1])),
-        memory_manager.get(MemoryId::new(memory_ids_key_manager[2])),
-    )
+    key_manager.enable_long_names(memory_manager.get(MemoryId::new(0)));
+    let key_id = (
+        owner,
+        key_manager.name_from_bytes(long_name.as_ref()).unwrap(),
+    );
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::Read)
+        .unwrap();
+    assert_eq!(
+        key_manager.name_to_bytes(&key_id.1).as_ref(),
+        key_id.1.as_slice()
+    );
+
+    key_manager.register_name(long_name.as_ref()).unwrap();
+    let shared_key_names: Vec<_> = key_manager
+        .get_accessible_shared_key_ids(user)
+        .into_iter()
+        .map(|key_id| key_manager.name_to_bytes(&key_id.1))
+        .collect();
+    assert_eq!(shared_key_names, vec![long_name]);
+}
+
+#[test]
+fn short_names_cannot_alias_registered_long_names() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager(rng);
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    key_manager.enable_long_names(memory_manager.get(MemoryId::new(0)));
+    let long_name = random_bytebuf(rng, 33..LongNames::MAX_NAME_LEN + 1);
+    let canonical_name = LongNames::canonical_name(long_name.as_ref()).unwrap();
+
+    // the canonical form is a valid name until the long name is registered
+    assert_eq!(
+        key_manager.name_from_bytes(canonical_name.as_slice()),
+        Ok(canonical_name)
+    );
+    key_manager.register_name(long_name.as_ref()).unwrap();
+    assert_eq!(
+        key_manager.name_from_bytes(long_name.as_ref()),
+        Ok(canonical_name)
+    );
+    assert_eq!(
+        key_manager.name_from_bytes(canonical_name.as_slice()),
+        Err("name is reserved for a longer name".to_string())
+    );
+    assert_eq!(
+        key_manager
+            .get_encrypted_vetkey_by_name(
+                owner,
+                owner,
+                canonical_name.as_slice(),
+                random_bytebuf(rng, 0..48)
+            )
+            .err(),
+        Some("name is reserved for a longer name".to_string())
+    );
+}
+
+#[test]
+fn can_remove_user_from_key() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let name = random_name(rng);
+    let mut key_manager = random_key_manager(rng);
+
+    let user_to_be_added = random_self_authenticating_principal(rng);
+    let access_rights = random_access_rights(rng);
+    assert_eq!(
+        key_manager.set_user_rights(caller, (caller, name), user_to_be_added, access_rights,),
+        Ok(None)
+    );
+    assert_eq!(
+        key_manager.remove_user(caller, (caller, name), user_to_be_added,),
+        Ok(Some(access_rights))
+    );
+    assert_eq!(
+        key_manager.get_user_rights(caller, (caller, name), user_to_be_added),
+        Ok(None)
+    );
+}
+
+#[test]
+fn owner_can_list_grants_and_revoke_user_everywhere() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let other_owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let other_user = random_self_authenticating_principal(rng);
+    let mut key_ids: Vec<_> = (0..3).map(|_| (owner, random_name(rng))).collect();
+    key_ids.sort();
+    let (accepted, pending, other) = (key_ids[0], key_ids[1], key_ids[2]);
+    let other_owner_key_id = (other_owner, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_invitations(
+            InvitationsConfig {
+                max_pending_invitations_per_recipient: 10,
+            },
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+
+    for (caller, key_id, user) in [
+        (owner, accepted, user),
+        (owner, pending, user),
+        (owner, other, other_user),
+        (other_owner, other_owner_key_id, user),
+    ] {
+        assert_eq!(
+            key_manager.set_user_rights(caller, key_id, user, AccessRights::ReadWrite),
+            Ok(None)
+        );
+    }
+    for (key_id, user) in [
+        (accepted, user),
+        (other, other_user),
+        (other_owner_key_id, user),
+    ] {
+        assert!(key_manager.accept_invitation(user, key_id).is_ok());
+    }
+
+    assert_eq!(
+        key_manager.get_owned_shared_key_ids(owner),
+        vec![accepted, other]
+    );
+    assert_eq!(
+        key_manager.get_all_grants_by_owner(owner),
+        vec![
+            (accepted, user, AccessRights::ReadWrite),
+            (other, other_user, AccessRights::ReadWrite),
+        ]
+    );
+    assert_eq!(key_manager.get_owned_shared_key_ids(user), vec![]);
+
+    assert_eq!(
+        key_manager.revoke_user_everywhere(owner, owner),
+        Err("cannot remove key owner".to_string())
+    );
+    assert_eq!(
+        key_manager.revoke_user_everywhere(owner, user),
+        Ok(vec![(accepted, AccessRights::ReadWrite)])
+    );
+    assert_eq!(
+        key_manager.get_accessible_shared_key_ids(user),
+        vec![other_owner_key_id]
+    );
+    assert_eq!(key_manager.get_pending_invitations(user), vec![]);
+    assert_eq!(
+        key_manager.get_all_grants_by_owner(owner),
+        vec![(other, other_user, AccessRights::ReadWrite)]
+    );
+    assert_eq!(key_manager.revoke_user_everywhere(owner, user), Ok(vec![]));
+}
+
+#[test]
+fn revoke_user_everywhere_releases_the_quota_of_pending_invitations() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let other_user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_invitations(
+            InvitationsConfig {
+                max_pending_invitations_per_recipient: 10,
+            },
+            memory(),
+            memory(),
+            memory(),
+        );
+        key_manager.enable_quotas(
+            QuotaConfig {
+                max_keys_per_owner: None,
+                max_shares_per_owner: Some(1),
+            },
+            memory(),
+            memory(),
+        )
+    });
+
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::Read)
+        .unwrap();
+    assert!(key_manager
+        .set_user_rights(owner, key_id, other_user, AccessRights::Read)
+        .is_err());
+    assert_eq!(key_manager.revoke_user_everywhere(owner, user), Ok(vec![]));
+
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id, other_user, AccessRights::Read),
+        Ok(None)
+    );
+}
+
+#[test]
+fn add_or_remove_user_by_unauthorized_fails() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let name = random_name(rng);
+    let mut key_manager = random_key_manager(rng);
+
+    let mut unauthorized_callers = vec![random_self_authenticating_principal(rng)];
+
+    for access_rights in [AccessRights::Read, AccessRights::ReadWrite] {
+        let user_to_be_added = random_self_authenticating_principal(rng);
+
+        assert_matches!(
+            key_manager.set_user_rights(caller, (caller, name), user_to_be_added, access_rights,),
+            Ok(_)
+        );
+
+        unauthorized_callers.push(user_to_be_added);
+    }
+
+    for unauthorized_caller in unauthorized_callers {
+        for target in [random_self_authenticating_principal(rng), caller] {
+            assert_eq!(
+                key_manager.remove_user(unauthorized_caller, (caller, name), target),
+                Err("unauthorized".to_string())
+            );
+            assert_eq!(
+                key_manager.set_user_rights(
+                    unauthorized_caller,
+                    (caller, name),
+                    target,
+                    AccessRights::Read,
+                ),
+                Err("unauthorized".to_string())
+            );
+        }
+    }
+}
+
+#[test]
+fn can_instantiate_two_key_managers() {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let key_manager_1 = KeyManager::<AccessRights>::init(
+        "key_manager_1",
+        bls12_381_dfx_test_key(),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+    );
+    let key_manager_2 = KeyManager::<AccessRights>::init(
+        "key_manager_2",
+        bls12_381_dfx_test_key(),
+        memory_manager.get(MemoryId::new(3)),
+        memory_manager.get(MemoryId::new(4)),
+        memory_manager.get(MemoryId::new(5)),
+    );
+    // prevent the compiler from optimizing away the function call
+    std::hint::black_box((key_manager_1, key_manager_2));
+}
+
+#[test]
+fn invited_user_gets_access_only_after_accepting() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let access_rights = random_access_rights(rng);
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_invitations(
+            InvitationsConfig {
+                max_pending_invitations_per_recipient: 10,
+            },
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id, user, access_rights),
+        Ok(None)
+    );
+    assert_eq!(key_manager.get_user_rights(owner, key_id, user), Ok(None));
+    assert_eq!(key_manager.get_accessible_shared_key_ids(user), vec![]);
+    assert_eq!(
+        key_manager.get_pending_invitations(user),
+        vec![(key_id, access_rights, owner)]
+    );
+    assert_eq!(
+        key_manager.get_pending_invitations_for_key(owner, key_id),
+        Ok(vec![(user, access_rights)])
+    );
+
+    assert_eq!(
+        key_manager.accept_invitation(user, key_id),
+        Ok(access_rights)
+    );
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, user),
+        Ok(Some(access_rights))
+    );
+    assert_eq!(
+        key_manager.get_accessible_shared_key_ids(user),
+        vec![key_id]
+    );
+    assert_eq!(key_manager.get_pending_invitations(user), vec![]);
+    assert_eq!(
+        key_manager.accept_invitation(user, key_id),
+        Err("no pending invitation".to_string())
+    );
+
+    // changing the rights of a user with access takes effect immediately
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id, user, AccessRights::Read),
+        Ok(Some(access_rights))
+    );
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, user),
+        Ok(Some(AccessRights::Read))
+    );
+}
+
+#[test]
+fn invited_user_can_decline_and_invitation_can_be_revoked() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_invitations(
+            InvitationsConfig {
+                max_pending_invitations_per_recipient: 10,
+            },
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::ReadWrite)
+        .unwrap();
+    assert_eq!(
+        key_manager.decline_invitation(user, key_id),
+        Ok(AccessRights::ReadWrite)
+    );
+    assert_eq!(key_manager.get_pending_invitations(user), vec![]);
+    assert_eq!(
+        key_manager.accept_invitation(user, key_id),
+        Err("no pending invitation".to_string())
+    );
+
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::ReadWrite)
+        .unwrap();
+    assert_eq!(key_manager.remove_user(owner, key_id, user), Ok(None));
+    assert_eq!(key_manager.get_pending_invitations(user), vec![]);
+    assert_eq!(
+        key_manager.get_pending_invitations_for_key(owner, key_id),
+        Ok(vec![])
+    );
+}
+
+#[test]
+fn invitations_are_only_accepted_if_the_inviter_can_still_grant_them() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let manager = random_self_authenticating_principal(rng);
+    let user1 = random_self_authenticating_principal(rng);
+    let user2 = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_invitations(
+            InvitationsConfig {
+                max_pending_invitations_per_recipient: 10,
+            },
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+
+    key_manager
+        .set_user_rights(owner, key_id, manager, AccessRights::ReadWriteManage)
+        .unwrap();
+    key_manager.accept_invitation(manager, key_id).unwrap();
+    key_manager
+        .set_user_rights(manager, key_id, user1, AccessRights::ReadWrite)
+        .unwrap();
+    key_manager
+        .set_user_rights(manager, key_id, user2, AccessRights::ReadWriteManage)
+        .unwrap();
+
+    // the delegation policy no longer allows managers to grant management rights
+    key_manager.set_delegation_policy(DelegationPolicy {
+        owner_only_manager_management: true,
+        ..Default::default()
+    });
+    assert_eq!(
+        key_manager.accept_invitation(user2, key_id),
+        Err("invitation is no longer valid: only the owner can manage managers".to_string())
+    );
+    assert_eq!(key_manager.get_pending_invitations(user2), vec![]);
+
+    // the inviter lost their management rights
+    key_manager
+        .set_user_rights(owner, key_id, manager, AccessRights::Read)
+        .unwrap();
+    assert_eq!(
+        key_manager.accept_invitation(user1, key_id),
+        Err("invitation is no longer valid: unauthorized".to_string())
+    );
+    assert_eq!(key_manager.get_user_rights(owner, key_id, user1), Ok(None));
+    assert_eq!(key_manager.get_pending_invitations(user1), vec![]);
+}
+
+#[test]
+fn blocked_principals_cannot_invite() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let manager = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_invitations(
+            InvitationsConfig {
+                max_pending_invitations_per_recipient: 10,
+            },
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+
+    key_manager
+        .set_user_rights(owner, key_id, manager, AccessRights::ReadWriteManage)
+        .unwrap();
+    key_manager.accept_invitation(manager, key_id).unwrap();
+
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::Read)
+        .unwrap();
+    key_manager.block_principal(user, owner).unwrap();
+    assert_eq!(key_manager.get_blocked_principals(user), vec![owner]);
+    assert_eq!(key_manager.get_pending_invitations(user), vec![]);
+
+    for inviter in [owner, manager] {
+        assert_eq!(
+            key_manager.set_user_rights(inviter, key_id, user, AccessRights::Read),
+            Err("blocked by recipient".to_string())
+        );
+    }
+
+    assert_eq!(key_manager.unblock_principal(user, owner), Ok(true));
+    assert_eq!(key_manager.unblock_principal(user, owner), Ok(false));
+    key_manager.block_principal(user, manager).unwrap();
+    assert_eq!(
+        key_manager.set_user_rights(manager, key_id, user, AccessRights::Read),
+        Err("blocked by recipient".to_string())
+    );
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id, user, AccessRights::Read),
+        Ok(None)
+    );
+}
+
+#[test]
+fn pending_invitations_per_recipient_are_capped() {
+    let rng = &mut reproducible_rng();
+    let user = random_self_authenticating_principal(rng);
+    let max_pending_invitations = rng.gen_range(1..5);
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_invitations(
+            InvitationsConfig {
+                max_pending_invitations_per_recipient: max_pending_invitations,
+            },
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+
+    let mut key_ids = vec![];
+    for _ in 0..max_pending_invitations {
+        let owner = random_self_authenticating_principal(rng);
+        let key_id = (owner, random_name(rng));
+        assert_eq!(
+            key_manager.set_user_rights(owner, key_id, user, AccessRights::Read),
+            Ok(None)
+        );
+        key_ids.push(key_id);
+    }
+
+    let owner = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id, user, AccessRights::Read),
+        Err("too many pending invitations".to_string())
+    );
+
+    // updating a pending invitation does not count towards the cap
+    assert_eq!(
+        key_manager.set_user_rights(key_ids[0].0, key_ids[0], user, AccessRights::ReadWrite),
+        Ok(None)
+    );
+
+    key_manager.accept_invitation(user, key_ids[0]).unwrap();
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id, user, AccessRights::Read),
+        Ok(None)
+    );
+}
+
+#[test]
+fn invitation_operations_fail_if_not_enabled() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager(rng);
+
+    assert_eq!(
+        key_manager.accept_invitation(user, key_id),
+        Err("invitations are not enabled".to_string())
+    );
+    assert_eq!(
+        key_manager.block_principal(user, owner),
+        Err("invitations are not enabled".to_string())
+    );
+    assert_eq!(key_manager.get_pending_invitations(user), vec![]);
+}
+
+#[test]
+fn organization_keys_require_quorum_of_admins() {
+    let rng = &mut reproducible_rng();
+    let organization = random_self_authenticating_principal(rng);
+    let admins: Vec<_> = (0..3)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let user = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_organizations(
+            OrganizationsConfig::default(),
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+    key_manager
+        .create_organization(organization, admins.clone(), 2)
+        .unwrap();
+    let key_id = (organization, random_name(rng));
+
+    // neither the organization nor its admins have implicit access
+    for principal in [organization, admins[0]] {
+        assert_eq!(
+            key_manager.ensure_user_can_read(principal, key_id),
+            Err("unauthorized".to_string())
+        );
+    }
+    assert_eq!(
+        key_manager.set_user_rights(admins[0], key_id, user, AccessRights::Read),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.get_user_rights(admins[0], key_id, user),
+        Ok(None)
+    );
+
+    let action = OrganizationAction::SetUserRights {
+        key_name: key_id.1,
+        user,
+        access_rights: AccessRights::Read,
+    };
+    for _ in 0..2 {
+        assert_eq!(
+            key_manager.approve_organization_action(admins[0], organization, action.clone(), 0),
+            Ok(ApprovalStatus::Pending {
+                approvals: 1,
+                quorum: 2
+            })
+        );
+    }
+    assert_eq!(
+        key_manager.approve_organization_action(user, organization, action.clone(), 0),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.approve_organization_action(admins[2], organization, action, 0),
+        Ok(ApprovalStatus::Executed)
+    );
+    assert_eq!(
+        key_manager.ensure_user_can_read(user, key_id),
+        Ok(AccessRights::Read)
+    );
+
+    let action = OrganizationAction::RemoveUser {
+        key_name: key_id.1,
+        user,
+    };
+    for (admin, expected_status) in [
+        (
+            admins[1],
+            ApprovalStatus::Pending {
+                approvals: 1,
+                quorum: 2,
+            },
+        ),
+        (admins[0], ApprovalStatus::Executed),
+    ] {
+        assert_eq!(
+            key_manager.approve_organization_action(admin, organization, action.clone(), 0),
+            Ok(expected_status)
+        );
+    }
+    assert!(key_manager.ensure_user_can_read(user, key_id).is_err());
+}
+
+#[test]
+fn admins_of_organization_with_quorum_of_one_act_as_owner() {
+    let rng = &mut reproducible_rng();
+    let organization = random_self_authenticating_principal(rng);
+    let admins: Vec<_> = (0..2)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let user = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_organizations(
+            OrganizationsConfig::default(),
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+    key_manager
+        .create_organization(organization, vec![admins[0]], 1)
+        .unwrap();
+    let key_id = (organization, random_name(rng));
+
+    assert_eq!(
+        key_manager.set_user_rights(admins[0], key_id, user, AccessRights::ReadWrite),
+        Ok(None)
+    );
+    assert!(key_manager.ensure_user_can_read(admins[0], key_id).is_err());
+
+    assert_eq!(
+        key_manager.approve_organization_action(
+            admins[0],
+            organization,
+            OrganizationAction::SetAdmins {
+                admins: admins.clone(),
+                quorum: 2,
+            },
+            0
+        ),
+        Ok(ApprovalStatus::Executed)
+    );
+    assert_eq!(
+        key_manager.get_organization_admins(organization),
+        Some((
+            admins
+                .iter()
+                .copied()
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+            2
+        ))
+    );
+    assert_eq!(
+        key_manager.remove_user(admins[0], key_id, user),
+        Err("unauthorized".to_string())
+    );
+}
+
+#[test]
+fn changing_organization_admins_voids_approvals() {
+    let rng = &mut reproducible_rng();
+    let organization = random_self_authenticating_principal(rng);
+    let admins: Vec<_> = (0..3)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_organizations(
+            OrganizationsConfig::default(),
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+    key_manager
+        .create_organization(organization, admins.clone(), 2)
+        .unwrap();
+    let action = OrganizationAction::SetUserRights {
+        key_name: random_name(rng),
+        user: random_self_authenticating_principal(rng),
+        access_rights: AccessRights::ReadWriteManage,
+    };
+    key_manager
+        .approve_organization_action(admins[0], organization, action.clone(), 0)
+        .unwrap();
+
+    // the same admin set in a different order is the same action
+    let mut reversed_admins = admins.clone();
+    reversed_admins.reverse();
+    key_manager
+        .approve_organization_action(
+            admins[0],
+            organization,
+            OrganizationAction::SetAdmins {
+                admins: admins.clone(),
+                quorum: 2,
+            },
+            0,
+        )
+        .unwrap();
+    assert_eq!(
+        key_manager.approve_organization_action(
+            admins[1],
+            organization,
+            OrganizationAction::SetAdmins {
+                admins: reversed_admins,
+                quorum: 2,
+            },
+            0
+        ),
+        Ok(ApprovalStatus::Executed)
+    );
+
+    assert_eq!(
+        key_manager.approve_organization_action(admins[1], organization, action, 0),
+        Ok(ApprovalStatus::Pending {
+            approvals: 1,
+            quorum: 2
+        })
+    );
+}
+
+#[test]
+fn organization_approvals_expire_and_can_be_withdrawn() {
+    let rng = &mut reproducible_rng();
+    let organization = random_self_authenticating_principal(rng);
+    let admins: Vec<_> = (0..3)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let user = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_organizations(
+            OrganizationsConfig::default(),
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+    key_manager
+        .create_organization(organization, admins.clone(), 2)
+        .unwrap();
+    let approval_expiry_ns = OrganizationsConfig::default().approval_expiry_ns;
+    let key_id = (organization, random_name(rng));
+    let action = OrganizationAction::SetUserRights {
+        key_name: key_id.1,
+        user,
+        access_rights: AccessRights::Read,
+    };
+    let pending = Ok(ApprovalStatus::Pending {
+        approvals: 1,
+        quorum: 2,
+    });
+
+    // an expired approval does not count towards the quorum
+    assert_eq!(
+        key_manager.approve_organization_action(admins[0], organization, action.clone(), 0),
+        pending
+    );
+    assert_eq!(
+        key_manager.approve_organization_action(
+            admins[1],
+            organization,
+            action.clone(),
+            approval_expiry_ns
+        ),
+        pending
+    );
+
+    // a withdrawn approval does not count towards the quorum
+    assert_eq!(
+        key_manager.withdraw_organization_approval(admins[1], organization, &action),
+        Ok(true)
+    );
+    assert_eq!(
+        key_manager.withdraw_organization_approval(admins[1], organization, &action),
+        Ok(false)
+    );
+    assert_eq!(
+        key_manager.approve_organization_action(
+            admins[2],
+            organization,
+            action.clone(),
+            approval_expiry_ns + 1
+        ),
+        pending
+    );
+    assert!(key_manager.ensure_user_can_read(user, key_id).is_err());
+
+    // renewed approvals count
+    assert_eq!(
+        key_manager.approve_organization_action(
+            admins[0],
+            organization,
+            action,
+            2 * approval_expiry_ns
+        ),
+        Ok(ApprovalStatus::Executed)
+    );
+    assert_eq!(
+        key_manager.ensure_user_can_read(user, key_id),
+        Ok(AccessRights::Read)
+    );
+}
+
+#[test]
+fn failed_organization_actions_do_not_record_the_final_approval() {
+    let rng = &mut reproducible_rng();
+    let organization = random_self_authenticating_principal(rng);
+    let admins: Vec<_> = (0..3)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_organizations(
+            OrganizationsConfig::default(),
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+    key_manager
+        .create_organization(organization, admins.clone(), 2)
+        .unwrap();
+    let action = OrganizationAction::SetAdmins {
+        admins: admins.clone(),
+        quorum: 0,
+    };
+
+    assert_eq!(
+        key_manager.approve_organization_action(admins[0], organization, action.clone(), 0),
+        Ok(ApprovalStatus::Pending {
+            approvals: 1,
+            quorum: 2
+        })
+    );
+    assert_eq!(
+        key_manager.approve_organization_action(admins[1], organization, action.clone(), 0),
+        Err("quorum must be between 1 and the number of admins".to_string())
+    );
+    assert_eq!(
+        key_manager.withdraw_organization_approval(admins[1], organization, &action),
+        Ok(false)
+    );
+    assert_eq!(
+        key_manager.withdraw_organization_approval(admins[0], organization, &action),
+        Ok(true)
+    );
+    assert_eq!(
+        key_manager.get_organization_admins(organization),
+        Some((BTreeSet::from_iter(admins).into_iter().collect(), 2))
+    );
+}
+
+#[test]
+fn creating_organizations_validates_admins_and_quorum() {
+    let rng = &mut reproducible_rng();
+    let organization = random_self_authenticating_principal(rng);
+    let admin = random_self_authenticating_principal(rng);
+
+    assert_eq!(
+        random_key_manager(rng).create_organization(organization, vec![admin], 1),
+        Err("organizations are not enabled".to_string())
+    );
+
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_organizations(
+            OrganizationsConfig::default(),
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+    for (admins, quorum) in [(vec![admin], 0), (vec![admin, admin], 2), (vec![], 1)] {
+        assert_eq!(
+            key_manager.create_organization(organization, admins, quorum),
+            Err("quorum must be between 1 and the number of admins".to_string())
+        );
+    }
+    assert!(!key_manager.is_organization(organization));
+    key_manager
+        .create_organization(organization, vec![admin], 1)
+        .unwrap();
+    assert!(key_manager.is_organization(organization));
+    assert_eq!(
+        key_manager.create_organization(organization, vec![admin], 1),
+        Err("organization already exists".to_string())
+    );
+}
+
+#[test]
+fn vetkd_call_errors_describe_the_failed_call() {
+    let error = VetKDCallError::CallFailed {
+        method: "vetkd_derive_key",
+        attempts: 3,
+        error: CallRejected::with_rejection(2, "subnet overloaded".to_string()).into(),
+    };
+    let message = error.to_string();
+    assert!(message.starts_with("call to vetkd_derive_key failed after 3 attempt(s): "));
+    assert!(message.contains("subnet overloaded"));
+
+    let default_policy = CallPolicy::default();
+    assert_eq!(default_policy.max_attempts, 1);
+    assert_eq!(default_policy.bounded_wait_timeout_seconds, None);
+}
+
+#[test]
+fn batched_vetkey_requests_are_checked_per_key() {
+    let rng = &mut reproducible_rng();
+    let caller = random_self_authenticating_principal(rng);
+    let key_manager = random_key_manager(rng);
+    let transport_key = random_bytebuf(rng, 0..48);
+
+    let key_ids: Vec<_> = (0..3)
+        .map(|_| (random_self_authenticating_principal(rng), random_name(rng)))
+        .collect();
+    let encrypted_vetkeys = futures::executor::block_on(
+        key_manager
+            .get_encrypted_vetkeys(caller, key_ids, transport_key.clone())
+            .unwrap(),
+    );
+    assert_eq!(encrypted_vetkeys.len(), 3);
+    for (_key_id, encrypted_vetkey) in encrypted_vetkeys {
+        assert_eq!(encrypted_vetkey, Err("unauthorized".to_string()));
+    }
+
+    let too_many_key_ids =
+        vec![(caller, random_name(rng)); KeyManager::<AccessRights>::MAX_VETKEYS_PER_BATCH + 1];
+    assert_eq!(
+        key_manager
+            .get_encrypted_vetkeys(caller, too_many_key_ids, transport_key)
+            .err(),
+        Some(format!(
+            "too many vetKeys requested: at most {} per batch",
+            KeyManager::<AccessRights>::MAX_VETKEYS_PER_BATCH
+        ))
+    );
+}
+
+#[test]
+fn purpose_bound_vetkeys_have_independent_inputs() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let name = random_name(rng);
+    let name_bytes = name.as_ref();
+
+    let input = key_id_to_vetkd_input(owner, name_bytes);
+    assert_eq!(
+        key_id_to_vetkd_input_for_purpose(owner, name_bytes, b""),
+        Ok(input.clone())
+    );
+    let encryption_input =
+        key_id_to_vetkd_input_for_purpose(owner, name_bytes, b"encryption").unwrap();
+    let signing_input = key_id_to_vetkd_input_for_purpose(owner, name_bytes, b"signing").unwrap();
+    assert_ne!(encryption_input, input);
+    assert_ne!(signing_input, input);
+    assert_ne!(encryption_input, signing_input);
+    // the purpose is length-prefixed, so it cannot be confused with the name
+    let mut shifted_name = b"n".to_vec();
+    shifted_name.extend(name_bytes);
+    assert_ne!(
+        key_id_to_vetkd_input_for_purpose(owner, &shifted_name, b"encryptio"),
+        Ok(encryption_input)
+    );
+
+    let max_len = KeyManager::<AccessRights>::MAX_PURPOSE_LEN;
+    assert!(key_id_to_vetkd_input_for_purpose(owner, name_bytes, &vec![1; max_len]).is_ok());
+    assert_eq!(
+        key_id_to_vetkd_input_for_purpose(owner, name_bytes, &vec![1; max_len + 1]),
+        Err(format!("purpose must be at most {max_len} bytes"))
+    );
+}
+
+#[test]
+fn purpose_bound_vetkeys_share_access_rights() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager(rng);
+    let key_id = (owner, random_name(rng));
+    let transport_key = ByteBuf::from(vec![0; 48]);
+
+    assert!(key_manager
+        .get_encrypted_vetkey_for_purpose(owner, key_id, b"signing", transport_key.clone())
+        .is_ok());
+    assert_eq!(
+        key_manager
+            .get_encrypted_vetkey_for_purpose(user, key_id, b"signing", transport_key.clone())
+            .err(),
+        Some("unauthorized".to_string())
+    );
+
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::Read)
+        .unwrap();
+    assert!(key_manager
+        .get_encrypted_vetkey_for_purpose(user, key_id, b"signing", transport_key.clone())
+        .is_ok());
+    assert_eq!(
+        key_manager
+            .get_encrypted_vetkey_for_purpose(user, key_id, &[1; 33], transport_key)
+            .err(),
+        Some("purpose must be at most 32 bytes".to_string())
+    );
+}
+
+#[test]
+fn backup_is_only_imported_into_matching_empty_instance() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let mut source = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_invitations(
+            InvitationsConfig {
+                max_pending_invitations_per_recipient: 10,
+            },
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+    source
+        .set_user_rights(
+            owner,
+            (owner, random_name(rng)),
+            random_self_authenticating_principal(rng),
+            AccessRights::Read,
+        )
+        .unwrap();
+    let canister_id = random_self_authenticating_principal(rng);
+    let first_chunk = source
+        .export_backup_chunk(canister_id, None, usize::MAX)
+        .unwrap();
+    assert_eq!(first_chunk.cursor.section, BackupSection::AccessControl);
+
+    let mut non_empty_target = random_key_manager(rng);
+    non_empty_target
+        .set_user_rights(
+            owner,
+            (owner, random_name(rng)),
+            random_self_authenticating_principal(rng),
+            AccessRights::Read,
+        )
+        .unwrap();
+    assert_eq!(
+        non_empty_target.import_backup_chunk(first_chunk),
+        Err("backup chunk does not continue the previously imported chunk".to_string())
+    );
+
+    let mut target_without_invitations = random_key_manager(rng);
+    let mut cursor = None;
+    let error = loop {
+        let chunk = source
+            .export_backup_chunk(canister_id, cursor, usize::MAX)
+            .unwrap();
+        cursor = chunk.next_cursor.clone();
+        if let Err(error) = target_without_invitations.import_backup_chunk(chunk) {
+            break error;
+        }
+    };
+    assert_eq!(
+        error,
+        "backup section PendingInvitations is not enabled".to_string()
+    );
+}
+
+#[test]
+fn unversioned_config_is_migrated() {
+    #[derive(serde::Serialize)]
+    struct UnversionedKeyManagerConfig {
+        domain_separator: String,
+        key_id: VetKDKeyId,
+    }
+
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let unversioned_config = UnversionedKeyManagerConfig {
+        domain_separator: "unversioned".to_string(),
+        key_id: bls12_381_dfx_test_key(),
+    };
+    StableCell::init(
+        memory_manager.get(MemoryId::new(0)),
+        serde_cbor::to_vec(&unversioned_config).unwrap(),
+    );
+
+    let key_manager = KeyManager::<AccessRights>::try_init(
+        "ignored",
+        bls12_381_dfx_test_key(),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+    )
+    .unwrap();
+    let config = key_manager.config.get();
+    assert_eq!(config.domain_separator, "unversioned");
+    assert_eq!(
+        config.schema_versions.key_manager,
+        KeyManager::<AccessRights>::SCHEMA_VERSION
+    );
+}
+
+#[test]
+fn corrupted_or_newer_stable_state_is_reported() {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    StableCell::init(memory_manager.get(MemoryId::new(0)), vec![0xff_u8, 0x00]);
+    let try_init = |memory_id| {
+        KeyManager::<AccessRights>::try_init(
+            "domain_separator",
+            bls12_381_dfx_test_key(),
+            memory_manager.get(MemoryId::new(memory_id)),
+            memory_manager.get(MemoryId::new(1)),
+            memory_manager.get(MemoryId::new(2)),
+        )
+    };
+    assert_matches!(try_init(0).err(), Some(StableStateError::Corrupted(_)));
+
+    let mut key_manager = try_init(3).unwrap();
+    let config = KeyManagerConfig {
+        schema_versions: SchemaVersions {
+            key_manager: KeyManager::<AccessRights>::SCHEMA_VERSION + 1,
+            encrypted_maps: 0,
+        },
+        ..key_manager.config.get().clone()
+    };
+    key_manager.config.set(config);
+    assert_eq!(
+        try_init(3).err(),
+        Some(StableStateError::UnsupportedSchemaVersion {
+            stored: KeyManager::<AccessRights>::SCHEMA_VERSION + 1,
+            latest: KeyManager::<AccessRights>::SCHEMA_VERSION,
+        })
+    );
+
+    assert_eq!(
+        AccessRights::try_from_bytes(&[3]),
+        Err("invalid AccessRights: unknown value 3".to_string())
+    );
+}
+
+#[test]
+fn verification_key_is_cached_after_retrieval() {
+    let rng = &mut reproducible_rng();
+    let canister_id = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager(rng);
+    assert_eq!(
+        key_manager.derive_vetkey_verification_key_offline(canister_id),
+        None
+    );
+    assert_eq!(
+        key_manager.get_cached_vetkey_verification_key(canister_id),
+        None
+    );
+
+    let verification_key = random_bytebuf(rng, 96..97);
+    key_manager
+        .cache_vetkey_verification_key(canister_id, verification_key.clone())
+        .unwrap();
+    assert_eq!(
+        key_manager.get_cached_vetkey_verification_key(canister_id),
+        Some(verification_key)
+    );
+}
+
+#[test]
+fn offline_verification_key_is_checked_against_retrieved_key() {
+    let rng = &mut reproducible_rng();
+    let canister_id = random_self_authenticating_principal(rng);
+    let domain_separator = random_utf8_string(rng, 16);
+    let key_id = VetKDKeyId {
+        curve: VetKDCurve::Bls12_381_G2,
+        name: "test_key_1".to_string(),
+    };
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let mut key_manager = KeyManager::<AccessRights>::init(
+        &domain_separator,
+        key_id.clone(),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+    );
+
+    let expected_verification_key = MasterPublicKey::for_mainnet_key(&key_id)
+        .unwrap()
+        .derive_canister_key(canister_id.as_slice())
+        .derive_sub_key(domain_separator.as_bytes())
+        .serialize();
+    let offline_verification_key = key_manager
+        .get_cached_vetkey_verification_key(canister_id)
+        .unwrap();
+    assert_eq!(offline_verification_key.as_ref(), expected_verification_key);
+    // the offline key is returned without a call to the management canister
+    assert_eq!(
+        futures::executor::block_on(key_manager.get_vetkey_verification_key(canister_id)).ok(),
+        Some(offline_verification_key.clone())
+    );
+
+    assert_eq!(
+        key_manager.cache_vetkey_verification_key(canister_id, random_bytebuf(rng, 96..97)),
+        Err("offline vetKD verification key does not match the management canister's".to_string())
+    );
+    assert_eq!(key_manager.config.get().verification_key, None);
+    key_manager
+        .cache_vetkey_verification_key(canister_id, offline_verification_key.clone())
+        .unwrap();
+    assert_eq!(
+        key_manager.config.get().verification_key,
+        Some(offline_verification_key)
+    );
+}
+
+#[test]
+fn tenant_keys_are_scoped_by_tenant() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let name = random_bytebuf(rng, 0..100);
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_tenants(memory(), memory(), memory())
+    });
+    let default_domain_separator = key_manager.config.get().domain_separator.clone();
+    let (tenant_a, tenant_b) = (random_name(rng), random_name(rng));
+    key_manager
+        .create_tenant(tenant_a, "tenant-a".to_string(), vec![])
+        .unwrap();
+
+    assert_eq!(
+        key_manager.create_tenant(tenant_a, "tenant-c".to_string(), vec![]),
+        Err("tenant already exists".to_string())
+    );
+    for domain_separator in [default_domain_separator, "tenant-a".to_string()] {
+        assert_eq!(
+            key_manager.create_tenant(tenant_b, domain_separator, vec![]),
+            Err("domain separator is already in use".to_string())
+        );
+    }
+    assert_eq!(
+        key_manager.register_tenant_key(tenant_b, owner, name.as_ref()),
+        Err("tenant does not exist".to_string())
+    );
+    key_manager
+        .create_tenant(tenant_b, "tenant-b".to_string(), vec![])
+        .unwrap();
+
+    let key_id_a = key_manager
+        .register_tenant_key(tenant_a, owner, name.as_ref())
+        .unwrap();
+    let key_id_b = key_manager
+        .register_tenant_key(tenant_b, owner, name.as_ref())
+        .unwrap();
+    assert_ne!(key_id_a, key_id_b);
+    assert_eq!(key_id_a, tenant_key_id(tenant_a, owner, name.as_ref()));
+    assert_eq!(key_id_a.0, owner);
+    assert_eq!(key_manager.get_key_tenant(key_id_a), Some(tenant_a));
+    assert_eq!(key_manager.get_key_tenant(key_id_b), Some(tenant_b));
+    assert_eq!(key_manager.get_key_tenant((owner, random_name(rng))), None);
+    assert_eq!(
+        key_manager.get_tenant(tenant_a),
+        Some(("tenant-a".to_string(), vec![]))
+    );
+    assert!(key_manager
+        .get_tenant_vetkey_verification_key(random_name(rng))
+        .is_err());
+}
+
+#[test]
+fn keys_in_use_cannot_be_registered_in_tenants() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_tenants(memory(), memory(), memory())
+    });
+    let tenant = random_name(rng);
+    key_manager
+        .create_tenant(tenant, "tenant".to_string(), vec![])
+        .unwrap();
+    let key_id = tenant_key_id(tenant, owner, b"key");
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::Read)
+        .unwrap();
+
+    assert_eq!(
+        key_manager.register_tenant_key(tenant, owner, b"key"),
+        Err("vetKey is already in use".to_string())
+    );
+    assert_eq!(key_manager.get_key_tenant(key_id), None);
+
+    // registration stays idempotent once the registered vetKey is shared
+    key_manager.remove_user(owner, key_id, user).unwrap();
+    assert_eq!(
+        key_manager.register_tenant_key(tenant, owner, b"key"),
+        Ok(key_id)
+    );
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::Read)
+        .unwrap();
+    assert_eq!(
+        key_manager.register_tenant_key(tenant, owner, b"key"),
+        Ok(key_id)
+    );
+}
+
+#[test]
+fn tenant_admins_manage_only_their_tenant() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let admin = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_tenants(memory(), memory(), memory())
+    });
+    let (tenant, other_tenant) = (random_name(rng), random_name(rng));
+    key_manager
+        .create_tenant(tenant, "tenant".to_string(), vec![admin])
+        .unwrap();
+    key_manager
+        .create_tenant(other_tenant, "other-tenant".to_string(), vec![])
+        .unwrap();
+    let key_id = key_manager
+        .register_tenant_key(tenant, owner, b"key")
+        .unwrap();
+    let other_tenant_key_id = key_manager
+        .register_tenant_key(other_tenant, owner, b"key")
+        .unwrap();
+    let untenanted_key_id = (owner, random_name(rng));
+
+    assert_eq!(
+        key_manager.set_user_rights(admin, key_id, user, AccessRights::Read),
+        Ok(None)
+    );
+    assert_eq!(
+        key_manager.get_user_rights(admin, key_id, user),
+        Ok(Some(AccessRights::Read))
+    );
+    assert_eq!(
+        key_manager.remove_user(admin, key_id, user),
+        Ok(Some(AccessRights::Read))
+    );
+    // tenant admins have no implicit read access
+    assert_eq!(
+        key_manager.ensure_user_can_read(admin, key_id),
+        Err("unauthorized".to_string())
+    );
+    for other_key_id in [other_tenant_key_id, untenanted_key_id] {
+        assert_eq!(
+            key_manager.set_user_rights(admin, other_key_id, user, AccessRights::Read),
+            Err("unauthorized".to_string())
+        );
+    }
+
+    key_manager.set_tenant_admins(tenant, vec![]).unwrap();
+    assert_eq!(
+        key_manager.set_user_rights(admin, key_id, user, AccessRights::Read),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.get_tenant(tenant),
+        Some(("tenant".to_string(), vec![]))
+    );
+}
+
+#[test]
+fn certified_queries_are_verified() {
+    let rng = &mut reproducible_rng();
+    let canister_id = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
+    let owner = random_self_authenticating_principal(rng);
+    let users: Vec<_> = (0..3)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager(rng);
+    key_manager
+        .set_user_rights(owner, key_id, users[0], AccessRights::Read)
+        .unwrap();
+    key_manager.enable_certified_queries();
+    let certified_data = key_manager.certified_data().unwrap();
+    key_manager
+        .set_user_rights(owner, key_id, users[1], AccessRights::ReadWrite)
+        .unwrap();
+    assert_ne!(key_manager.certified_data(), Some(certified_data));
+
+    let root_secret_key = Scalar::from(rng.gen::<u64>());
+    let time = 1_700_000_000_000_000_000;
+    let certificate = test_certificate(
+        canister_id,
+        key_manager.certified_data().unwrap(),
+        time,
+        root_secret_key,
+    );
+    let verifier = CertifiedQueryVerifier {
+        canister_id,
+        root_public_key: test_public_key_der(root_secret_key),
+        now_nanos: time + 1_000,
+        max_certificate_age_nanos: 60_000_000_000,
+    };
+
+    let response = key_manager
+        .get_certified_user_rights(owner, key_id, users[1], certificate.clone())
+        .unwrap();
+    assert_eq!(
+        verifier.verify_user_rights(&response, key_id, users[1]),
+        Ok(Some(AccessRights::ReadWrite))
+    );
+    let response = key_manager
+        .get_certified_user_rights(owner, key_id, users[2], certificate.clone())
+        .unwrap();
+    assert_eq!(
+        verifier.verify_user_rights(&response, key_id, users[2]),
+        Ok(None)
+    );
+    let tampered = CertifiedResponse {
+        value: Some(AccessRights::ReadWriteManage),
+        ..response
+    };
+    assert_eq!(
+        verifier.verify_user_rights(&tampered, key_id, users[2]),
+        Err("response does not match the certified value".to_string())
+    );
+
+    let response = key_manager
+        .get_certified_shared_user_access_for_key(owner, key_id, certificate.clone())
+        .unwrap();
+    let mut expected = vec![
+        (users[0], AccessRights::Read),
+        (users[1], AccessRights::ReadWrite),
+    ];
+    expected.sort_by(|(a, _), (b, _)| a.as_slice().cmp(b.as_slice()));
+    assert_eq!(
+        verifier.verify_shared_user_access(&response, key_id),
+        Ok(expected)
+    );
+
+    let response = key_manager
+        .get_certified_accessible_shared_key_ids(users[0], certificate.clone())
+        .unwrap();
+    let response = CertifiedResponse {
+        value: vec![(owner, ByteBuf::from(key_id.1.as_slice().to_vec()))],
+        certificate: response.certificate,
+        witness: response.witness,
+    };
+    assert_eq!(
+        verifier.verify_accessible_shared_key_ids(&response, users[0]),
+        Ok(vec![key_id])
+    );
+    assert!(verifier
+        .verify_accessible_shared_key_ids(&response, users[2])
+        .is_err());
+
+    // the witness of the previous state is not certified anymore
+    key_manager.remove_user(owner, key_id, users[0]).unwrap();
+    let response = key_manager
+        .get_certified_user_rights(owner, key_id, users[0], certificate.clone())
+        .unwrap();
+    assert_eq!(
+        verifier.verify_user_rights(&response, key_id, users[0]),
+        Err("certified data does not match the witness".to_string())
+    );
+
+    let other_verifiers = [
+        CertifiedQueryVerifier {
+            root_public_key: test_public_key_der(Scalar::from(rng.gen::<u64>())),
+            ..verifier.clone()
+        },
+        CertifiedQueryVerifier {
+            now_nanos: time + 120_000_000_000,
+            ..verifier.clone()
+        },
+    ];
+    let certificate = test_certificate(
+        canister_id,
+        key_manager.certified_data().unwrap(),
+        time,
+        root_secret_key,
+    );
+    let response = key_manager
+        .get_certified_user_rights(owner, key_id, users[1], certificate)
+        .unwrap();
+    assert_eq!(
+        verifier.verify_user_rights(&response, key_id, users[1]),
+        Ok(Some(AccessRights::ReadWrite))
+    );
+    assert_eq!(
+        other_verifiers[0].verify_user_rights(&response, key_id, users[1]),
+        Err("invalid certificate signature".to_string())
+    );
+    assert_eq!(
+        other_verifiers[1].verify_user_rights(&response, key_id, users[1]),
+        Err("certificate is too old".to_string())
+    );
+}
+
+#[test]
+fn certified_queries_accept_subnet_delegations() {
+    let rng = &mut reproducible_rng();
+    let canister_id = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 5, 1, 1]);
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager(rng);
+    key_manager.enable_certified_queries();
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::Read)
+        .unwrap();
+
+    let (root_secret_key, subnet_secret_key) = (
+        Scalar::from(rng.gen::<u64>()),
+        Scalar::from(rng.gen::<u64>()),
+    );
+    let time = 1_700_000_000_000_000_000;
+    let subnet_id = random_self_authenticating_principal(rng);
+    let certificate_for_ranges = |low: &[u8], high: &[u8]| {
+        let ranges = vec![(
+            serde_bytes::ByteBuf::from(low.to_vec()),
+            serde_bytes::ByteBuf::from(high.to_vec()),
+        )];
+        let subnet_tree = labeled(
+            "subnet",
+            labeled(
+                subnet_id.as_slice(),
+                fork(
+                    labeled(
+                        "canister_ranges",
+                        leaf(serde_cbor::to_vec(&ranges).unwrap()),
+                    ),
+                    labeled("public_key", leaf(test_public_key_der(subnet_secret_key))),
+                ),
+            ),
+        );
+        let delegation = Delegation {
+            subnet_id: subnet_id.as_slice().to_vec(),
+            certificate: serde_cbor::to_vec(&sign_test_tree(subnet_tree, root_secret_key)).unwrap(),
+        };
+        let mut certificate = serde_cbor::from_slice::<Certificate>(&test_certificate(
+            canister_id,
+            key_manager.certified_data().unwrap(),
+            time,
+            subnet_secret_key,
+        ))
+        .unwrap();
+        certificate.delegation = Some(delegation);
+        serde_cbor::to_vec(&certificate).unwrap()
+    };
+    let verifier = CertifiedQueryVerifier {
+        canister_id,
+        root_public_key: test_public_key_der(root_secret_key),
+        now_nanos: time,
+        max_certificate_age_nanos: 60_000_000_000,
+    };
+
+    let certificate = certificate_for_ranges(
+        &[0, 0, 0, 0, 0, 0, 0, 0, 1, 1],
+        &[0, 0, 0, 0, 0, 0, 0, 9, 1, 1],
+    );
+    let response = key_manager
+        .get_certified_user_rights(owner, key_id, user, certificate)
+        .unwrap();
+    assert_eq!(
+        verifier.verify_user_rights(&response, key_id, user),
+        Ok(Some(AccessRights::Read))
+    );
+
+    let certificate = certificate_for_ranges(
+        &[0, 0, 0, 0, 0, 0, 0, 6, 1, 1],
+        &[0, 0, 0, 0, 0, 0, 0, 9, 1, 1],
+    );
+    let response = key_manager
+        .get_certified_user_rights(owner, key_id, user, certificate)
+        .unwrap();
+    assert_eq!(
+        verifier.verify_user_rights(&response, key_id, user),
+        Err("canister is not in the delegated ranges".to_string())
+    );
+}
+
+fn test_certificate(
+    canister_id: Principal,
+    certified_data: [u8; 32],
+    time: u64,
+    secret_key: Scalar,
+) -> Vec<u8> {
+    let mut encoded_time = Vec::new();
+    let mut remaining = time;
+    loop {
+        let byte = (remaining & 0x7f) as u8;
+        remaining >>= 7;
+        if remaining == 0 {
+            encoded_time.push(byte);
+            break;
+        }
+        encoded_time.push(byte | 0x80);
+    }
+    let tree = fork(
+        labeled(
+            "canister",
+            labeled(
+                canister_id.as_slice(),
+                labeled("certified_data", leaf(certified_data.to_vec())),
+            ),
+        ),
+        labeled("time", leaf(encoded_time)),
+    );
+    serde_cbor::to_vec(&sign_test_tree(tree, secret_key)).unwrap()
+}
+
+fn sign_test_tree(tree: HashTree, secret_key: Scalar) -> Certificate {
+    let mut message = b"\x0dic-state-root".to_vec();
+    message.extend_from_slice(&tree.digest());
+    let hash = <G1Projective as HashToCurve<ExpandMsgXmd<sha2::Sha256>>>::hash_to_curve(
+        message,
+        b"BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_NUL_",
+    );
+    Certificate {
+        tree,
+        signature: G1Affine::from(hash * secret_key).to_compressed().to_vec(),
+        delegation: None,
+    }
+}
+
+fn test_public_key_der(secret_key: Scalar) -> Vec<u8> {
+    let mut der =
+        hex::decode("308182301d060d2b0601040182dc7c0503010201060c2b0601040182dc7c05030201036100")
+            .unwrap();
+    der.extend_from_slice(&G2Affine::from(G2Affine::generator() * secret_key).to_compressed());
+    der
+}
+
+#[test]
+fn frozen_keys_cannot_be_retrieved() {
+    const DELAY_NS: u64 = 1_000;
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let manager = random_self_authenticating_principal(rng);
+    let reader = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_key_freezing(
+            FreezeConfig {
+                unfreeze_delay_ns: DELAY_NS,
+            },
+            memory(),
+        )
+    });
+    for (user, access_rights) in [
+        (manager, AccessRights::ReadWriteManage),
+        (reader, AccessRights::Read),
+    ] {
+        key_manager
+            .set_user_rights(owner, key_id, user, access_rights)
+            .unwrap();
+    }
+    let retrieve = |key_manager: &KeyManager<AccessRights>, user| {
+        key_manager
+            .get_encrypted_vetkey(user, key_id, ByteBuf::from(vec![0; 48]))
+            .map(|_future| ())
+    };
+    let frozen = Err("vetKey is frozen".to_string());
+
+    assert_eq!(
+        key_manager.freeze_key(reader, key_id, false),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.freeze_key(manager, key_id, true),
+        Err("only the key owner can lock out the key owner".to_string())
+    );
+    assert_eq!(key_manager.freeze_key(manager, key_id, false), Ok(()));
+    assert_eq!(retrieve(&key_manager, reader), frozen);
+    assert_eq!(retrieve(&key_manager, manager), frozen);
+    assert_eq!(retrieve(&key_manager, owner), Ok(()));
+    assert_eq!(
+        key_manager.get_freeze_state(owner, key_id),
+        Ok(Some(FreezeState {
+            frozen_by: manager,
+            includes_owner: false,
+            unfreeze_after_ns: None,
+        }))
+    );
+    assert_eq!(
+        key_manager.get_freeze_state(reader, key_id),
+        Err("unauthorized".to_string())
+    );
+    // the shares are kept
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, reader),
+        Ok(Some(AccessRights::Read))
+    );
+    assert_eq!(key_manager.unfreeze_key(manager, key_id, 0), Ok(None));
+    assert_eq!(retrieve(&key_manager, reader), Ok(()));
+    assert_eq!(key_manager.get_freeze_state(owner, key_id), Ok(None));
+
+    // a freeze including the owner is lifted only after the delay
+    assert_eq!(key_manager.freeze_key(owner, key_id, true), Ok(()));
+    assert_eq!(retrieve(&key_manager, owner), frozen);
+    assert_eq!(
+        key_manager.unfreeze_key(manager, key_id, 0),
+        Err("only the key owner can unfreeze the key".to_string())
+    );
+    assert_eq!(
+        key_manager.unfreeze_key(owner, key_id, 100),
+        Ok(Some(100 + DELAY_NS))
+    );
+    assert_eq!(
+        key_manager.unfreeze_key(owner, key_id, 99 + DELAY_NS),
+        Ok(Some(100 + DELAY_NS))
+    );
+    assert_eq!(retrieve(&key_manager, owner), frozen);
+    // freezing again cancels the requested unfreeze but keeps the owner locked out
+    assert_eq!(key_manager.freeze_key(manager, key_id, false), Ok(()));
+    assert_eq!(retrieve(&key_manager, owner), frozen);
+    assert_eq!(
+        key_manager.unfreeze_key(owner, key_id, 100 + DELAY_NS),
+        Ok(Some(100 + 2 * DELAY_NS))
+    );
+    assert_eq!(
+        key_manager.unfreeze_key(owner, key_id, 100 + 2 * DELAY_NS),
+        Ok(None)
+    );
+    assert_eq!(retrieve(&key_manager, owner), Ok(()));
+    assert_eq!(
+        key_manager.unfreeze_key(owner, key_id, 100 + 2 * DELAY_NS),
+        Err("vetKey is not frozen".to_string())
+    );
+}
+
+#[test]
+fn key_freezing_fails_if_not_enabled() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager(rng);
+
+    assert_eq!(
+        key_manager.freeze_key(owner, key_id, false),
+        Err("key freezing is not enabled".to_string())
+    );
+    assert_eq!(key_manager.get_freeze_state(owner, key_id), Ok(None));
+}
+
+#[test]
+fn guardians_can_recover_keys_after_delay() {
+    const DELAY_NS: u64 = 1_000;
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let new_owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let guardians: Vec<Principal> = (0..3)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_recovery(
+            RecoveryConfig {
+                recovery_delay_ns: DELAY_NS,
+            },
+            memory(),
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::Read)
+        .unwrap();
+
+    assert_eq!(
+        key_manager.approve_recovery(guardians[0], owner, new_owner, 0),
+        Err("recovery is not set up".to_string())
+    );
+    assert_eq!(
+        key_manager.set_recovery_guardians(owner, vec![owner, guardians[0]], 1),
+        Err("cannot be one's own guardian".to_string())
+    );
+    assert_eq!(
+        key_manager.set_recovery_guardians(owner, guardians.clone(), 4),
+        Err("threshold must be between 1 and the number of guardians".to_string())
+    );
+    assert_eq!(
+        key_manager.set_recovery_guardians(owner, guardians.clone(), 2),
+        Ok(())
+    );
+    let mut sorted_guardians = guardians.clone();
+    sorted_guardians.sort();
+    assert_eq!(
+        key_manager.get_recovery_guardians(owner),
+        Some(RecoveryGuardians {
+            guardians: sorted_guardians,
+            threshold: 2,
+        })
+    );
+
+    assert_eq!(
+        key_manager.approve_recovery(user, owner, new_owner, 0),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.approve_recovery(guardians[0], owner, owner, 0),
+        Err("invalid new owner".to_string())
+    );
+    assert_eq!(
+        key_manager.approve_recovery(guardians[0], owner, new_owner, 0),
+        Ok(RecoveryStatus::Pending {
+            approvals: 1,
+            threshold: 2,
+        })
+    );
+    assert_eq!(
+        key_manager.complete_recovery(new_owner, owner, new_owner, DELAY_NS),
+        Err("recovery is not approved".to_string())
+    );
+
+    // the owner can cancel a scheduled recovery
+    assert_eq!(
+        key_manager.approve_recovery(guardians[1], owner, new_owner, 10),
+        Ok(RecoveryStatus::Scheduled {
+            executable_after_ns: 10 + DELAY_NS,
+        })
+    );
+    assert_eq!(
+        key_manager.get_pending_recoveries(guardians[2], owner),
+        Ok(vec![(
+            new_owner,
+            PendingRecovery {
+                approvals: vec![guardians[0], guardians[1]],
+                executable_after_ns: Some(10 + DELAY_NS),
+            }
+        )])
+    );
+    assert_eq!(
+        key_manager.get_pending_recoveries(user, owner),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(key_manager.cancel_recovery(owner), Ok(()));
+    assert_eq!(
+        key_manager.cancel_recovery(owner),
+        Err("no pending recovery".to_string())
+    );
+    assert_eq!(key_manager.get_pending_recoveries(owner, owner), Ok(vec![]));
+
+    // otherwise, it can be completed after the delay
+    for guardian in &guardians[1..] {
+        key_manager
+            .approve_recovery(*guardian, owner, new_owner, 20)
+            .unwrap();
+    }
+    assert_eq!(
+        key_manager.complete_recovery(user, owner, new_owner, 20 + DELAY_NS),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.complete_recovery(new_owner, owner, new_owner, 19 + DELAY_NS),
+        Err(format!("recovery can be completed after {}", 20 + DELAY_NS))
+    );
+    assert_eq!(
+        key_manager.complete_recovery(new_owner, owner, new_owner, 20 + DELAY_NS),
+        Ok(())
+    );
+
+    assert_eq!(key_manager.resolve_owner(owner), new_owner);
+    assert_eq!(key_manager.get_recovered_principals(new_owner), vec![owner]);
+    assert_eq!(key_manager.get_recovery_guardians(owner), None);
+    assert_eq!(
+        key_manager.get_user_rights(new_owner, key_id, new_owner),
+        Ok(Some(AccessRights::ReadWriteManage))
+    );
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, owner),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.remove_user(new_owner, key_id, user),
+        Ok(Some(AccessRights::Read))
+    );
+    assert_eq!(
+        key_manager.set_user_rights(owner, key_id, user, AccessRights::Read),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.set_recovery_guardians(owner, guardians.clone(), 1),
+        Err("caller has been recovered or migrated".to_string())
+    );
+    assert!(key_manager
+        .get_encrypted_vetkey(new_owner, key_id, ByteBuf::from(vec![0; 48]))
+        .is_ok());
+    assert!(key_manager
+        .get_encrypted_vetkey(owner, key_id, ByteBuf::from(vec![0; 48]))
+        .is_err());
+}
+
+#[test]
+fn recovered_principals_are_found_along_chains_of_recoveries_and_migrations() {
+    let rng = &mut reproducible_rng();
+    let principals: Vec<_> = (0..4)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let guardian = random_self_authenticating_principal(rng);
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let mut key_manager = KeyManager::<AccessRights>::init(
+        "recovery_chains",
+        bls12_381_dfx_test_key(),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+    );
+    let enable_recovery = |key_manager: &mut KeyManager<AccessRights>, memory_predecessors| {
+        key_manager.enable_recovery(
+            RecoveryConfig {
+                recovery_delay_ns: 0,
+            },
+            memory_manager.get(MemoryId::new(3)),
+            memory_manager.get(MemoryId::new(4)),
+            memory_manager.get(MemoryId::new(5)),
+            memory_manager.get(MemoryId::new(memory_predecessors)),
+        )
+    };
+    enable_recovery(&mut key_manager, 6);
+    key_manager.enable_migrations(
+        memory_manager.get(MemoryId::new(7)),
+        memory_manager.get(MemoryId::new(8)),
+        memory_manager.get(MemoryId::new(10)),
+    );
+
+    // principals[0] -> principals[1] -> principals[2] by recovery
+    for (owner, new_owner) in [
+        (principals[0], principals[1]),
+        (principals[1], principals[2]),
+    ] {
+        key_manager
+            .set_recovery_guardians(owner, vec![guardian], 1)
+            .unwrap();
+        key_manager
+            .approve_recovery(guardian, owner, new_owner, 0)
+            .unwrap();
+        key_manager
+            .complete_recovery(guardian, owner, new_owner, 0)
+            .unwrap();
+    }
+    let mut recovered = vec![principals[0], principals[1]];
+    recovered.sort();
+    let sorted = |mut principals: Vec<Principal>| {
+        principals.sort();
+        principals
+    };
+    assert_eq!(
+        sorted(key_manager.get_recovered_principals(principals[2])),
+        recovered
+    );
+    // a recovered principal owns nothing
+    assert_eq!(key_manager.get_recovered_principals(principals[1]), vec![]);
+
+    // the reverse index is rebuilt if it is empty
+    enable_recovery(&mut key_manager, 9);
+    assert_eq!(
+        sorted(key_manager.get_recovered_principals(principals[2])),
+        recovered
+    );
+
+    // principals[2] -> principals[3] by migration
+    key_manager
+        .request_migration(principals[2], principals[3])
+        .unwrap();
+    key_manager
+        .confirm_migration(principals[3], principals[2])
+        .unwrap();
+    assert_eq!(
+        sorted(key_manager.get_recovered_principals(principals[3])),
+        recovered
+    );
+    assert_eq!(
+        key_manager.get_migrated_principals(principals[3]),
+        vec![principals[2]]
+    );
+    assert_eq!(key_manager.get_recovered_principals(principals[2]), vec![]);
+
+    // the reverse index of migrations is rebuilt if it is empty
+    key_manager.enable_migrations(
+        memory_manager.get(MemoryId::new(7)),
+        memory_manager.get(MemoryId::new(8)),
+        memory_manager.get(MemoryId::new(11)),
+    );
+    assert_eq!(
+        key_manager.get_migrated_principals(principals[3]),
+        vec![principals[2]]
+    );
+    assert_eq!(
+        sorted(key_manager.get_recovered_principals(principals[3])),
+        recovered
+    );
+}
+
+#[test]
+fn recovery_fails_if_not_enabled() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let guardian = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager(rng);
+
+    assert_eq!(
+        key_manager.set_recovery_guardians(owner, vec![guardian], 1),
+        Err("recovery is not enabled".to_string())
+    );
+    assert_eq!(key_manager.get_recovery_guardians(owner), None);
+    assert_eq!(key_manager.resolve_owner(owner), owner);
+}
+
+#[test]
+fn inbox_messages_can_be_deposited_by_anyone_and_read_by_key_readers() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let reader = random_self_authenticating_principal(rng);
+    let sender = random_self_authenticating_principal(rng);
+    let spammer = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let other_key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_invitations(
+            InvitationsConfig {
+                max_pending_invitations_per_recipient: 10,
+            },
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+    let ciphertext = random_bytebuf(rng, 1..64);
+
+    assert_eq!(
+        key_manager.deposit_inbox_message(sender, key_id, ciphertext.clone(), 1),
+        Err("inbox is not enabled".to_string())
+    );
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    key_manager.enable_inbox(
+        InboxConfig {
+            max_message_bytes: 64,
+            max_messages_per_inbox: 3,
+            max_pending_messages_per_sender: 2,
+        },
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+    );
+    key_manager
+        .set_user_rights(owner, key_id, reader, AccessRights::Read)
+        .unwrap();
+    key_manager.accept_invitation(reader, key_id).unwrap();
+
+    assert_eq!(
+        key_manager.deposit_inbox_message(Principal::anonymous(), key_id, ciphertext.clone(), 1),
+        Err("anonymous principal cannot deposit messages".to_string())
+    );
+    for invalid_ciphertext in [ByteBuf::new(), ByteBuf::from(vec![0; 65])] {
+        assert_eq!(
+            key_manager.deposit_inbox_message(sender, key_id, invalid_ciphertext, 1),
+            Err("message must be between 1 and 64 bytes".to_string())
+        );
+    }
+    assert_eq!(
+        key_manager.deposit_inbox_message(sender, key_id, ciphertext.clone(), 1),
+        Ok(0)
+    );
+    assert_eq!(
+        key_manager.deposit_inbox_message(sender, key_id, ciphertext.clone(), 2),
+        Ok(1)
+    );
+    assert_eq!(
+        key_manager.deposit_inbox_message(sender, other_key_id, ciphertext.clone(), 3),
+        Err("too many pending messages".to_string())
+    );
+    assert_eq!(
+        key_manager.deposit_inbox_message(spammer, key_id, ciphertext.clone(), 3),
+        Ok(2)
+    );
+    assert_eq!(
+        key_manager.deposit_inbox_message(spammer, key_id, ciphertext.clone(), 4),
+        Err("inbox is full".to_string())
+    );
+    key_manager.block_principal(owner, spammer).unwrap();
+    assert_eq!(
+        key_manager.deposit_inbox_message(spammer, other_key_id, ciphertext.clone(), 4),
+        Err("blocked by recipient".to_string())
+    );
+
+    assert_eq!(
+        key_manager
+            .get_inbox_messages_paginated(sender, key_id, None, 10)
+            .err(),
+        Some("unauthorized".to_string())
+    );
+    let page = key_manager
+        .get_inbox_messages_paginated(reader, key_id, None, 2)
+        .unwrap();
+    assert_eq!(
+        page.items,
+        vec![
+            (
+                0,
+                InboxMessage {
+                    sender,
+                    deposited_at_ns: 1,
+                    ciphertext: ciphertext.clone(),
+                }
+            ),
+            (
+                1,
+                InboxMessage {
+                    sender,
+                    deposited_at_ns: 2,
+                    ciphertext: ciphertext.clone(),
+                }
+            ),
+        ]
+    );
+    assert_eq!(page.next_cursor, Some(1));
+    let page = key_manager
+        .get_inbox_messages_paginated(owner, key_id, page.next_cursor, 2)
+        .unwrap();
+    assert_eq!(
+        page.items.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+        vec![2]
+    );
+    assert_eq!(page.next_cursor, None);
+
+    assert_eq!(
+        key_manager.remove_inbox_message(reader, key_id, 1),
+        Err("unauthorized".to_string())
+    );
+    assert!(key_manager
+        .remove_inbox_message(owner, key_id, 1)
+        .unwrap()
+        .is_some());
+    assert_eq!(key_manager.remove_inbox_message(owner, key_id, 1), Ok(None));
+    // removing a message frees space in the inbox and in the sender's quota,
+    // and message IDs are not reused
+    assert_eq!(
+        key_manager.deposit_inbox_message(sender, key_id, ciphertext, 5),
+        Ok(3)
+    );
+    let message_ids: Vec<u64> = key_manager
+        .get_inbox_messages_paginated(reader, key_id, None, 10)
+        .unwrap()
+        .items
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect();
+    assert_eq!(message_ids, vec![0, 2, 3]);
+}
+
+#[test]
+fn deleting_keys_removes_related_metadata() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let invitee = random_self_authenticating_principal(rng);
+    let sender = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_invitations(
+            InvitationsConfig {
+                max_pending_invitations_per_recipient: 10,
+            },
+            memory(),
+            memory(),
+            memory(),
+        );
+        key_manager.enable_public_keys(memory());
+        key_manager.enable_inbox(InboxConfig::default(), memory(), memory(), memory());
+        key_manager.enable_key_deletion(KeyDeletionConfig::default(), memory());
+    });
+
+    key_manager
+        .set_user_rights(owner, key_id, user, AccessRights::ReadWrite)
+        .unwrap();
+    key_manager.accept_invitation(user, key_id).unwrap();
+    key_manager
+        .set_user_rights(owner, key_id, invitee, AccessRights::Read)
+        .unwrap();
+    key_manager
+        .make_key_public(owner, key_id, AccessRights::Read)
+        .unwrap();
+    key_manager
+        .deposit_inbox_message(sender, key_id, random_bytebuf(rng, 1..64), 1)
+        .unwrap();
+
+    assert_eq!(
+        key_manager.delete_key(user, key_id, 2),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.delete_key(owner, key_id, 2),
+        Ok(vec![(user, AccessRights::ReadWrite)])
+    );
+
+    assert_eq!(
+        key_manager.get_key_tombstone(key_id),
+        Some(KeyTombstone {
+            deleted_by: owner,
+            deleted_at_ns: 2,
+        })
+    );
+    assert!(key_manager.access_control.is_empty());
+    assert!(key_manager.shared_keys.is_empty());
+    assert_eq!(key_manager.get_pending_invitations(invitee), vec![]);
+    assert_eq!(key_manager.get_public_access_rights(key_id), None);
+    let inbox = key_manager.inbox.as_ref().unwrap();
+    assert!(inbox.messages.is_empty());
+    assert!(inbox.pending_messages_per_sender.is_empty());
+    for caller in [owner, user, Principal::anonymous()] {
+        assert_eq!(
+            key_manager.ensure_user_can_read(caller, key_id),
+            Err("vetKey was deleted".to_string())
+        );
+    }
+    assert!(key_manager
+        .get_encrypted_vetkey(owner, key_id, ByteBuf::from(vec![0; 48]))
+        .is_err());
+    assert_eq!(
+        key_manager.deposit_inbox_message(sender, key_id, random_bytebuf(rng, 1..64), 3),
+        Err("vetKey was deleted".to_string())
+    );
+}
+
+#[test]
+fn public_keys_can_be_read_by_anyone() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let manager = random_self_authenticating_principal(rng);
+    let writer = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let other_key_id = (owner, random_name(rng));
+    let mut key_manager = random_key_manager(rng);
+
+    assert_eq!(
+        key_manager.make_key_public(owner, key_id, AccessRights::Read),
+        Err("public keys are not enabled".to_string())
+    );
+    key_manager.enable_public_keys(
+        MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0)),
+    );
+    for (user, access_rights) in [
+        (manager, AccessRights::ReadWriteManage),
+        (writer, AccessRights::ReadWrite),
+    ] {
+        key_manager
+            .set_user_rights(owner, key_id, user, access_rights)
+            .unwrap();
+    }
+
+    assert_eq!(
+        key_manager.make_key_public(manager, key_id, AccessRights::Read),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.make_key_public(owner, key_id, AccessRights::ReadWrite),
+        Err("public access rights must be read-only".to_string())
+    );
+    assert_eq!(
+        key_manager.make_key_public(owner, key_id, AccessRights::Read),
+        Ok(None)
+    );
+    assert_eq!(
+        key_manager.make_key_public(owner, other_key_id, AccessRights::Read),
+        Ok(None)
+    );
+
+    for user in [
+        Principal::anonymous(),
+        random_self_authenticating_principal(rng),
+    ] {
+        assert_eq!(
+            key_manager.ensure_user_can_read(user, key_id),
+            Ok(AccessRights::Read)
+        );
+        assert_eq!(
+            key_manager.ensure_user_can_write(user, key_id),
+            Err("unauthorized".to_string())
+        );
+        assert_eq!(
+            key_manager.get_user_rights(user, key_id, writer),
+            Err("unauthorized".to_string())
+        );
+        assert!(key_manager
+            .get_encrypted_vetkey(user, key_id, ByteBuf::from(vec![0; 48]))
+            .is_ok());
+    }
+    // shared access rights are kept
+    assert_eq!(
+        key_manager.ensure_user_can_write(writer, key_id),
+        Ok(AccessRights::ReadWrite)
+    );
+
+    let mut public_key_ids = [key_id, other_key_id];
+    public_key_ids.sort();
+    let first_page = key_manager.get_public_key_ids_paginated(None, 1);
+    assert_eq!(
+        first_page.items,
+        vec![(public_key_ids[0], AccessRights::Read)]
+    );
+    assert_eq!(first_page.next_cursor, Some(public_key_ids[0]));
+    let second_page = key_manager.get_public_key_ids_paginated(first_page.next_cursor, 1);
+    assert_eq!(
+        second_page.items,
+        vec![(public_key_ids[1], AccessRights::Read)]
+    );
+    assert_eq!(second_page.next_cursor, None);
+
+    assert_eq!(
+        key_manager.make_key_private(owner, key_id),
+        Ok(Some(AccessRights::Read))
+    );
+    assert_eq!(key_manager.get_public_access_rights(key_id), None);
+    assert_eq!(
+        key_manager.ensure_user_can_read(Principal::anonymous(), key_id),
+        Err("unauthorized".to_string())
+    );
+}
+
+#[test]
+fn migration_moves_owned_keys_and_shares() {
+    let rng = &mut reproducible_rng();
+    let old_principal = random_self_authenticating_principal(rng);
+    let new_principal = random_self_authenticating_principal(rng);
+    let sharer = random_self_authenticating_principal(rng);
+    let user = random_self_authenticating_principal(rng);
+    let owned_key_id = (old_principal, random_name(rng));
+    let shared_key_id = (sharer, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_migrations(memory(), memory(), memory())
+    });
+    key_manager
+        .set_user_rights(old_principal, owned_key_id, user, AccessRights::Read)
+        .unwrap();
+    key_manager
+        .set_user_rights(
+            sharer,
+            shared_key_id,
+            old_principal,
+            AccessRights::ReadWrite,
+        )
+        .unwrap();
+
+    assert_eq!(
+        key_manager.request_migration(old_principal, old_principal),
+        Err("cannot migrate to oneself".to_string())
+    );
+    assert_eq!(
+        key_manager.confirm_migration(new_principal, old_principal),
+        Err("no pending migration".to_string())
+    );
+    assert_eq!(
+        key_manager.request_migration(old_principal, new_principal),
+        Ok(())
+    );
+    assert_eq!(
+        key_manager.get_pending_migration(old_principal),
+        Some(new_principal)
+    );
+    // only the requested principal can confirm
+    assert_eq!(
+        key_manager.confirm_migration(user, old_principal),
+        Err("no pending migration".to_string())
+    );
+    assert_eq!(
+        key_manager.confirm_migration(new_principal, old_principal),
+        Ok(vec![shared_key_id])
+    );
+
+    assert_eq!(
+        key_manager.get_migrated_principal(old_principal),
+        Some(new_principal)
+    );
+    assert_eq!(key_manager.get_pending_migration(old_principal), None);
+    assert_eq!(
+        key_manager.get_migrated_principals(new_principal),
+        vec![old_principal]
+    );
+    assert_eq!(
+        key_manager.get_user_rights(new_principal, owned_key_id, user),
+        Ok(Some(AccessRights::Read))
+    );
+    assert_eq!(
+        key_manager.get_user_rights(new_principal, owned_key_id, new_principal),
+        Ok(Some(AccessRights::ReadWriteManage))
+    );
+    assert_eq!(
+        key_manager.get_user_rights(old_principal, owned_key_id, user),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.get_accessible_shared_key_ids(new_principal),
+        vec![shared_key_id]
+    );
+    assert_eq!(
+        key_manager.get_accessible_shared_key_ids(old_principal),
+        vec![]
+    );
+    assert_eq!(
+        key_manager.get_shared_user_access_for_key(sharer, shared_key_id),
+        Ok(vec![(new_principal, AccessRights::ReadWrite)])
+    );
+    assert_eq!(
+        key_manager.request_migration(old_principal, user),
+        Err("caller has been recovered or migrated".to_string())
+    );
+    assert_eq!(
+        key_manager.request_migration(user, old_principal),
+        Err("invalid new principal".to_string())
+    );
+}
+
+#[test]
+fn migration_keeps_the_higher_access_rights() {
+    let rng = &mut reproducible_rng();
+    let old_principal = random_self_authenticating_principal(rng);
+    let new_principal = random_self_authenticating_principal(rng);
+    let sharer = random_self_authenticating_principal(rng);
+    let upgraded_key_id = (sharer, random_name(rng));
+    let kept_key_id = (sharer, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_migrations(memory(), memory(), memory())
+    });
+    for (key_id, old_rights, new_rights) in [
+        (upgraded_key_id, AccessRights::ReadWrite, AccessRights::Read),
+        (kept_key_id, AccessRights::Read, AccessRights::ReadWrite),
+    ] {
+        key_manager
+            .set_user_rights(sharer, key_id, old_principal, old_rights)
+            .unwrap();
+        key_manager
+            .set_user_rights(sharer, key_id, new_principal, new_rights)
+            .unwrap();
+    }
+
+    key_manager
+        .request_migration(old_principal, new_principal)
+        .unwrap();
+    assert_eq!(
+        key_manager.confirm_migration(new_principal, old_principal),
+        Ok(vec![upgraded_key_id])
+    );
+
+    assert_eq!(
+        key_manager.get_user_rights(sharer, upgraded_key_id, new_principal),
+        Ok(Some(AccessRights::ReadWrite))
+    );
+    assert_eq!(
+        key_manager.get_user_rights(sharer, kept_key_id, new_principal),
+        Ok(Some(AccessRights::ReadWrite))
+    );
+    for key_id in [upgraded_key_id, kept_key_id] {
+        assert_eq!(
+            key_manager.get_user_rights(sharer, key_id, old_principal),
+            Ok(None)
+        );
+    }
+}
+
+#[test]
+fn migration_moves_pending_invitations() {
+    let rng = &mut reproducible_rng();
+    let old_principal = random_self_authenticating_principal(rng);
+    let new_principal = random_self_authenticating_principal(rng);
+    let sharer = random_self_authenticating_principal(rng);
+    let blocked_sharer = random_self_authenticating_principal(rng);
+    let key_id = (sharer, random_name(rng));
+    let blocked_key_id = (blocked_sharer, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_invitations(
+            InvitationsConfig {
+                max_pending_invitations_per_recipient: 10,
+            },
+            memory(),
+            memory(),
+            memory(),
+        );
+        key_manager.enable_migrations(memory(), memory(), memory());
+    });
+    for key_id in [key_id, blocked_key_id] {
+        key_manager
+            .set_user_rights(key_id.0, key_id, old_principal, AccessRights::Read)
+            .unwrap();
+    }
+    key_manager
+        .block_principal(new_principal, blocked_sharer)
+        .unwrap();
+
+    key_manager
+        .request_migration(old_principal, new_principal)
+        .unwrap();
+    assert_eq!(
+        key_manager.confirm_migration(new_principal, old_principal),
+        Ok(vec![])
+    );
+
+    assert_eq!(key_manager.get_pending_invitations(old_principal), vec![]);
+    assert_eq!(
+        key_manager.get_pending_invitations(new_principal),
+        vec![(key_id, AccessRights::Read, sharer)]
+    );
+    assert_eq!(
+        key_manager.get_pending_invitations_for_key(blocked_sharer, blocked_key_id),
+        Ok(vec![])
+    );
+    assert_eq!(
+        key_manager.accept_invitation(new_principal, key_id),
+        Ok(AccessRights::Read)
+    );
+    assert_eq!(
+        key_manager.get_user_rights(sharer, key_id, new_principal),
+        Ok(Some(AccessRights::Read))
+    );
+}
+
+#[test]
+fn beneficiaries_inherit_rights_after_inactivity() {
+    const MIN_PERIOD_NS: u64 = 100;
+    const PERIOD_NS: u64 = 1_000;
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let beneficiary = random_self_authenticating_principal(rng);
+    let other_owner = random_self_authenticating_principal(rng);
+    let key_id = (owner, random_name(rng));
+    let other_key_id = (other_owner, random_name(rng));
+    let mut key_manager = random_key_manager_with(rng, |key_manager, memory| {
+        key_manager.enable_inheritance(
+            InheritanceConfig {
+                min_inactivity_period_ns: MIN_PERIOD_NS,
+            },
+            memory(),
+            memory(),
+            memory(),
+        )
+    });
+
+    assert_eq!(
+        key_manager.add_inheritance_grant(owner, key_id, beneficiary, AccessRights::Read),
+        Err("no inheritance plan".to_string())
+    );
+    assert_eq!(
+        key_manager.set_inheritance_plan(owner, MIN_PERIOD_NS - 1, 0),
+        Err(format!(
+            "inactivity period must be at least {MIN_PERIOD_NS} ns"
+        ))
+    );
+    assert_eq!(
+        key_manager.set_inheritance_plan(owner, PERIOD_NS, 0),
+        Ok(())
+    );
+    assert_eq!(
+        key_manager.add_inheritance_grant(owner, other_key_id, beneficiary, AccessRights::Read),
+        Err("unauthorized".to_string())
+    );
+    assert_eq!(
+        key_manager.add_inheritance_grant(owner, key_id, owner, AccessRights::Read),
+        Err("cannot inherit from oneself".to_string())
+    );
+    assert_eq!(
+        key_manager.add_inheritance_grant(owner, key_id, beneficiary, AccessRights::Read),
+        Ok(None)
+    );
+    assert_eq!(
+        key_manager.add_inheritance_grant(owner, key_id, beneficiary, AccessRights::ReadWrite),
+        Ok(Some(AccessRights::Read))
+    );
+    assert_eq!(
+        key_manager.get_inheritance_grants(owner),
+        vec![(key_id, beneficiary, AccessRights::ReadWrite)]
+    );
+    assert_eq!(key_manager.next_inheritance_due_ns(), Some(PERIOD_NS));
+
+    // activity postpones the inheritance
+    key_manager.record_activity(owner, 500);
+    assert_eq!(
+        key_manager.get_inheritance_plan(owner),
+        Some(InheritancePlan {
+            inactivity_period_ns: PERIOD_NS,
+            last_activity_ns: 500,
+        })
+    );
+    assert_eq!(key_manager.execute_inheritance(PERIOD_NS), vec![]);
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, beneficiary),
+        Ok(None)
+    );
+
+    assert_eq!(
+        key_manager.execute_inheritance(500 + PERIOD_NS),
+        vec![owner]
+    );
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, beneficiary),
+        Ok(Some(AccessRights::ReadWrite))
+    );
+    // an executed plan is removed
+    assert_eq!(key_manager.get_inheritance_plan(owner), None);
+    assert_eq!(key_manager.get_inheritance_grants(owner), vec![]);
+    assert_eq!(key_manager.next_inheritance_due_ns(), None);
+
+    // a cancelled plan is never executed
+    key_manager
+        .set_inheritance_plan(owner, PERIOD_NS, 0)
+        .unwrap();
+    key_manager
+        .add_inheritance_grant(owner, key_id, other_owner, AccessRights::Read)
+        .unwrap();
+    assert_eq!(key_manager.cancel_inheritance_plan(owner), Ok(()));
+    assert_eq!(
+        key_manager.cancel_inheritance_plan(owner),
+        Err("no inheritance plan".to_string())
+    );
+    assert_eq!(key_manager.execute_inheritance(u64::MAX), vec![]);
+    assert_eq!(
+        key_manager.get_user_rights(owner, key_id, other_owner),
+        Ok(None)
+    );
+}
+
+#[test]
+fn inheritance_plans_are_executed_in_order_of_due_time() {
+    let rng = &mut reproducible_rng();
+    let owners: Vec<_> = (0..3)
+        .map(|_| random_self_authenticating_principal(rng))
+        .collect();
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let mut key_manager = KeyManager::<AccessRights>::init(
+        "inheritance_due_plans",
+        bls12_381_dfx_test_key(),
+        memory_manager.get(MemoryId::new(0)),
+        memory_manager.get(MemoryId::new(1)),
+        memory_manager.get(MemoryId::new(2)),
+    );
+    let enable_inheritance = |key_manager: &mut KeyManager<AccessRights>, memory_due_plans| {
+        key_manager.enable_inheritance(
+            InheritanceConfig {
+                min_inactivity_period_ns: 0,
+            },
+            memory_manager.get(MemoryId::new(3)),
+            memory_manager.get(MemoryId::new(4)),
+            memory_manager.get(MemoryId::new(memory_due_plans)),
+        )
+    };
+    enable_inheritance(&mut key_manager, 5);
+
+    // due at 300, 100, and 200, respectively
+    for (owner, period_ns) in owners.iter().zip([300, 100, 200]) {
+        key_manager
+            .set_inheritance_plan(*owner, period_ns, 0)
+            .unwrap();
+    }
+    assert_eq!(key_manager.next_inheritance_due_ns(), Some(100));
+    // a shorter period moves the plan forward
+    key_manager.set_inheritance_plan(owners[2], 50, 0).unwrap();
+    assert_eq!(key_manager.next_inheritance_due_ns(), Some(50));
+    // activity moves the plan back
+    key_manager.record_activity(owners[2], 500);
+    assert_eq!(key_manager.next_inheritance_due_ns(), Some(100));
+
+    // the due-time index is rebuilt if it is empty
+    enable_inheritance(&mut key_manager, 6);
+    assert_eq!(key_manager.next_inheritance_due_ns(), Some(100));
+
+    assert_eq!(key_manager.execute_inheritance(99), vec![]);
+    assert_eq!(
+        key_manager.execute_inheritance(300),
+        vec![owners[1], owners[0]]
+    );
+    assert_eq!(key_manager.next_inheritance_due_ns(), Some(550));
+    assert_eq!(key_manager.execute_inheritance(549), vec![]);
+    assert_eq!(key_manager.execute_inheritance(550), vec![owners[2]]);
+    assert_eq!(key_manager.next_inheritance_due_ns(), None);
+}
+
+#[test]
+fn inheritance_fails_if_not_enabled() {
+    let rng = &mut reproducible_rng();
+    let owner = random_self_authenticating_principal(rng);
+    let mut key_manager = random_key_manager(rng);
+
+    assert_eq!(
+        key_manager.set_inheritance_plan(owner, 1_000, 0),
+        Err("inheritance is not enabled".to_string())
+    );
+    key_manager.record_activity(owner, 0);
+    assert_eq!(key_manager.execute_inheritance(u64::MAX), vec![]);
+}
+
+fn random_key_manager<R: Rng + CryptoRng>(rng: &mut R) -> KeyManager<AccessRights> {
+    random_key_manager_with(rng, |_key_manager, _memory| {})
+}
+
+/// Creates a key manager with random memory IDs and calls `enable` on it,
+/// e.g., to enable an optional feature. Each call of `memory` returns a
+/// memory with an ID not used by any other memory of the key manager.
+fn random_key_manager_with<R: Rng + CryptoRng>(
+    rng: &mut R,
+    enable: impl FnOnce(&mut KeyManager<AccessRights>, &mut dyn FnMut() -> Memory),
+) -> KeyManager<AccessRights> {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let (_memory_id_encrypted_maps, memory_ids_key_manager) = random_unique_memory_ids(rng);
+    let domain_separator_len = rng.gen_range(0..32);
+    let mut key_manager = KeyManager::<AccessRights>::init(
+        &random_utf8_string(rng, domain_separator_len),
+        bls12_381_dfx_test_key(),
+        memory_manager.get(MemoryId::new(memory_ids_key_manager[0])),
+        memory_manager.get(MemoryId::new(memory_ids_key_manager[1])),
+        memory_manager.get(MemoryId::new(memory_ids_key_manager[2])),
+    );
+    let mut unused_memory_ids = (0..u8::MAX).filter(|id| !memory_ids_key_manager.contains(id));
+    enable(&mut key_manager, &mut || {
+        memory_manager.get(MemoryId::new(unused_memory_ids.next().unwrap()))
+    });
+    key_manager
 }
 
 fn bls12_381_dfx_test_key() -> VetKDKeyId {
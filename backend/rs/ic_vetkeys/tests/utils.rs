@@ -146,7 +146,7 @@ fn test_derivation_using_pocketic_keys() {
     let canister_id = candid::Principal::from_text("uzt4z-lp777-77774-qaabq-cai").unwrap();
 
     for (key_id, expected) in &test_vectors {
-        let context = format!("Test Derivation For PocketIC VetKD {}", key_id);
+        let context = format!("Test Derivation For PocketIC VetKD {key_id}");
 
         let key_id = VetKDKeyId {
             curve: VetKDCurve::Bls12_381_G2,
@@ -223,6 +223,43 @@ fn protocol_flow_with_emulated_server_side() {
     assert_eq!(ptext, msg);
 }
 
+#[test]
+fn ibe_identity_for_key_id_can_be_decrypted_with_the_vetkey_of_the_key_id() {
+    let mut rng = reproducible_rng();
+
+    let owner = random_self_authenticating_principal(&mut rng);
+    let key_name = b"inbox";
+    let identity = IbeIdentity::for_key_id(&owner, key_name);
+    let vetkd_input = ic_vetkeys::key_manager::key_id_to_vetkd_input(owner, key_name);
+    assert_eq!(identity.value(), vetkd_input.as_slice());
+
+    let derivation_context = DerivationContext::new(b"canister-id", b"domain separator");
+    let tsk = TransportSecretKey::from_seed(rng.gen::<[u8; 32]>().to_vec()).unwrap();
+    let tpk_bytes: [u8; 48] = tsk.public_key().try_into().unwrap();
+    let tpk = G1Affine::from_compressed(&tpk_bytes).unwrap();
+    let master_sk = random_scalar(&mut rng);
+    let master_pk = G2Affine::from(G2Affine::generator() * master_sk);
+    let (derived_public_key, _delta) = derivation_context.derive_key(&master_pk);
+    let dpk = DerivedPublicKey::deserialize(&derived_public_key.to_compressed()).unwrap();
+
+    let msg = rng.gen::<[u8; 32]>().to_vec();
+    let ctext = IbeCiphertext::encrypt(&dpk, &identity, &msg, &IbeSeed::random(&mut rng));
+
+    let ek_bytes = create_encrypted_key(
+        &mut rng,
+        &master_pk,
+        &master_sk,
+        &tpk,
+        &derivation_context,
+        &vetkd_input,
+    );
+    let vetkey = EncryptedVetKey::deserialize(&ek_bytes)
+        .unwrap()
+        .decrypt_and_verify(&tsk, &dpk, &vetkd_input)
+        .unwrap();
+    assert_eq!(ctext.decrypt(&vetkey), Ok(msg));
+}
+
 #[test]
 fn derivation_matches_expected_value() {
     let vetkey = VetKey::deserialize(&hex::decode("ad19676dd92f116db11f326ff0822f295d87cc00cf65d9f132b5a618bb7381e5b0c3cb814f15e4a0f015359dcfa8a1da").unwrap()).unwrap();
@@ -359,7 +396,7 @@ fn aes_gcm_encryption() {
     for i in 0..ctext.len() * 8 {
         let mod_ctext = {
             let mut m = ctext.clone();
-            m[i / 8] ^= 0x80 >> i % 8;
+            m[i / 8] ^= 0x80 >> (i % 8);
             m
         };
 
@@ -370,8 +407,8 @@ fn aes_gcm_encryption() {
 
     for i in 0..aad.len() * 8 {
         let mod_aad = {
-            let mut a = aad.clone();
-            a[i / 8] ^= 0x80 >> i % 8;
+            let mut a = *aad;
+            a[i / 8] ^= 0x80 >> (i % 8);
             a
         };
 